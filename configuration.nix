@@ -3,6 +3,47 @@ self:
 with lib;
 let
   cfg = config.services.nyanpasswd;
+  # Dovecot talks to the API over the socket instead, if there is one
+  dovecot2Socket = cfg.dovecot2.enable && cfg.apiSocket.enable;
+  # Integrations that use the API, each registered as an API consumer with its own token
+  consumers = lib.filter (consumer: consumer.enable) [
+    {
+      name = "dovecot2";
+      enable = cfg.dovecot2.enable && !dovecot2Socket;
+      inherit (cfg.dovecot2) tokenFile;
+      group = config.services.dovecot2.group;
+      permissions = [ "authenticate" "lookup_users" ];
+    }
+    {
+      name = "radicale";
+      inherit (cfg.radicale) enable tokenFile;
+      group = "radicale";
+      permissions = [ "authenticate" ];
+    }
+  ];
+  settingsFile = (pkgs.formats.toml {}).generate "nyanpasswd.toml" {
+    mail.domains = cfg.mailDomains;
+    api = {
+      tcp = cfg.tcpApi;
+      consumers = map (consumer: {
+        inherit (consumer) name permissions;
+        token_file = "/run/credentials/nyanpasswd.service/${consumer.name}-token";
+      }) consumers;
+    } // lib.optionalAttrs cfg.apiSocket.enable {
+      socket = cfg.apiSocket.path;
+      socket_group = cfg.apiSocket.group;
+      socket_peers = lib.optional dovecot2Socket "user:${config.services.dovecot2.user}=authenticate,lookup_users"
+        ++ cfg.apiSocket.peers;
+    };
+  };
+  tokenFileOption = name: mkOption {
+    type = types.str;
+    default = "/var/lib/nyanpasswd-tokens/${name}";
+    description = mdDoc ''
+      File holding the API token ${name} uses to talk to nyanpasswd. A
+      random token is generated if the file doesn't exist.
+    '';
+  };
 in {
   imports = [
     ./autoconfig.nix
@@ -45,6 +86,18 @@ in {
           automatically configured.
         '';
       };
+      mailDomains = mkOption {
+        type = types.listOf types.str;
+        default = [ cfg.domain ];
+        defaultText = literalExpression "[ config.services.nyanpasswd.domain ]";
+        example = [ "nyantec.com" ];
+        description = mdDoc ''
+          Domains users and aliases are valid on, registered on
+          startup. Integrations may authenticate users by their
+          address on these domains. More can be added in the admin
+          dashboard.
+        '';
+      };
       rootCACertificate = mkOption {
         type = types.either types.str types.path;
         example = "/var/lib/nyantec-crl/nyantec_Root_CA.pem";
@@ -79,6 +132,44 @@ in {
           to autoconfigure.
         '';
       };
+      tcpApi = mkOption {
+        type = types.bool;
+        default = true;
+        description = mdDoc ''
+          Whether to serve the API on the TCP listener, next to the
+          dashboard. Can be disabled if `apiSocket` is enabled and
+          every integration uses it.
+        '';
+      };
+      apiSocket = {
+        enable = mkEnableOption "serving the API on a Unix socket, which Dovecot then uses instead of a token";
+        path = mkOption {
+          type = types.str;
+          default = "/run/nyanpasswd/api.sock";
+          description = mdDoc ''
+            Path of the API socket.
+          '';
+        };
+        group = mkOption {
+          type = types.str;
+          default = config.services.dovecot2.group;
+          defaultText = literalExpression "config.services.dovecot2.group";
+          description = mdDoc ''
+            Group owning the API socket. Only the owner and this group
+            can connect to it.
+          '';
+        };
+        peers = mkOption {
+          type = types.listOf types.str;
+          default = [];
+          example = [ "group:postfix=lookup_aliases" ];
+          description = mdDoc ''
+            Rules granting API permissions to local users or groups
+            connecting to the socket, in addition to the one for
+            Dovecot. See `api.socket_peers` in the README.
+          '';
+        };
+      };
       dovecot2.enable = mkEnableOption "integration with Dovecot";
       dovecot2.mailhome = mkOption {
         type = types.str;
@@ -88,11 +179,13 @@ in {
           The location to store mail data of virtual users managed by nyanpasswd in.
         '';
       };
+      dovecot2.tokenFile = tokenFileOption "dovecot2";
       postfix = {
         enable = mkEnableOption "integration with Postfix";
       };
       radicale = {
         enable = mkEnableOption "integration with Radicale";
+        tokenFile = tokenFileOption "radicale";
       };
     };
   };
@@ -107,6 +200,7 @@ in {
           extraConfig = ''
             proxy_set_header X-SSL-Verify $ssl_client_verify;
             proxy_set_header X-SSL-Client-Dn $ssl_client_s_dn;
+            proxy_set_header X-SSL-Client-Cert $ssl_client_escaped_cert;
           '';
         };
         locations."/api" = {
@@ -129,8 +223,10 @@ in {
         serviceConfig = {
           ExecStart = "${pkgs.nyanpasswd}/bin/nyanpasswd";
           User = lib.mkIf (cfg.user != null) cfg.user;
+          LoadCredential = map (consumer: "${consumer.name}-token:${consumer.tokenFile}") consumers;
         };
         environment = {
+          NYANPASSWD_CONFIG = settingsFile;
           DATABASE_URL = if (cfg.databaseUri == null)
                          then
                            "postgres://localhost?dbname=mailpasswd&host=/run/postgresql"
@@ -140,6 +236,34 @@ in {
         };
       };
     })
+    (lib.mkIf (cfg.enable && cfg.apiSocket.enable) {
+      systemd.services.nyanpasswd.serviceConfig = {
+        RuntimeDirectory = "nyanpasswd";
+        # Lets the service hand the socket to its group
+        SupplementaryGroups = [ cfg.apiSocket.group ];
+      };
+    })
+    (lib.mkIf (cfg.enable && consumers != []) {
+      systemd.services.nyanpasswd-tokens = {
+        description = "Generate API tokens for nyanpasswd consumers";
+        before = [ "nyanpasswd.service" "dovecot2.service" "radicale.service" ];
+        requiredBy = [ "nyanpasswd.service" ];
+        serviceConfig = {
+          Type = "oneshot";
+          RemainAfterExit = true;
+        };
+        script = lib.concatMapStrings (consumer: let
+          file = lib.escapeShellArg consumer.tokenFile;
+        in ''
+          if [ ! -s ${file} ]; then
+            mkdir -p "$(dirname ${file})"
+            (umask 077; tr -dc A-Za-z0-9 < /dev/urandom | head -c 64 > ${file})
+          fi
+          chown root:${consumer.group} ${file}
+          chmod 0440 ${file}
+        '') consumers;
+      };
+    })
     (lib.mkIf (cfg.enable && cfg.user == null) {
       users.users.mailpasswd = {
         isSystemUser = true;
@@ -152,6 +276,10 @@ in {
     })
     (lib.mkIf (cfg.enable && cfg.dovecot2.enable) {
       assertions = [
+        {
+          assertion = dovecot2Socket || cfg.tcpApi;
+          message = "services.nyanpasswd.dovecot2 needs either services.nyanpasswd.apiSocket or services.nyanpasswd.tcpApi";
+        }
         {
           assertion = let
             getLastChar = s: lib.strings.substring
@@ -172,16 +300,26 @@ in {
         makeLuaPath = subDir: paths: concatStringsSep ";" (map (path: path + "/" + subDir) (filter (x: x != null) paths));
         packages = with pkgs.lua53Packages; [
           rapidjson
+          luasocket
         ];
-        luaPath = (makeLuaPath "lib/lua/5.3/?.lua" packages);
+        luaPath = (makeLuaPath "lib/lua/5.3/?.lua" packages) + ";" + (makeLuaPath "share/lua/5.3/?.lua" packages);
         luaCPath = (makeLuaPath "lib/lua/5.3/?.so" packages);
         userdb = pkgs.substituteAll {
           src = ./userdb.lua;
           lua_path = luaPath;
           lua_cpath = luaCPath;
           mailhome = cfg.dovecot2.mailhome;
+          token_file = cfg.dovecot2.tokenFile;
+          api_socket = if dovecot2Socket then cfg.apiSocket.path else "";
         };
       in ''
+        ${lib.optionalString dovecot2Socket ''
+          # The Lua scripts run in the auth worker, which must run as the user the socket lets in
+          service auth-worker {
+            user = $default_internal_user
+            group = $default_internal_group
+          }
+        ''}
         userdb {
           driver = lua
           args = file=${userdb}
@@ -236,11 +374,18 @@ in {
         virtual_alias_maps = "pgsql:${pkgs.writeText "postfix-nyanpasswd-aliases.cf" ''
           hosts = postgresql:///mailpasswd?host=/run/postgresql
           dbname = mailpasswd
-          query = SELECT userdb.username FROM mailpasswd.userdb INNER JOIN mailpasswd.aliases ON userdb.id = aliases.destination WHERE alias_name = '%u'
+          # Follows the same matching rules as the API, including patterns and subaddresses
+          query = SELECT * FROM mailpasswd.match_alias('%u', '%d')
         ''}";
       };
     })
     (lib.mkIf (cfg.enable && cfg.radicale.enable) {
+      assertions = [
+        {
+          assertion = cfg.tcpApi;
+          message = "services.nyanpasswd.radicale needs services.nyanpasswd.tcpApi";
+        }
+      ];
       services.radicale = {
         enable = true;
         package = pkgs.radicale.overrideAttrs (old: {
@@ -252,6 +397,7 @@ in {
           auth = {
             type = "radicale_mail_passwd_auth";
             mail_passwd_uri = "http://localhost:3000";
+            mail_passwd_token_file = cfg.radicale.tokenFile;
           };
         };
       };
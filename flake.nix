@@ -91,6 +91,7 @@
       pkgs = (nixpkgsFor.${system});
     in {
       nixos-test = nixpkgs.lib.nixos.runTest (import ./nixos-test.nix self pkgs);
+      nixos-test-socket = nixpkgs.lib.nixos.runTest (import ./nixos-test-socket.nix self pkgs);
     });
 
     devShells = forAllSystems (system: {
@@ -0,0 +1,8 @@
+CREATE TABLE mailpasswd.domains (
+	   name VARCHAR(253) NOT NULL PRIMARY KEY CHECK (name != '' AND name = lower(name))
+);
+
+ALTER TABLE mailpasswd.aliases ADD COLUMN domain VARCHAR(253) REFERENCES mailpasswd.domains(name);
+ALTER TABLE mailpasswd.aliases DROP CONSTRAINT aliases_alias_name_destination_key;
+CREATE UNIQUE INDEX aliases_unscoped_key ON mailpasswd.aliases (alias_name, destination) WHERE domain IS NULL;
+CREATE UNIQUE INDEX aliases_scoped_key ON mailpasswd.aliases (alias_name, domain, destination) WHERE domain IS NOT NULL;
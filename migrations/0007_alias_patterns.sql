@@ -0,0 +1,3 @@
+-- Aliases ending with `*` are prefix patterns, a lone `*` is a catch-all.
+-- Wildcards are not allowed anywhere else.
+ALTER TABLE mailpasswd.aliases ADD CONSTRAINT aliases_alias_name_pattern_check CHECK (strpos(alias_name, '*') IN (0, length(alias_name)));
@@ -0,0 +1,12 @@
+-- Aliases created by users themselves have an owner, and always point to them.
+ALTER TABLE mailpasswd.aliases ADD COLUMN owner UUID REFERENCES mailpasswd.userdb(id);
+ALTER TABLE mailpasswd.aliases ADD COLUMN enabled BOOLEAN NOT NULL DEFAULT true;
+ALTER TABLE mailpasswd.aliases ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+ALTER TABLE mailpasswd.aliases ADD CONSTRAINT aliases_owner_check CHECK (owner IS NULL OR (owner = destination AND domain IS NULL));
+
+ALTER TABLE mailpasswd.userdb ADD COLUMN alias_limit INTEGER NOT NULL DEFAULT 0 CHECK (alias_limit >= 0);
+
+-- Admin-approved templates for self-service alias names.
+CREATE TABLE mailpasswd.alias_templates (
+	   template VARCHAR(64) NOT NULL PRIMARY KEY CHECK (strpos(template, '<word>') > 0 AND strpos(template, '*') = 0)
+);
@@ -0,0 +1,9 @@
+-- Settings shared by all entries of an alias.
+CREATE TABLE mailpasswd.alias_info (
+	   alias_name VARCHAR(64) NOT NULL CHECK (alias_name != ''),
+	   domain VARCHAR(253) REFERENCES mailpasswd.domains(name),
+	   description TEXT NOT NULL DEFAULT '',
+	   self_removable BOOLEAN NOT NULL DEFAULT false
+);
+CREATE UNIQUE INDEX alias_info_unscoped_key ON mailpasswd.alias_info (alias_name) WHERE domain IS NULL;
+CREATE UNIQUE INDEX alias_info_scoped_key ON mailpasswd.alias_info (alias_name, domain) WHERE domain IS NOT NULL;
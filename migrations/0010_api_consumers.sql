@@ -0,0 +1,11 @@
+-- Services allowed to use the API, e.g. Dovecot or Radicale.
+CREATE TABLE mailpasswd.api_consumers (
+	   id UUID NOT NULL PRIMARY KEY DEFAULT gen_random_uuid(),
+	   name VARCHAR(64) NOT NULL CHECK (name != '') UNIQUE,
+	   -- SHA-256 of the bearer token, hex-encoded. Tokens are random, so a fast hash is enough.
+	   token_hash CHAR(64) UNIQUE,
+	   -- Subject DN of the TLS client certificate this consumer may use instead of a token.
+	   cert_dn TEXT CHECK (cert_dn != '') UNIQUE,
+	   permissions TEXT[] NOT NULL DEFAULT '{}' CHECK (permissions <@ ARRAY['authenticate', 'lookup_users', 'list_users', 'lookup_aliases']),
+	   created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,17 @@
+-- TLS client certificates users logged into the dashboard with.
+CREATE TABLE mailpasswd.client_certificates (
+	   userid UUID NOT NULL REFERENCES mailpasswd.userdb(id) ON DELETE CASCADE,
+	   -- SHA-256 of the DER-encoded certificate, formatted like `openssl x509 -fingerprint -sha256`.
+	   fingerprint CHAR(95) NOT NULL,
+	   serial TEXT NOT NULL,
+	   subject TEXT NOT NULL,
+	   pinned BOOLEAN NOT NULL DEFAULT false,
+	   -- Revoked certificates can't be used for the dashboard, regardless of CRLs.
+	   revoked BOOLEAN NOT NULL DEFAULT false,
+	   first_seen TIMESTAMPTZ NOT NULL DEFAULT now(),
+	   last_seen TIMESTAMPTZ NOT NULL DEFAULT now(),
+	   PRIMARY KEY (userid, fingerprint)
+);
+
+-- Only let pinned certificates into the dashboard.
+ALTER TABLE mailpasswd.userdb ADD COLUMN require_pinned_certificate BOOLEAN NOT NULL DEFAULT false;
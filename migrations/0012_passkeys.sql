@@ -0,0 +1,22 @@
+-- Passkeys users registered to log into the dashboard without a client certificate.
+CREATE TABLE mailpasswd.passkeys (
+	   credential_id BYTEA NOT NULL PRIMARY KEY,
+	   userid UUID NOT NULL REFERENCES mailpasswd.userdb(id) ON DELETE CASCADE,
+	   name VARCHAR(64) NOT NULL CHECK (name != ''),
+	   -- COSE-encoded, as the authenticator sent it.
+	   public_key BYTEA NOT NULL,
+	   sign_count BIGINT NOT NULL DEFAULT 0,
+	   created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+	   last_used TIMESTAMPTZ,
+	   UNIQUE (userid, name)
+);
+
+-- Dashboard logins with a passkey. Deleting the passkey ends them.
+CREATE TABLE mailpasswd.sessions (
+	   -- SHA-256 of the session cookie, hex-encoded.
+	   token_hash CHAR(64) NOT NULL PRIMARY KEY,
+	   userid UUID NOT NULL REFERENCES mailpasswd.userdb(id) ON DELETE CASCADE,
+	   credential_id BYTEA NOT NULL REFERENCES mailpasswd.passkeys(credential_id) ON DELETE CASCADE,
+	   created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+	   expires_at TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Dashboard logins through an OpenID Connect provider don't involve a passkey.
+ALTER TABLE mailpasswd.sessions ALTER COLUMN credential_id DROP NOT NULL;
@@ -0,0 +1,57 @@
+-- Users and aliases declared in the reconciliation file may only be changed by reconciling.
+ALTER TABLE mailpasswd.userdb ADD COLUMN managed BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE mailpasswd.aliases ADD COLUMN managed BOOLEAN NOT NULL DEFAULT false;
+
+-- Reconciliation sets this for its own transaction only.
+CREATE FUNCTION mailpasswd.reconciling() RETURNS BOOLEAN LANGUAGE sql STABLE AS $$
+	SELECT coalesce(current_setting('nyanpasswd.reconciling', true), '') = 'on'
+$$;
+
+CREATE FUNCTION mailpasswd.alias_managed(name VARCHAR, dom VARCHAR) RETURNS BOOLEAN LANGUAGE sql STABLE AS $$
+	SELECT EXISTS(SELECT 1 FROM mailpasswd.aliases WHERE managed AND alias_name = name AND domain IS NOT DISTINCT FROM dom)
+$$;
+
+-- Violations use their own SQLSTATE, so that they can be told apart from other errors.
+CREATE FUNCTION mailpasswd.protect_managed_users() RETURNS TRIGGER LANGUAGE plpgsql AS $$
+BEGIN
+	IF NOT mailpasswd.reconciling() AND (
+		(TG_OP <> 'INSERT' AND OLD.managed) OR (TG_OP <> 'DELETE' AND NEW.managed)
+	) THEN
+		RAISE EXCEPTION 'user % is managed by the reconciliation file', coalesce(OLD.username, NEW.username)
+			USING ERRCODE = '23M01';
+	END IF;
+	IF TG_OP = 'DELETE' THEN
+		RETURN OLD;
+	END IF;
+	RETURN NEW;
+END
+$$;
+
+CREATE FUNCTION mailpasswd.protect_managed_aliases() RETURNS TRIGGER LANGUAGE plpgsql AS $$
+BEGIN
+	IF mailpasswd.reconciling() THEN
+		NULL;
+	ELSIF (TG_OP <> 'INSERT' AND mailpasswd.alias_managed(OLD.alias_name, OLD.domain))
+		OR (TG_OP <> 'DELETE' AND mailpasswd.alias_managed(NEW.alias_name, NEW.domain)) THEN
+		RAISE EXCEPTION 'alias % is managed by the reconciliation file', coalesce(OLD.alias_name, NEW.alias_name)
+			USING ERRCODE = '23M01';
+	-- Only alias members have the flag, alias settings follow them
+	ELSIF TG_TABLE_NAME = 'aliases' AND TG_OP <> 'DELETE' THEN
+		IF NEW.managed THEN
+			RAISE EXCEPTION 'alias % can only be managed by the reconciliation file', NEW.alias_name
+				USING ERRCODE = '23M01';
+		END IF;
+	END IF;
+	IF TG_OP = 'DELETE' THEN
+		RETURN OLD;
+	END IF;
+	RETURN NEW;
+END
+$$;
+
+CREATE TRIGGER protect_managed BEFORE INSERT OR UPDATE OR DELETE ON mailpasswd.userdb
+	FOR EACH ROW EXECUTE FUNCTION mailpasswd.protect_managed_users();
+CREATE TRIGGER protect_managed BEFORE INSERT OR UPDATE OR DELETE ON mailpasswd.aliases
+	FOR EACH ROW EXECUTE FUNCTION mailpasswd.protect_managed_aliases();
+CREATE TRIGGER protect_managed BEFORE INSERT OR UPDATE OR DELETE ON mailpasswd.alias_info
+	FOR EACH ROW EXECUTE FUNCTION mailpasswd.protect_managed_aliases();
@@ -0,0 +1,54 @@
+-- Alias lookup for consumers reading the database directly, like Postfix.
+-- This follows the same rules as `match_recipient` in `src/lib.rs`, keep them in sync.
+
+-- Enabled alias entries matching a name on a domain, including patterns, with live destinations.
+CREATE FUNCTION mailpasswd.alias_candidates(lookup VARCHAR, dom VARCHAR)
+RETURNS TABLE (alias_name VARCHAR, domain VARCHAR, username VARCHAR) LANGUAGE sql STABLE AS $$
+	SELECT aliases.alias_name, aliases.domain, userdb.username
+	FROM mailpasswd.aliases INNER JOIN mailpasswd.userdb ON destination = userdb.id
+	WHERE (aliases.alias_name = lookup OR (right(aliases.alias_name, 1) = '*' AND starts_with(lookup, left(aliases.alias_name, -1))))
+		AND (aliases.domain IS NULL OR aliases.domain = dom)
+		AND aliases.enabled
+		AND (userdb.expires_at IS NULL OR userdb.expires_at > now())
+$$;
+
+-- Usernames the alias matching a name on a domain delivers to.
+-- Returns nothing if no alias matches, or if the name belongs to a user.
+CREATE FUNCTION mailpasswd.match_alias(lookup VARCHAR, dom VARCHAR) RETURNS SETOF VARCHAR LANGUAGE plpgsql STABLE AS $$
+DECLARE
+	names VARCHAR[] := ARRAY[lookup];
+	base VARCHAR := nullif(split_part(lookup, '+', 1), '');
+	n VARCHAR;
+	best RECORD;
+BEGIN
+	-- Postfix looks up `@domain` last, catch-alls are handled below instead
+	IF lookup = '' THEN
+		RETURN;
+	END IF;
+	IF base <> lookup THEN
+		names := names || base;
+	END IF;
+
+	-- Exact aliases shadow users, and users shadow patterns, first with and then without the subaddress
+	FOREACH n IN ARRAY names LOOP
+		SELECT c.alias_name, c.domain INTO best FROM mailpasswd.alias_candidates(n, dom) c
+			WHERE c.alias_name = n ORDER BY c.domain IS NOT NULL DESC LIMIT 1;
+		IF FOUND THEN
+			RETURN QUERY SELECT c.username FROM mailpasswd.alias_candidates(n, dom) c
+				WHERE c.alias_name = best.alias_name AND c.domain IS NOT DISTINCT FROM best.domain ORDER BY c.username;
+			RETURN;
+		END IF;
+		IF EXISTS(SELECT 1 FROM mailpasswd.userdb WHERE username = n AND (expires_at IS NULL OR expires_at > now())) THEN
+			RETURN;
+		END IF;
+	END LOOP;
+
+	-- The longest matching prefix wins, a catch-all has an empty one
+	SELECT c.alias_name, c.domain INTO best FROM mailpasswd.alias_candidates(lookup, dom) c
+		WHERE c.alias_name <> lookup ORDER BY length(c.alias_name) DESC, c.domain IS NOT NULL DESC LIMIT 1;
+	IF FOUND THEN
+		RETURN QUERY SELECT c.username FROM mailpasswd.alias_candidates(lookup, dom) c
+			WHERE c.alias_name = best.alias_name AND c.domain IS NOT DISTINCT FROM best.domain ORDER BY c.username;
+	END IF;
+END
+$$;
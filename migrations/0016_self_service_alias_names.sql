@@ -0,0 +1,13 @@
+-- Users can't take the name of a self-service alias, since the alias would shadow them.
+CREATE FUNCTION mailpasswd.check_self_service_alias_names() RETURNS TRIGGER LANGUAGE plpgsql AS $$
+BEGIN
+	IF EXISTS(SELECT 1 FROM mailpasswd.aliases WHERE owner IS NOT NULL AND alias_name = NEW.username) THEN
+		RAISE EXCEPTION 'username % is taken by a self-service alias', NEW.username
+			USING ERRCODE = 'unique_violation';
+	END IF;
+	RETURN NEW;
+END
+$$;
+
+CREATE TRIGGER check_self_service_alias_names BEFORE INSERT OR UPDATE OF username ON mailpasswd.userdb
+	FOR EACH ROW EXECUTE FUNCTION mailpasswd.check_self_service_alias_names();
@@ -0,0 +1,15 @@
+-- Alias settings are removed together with the last member of the alias.
+CREATE FUNCTION mailpasswd.remove_orphaned_alias_info() RETURNS TRIGGER LANGUAGE plpgsql AS $$
+BEGIN
+	DELETE FROM mailpasswd.alias_info WHERE alias_name = OLD.alias_name AND domain IS NOT DISTINCT FROM OLD.domain
+		AND NOT EXISTS(SELECT 1 FROM mailpasswd.aliases WHERE alias_name = OLD.alias_name AND domain IS NOT DISTINCT FROM OLD.domain);
+	RETURN NULL;
+END
+$$;
+
+CREATE TRIGGER remove_orphaned_alias_info AFTER UPDATE OF alias_name, domain OR DELETE ON mailpasswd.aliases
+	FOR EACH ROW EXECUTE FUNCTION mailpasswd.remove_orphaned_alias_info();
+
+DELETE FROM mailpasswd.alias_info WHERE NOT EXISTS(
+	SELECT 1 FROM mailpasswd.aliases WHERE aliases.alias_name = alias_info.alias_name AND aliases.domain IS NOT DISTINCT FROM alias_info.domain
+);
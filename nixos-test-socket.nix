@@ -0,0 +1,66 @@
+self: pkgs:
+{ lib, nodes, ... }: {
+  name = "nixos-nyanpasswd-socket";
+  hostPkgs = pkgs;
+
+  nodes = {
+    server = { config, pkgs, lib, ... }: {
+      imports = [ self.nixosModules.default ];
+
+      services.nyanpasswd = {
+        enable = true;
+        domain = "localhost";
+        # Here, we shim certificate validation since we won't use nginx
+        rootCACertificate = "";
+        crlFile = "";
+        dovecot2.enable = true;
+        adminUids = ["mvs"];
+        # Dovecot is the only integration, so it can do without the TCP API
+        apiSocket.enable = true;
+        tcpApi = false;
+      };
+
+      services.dovecot2 = {
+        enable = true;
+        createMailUser = true;
+        mailUser = "vmail";
+        mailGroup = "vmail";
+      };
+
+      # We disable nginx since we'll access the service directly
+      services.nginx.enable = lib.mkForce false;
+    };
+  };
+
+  testScript = ''
+    import re
+    server.wait_for_unit("default.target")
+    server.wait_for_open_port(3000)
+    server.wait_for_file("${nodes.server.services.nyanpasswd.apiSocket.path}")
+    vsh = "O = nyantec GmbH, CN = Vika Shleina, GN = Viktoriya, SN = Shleina, pseudonym = Vika, UID = vsh"
+    mvs = "O = nyantec GmbH, CN = Mikael Voss, GN = Mikael, SN = Voss, UID = mvs"
+    socket = "${nodes.server.services.nyanpasswd.apiSocket.path}"
+
+    # Forms are protected against CSRF, so take the token from a page first
+    def csrf_token(dn, page):
+        html = server.succeed(f"curl --silent --fail -H 'X-Ssl-Verify: SUCCESS' -H 'X-Ssl-Client-Dn: {dn}' http://localhost:3000{page}")
+        return re.search(r'name="csrf_token" value="([^"]*)"', html).group(1)
+
+    server.succeed(f"curl --fail -H 'X-Ssl-Verify: SUCCESS' -H 'X-Ssl-Client-Dn: {mvs}' -H 'X-CSRF-Token: {csrf_token(mvs, '/admin/')}' http://localhost:3000/admin/create_user -d username=vsh -d expires_at=\"\" -d non_human=false")
+    password = server.succeed(f"curl --silent --fail -H 'X-Ssl-Verify: SUCCESS' -H 'X-Ssl-Client-Dn: {vsh}' -H 'X-CSRF-Token: {csrf_token(vsh, '/')}' -d label=test -d expires_in=noexpiry http://localhost:3000/create_password | grep -o '<code>[^<]*</code>' | cut -b7- | cut -d'<' -f1").strip()
+
+    with subtest("Check that the API is only served on the socket"):
+        server.fail("curl --silent --fail http://localhost:3000/api/v1/user_lookup -H 'Content-Type: application/json' -d '{\"user\": \"vsh\"}'")
+        server.succeed(f"runuser -u dovecot2 -- curl --silent --fail --unix-socket {socket} http://localhost/api/v1/user_lookup -H 'Content-Type: application/json' -d '{{\"user\": \"vsh\"}}'")
+
+    with subtest("Check that only peers with a rule are let in"):
+        status = server.succeed(f"curl --silent --output /dev/null --write-out '%{{http_code}}' --unix-socket {socket} http://localhost/api/v1/user_lookup -H 'Content-Type: application/json' -d '{{\"user\": \"vsh\"}}'")
+        if status != "403":
+            raise Exception(f"root was let in with {status}")
+        server.fail(f"runuser -u vmail -- curl --silent --fail --unix-socket {socket} http://localhost/api/v1/user_lookup -H 'Content-Type: application/json' -d '{{\"user\": \"vsh\"}}'")
+
+    with subtest("Check that IMAP authentication works over the socket"):
+        server.succeed(f"curl --no-progress-meter imap://localhost:143/ -u vsh:{password}")
+        server.fail("curl --no-progress-meter imap://localhost:143/ -u vsh:wrong")
+  '';
+}
@@ -95,23 +95,31 @@ in {
   testScript = ''
     import time
     import json
+    import re
     server.wait_for_unit("default.target")
     # XXX workaround for flaky test, replace by checking for open port on localhost
     time.sleep(1)    
     vsh = "O = nyantec GmbH, CN = Vika Shleina, GN = Viktoriya, SN = Shleina, pseudonym = Vika, UID = vsh"
     mvs = "O = nyantec GmbH, CN = Mikael Voss, GN = Mikael, SN = Voss, UID = mvs"
 
+    # Forms are protected against CSRF, so take the token from a page first
+    def csrf_token(dn, page):
+        html = server.succeed(f"curl --silent --fail -H 'X-Ssl-Verify: SUCCESS' -H 'X-Ssl-Client-Dn: {dn}' http://localhost:3000{page}")
+        return re.search(r'name="csrf_token" value="([^"]*)"', html).group(1)
+
     with subtest("Check that user creation works when admin doesn't have an account"):
-        server.succeed(f"curl --fail -H 'X-Ssl-Verify: SUCCESS' -H 'X-Ssl-Client-Dn: {mvs}' http://localhost:3000/admin/create_user -d username=vsh -d expires_at=\"\" -d non_human=false")
+        server.succeed(f"curl --fail -H 'X-Ssl-Verify: SUCCESS' -H 'X-Ssl-Client-Dn: {mvs}' -H 'X-CSRF-Token: {csrf_token(mvs, '/admin/')}' http://localhost:3000/admin/create_user -d username=vsh -d expires_at=\"\" -d non_human=false")
 
     with subtest("Check that passwords can be generated"):
-        password = server.succeed(f"curl --silent --fail -H 'X-Ssl-Verify: SUCCESS' -H 'X-Ssl-Client-Dn: {vsh}' -d label=test -d expires_in=noexpiry http://localhost:3000/create_password | grep -o '<code>[^<]*</code>' | cut -b7- | cut -d'<' -f1").strip()
+        password = server.succeed(f"curl --silent --fail -H 'X-Ssl-Verify: SUCCESS' -H 'X-Ssl-Client-Dn: {vsh}' -H 'X-CSRF-Token: {csrf_token(vsh, '/')}' -d label=test -d expires_in=noexpiry http://localhost:3000/create_password | grep -o '<code>[^<]*</code>' | cut -b7- | cut -d'<' -f1").strip()
         print("Generated password:", password)
 
     with subtest("Check that IMAP authentication works"):
         server.succeed(f"curl -vvvvvvv --no-progress-meter imap://localhost:143/ -u vsh:{password}")
 
-    user_json = json.loads(server.succeed("curl --silent --fail http://localhost:3000/api/user_lookup -H 'Content-Type: application/json' -d '{}'".format(json.dumps({"user": "vsh"}))))
+    # The module registers Dovecot as an API consumer, borrow its token
+    token = server.succeed("cat ${nodes.server.services.nyanpasswd.dovecot2.tokenFile}").strip()
+    user_json = json.loads(server.succeed("curl --silent --fail http://localhost:3000/api/v1/user_lookup -H 'Content-Type: application/json' -H 'Authorization: Bearer {}' -d '{}'".format(token, json.dumps({"user": "vsh"}))))
 
     with subtest("Check that Dovecot delivers incoming mail properly"):
         status = server.succeed(f"curl --no-progress-meter imap://localhost:143 -u vsh:{password} -X 'STATUS INBOX (MESSAGES)'").strip()
@@ -134,7 +142,7 @@ in {
             server.succeed(f"ls -d ${nodes.server.services.nyanpasswd.dovecot2.mailhome}/{user_json['id']}/Maildir")
 
     with subtest("Check that Postfix resolves aliases correctly"):
-        server.succeed(f"curl --silent --fail -H 'X-Ssl-Verify: SUCCESS' -H 'X-Ssl-Client-Dn: {mvs}' http://localhost:3000/admin/aliases/ -d alias_name=ops -d destination={user_json['id']}")
+        server.succeed(f"curl --silent --fail -H 'X-Ssl-Verify: SUCCESS' -H 'X-Ssl-Client-Dn: {mvs}' -H 'X-CSRF-Token: {csrf_token(mvs, '/admin/')}' http://localhost:3000/admin/aliases/ -d alias_name=ops -d destination={user_json['id']}")
         server.succeed("cat ${test-email-to-alias} | sendmail ops")
         # Allow things to settle a bit
         # XXX replace with `wait_until_succeeds`
@@ -0,0 +1,72 @@
+// Registering passkeys and logging in with them, see src/passkeys.rs.
+"use strict";
+
+// WebAuthn JSON encodes binary data as unpadded base64url
+function decode(data) {
+	return Uint8Array.from(atob(data.replace(/-/g, "+").replace(/_/g, "/")), c => c.charCodeAt(0));
+}
+
+function encode(data) {
+	return btoa(String.fromCharCode(...new Uint8Array(data))).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+}
+
+async function post(form, path, body) {
+	const response = await fetch(path, {
+		method: "POST",
+		headers: {
+			"Content-Type": "application/json",
+			"X-CSRF-Token": form.elements.namedItem("csrf_token").value,
+		},
+		body: JSON.stringify(body),
+	});
+	if (!response.ok) {
+		throw new Error(await response.text());
+	}
+	return response;
+}
+
+async function register(form) {
+	const options = await (await post(form, "/passkeys/register/begin", {})).json();
+	const credential = await navigator.credentials.create({
+		publicKey: {
+			...options,
+			challenge: decode(options.challenge),
+			user: { ...options.user, id: decode(options.user.id) },
+			excludeCredentials: options.excludeCredentials.map(passkey => ({ ...passkey, id: decode(passkey.id) })),
+		},
+	});
+	await post(form, "/passkeys/register/finish", {
+		name: form.elements.namedItem("name").value,
+		challenge: options.challenge,
+		clientDataJson: encode(credential.response.clientDataJSON),
+		attestationObject: encode(credential.response.attestationObject),
+	});
+	location.reload();
+}
+
+async function login(form) {
+	const options = await (await post(form, "/passkeys/login/begin", {})).json();
+	const credential = await navigator.credentials.get({
+		publicKey: { ...options, challenge: decode(options.challenge) },
+	});
+	await post(form, "/passkeys/login/finish", {
+		challenge: options.challenge,
+		credentialId: encode(credential.rawId),
+		clientDataJson: encode(credential.response.clientDataJSON),
+		authenticatorData: encode(credential.response.authenticatorData),
+		signature: encode(credential.response.signature),
+	});
+	location.href = "/";
+}
+
+for (const [id, action] of [["register_passkey", register], ["passkey_login", login]]) {
+	const form = document.getElementById(id);
+	if (form) {
+		form.addEventListener("submit", event => {
+			event.preventDefault();
+			action(form).catch(err => {
+				form.querySelector(".error").textContent = err.message;
+			});
+		});
+	}
+}
@@ -24,6 +24,10 @@ PLUGIN_CONFIG_SCHEMA = {
         "mail_passwd_uri": {
             "value": "",
             "type": str
+        },
+        "mail_passwd_token_file": {
+            "value": "",
+            "type": str
         }
     }
 }
@@ -33,6 +37,11 @@ class Auth(BaseAuth):
     def __init__(self, configuration):
         super().__init__(configuration.copy(PLUGIN_CONFIG_SCHEMA))
 
+    def _token(self):
+        # Read on every login, so that a new token is picked up without restarting Radicale
+        with open(self.configuration.get("auth", "mail_passwd_token_file")) as f:
+            return f.readline().strip()
+
     def login(self, login, password):
         # Get password from configuration option
         uri = self.configuration.get("auth", "mail_passwd_uri")
@@ -40,32 +49,19 @@ class Auth(BaseAuth):
         logger.debug("Login attempt by %r with password %s", login, password)
 
         # Note: some applications try to use the email address as the login.
-        # Mail-passwd is designed to manage unlimited domains with the same users,
-        # so we shall strip the domain.
-        if "@" in login:
-            login = login.split("@")[0]
-            if login == "":
-                return ""
-
+        # nyanpasswd accepts full addresses and validates the domain itself.
         response = requests.post(
-            uri + "/api/authenticate",
+            uri + "/api/v1/authenticate",
             json = { "user": login, "password": password },
             headers = {
                 "Content-Type": "application/json",
+                "Authorization": "Bearer " + self._token(),
             }
         )
         logger.debug("mail-passwd responded with %s: %s", response.status_code, response)
         if response.status_code == 200:
-            userdata_response = requests.post(
-                uri + "/api/user_lookup",
-                json = { "user": login },
-                headers = {
-                    "Content-Type": "application/json",
-                    "Accept": "application/json"
-                }
-            )
-            userdata = userdata_response.json()
-            logger.debug("Login attempt successful, looked up userdata: %s", userdata),
+            userdata = response.json()["user"]
+            logger.debug("Login attempt successful, userdata: %s", userdata)
             # Return the UUID instead of the username
             return userdata["id"]
         else:
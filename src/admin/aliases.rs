@@ -18,27 +18,57 @@
   of said person's immediate fault when using the work as intended.
  */
 use sailfish::TemplateOnce;
-use std::{sync::Arc, collections::HashMap};
+use std::{sync::Arc, collections::{HashMap, HashSet}};
 
 use axum::{
-	extract::State,
+	extract::{Query, State},
 	http::StatusCode,
 	response::IntoResponse,
 	Form,
 };
 use uuid::Uuid;
 
-use crate::{Layout, Service, COMPANY_NAME, IMPRESSUM};
-use nyanpasswd::{User, Alias};
+use crate::{config, csrf::CsrfToken, Layout, Service};
+use nyanpasswd::{User, Alias, AliasInfo, RecipientMatch, SelfServiceAlias};
 
 #[derive(sailfish::TemplateOnce)]
 #[template(path = "aliases.stpl")]
 struct AliasesPage {
-	aliases: Vec<(String, Vec<Uuid>)>,
-	users: HashMap<Uuid, User>
+	aliases: Vec<(String, Option<String>, Vec<Uuid>)>,
+	users: HashMap<Uuid, User>,
+	domains: Vec<String>,
+	info: HashMap<(String, Option<String>), AliasInfo>,
+	/// Aliases managed by the reconciliation file, which can't be edited here.
+	managed: HashSet<(String, Option<String>)>,
+	self_service: Vec<SelfServiceAlias>,
+	templates: Vec<String>,
+	simulation: Option<(String, RecipientMatch, Vec<User>)>,
+	csrf_token: String,
 }
 
-async fn list_aliases(State(backend): State<Arc<Service>>) -> axum::response::Response {
+#[derive(serde::Deserialize)]
+struct SimulateDeliveryQuery {
+	recipient: Option<String>
+}
+
+async fn list_aliases(
+	State(backend): State<Arc<Service>>,
+	Query(query): Query<SimulateDeliveryQuery>,
+	CsrfToken(csrf_token): CsrfToken,
+) -> axum::response::Response {
+	let simulation = match query.recipient.filter(|r| !r.is_empty()) {
+		Some(recipient) => match futures::try_join!(backend.match_recipient(&recipient), backend.resolve_recipient(&recipient)) {
+			Ok((matched, users)) => Some((recipient, matched, users)),
+			Err(err) => return (
+				StatusCode::INTERNAL_SERVER_ERROR,
+				[("Content-Type", "text/plain")],
+				format!("SQL layer error: {}", err),
+			)
+				.into_response()
+		},
+		None => None
+	};
+
 	match backend.list_all_aliases().await {
 		Ok(aliases) => {
 			let users: HashMap<Uuid, User> = {
@@ -55,12 +85,33 @@ async fn list_aliases(State(backend): State<Arc<Service>>) -> axum::response::Re
 
 				users
 			};
+			let (domains, info, managed, self_service, templates) = match futures::try_join!(
+				backend.list_domains(),
+				backend.list_alias_info(),
+				backend.list_managed_aliases(),
+				backend.list_all_self_service_aliases(),
+				backend.list_alias_templates()
+			) {
+				Ok((domains, info, managed, self_service, templates)) => (
+					domains,
+					info.into_iter().map(|i| ((i.alias_name.clone(), i.domain.clone()), i)).collect(),
+					managed.into_iter().collect(),
+					self_service,
+					templates
+				),
+				Err(err) => return (
+					StatusCode::INTERNAL_SERVER_ERROR,
+					[("Content-Type", "text/plain")],
+					format!("SQL layer error: {}", err),
+				)
+					.into_response()
+			};
 
 			axum::response::Html(
 				Layout {
-					company_name: COMPANY_NAME,
-					impressum_link: IMPRESSUM,
-					body: AliasesPage { aliases, users },
+					company_name: &config::get().site.company_name,
+					impressum_link: &config::get().site.impressum,
+					body: AliasesPage { aliases, users, domains, info, managed, self_service, templates, simulation, csrf_token },
 				}
 				.render_once()
 				.unwrap(),
@@ -78,6 +129,49 @@ async fn list_aliases(State(backend): State<Arc<Service>>) -> axum::response::Re
 
 async fn add_alias(State(backend): State<Arc<Service>>, Form(alias): Form<Alias>) -> axum::response::Response {
 	match backend.add_alias(&alias).await {
+		Ok(()) => (StatusCode::FOUND, [("Location", "/admin/aliases/")]).into_response(),
+		Err(err) => crate::sql_error(err),
+	}
+}
+
+async fn delete_alias(State(backend): State<Arc<Service>>, Form(alias): Form<Alias>) -> axum::response::Response {
+	match backend.remove_alias(&alias).await {
+		Ok(()) => (StatusCode::FOUND, [("Location", "/admin/aliases/")]).into_response(),
+		Err(err) => crate::sql_error(err),
+	}
+
+}
+
+#[derive(serde::Deserialize)]
+struct AliasInfoForm {
+	alias_name: String,
+	#[serde(default)]
+	domain: String,
+	description: String,
+	#[serde(default)]
+	self_removable: bool,
+}
+
+async fn set_alias_info(State(backend): State<Arc<Service>>, Form(form): Form<AliasInfoForm>) -> axum::response::Response {
+	let info = AliasInfo {
+		alias_name: form.alias_name,
+		domain: Some(form.domain).filter(|d| !d.is_empty()),
+		description: form.description,
+		self_removable: form.self_removable,
+	};
+	match backend.set_alias_info(&info).await {
+		Ok(()) => (StatusCode::FOUND, [("Location", "/admin/aliases/")]).into_response(),
+		Err(err) => crate::sql_error(err),
+	}
+}
+
+#[derive(serde::Deserialize)]
+struct TemplateForm {
+	template: String,
+}
+
+async fn add_template(State(backend): State<Arc<Service>>, Form(form): Form<TemplateForm>) -> axum::response::Response {
+	match backend.add_alias_template(&form.template).await {
 		Ok(()) => (StatusCode::FOUND, [("Location", "/admin/aliases/")]).into_response(),
 		Err(err) => (
 			StatusCode::INTERNAL_SERVER_ERROR,
@@ -88,8 +182,8 @@ async fn add_alias(State(backend): State<Arc<Service>>, Form(alias): Form<Alias>
 	}
 }
 
-async fn delete_alias(State(backend): State<Arc<Service>>, Form(alias): Form<Alias>) -> axum::response::Response {
-	match backend.remove_alias(&alias).await {
+async fn delete_template(State(backend): State<Arc<Service>>, Form(form): Form<TemplateForm>) -> axum::response::Response {
+	match backend.remove_alias_template(&form.template).await {
 		Ok(()) => (StatusCode::FOUND, [("Location", "/admin/aliases/")]).into_response(),
 		Err(err) => (
 			StatusCode::INTERNAL_SERVER_ERROR,
@@ -98,12 +192,14 @@ async fn delete_alias(State(backend): State<Arc<Service>>, Form(alias): Form<Ali
 		)
 		.into_response()
 	}
-
 }
 
 pub fn router(backend: Arc<Service>) -> axum::Router {
 	axum::Router::new()
 		.route("/", axum::routing::get(list_aliases).post(add_alias))
 		.route("/delete", axum::routing::post(delete_alias))
+		.route("/info", axum::routing::post(set_alias_info))
+		.route("/templates", axum::routing::post(add_template))
+		.route("/templates/delete", axum::routing::post(delete_template))
 		.with_state(backend)
 }
@@ -0,0 +1,824 @@
+/*
+  Copyright © 2022 nyantec GmbH <oss@nyantec.com>
+  Written by Vika Shleina <vsh@nyantec.com>
+  
+  Provided that these terms and disclaimer and all copyright notices
+  are retained or reproduced in an accompanying document, permission
+  is granted to deal in this work without restriction, including un‐
+  limited rights to use, publicly perform, distribute, sell, modify,
+  merge, give away, or sublicence.
+  
+  This work is provided "AS IS" and WITHOUT WARRANTY of any kind, to
+  the utmost extent permitted by applicable law, neither express nor
+  implied; without malicious intent or gross negligence. In no event
+  may a licensor, author or contributor be held liable for indirect,
+  direct, other damage, loss, or other issues arising in any way out
+  of dealing in the work, even if advised of the possibility of such
+  damage or existence of a defect, except proven that it results out
+  of said person's immediate fault when using the work as intended.
+ */
+//! JSON equivalents of the admin dashboard, for driving account management from scripts.
+//!
+//! Callers are authorized like admins using the dashboard. Updates set values instead of toggling
+//! them, and adding something that's already there succeeds, so that requests can be retried.
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::{
+	extract::{Path, Query, State},
+	http::{header, StatusCode},
+	response::{IntoResponse, Response},
+	Json,
+};
+use chrono::{DateTime, FixedOffset};
+use nyanpasswd::{Alias, AliasInfo, ClientCertificate, Password, User};
+use utoipa::OpenApi;
+use uuid::Uuid;
+
+use crate::Service;
+
+const UNIQUE_VIOLATION: &str = "23505";
+const FOREIGN_KEY_VIOLATION: &str = "23503";
+const CHECK_VIOLATION: &str = "23514";
+const VALUE_TOO_LONG: &str = "22001";
+
+/// Whether a query failed with the given SQLSTATE.
+fn sql_state_is(err: &sqlx::Error, state: &str) -> bool {
+	matches!(err, sqlx::Error::Database(err) if err.code().as_deref() == Some(state))
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct ErrorBody {
+	error: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+enum ApiError {
+	#[error("No such user")]
+	NoSuchUser,
+	#[error("No such client certificate")]
+	NoSuchCertificate,
+	#[error("No such user or domain")]
+	NoSuchUserOrDomain,
+	#[error("No such domain")]
+	NoSuchDomain,
+	#[error("A user with this name already exists")]
+	UsernameTaken,
+	#[error("A password with this label already exists")]
+	LabelTaken,
+	#[error("Passwords of human users are managed by themselves")]
+	HumanUser,
+	#[error("This domain is still used by aliases")]
+	DomainInUse,
+	#[error("Managed by the reconciliation file")]
+	Managed,
+	#[error("Invalid {0}")]
+	Invalid(&'static str),
+	#[error("SQL layer error: {0}")]
+	Sql(sqlx::Error),
+}
+
+// Any change can run into a managed user or alias, so this isn't left to each handler.
+impl From<sqlx::Error> for ApiError {
+	fn from(err: sqlx::Error) -> Self {
+		if nyanpasswd::reconcile::is_managed_violation(&err) {
+			ApiError::Managed
+		} else {
+			ApiError::Sql(err)
+		}
+	}
+}
+
+impl IntoResponse for ApiError {
+	fn into_response(self) -> Response {
+		(
+			match &self {
+				Self::NoSuchUser | Self::NoSuchCertificate | Self::NoSuchUserOrDomain | Self::NoSuchDomain => StatusCode::NOT_FOUND,
+				Self::UsernameTaken | Self::LabelTaken | Self::HumanUser | Self::DomainInUse | Self::Managed => {
+					StatusCode::CONFLICT
+				}
+				Self::Invalid(_) => StatusCode::BAD_REQUEST,
+				Self::Sql(_) => StatusCode::INTERNAL_SERVER_ERROR,
+			},
+			Json(ErrorBody { error: self.to_string() }),
+		)
+			.into_response()
+	}
+}
+
+/// Turn constraint violations on user-supplied values into client errors.
+fn invalid(what: &'static str) -> impl FnOnce(sqlx::Error) -> ApiError {
+	move |err| {
+		if sql_state_is(&err, CHECK_VIOLATION) || sql_state_is(&err, VALUE_TOO_LONG) {
+			ApiError::Invalid(what)
+		} else {
+			err.into()
+		}
+	}
+}
+
+/// Distinguish a field set to `null` from an omitted one.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+	D: serde::Deserializer<'de>,
+	T: serde::Deserialize<'de>,
+{
+	T::deserialize(deserializer).map(Some)
+}
+
+async fn get_user(backend: &Service, id: Uuid) -> Result<User, ApiError> {
+	backend.get_user_by_id(id).await?.ok_or(ApiError::NoSuchUser)
+}
+
+/// A user, along with settings only admins can see.
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct UserDetails {
+	#[serde(flatten)]
+	user: User,
+	/// How many self-service aliases the user may have.
+	alias_limit: i32,
+	/// Whether only pinned client certificates let the user into the dashboard.
+	require_pinned_certificate: bool,
+}
+
+impl UserDetails {
+	async fn fetch(backend: &Service, id: Uuid) -> Result<Self, ApiError> {
+		let user = get_user(backend, id).await?;
+		let (alias_limit, require_pinned_certificate) =
+			futures::try_join!(backend.get_alias_limit(id), backend.get_require_pinned_certificate(id))?;
+		Ok(UserDetails { user, alias_limit, require_pinned_certificate })
+	}
+}
+
+/// List all users, ordered by username.
+#[utoipa::path(
+	get,
+	path = "/users",
+	responses(
+		(status = 200, description = "All users", body = [User]),
+		(status = 500, description = "Service suffered an internal error", body = ErrorBody),
+	)
+)]
+async fn list_users(State(backend): State<Arc<Service>>) -> Result<Json<Vec<User>>, ApiError> {
+	Ok(Json(backend.list_users().await?))
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+#[serde(deny_unknown_fields)]
+struct NewUser {
+	username: String,
+	/// When the user expires. Users without an expiry date never expire.
+	#[serde(default)]
+	expires_at: Option<DateTime<FixedOffset>>,
+	/// Non-human users get their passwords from admins.
+	#[serde(default)]
+	non_human: bool,
+}
+
+/// Create a user. Usernames are unique, so retrying a request fails with `409 Conflict` instead of
+/// creating a second user.
+#[utoipa::path(
+	post,
+	path = "/users",
+	request_body = NewUser,
+	responses(
+		(status = 201, description = "User created", body = UserDetails, headers(("Location" = String, description = "Path of the new user"))),
+		(status = 400, description = "Invalid username", body = ErrorBody),
+		(status = 409, description = "A user with this name already exists", body = ErrorBody),
+		(status = 500, description = "Service suffered an internal error", body = ErrorBody),
+	)
+)]
+async fn create_user(State(backend): State<Arc<Service>>, Json(form): Json<NewUser>) -> Result<Response, ApiError> {
+	let id = match backend.create_user(&form.username, form.expires_at, form.non_human).await {
+		Ok(id) => id,
+		Err(err) if sql_state_is(&err, UNIQUE_VIOLATION) => return Err(ApiError::UsernameTaken),
+		Err(err) => return Err(invalid("username")(err)),
+	};
+	tracing::info!("Created user {} ({})", form.username, id);
+
+	Ok((
+		StatusCode::CREATED,
+		[(header::LOCATION, format!("/admin/api/v1/users/{}", id))],
+		Json(UserDetails::fetch(&backend, id).await?),
+	)
+		.into_response())
+}
+
+/// Look up a user by their ID.
+#[utoipa::path(
+	get,
+	path = "/users/{id}",
+	params(("id" = Uuid, Path, description = "User ID")),
+	responses(
+		(status = 200, description = "User found", body = UserDetails),
+		(status = 404, description = "No such user", body = ErrorBody),
+		(status = 500, description = "Service suffered an internal error", body = ErrorBody),
+	)
+)]
+async fn get_user_details(State(backend): State<Arc<Service>>, Path(id): Path<Uuid>) -> Result<Json<UserDetails>, ApiError> {
+	Ok(Json(UserDetails::fetch(&backend, id).await?))
+}
+
+/// Changes to a user. Omitted fields are left as they are.
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+#[serde(deny_unknown_fields)]
+struct UserUpdate {
+	/// When the user expires, or `null` to never expire.
+	#[serde(default, deserialize_with = "present")]
+	#[schema(value_type = Option<String>, format = DateTime, nullable)]
+	expires_at: Option<Option<DateTime<FixedOffset>>>,
+	login_allowed: Option<bool>,
+	alias_limit: Option<i32>,
+	require_pinned_certificate: Option<bool>,
+}
+
+/// Change a user's settings.
+#[utoipa::path(
+	patch,
+	path = "/users/{id}",
+	params(("id" = Uuid, Path, description = "User ID")),
+	request_body = UserUpdate,
+	responses(
+		(status = 200, description = "User updated", body = UserDetails),
+		(status = 400, description = "Invalid alias limit", body = ErrorBody),
+		(status = 404, description = "No such user", body = ErrorBody),
+		(status = 409, description = "The user is managed by the reconciliation file", body = ErrorBody),
+		(status = 500, description = "Service suffered an internal error", body = ErrorBody),
+	)
+)]
+async fn update_user(
+	State(backend): State<Arc<Service>>,
+	Path(id): Path<Uuid>,
+	Json(update): Json<UserUpdate>,
+) -> Result<Json<UserDetails>, ApiError> {
+	get_user(&backend, id).await?;
+	if let Some(alias_limit) = update.alias_limit {
+		backend.set_alias_limit(id, alias_limit).await.map_err(invalid("alias limit"))?;
+	}
+	if let Some(expires_at) = update.expires_at {
+		backend.set_user_expiry_date(id, expires_at).await?;
+	}
+	if let Some(login_allowed) = update.login_allowed {
+		backend.set_user_login_allowed(id, login_allowed).await?;
+	}
+	if let Some(require_pinned_certificate) = update.require_pinned_certificate {
+		backend.set_require_pinned_certificate(id, require_pinned_certificate).await?;
+	}
+
+	Ok(Json(UserDetails::fetch(&backend, id).await?))
+}
+
+/// A password, without its hash.
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct PasswordInfo {
+	label: String,
+	created_at: DateTime<FixedOffset>,
+	expires_at: Option<DateTime<FixedOffset>>,
+}
+
+impl From<Password> for PasswordInfo {
+	fn from(password: Password) -> Self {
+		PasswordInfo {
+			label: password.label,
+			created_at: password.created_at,
+			expires_at: password.expires_at,
+		}
+	}
+}
+
+/// List a user's passwords.
+#[utoipa::path(
+	get,
+	path = "/users/{id}/passwords",
+	params(("id" = Uuid, Path, description = "User ID")),
+	responses(
+		(status = 200, description = "The user's passwords", body = [PasswordInfo]),
+		(status = 404, description = "No such user", body = ErrorBody),
+		(status = 500, description = "Service suffered an internal error", body = ErrorBody),
+	)
+)]
+async fn list_passwords(State(backend): State<Arc<Service>>, Path(id): Path<Uuid>) -> Result<Json<Vec<PasswordInfo>>, ApiError> {
+	let user = get_user(&backend, id).await?;
+	let passwords = backend.list_passwords_for(&user).await?;
+	Ok(Json(passwords.into_iter().map(PasswordInfo::from).collect()))
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+#[serde(deny_unknown_fields)]
+struct NewPassword {
+	label: String,
+	/// When the password expires. Passwords without an expiry date never expire.
+	#[serde(default)]
+	expires_at: Option<DateTime<FixedOffset>>,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct CreatedPassword {
+	label: String,
+	/// The generated password. It can't be retrieved again later.
+	password: String,
+}
+
+/// Generate a password for a non-human user. Labels are unique per user, so retrying a request
+/// fails with `409 Conflict` instead of generating a second password.
+#[utoipa::path(
+	post,
+	path = "/users/{id}/passwords",
+	params(("id" = Uuid, Path, description = "User ID")),
+	request_body = NewPassword,
+	responses(
+		(status = 201, description = "Password generated", body = CreatedPassword),
+		(status = 400, description = "Invalid label", body = ErrorBody),
+		(status = 404, description = "No such user", body = ErrorBody),
+		(status = 409, description = "The label is taken, or the user is human", body = ErrorBody),
+		(status = 500, description = "Service suffered an internal error", body = ErrorBody),
+	)
+)]
+async fn create_password(
+	State(backend): State<Arc<Service>>,
+	Path(id): Path<Uuid>,
+	Json(form): Json<NewPassword>,
+) -> Result<(StatusCode, Json<CreatedPassword>), ApiError> {
+	let user = get_user(&backend, id).await?;
+	if !user.non_human {
+		return Err(ApiError::HumanUser);
+	}
+	let password = match backend.new_password(&user, &form.label, form.expires_at).await {
+		Ok(password) => password,
+		Err(err) if sql_state_is(&err, UNIQUE_VIOLATION) => return Err(ApiError::LabelTaken),
+		Err(err) => return Err(invalid("label")(err)),
+	};
+
+	Ok((StatusCode::CREATED, Json(CreatedPassword { label: form.label, password })))
+}
+
+/// Remove a password from a non-human user. Passwords that don't exist count as removed.
+#[utoipa::path(
+	delete,
+	path = "/users/{id}/passwords/{label}",
+	params(
+		("id" = Uuid, Path, description = "User ID"),
+		("label" = String, Path, description = "Password label"),
+	),
+	responses(
+		(status = 204, description = "Password removed"),
+		(status = 404, description = "No such user", body = ErrorBody),
+		(status = 409, description = "The user is human", body = ErrorBody),
+		(status = 500, description = "Service suffered an internal error", body = ErrorBody),
+	)
+)]
+async fn delete_password(
+	State(backend): State<Arc<Service>>,
+	Path((id, label)): Path<(Uuid, String)>,
+) -> Result<StatusCode, ApiError> {
+	let user = get_user(&backend, id).await?;
+	if !user.non_human {
+		return Err(ApiError::HumanUser);
+	}
+	backend.rm_password_for(&user, &label).await?;
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+/// A client certificate a user logged into the dashboard with.
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct Certificate {
+	/// SHA-256 fingerprint of the certificate.
+	fingerprint: String,
+	serial: String,
+	subject: String,
+	pinned: bool,
+	revoked: bool,
+	first_seen: DateTime<FixedOffset>,
+	last_seen: DateTime<FixedOffset>,
+}
+
+impl From<ClientCertificate> for Certificate {
+	fn from(cert: ClientCertificate) -> Self {
+		Certificate {
+			fingerprint: cert.fingerprint,
+			serial: cert.serial,
+			subject: cert.subject,
+			pinned: cert.pinned,
+			revoked: cert.revoked,
+			first_seen: cert.first_seen,
+			last_seen: cert.last_seen,
+		}
+	}
+}
+
+/// List client certificates a user logged into the dashboard with, most recently seen first.
+#[utoipa::path(
+	get,
+	path = "/users/{id}/certificates",
+	params(("id" = Uuid, Path, description = "User ID")),
+	responses(
+		(status = 200, description = "The user's certificates", body = [Certificate]),
+		(status = 404, description = "No such user", body = ErrorBody),
+		(status = 500, description = "Service suffered an internal error", body = ErrorBody),
+	)
+)]
+async fn list_certificates(State(backend): State<Arc<Service>>, Path(id): Path<Uuid>) -> Result<Json<Vec<Certificate>>, ApiError> {
+	get_user(&backend, id).await?;
+	let certificates = backend.list_client_certificates_for(id).await?;
+	Ok(Json(certificates.into_iter().map(Certificate::from).collect()))
+}
+
+/// Changes to a client certificate. Omitted fields are left as they are.
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+#[serde(deny_unknown_fields)]
+struct CertificateUpdate {
+	pinned: Option<bool>,
+	revoked: Option<bool>,
+}
+
+/// Pin or revoke a client certificate, or undo that.
+#[utoipa::path(
+	patch,
+	path = "/users/{id}/certificates/{fingerprint}",
+	params(
+		("id" = Uuid, Path, description = "User ID"),
+		("fingerprint" = String, Path, description = "SHA-256 fingerprint of the certificate"),
+	),
+	request_body = CertificateUpdate,
+	responses(
+		(status = 200, description = "Certificate updated", body = Certificate),
+		(status = 404, description = "No such user or certificate", body = ErrorBody),
+		(status = 500, description = "Service suffered an internal error", body = ErrorBody),
+	)
+)]
+async fn update_certificate(
+	State(backend): State<Arc<Service>>,
+	Path((id, fingerprint)): Path<(Uuid, String)>,
+	Json(update): Json<CertificateUpdate>,
+) -> Result<Json<Certificate>, ApiError> {
+	get_user(&backend, id).await?;
+	let find = || async {
+		let certificates = backend.list_client_certificates_for(id).await?;
+		certificates
+			.into_iter()
+			.find(|cert| cert.fingerprint == fingerprint)
+			.ok_or(ApiError::NoSuchCertificate)
+	};
+	find().await?;
+	if let Some(pinned) = update.pinned {
+		backend.set_client_certificate_pinned(id, &fingerprint, pinned).await?;
+	}
+	if let Some(revoked) = update.revoked {
+		backend.set_client_certificate_revoked(id, &fingerprint, revoked).await?;
+	}
+
+	Ok(Json(find().await?.into()))
+}
+
+/// List all domains addresses are accepted on.
+#[utoipa::path(
+	get,
+	path = "/domains",
+	responses(
+		(status = 200, description = "All domains", body = [String]),
+		(status = 500, description = "Service suffered an internal error", body = ErrorBody),
+	)
+)]
+async fn list_domains(State(backend): State<Arc<Service>>) -> Result<Json<Vec<String>>, ApiError> {
+	Ok(Json(backend.list_domains().await?))
+}
+
+/// Add a domain, if it isn't there yet.
+#[utoipa::path(
+	put,
+	path = "/domains/{domain}",
+	params(("domain" = String, Path, description = "Domain name")),
+	responses(
+		(status = 201, description = "Domain added"),
+		(status = 204, description = "Domain was already there"),
+		(status = 400, description = "Invalid domain name", body = ErrorBody),
+		(status = 500, description = "Service suffered an internal error", body = ErrorBody),
+	)
+)]
+async fn add_domain(State(backend): State<Arc<Service>>, Path(domain): Path<String>) -> Result<StatusCode, ApiError> {
+	match backend.add_domain(&domain).await {
+		Ok(()) => Ok(StatusCode::CREATED),
+		Err(err) if sql_state_is(&err, UNIQUE_VIOLATION) => Ok(StatusCode::NO_CONTENT),
+		Err(err) => Err(invalid("domain name")(err)),
+	}
+}
+
+/// Remove a domain. Domains that don't exist count as removed.
+#[utoipa::path(
+	delete,
+	path = "/domains/{domain}",
+	params(("domain" = String, Path, description = "Domain name")),
+	responses(
+		(status = 204, description = "Domain removed"),
+		(status = 409, description = "The domain is still used by aliases", body = ErrorBody),
+		(status = 500, description = "Service suffered an internal error", body = ErrorBody),
+	)
+)]
+async fn delete_domain(State(backend): State<Arc<Service>>, Path(domain): Path<String>) -> Result<StatusCode, ApiError> {
+	match backend.remove_domain(&domain).await {
+		Ok(()) => Ok(StatusCode::NO_CONTENT),
+		Err(err) if sql_state_is(&err, FOREIGN_KEY_VIOLATION) => Err(ApiError::DomainInUse),
+		Err(err) => Err(err.into()),
+	}
+}
+
+/// An alias managed by administrators, with its settings.
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct AliasEntry {
+	alias_name: String,
+	/// Domain the alias is valid on. Unscoped aliases are valid on all domains.
+	domain: Option<String>,
+	/// IDs of users the alias delivers to directly.
+	members: Vec<Uuid>,
+	description: String,
+	/// Whether members may remove themselves from the alias.
+	self_removable: bool,
+}
+
+/// List all aliases managed by administrators. Self-service aliases aren't included.
+#[utoipa::path(
+	get,
+	path = "/aliases",
+	responses(
+		(status = 200, description = "All aliases", body = [AliasEntry]),
+		(status = 500, description = "Service suffered an internal error", body = ErrorBody),
+	)
+)]
+async fn list_aliases(State(backend): State<Arc<Service>>) -> Result<Json<Vec<AliasEntry>>, ApiError> {
+	let (aliases, info) = futures::try_join!(backend.list_all_aliases(), backend.list_alias_info())?;
+	let mut info: HashMap<_, _> = info.into_iter().map(|i| ((i.alias_name.clone(), i.domain.clone()), i)).collect();
+
+	Ok(Json(
+		aliases
+			.into_iter()
+			.map(|(alias_name, domain, members)| {
+				let info = info.remove(&(alias_name.clone(), domain.clone()));
+				AliasEntry {
+					alias_name,
+					domain,
+					members,
+					description: info.as_ref().map(|i| i.description.clone()).unwrap_or_default(),
+					self_removable: info.map_or(false, |i| i.self_removable),
+				}
+			})
+			.collect(),
+	))
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AliasDomain {
+	/// Domain the alias is scoped to. Omit for unscoped aliases.
+	domain: Option<String>,
+}
+
+/// Make a user a member of an alias, if they aren't already.
+#[utoipa::path(
+	put,
+	path = "/aliases/{alias_name}/members/{id}",
+	params(
+		("alias_name" = String, Path, description = "Alias name, optionally ending in `*` to match all names with this prefix"),
+		("id" = Uuid, Path, description = "User ID"),
+		AliasDomain,
+	),
+	responses(
+		(status = 201, description = "Member added"),
+		(status = 204, description = "The user was already a member"),
+		(status = 400, description = "Invalid alias name", body = ErrorBody),
+		(status = 404, description = "No such user or domain", body = ErrorBody),
+		(status = 409, description = "The alias is managed by the reconciliation file", body = ErrorBody),
+		(status = 500, description = "Service suffered an internal error", body = ErrorBody),
+	)
+)]
+async fn add_alias_member(
+	State(backend): State<Arc<Service>>,
+	Path((alias_name, destination)): Path<(String, Uuid)>,
+	Query(AliasDomain { domain }): Query<AliasDomain>,
+) -> Result<StatusCode, ApiError> {
+	match backend.add_alias(&Alias { alias_name, destination, domain }).await {
+		Ok(()) => Ok(StatusCode::CREATED),
+		Err(err) if sql_state_is(&err, UNIQUE_VIOLATION) => Ok(StatusCode::NO_CONTENT),
+		Err(err) if sql_state_is(&err, FOREIGN_KEY_VIOLATION) => Err(ApiError::NoSuchUserOrDomain),
+		Err(err) => Err(invalid("alias name")(err)),
+	}
+}
+
+/// Remove a user from an alias. Users that aren't members count as removed.
+#[utoipa::path(
+	delete,
+	path = "/aliases/{alias_name}/members/{id}",
+	params(
+		("alias_name" = String, Path, description = "Alias name"),
+		("id" = Uuid, Path, description = "User ID"),
+		AliasDomain,
+	),
+	responses(
+		(status = 204, description = "Member removed"),
+		(status = 409, description = "The alias is managed by the reconciliation file", body = ErrorBody),
+		(status = 500, description = "Service suffered an internal error", body = ErrorBody),
+	)
+)]
+async fn delete_alias_member(
+	State(backend): State<Arc<Service>>,
+	Path((alias_name, destination)): Path<(String, Uuid)>,
+	Query(AliasDomain { domain }): Query<AliasDomain>,
+) -> Result<StatusCode, ApiError> {
+	backend.remove_alias(&Alias { alias_name, destination, domain }).await?;
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+#[serde(deny_unknown_fields)]
+struct AliasSettings {
+	#[serde(default)]
+	description: String,
+	/// Whether members may remove themselves from the alias.
+	#[serde(default)]
+	self_removable: bool,
+}
+
+/// Set the description of an alias, and whether its members may remove themselves from it.
+#[utoipa::path(
+	put,
+	path = "/aliases/{alias_name}/settings",
+	params(("alias_name" = String, Path, description = "Alias name"), AliasDomain),
+	request_body = AliasSettings,
+	responses(
+		(status = 204, description = "Settings saved"),
+		(status = 400, description = "Invalid alias name", body = ErrorBody),
+		(status = 404, description = "No such domain", body = ErrorBody),
+		(status = 409, description = "The alias is managed by the reconciliation file", body = ErrorBody),
+		(status = 500, description = "Service suffered an internal error", body = ErrorBody),
+	)
+)]
+async fn set_alias_settings(
+	State(backend): State<Arc<Service>>,
+	Path(alias_name): Path<String>,
+	Query(AliasDomain { domain }): Query<AliasDomain>,
+	Json(settings): Json<AliasSettings>,
+) -> Result<StatusCode, ApiError> {
+	let info = AliasInfo {
+		alias_name,
+		domain,
+		description: settings.description,
+		self_removable: settings.self_removable,
+	};
+	match backend.set_alias_info(&info).await {
+		Ok(()) => Ok(StatusCode::NO_CONTENT),
+		Err(err) if sql_state_is(&err, FOREIGN_KEY_VIOLATION) => Err(ApiError::NoSuchDomain),
+		Err(err) => Err(invalid("alias name")(err)),
+	}
+}
+
+/// List templates users may create self-service aliases from.
+#[utoipa::path(
+	get,
+	path = "/alias_templates",
+	responses(
+		(status = 200, description = "All templates", body = [String]),
+		(status = 500, description = "Service suffered an internal error", body = ErrorBody),
+	)
+)]
+async fn list_templates(State(backend): State<Arc<Service>>) -> Result<Json<Vec<String>>, ApiError> {
+	Ok(Json(backend.list_alias_templates().await?))
+}
+
+/// Approve a template for self-service alias names, if it isn't approved yet.
+#[utoipa::path(
+	put,
+	path = "/alias_templates/{template}",
+	params(("template" = String, Path, description = "Template containing a `<word>` and optionally a `<username>` placeholder")),
+	responses(
+		(status = 201, description = "Template approved"),
+		(status = 204, description = "Template was already approved"),
+		(status = 400, description = "Invalid template", body = ErrorBody),
+		(status = 500, description = "Service suffered an internal error", body = ErrorBody),
+	)
+)]
+async fn add_template(State(backend): State<Arc<Service>>, Path(template): Path<String>) -> Result<StatusCode, ApiError> {
+	match backend.add_alias_template(&template).await {
+		Ok(()) => Ok(StatusCode::CREATED),
+		Err(err) if sql_state_is(&err, UNIQUE_VIOLATION) => Ok(StatusCode::NO_CONTENT),
+		Err(err) => Err(invalid("template")(err)),
+	}
+}
+
+/// Revoke approval of a template. Existing aliases are left as-is.
+#[utoipa::path(
+	delete,
+	path = "/alias_templates/{template}",
+	params(("template" = String, Path, description = "Template")),
+	responses(
+		(status = 204, description = "Template removed"),
+		(status = 500, description = "Service suffered an internal error", body = ErrorBody),
+	)
+)]
+async fn delete_template(State(backend): State<Arc<Service>>, Path(template): Path<String>) -> Result<StatusCode, ApiError> {
+	backend.remove_alias_template(&template).await?;
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(OpenApi)]
+#[openapi(
+	servers((url = "/admin/api/v1")),
+	paths(
+		list_users,
+		create_user,
+		get_user_details,
+		update_user,
+		list_passwords,
+		create_password,
+		delete_password,
+		list_certificates,
+		update_certificate,
+		list_domains,
+		add_domain,
+		delete_domain,
+		list_aliases,
+		add_alias_member,
+		delete_alias_member,
+		set_alias_settings,
+		list_templates,
+		add_template,
+		delete_template,
+	),
+	components(schemas(
+		ErrorBody,
+		User,
+		UserDetails,
+		NewUser,
+		UserUpdate,
+		PasswordInfo,
+		NewPassword,
+		CreatedPassword,
+		Certificate,
+		CertificateUpdate,
+		AliasEntry,
+		AliasSettings,
+	))
+)]
+struct ApiDoc;
+
+async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+	Json(ApiDoc::openapi())
+}
+
+/// Build the admin API router. Authorization is left to the admin router it's nested in.
+pub fn router(backend: Arc<Service>) -> axum::Router {
+	use axum::routing::{get, put};
+
+	axum::Router::new()
+		.route("/openapi.json", get(openapi))
+		.route("/users", get(list_users).post(create_user))
+		.route("/users/:id", get(get_user_details).patch(update_user))
+		.route("/users/:id/passwords", get(list_passwords).post(create_password))
+		.route("/users/:id/passwords/:label", axum::routing::delete(delete_password))
+		.route("/users/:id/certificates", get(list_certificates))
+		.route("/users/:id/certificates/:fingerprint", axum::routing::patch(update_certificate))
+		.route("/domains", get(list_domains))
+		.route("/domains/:domain", put(add_domain).delete(delete_domain))
+		.route("/aliases", get(list_aliases))
+		.route("/aliases/:alias_name/members/:id", put(add_alias_member).delete(delete_alias_member))
+		.route("/aliases/:alias_name/settings", put(set_alias_settings))
+		.route("/alias_templates", get(list_templates))
+		.route("/alias_templates/:template", put(add_template).delete(delete_template))
+		.with_state(backend)
+}
+
+#[cfg(test)]
+mod test {
+	use utoipa::OpenApi;
+
+	/// Like the consumer API description, this one is checked in so that changes are visible in
+	/// review. Run with `UPDATE_OPENAPI=1` to regenerate it after an intentional change.
+	#[test]
+	fn test_openapi_document() {
+		let path = concat!(env!("CARGO_MANIFEST_DIR"), "/openapi-admin.json");
+		let document = super::ApiDoc::openapi().to_pretty_json().unwrap() + "\n";
+		if std::env::var_os("UPDATE_OPENAPI").is_some() {
+			std::fs::write(path, &document).unwrap();
+		}
+		assert_eq!(
+			std::fs::read_to_string(path).unwrap(),
+			document,
+			"Admin API description changed, rerun with UPDATE_OPENAPI=1 if this is intentional"
+		);
+	}
+
+	#[test]
+	fn test_user_update() {
+		let update: super::UserUpdate = serde_json::from_str(r#"{"login_allowed": false}"#).unwrap();
+		assert_eq!(update.login_allowed, Some(false));
+		assert!(update.expires_at.is_none());
+		// `null` removes the expiry date instead of leaving it alone
+		let update: super::UserUpdate = serde_json::from_str(r#"{"expires_at": null}"#).unwrap();
+		assert_eq!(update.expires_at, Some(None));
+		let update: super::UserUpdate = serde_json::from_str(r#"{"expires_at": "2038-01-19T03:14:07Z"}"#).unwrap();
+		assert!(update.expires_at.unwrap().is_some());
+		// Typos shouldn't silently do nothing
+		assert!(serde_json::from_str::<super::UserUpdate>(r#"{"login_alowed": false}"#).is_err());
+	}
+}
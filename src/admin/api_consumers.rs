@@ -0,0 +1,183 @@
+/*
+  Copyright © 2022 nyantec GmbH <oss@nyantec.com>
+  Written by Vika Shleina <vsh@nyantec.com>
+  
+  Provided that these terms and disclaimer and all copyright notices
+  are retained or reproduced in an accompanying document, permission
+  is granted to deal in this work without restriction, including un‐
+  limited rights to use, publicly perform, distribute, sell, modify,
+  merge, give away, or sublicence.
+  
+  This work is provided "AS IS" and WITHOUT WARRANTY of any kind, to
+  the utmost extent permitted by applicable law, neither express nor
+  implied; without malicious intent or gross negligence. In no event
+  may a licensor, author or contributor be held liable for indirect,
+  direct, other damage, loss, or other issues arising in any way out
+  of dealing in the work, even if advised of the possibility of such
+  damage or existence of a defect, except proven that it results out
+  of said person's immediate fault when using the work as intended.
+ */
+use sailfish::TemplateOnce;
+use std::sync::Arc;
+
+use axum::{
+	extract::State,
+	http::StatusCode,
+	response::IntoResponse,
+	Form,
+};
+use nyanpasswd::{ApiConsumer, ApiPermission};
+use uuid::Uuid;
+
+use crate::{config, csrf::CsrfToken, Layout, Service};
+
+#[derive(sailfish::TemplateOnce)]
+#[template(path = "api_consumers.stpl")]
+struct ApiConsumersPage {
+	consumers: Vec<ApiConsumer>,
+	csrf_token: String,
+}
+
+#[derive(sailfish::TemplateOnce)]
+#[template(path = "api_token.stpl")]
+struct ApiTokenPage {
+	name: String,
+	token: String,
+}
+
+fn sql_error(err: sqlx::Error) -> axum::response::Response {
+	(
+		StatusCode::INTERNAL_SERVER_ERROR,
+		[("Content-Type", "text/plain")],
+		format!("SQL layer error: {}", err),
+	)
+		.into_response()
+}
+
+fn token_page(name: String, token: String) -> axum::response::Response {
+	axum::response::Html(
+		Layout {
+			company_name: &config::get().site.company_name,
+			impressum_link: &config::get().site.impressum,
+			body: ApiTokenPage { name, token },
+		}
+		.render_once()
+		.unwrap(),
+	)
+	.into_response()
+}
+
+/// Turn permission checkboxes, in the order of [`ApiPermission::ALL`], into a permission set.
+fn selected_permissions(checked: [bool; 4]) -> Vec<ApiPermission> {
+	ApiPermission::ALL
+		.into_iter()
+		.zip(checked)
+		.filter_map(|(permission, checked)| checked.then_some(permission))
+		.collect()
+}
+
+async fn list_consumers(State(backend): State<Arc<Service>>, CsrfToken(csrf_token): CsrfToken) -> axum::response::Response {
+	match backend.list_api_consumers().await {
+		Ok(consumers) => axum::response::Html(
+			Layout {
+				company_name: &config::get().site.company_name,
+				impressum_link: &config::get().site.impressum,
+				body: ApiConsumersPage { consumers, csrf_token },
+			}
+			.render_once()
+			.unwrap(),
+		)
+		.into_response(),
+		Err(err) => sql_error(err),
+	}
+}
+
+#[derive(serde::Deserialize)]
+struct CreateConsumerForm {
+	name: String,
+	#[serde(default)]
+	cert_dn: String,
+	#[serde(default)]
+	authenticate: bool,
+	#[serde(default)]
+	lookup_users: bool,
+	#[serde(default)]
+	list_users: bool,
+	#[serde(default)]
+	lookup_aliases: bool,
+}
+
+async fn create_consumer(
+	State(backend): State<Arc<Service>>,
+	Form(form): Form<CreateConsumerForm>,
+) -> axum::response::Response {
+	let permissions = selected_permissions([form.authenticate, form.lookup_users, form.list_users, form.lookup_aliases]);
+	let cert_dn = match Some(form.cert_dn.trim()).filter(|dn| !dn.is_empty()).map(str::parse::<nyanpasswd::dn::DistinguishedName>).transpose() {
+		Ok(cert_dn) => cert_dn,
+		Err(err) => {
+			return (
+				StatusCode::BAD_REQUEST,
+				[("Content-Type", "text/plain")],
+				format!("Invalid certificate DN: {}", err),
+			)
+				.into_response()
+		}
+	};
+	match backend.create_api_consumer(&form.name, cert_dn.as_ref(), &permissions).await {
+		Ok(token) => token_page(form.name, token),
+		Err(err) => sql_error(err),
+	}
+}
+
+#[derive(serde::Deserialize)]
+struct PermissionsForm {
+	id: Uuid,
+	#[serde(default)]
+	authenticate: bool,
+	#[serde(default)]
+	lookup_users: bool,
+	#[serde(default)]
+	list_users: bool,
+	#[serde(default)]
+	lookup_aliases: bool,
+}
+
+async fn set_permissions(
+	State(backend): State<Arc<Service>>,
+	Form(form): Form<PermissionsForm>,
+) -> axum::response::Response {
+	let permissions = selected_permissions([form.authenticate, form.lookup_users, form.list_users, form.lookup_aliases]);
+	match backend.set_api_consumer_permissions(form.id, &permissions).await {
+		Ok(()) => (StatusCode::FOUND, [("Location", "/admin/api_consumers/")]).into_response(),
+		Err(err) => sql_error(err),
+	}
+}
+
+#[derive(serde::Deserialize)]
+struct ConsumerForm {
+	id: Uuid,
+	name: String,
+}
+
+async fn reset_token(State(backend): State<Arc<Service>>, Form(form): Form<ConsumerForm>) -> axum::response::Response {
+	match backend.reset_api_consumer_token(form.id).await {
+		Ok(token) => token_page(form.name, token),
+		Err(err) => sql_error(err),
+	}
+}
+
+async fn delete_consumer(State(backend): State<Arc<Service>>, Form(form): Form<ConsumerForm>) -> axum::response::Response {
+	match backend.rm_api_consumer(form.id).await {
+		Ok(()) => (StatusCode::FOUND, [("Location", "/admin/api_consumers/")]).into_response(),
+		Err(err) => sql_error(err),
+	}
+}
+
+pub fn router(backend: Arc<Service>) -> axum::Router {
+	axum::Router::new()
+		.route("/", axum::routing::get(list_consumers).post(create_consumer))
+		.route("/permissions", axum::routing::post(set_permissions))
+		.route("/reset_token", axum::routing::post(reset_token))
+		.route("/delete", axum::routing::post(delete_consumer))
+		.with_state(backend)
+}
@@ -0,0 +1,94 @@
+/*
+  Copyright © 2022 nyantec GmbH <oss@nyantec.com>
+  Written by Vika Shleina <vsh@nyantec.com>
+  
+  Provided that these terms and disclaimer and all copyright notices
+  are retained or reproduced in an accompanying document, permission
+  is granted to deal in this work without restriction, including un‐
+  limited rights to use, publicly perform, distribute, sell, modify,
+  merge, give away, or sublicence.
+  
+  This work is provided "AS IS" and WITHOUT WARRANTY of any kind, to
+  the utmost extent permitted by applicable law, neither express nor
+  implied; without malicious intent or gross negligence. In no event
+  may a licensor, author or contributor be held liable for indirect,
+  direct, other damage, loss, or other issues arising in any way out
+  of dealing in the work, even if advised of the possibility of such
+  damage or existence of a defect, except proven that it results out
+  of said person's immediate fault when using the work as intended.
+ */
+use sailfish::TemplateOnce;
+use std::sync::Arc;
+
+use axum::{
+	extract::State,
+	http::StatusCode,
+	response::IntoResponse,
+	Form,
+};
+
+use crate::{config, csrf::CsrfToken, Layout, Service};
+
+#[derive(sailfish::TemplateOnce)]
+#[template(path = "domains.stpl")]
+struct DomainsPage {
+	domains: Vec<String>,
+	csrf_token: String,
+}
+
+async fn list_domains(State(backend): State<Arc<Service>>, CsrfToken(csrf_token): CsrfToken) -> axum::response::Response {
+	match backend.list_domains().await {
+		Ok(domains) => axum::response::Html(
+			Layout {
+				company_name: &config::get().site.company_name,
+				impressum_link: &config::get().site.impressum,
+				body: DomainsPage { domains, csrf_token },
+			}
+			.render_once()
+			.unwrap(),
+		)
+		.into_response(),
+		Err(err) => (
+			StatusCode::INTERNAL_SERVER_ERROR,
+			[("Content-Type", "text/plain")],
+			format!("SQL layer error: {}", err),
+		)
+			.into_response()
+	}
+}
+
+#[derive(serde::Deserialize)]
+struct DomainForm {
+	domain: String,
+}
+
+async fn add_domain(State(backend): State<Arc<Service>>, Form(form): Form<DomainForm>) -> axum::response::Response {
+	match backend.add_domain(&form.domain).await {
+		Ok(()) => (StatusCode::FOUND, [("Location", "/admin/domains/")]).into_response(),
+		Err(err) => (
+			StatusCode::INTERNAL_SERVER_ERROR,
+			[("Content-Type", "text/plain")],
+			format!("SQL layer error: {}", err)
+		)
+		.into_response()
+	}
+}
+
+async fn delete_domain(State(backend): State<Arc<Service>>, Form(form): Form<DomainForm>) -> axum::response::Response {
+	match backend.remove_domain(&form.domain).await {
+		Ok(()) => (StatusCode::FOUND, [("Location", "/admin/domains/")]).into_response(),
+		Err(err) => (
+			StatusCode::INTERNAL_SERVER_ERROR,
+			[("Content-Type", "text/plain")],
+			format!("SQL layer error: {}", err)
+		)
+		.into_response()
+	}
+}
+
+pub fn router(backend: Arc<Service>) -> axum::Router {
+	axum::Router::new()
+		.route("/", axum::routing::get(list_domains).post(add_domain))
+		.route("/delete", axum::routing::post(delete_domain))
+		.with_state(backend)
+}
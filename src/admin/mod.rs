@@ -27,32 +27,51 @@ use axum::{
 };
 use chrono::{DateTime, FixedOffset};
 use hyper::StatusCode;
-use nyanpasswd::{axum::CertDn, Password, User};
+use nyanpasswd::axum::{session_token, CertDn, CertDnExtractionError, UserExtractionError};
+use nyanpasswd::{CertificateCheck, ClientCertificate, LoginMethod, Password, User};
 use sailfish::TemplateOnce;
 use uuid::Uuid;
 
-use crate::{Layout, Service, COMPANY_NAME, IMPRESSUM};
+use crate::{config, csrf::CsrfToken, Layout, Service};
 
 mod aliases;
+mod api;
+mod api_consumers;
+mod domains;
 mod non_human;
+mod reconcile;
 
-pub struct Admin(String);
+pub struct Admin;
 #[derive(thiserror::Error, Debug)]
 pub enum AdminRejection {
 	#[error("Not an administrator")]
 	NotAnAdmin,
-	#[error("No UID in certificate")]
-	NoUidInCert,
+	#[error("This client certificate is not pinned for your account")]
+	CertificateNotPinned,
+	#[error("This client certificate was revoked")]
+	CertificateRevoked,
+	#[error("A client certificate of your account was revoked, and this one can't be told apart from it")]
+	CertificateUnidentified,
+	#[error("SQL layer error: {0}")]
+	Sql(#[from] sqlx::Error),
+	#[error("{0}")]
+	Identity(#[from] nyanpasswd::axum::IdentityError),
 	#[error("Certificate parsing error: {0}")]
-	Certificate(#[from] nyanpasswd::axum::CertDnExtractionError),
+	Certificate(#[from] CertDnExtractionError),
+	#[error("{0}")]
+	User(#[from] UserExtractionError),
 }
 impl IntoResponse for AdminRejection {
 	fn into_response(self) -> axum::response::Response {
 		(
 			match &self {
-				Self::NotAnAdmin => StatusCode::FORBIDDEN,
-				Self::NoUidInCert => StatusCode::UNAUTHORIZED,
+				Self::NotAnAdmin | Self::CertificateNotPinned | Self::CertificateRevoked | Self::CertificateUnidentified => {
+					StatusCode::FORBIDDEN
+				}
+				Self::Sql(_) => StatusCode::INTERNAL_SERVER_ERROR,
+				Self::Identity(err) => StatusCode::from(err),
 				Self::Certificate(err) => StatusCode::from(err),
+				Self::User(err) => StatusCode::from(err),
 			},
 			[("Content-Type", "text/plain")],
 			self.to_string(),
@@ -61,21 +80,44 @@ impl IntoResponse for AdminRejection {
 	}
 }
 #[async_trait::async_trait]
-impl<T> FromRequestParts<T> for Admin
-where
-	T: Send + Sync,
-{
+impl FromRequestParts<Arc<Service>> for Admin {
 	type Rejection = AdminRejection;
-	async fn from_request_parts(parts: &mut Parts, state: &T) -> Result<Self, Self::Rejection> {
-		let dn = CertDn::from_request_parts(parts, state).await?;
-		let uid = dn.uid().ok_or(Self::Rejection::NoUidInCert)?;
+	async fn from_request_parts(parts: &mut Parts, backend: &Arc<Service>) -> Result<Self, Self::Rejection> {
+		let dn = match CertDn::from_request_parts(parts, backend).await {
+			// Other ways of logging in only let admins in if explicitly allowed
+			Err(CertDnExtractionError::NoTlsCert) => {
+				let token = session_token(&parts.headers).ok_or(CertDnExtractionError::NoTlsCert)?;
+				let session = backend
+					.find_session(token)
+					.await?
+					.ok_or(UserExtractionError::SessionExpired)?;
+				let allowed = match session.method {
+					LoginMethod::Passkey => config::get().passkeys.as_ref().map_or(false, |passkeys| passkeys.admin),
+					LoginMethod::Oidc => config::get().oidc.as_ref().map_or(false, |oidc| oidc.admin),
+				};
+				if !allowed || !config::get().admin.uids.contains(&session.user.username) {
+					return Err(Self::Rejection::NotAnAdmin);
+				}
+				return Ok(Admin);
+			}
+			result => result?,
+		};
+		let username = dn.username()?;
 
-		// TODO(@vsh): should this be configurable in other ways?
-		if std::env::var("ADMIN_UIDS").unwrap_or_default().split(' ').any(|a| a == uid) {
-			Ok(Admin(uid.to_string()))
-		} else {
-			Err(Self::Rejection::NotAnAdmin)
+		if !config::get().admin.uids.contains(&username) {
+			return Err(Self::Rejection::NotAnAdmin);
 		}
+		// Admins don't need an account, but if they have one, its certificate restrictions apply
+		if let Some(user) = backend.find_user_by_name(&username).await? {
+			match backend.check_client_certificate(&user, &dn).await? {
+				CertificateCheck::Allowed => {}
+				CertificateCheck::NotPinned => return Err(Self::Rejection::CertificateNotPinned),
+				CertificateCheck::Revoked => return Err(Self::Rejection::CertificateRevoked),
+				CertificateCheck::Unidentified => return Err(Self::Rejection::CertificateUnidentified),
+			}
+		}
+
+		Ok(Admin)
 	}
 }
 
@@ -83,15 +125,21 @@ where
 #[template(path = "admin.stpl")]
 struct AdminPage {
 	users: Vec<nyanpasswd::User>,
+	/// How many changes reconciling would make, if a reconciliation file is configured.
+	drift: Option<Result<usize, String>>,
+	csrf_token: String,
 }
 
-async fn homepage(State(backend): State<Arc<Service>>) -> axum::response::Response {
+async fn homepage(State(backend): State<Arc<Service>>, CsrfToken(csrf_token): CsrfToken) -> axum::response::Response {
+	let drift = reconcile::drift(&backend)
+		.await
+		.map(|drift| drift.map(|changes| changes.len()).map_err(|err| err.to_string()));
 	match backend.list_users().await {
 		Ok(users) => axum::response::Html(
 			Layout {
-				company_name: COMPANY_NAME,
-				impressum_link: IMPRESSUM,
-				body: AdminPage { users },
+				company_name: &config::get().site.company_name,
+				impressum_link: &config::get().site.impressum,
+				body: AdminPage { users, drift, csrf_token },
 			}
 			.render_once()
 			.unwrap(),
@@ -184,16 +232,36 @@ struct ManageUserQuery {
 struct ManageUserPage {
 	user: User,
 	passwords: Vec<Password>,
+	alias_limit: i32,
+	certificates: Vec<ClientCertificate>,
+	require_pinned_certificate: bool,
+	csrf_token: String,
 }
 
-async fn manage_user(State(backend): State<Arc<Service>>, Query(user): Query<ManageUserQuery>) -> axum::response::Response {
+async fn manage_user(
+	State(backend): State<Arc<Service>>,
+	Query(user): Query<ManageUserQuery>,
+	CsrfToken(csrf_token): CsrfToken,
+) -> axum::response::Response {
 	match backend.get_user_by_id(user.uid).await {
-		Ok(Some(user)) => match backend.list_passwords_for(&user).await {
-			Ok(passwords) => axum::response::Html(
+		Ok(Some(user)) => match futures::try_join!(
+			backend.list_passwords_for(&user),
+			backend.get_alias_limit(user.id),
+			backend.list_client_certificates_for(user.id),
+			backend.get_require_pinned_certificate(user.id),
+		) {
+			Ok((passwords, alias_limit, certificates, require_pinned_certificate)) => axum::response::Html(
 				Layout {
-					company_name: COMPANY_NAME,
-					impressum_link: IMPRESSUM,
-					body: ManageUserPage { user, passwords },
+					company_name: &config::get().site.company_name,
+					impressum_link: &config::get().site.impressum,
+					body: ManageUserPage {
+						user,
+						passwords,
+						alias_limit,
+						certificates,
+						require_pinned_certificate,
+						csrf_token,
+					},
 				}
 				.render_once()
 				.unwrap(),
@@ -225,6 +293,75 @@ struct ManageUserForm {
 
 async fn deactivate_user(State(backend): State<Arc<Service>>, Form(form): Form<ManageUserForm>) -> axum::response::Response {
 	match backend.toggle_user_login_allowed(form.uid).await {
+		Ok(()) => (
+			StatusCode::FOUND,
+			[("Location", format!("/admin/manage_user?uid={}", form.uid))],
+		)
+			.into_response(),
+		Err(err) => crate::sql_error(err),
+	}
+}
+
+async fn expire_user(State(backend): State<Arc<Service>>, Form(form): Form<ManageUserForm>) -> axum::response::Response {
+	match backend.set_user_expiry_date(form.uid, form.expires_at.into()).await {
+		Ok(()) => (
+			StatusCode::FOUND,
+			[("Location", format!("/admin/manage_user?uid={}", form.uid))],
+		)
+			.into_response(),
+		Err(err) => crate::sql_error(err),
+	}
+}
+
+#[derive(serde::Deserialize)]
+struct AliasLimitForm {
+	uid: Uuid,
+	alias_limit: i32,
+}
+
+async fn set_alias_limit(State(backend): State<Arc<Service>>, Form(form): Form<AliasLimitForm>) -> axum::response::Response {
+	if form.alias_limit < 0 {
+		return (
+			StatusCode::BAD_REQUEST,
+			[("Content-Type", "text/plain")],
+			"The alias limit can't be negative.",
+		)
+			.into_response();
+	}
+	match backend.set_alias_limit(form.uid, form.alias_limit).await {
+		Ok(()) => (
+			StatusCode::FOUND,
+			[("Location", format!("/admin/manage_user?uid={}", form.uid))],
+		)
+			.into_response(),
+		Err(err) => crate::sql_error(err),
+	}
+}
+
+async fn toggle_require_pinned_certificate(
+	State(backend): State<Arc<Service>>,
+	Form(form): Form<ManageUserQuery>,
+) -> axum::response::Response {
+	match backend.toggle_require_pinned_certificate(form.uid).await {
+		Ok(()) => (
+			StatusCode::FOUND,
+			[("Location", format!("/admin/manage_user?uid={}", form.uid))],
+		)
+			.into_response(),
+		Err(err) => crate::sql_error(err),
+	}
+}
+
+#[derive(serde::Deserialize)]
+struct ClientCertificateForm {
+	uid: Uuid,
+	fingerprint: String,
+	/// Whether to pin or revoke the certificate, as opposed to undoing that.
+	set: bool,
+}
+
+async fn pin_certificate(State(backend): State<Arc<Service>>, Form(form): Form<ClientCertificateForm>) -> axum::response::Response {
+	match backend.set_client_certificate_pinned(form.uid, &form.fingerprint, form.set).await {
 		Ok(()) => (
 			StatusCode::FOUND,
 			[("Location", format!("/admin/manage_user?uid={}", form.uid))],
@@ -239,8 +376,8 @@ async fn deactivate_user(State(backend): State<Arc<Service>>, Form(form): Form<M
 	}
 }
 
-async fn expire_user(State(backend): State<Arc<Service>>, Form(form): Form<ManageUserForm>) -> axum::response::Response {
-	match backend.set_user_expiry_date(form.uid, form.expires_at.into()).await {
+async fn revoke_certificate(State(backend): State<Arc<Service>>, Form(form): Form<ClientCertificateForm>) -> axum::response::Response {
+	match backend.set_client_certificate_revoked(form.uid, &form.fingerprint, form.set).await {
 		Ok(()) => (
 			StatusCode::FOUND,
 			[("Location", format!("/admin/manage_user?uid={}", form.uid))],
@@ -262,9 +399,19 @@ pub fn router(backend: Arc<Service>) -> axum::Router {
 		.route("/manage_user", axum::routing::get(manage_user))
 		.route("/expire_user", axum::routing::post(expire_user))
 		.route("/deactivate_user", axum::routing::post(deactivate_user))
+		.route("/set_alias_limit", axum::routing::post(set_alias_limit))
+		.route("/require_pinned_certificate", axum::routing::post(toggle_require_pinned_certificate))
+		.route("/pin_certificate", axum::routing::post(pin_certificate))
+		.route("/revoke_certificate", axum::routing::post(revoke_certificate))
 		.route("/non_human/create_password", axum::routing::post(non_human::create_password))
 		.route("/non_human/delete_password", axum::routing::post(non_human::delete_password))
 		.nest_service("/aliases", aliases::router(backend.clone()))
-		.with_state(backend)
-		.layer(axum::middleware::from_extractor::<Admin>())
+		.nest_service("/domains", domains::router(backend.clone()))
+		.nest_service("/api_consumers", api_consumers::router(backend.clone()))
+		.nest_service("/reconcile", reconcile::router(backend.clone()))
+		.with_state(backend.clone())
+		.layer(axum::middleware::from_fn(crate::csrf::protect))
+		// The admin API only accepts JSON, which other sites can't send, so it's nested afterwards
+		.nest_service("/api/v1", api::router(backend.clone()))
+		.layer(axum::middleware::from_extractor_with_state::<Admin, _>(backend))
 }
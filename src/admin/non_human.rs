@@ -21,17 +21,14 @@ use std::sync::Arc;
 
 use axum::{
 	extract::State,
-	http::request::Parts,
 	response::IntoResponse,
 	Form,
 };
-use chrono::{DateTime, FixedOffset};
 use hyper::StatusCode;
-use nyanpasswd::{axum::CertDn, Password, User};
 use sailfish::TemplateOnce;
 use uuid::Uuid;
 
-use crate::{Layout, Service, COMPANY_NAME, IMPRESSUM, DeletedPasswordPage, NewPasswordPage};
+use crate::{config, Layout, Service, DeletedPasswordPage, NewPasswordPage};
 
 #[derive(serde::Deserialize)]
 #[serde(rename_all = "lowercase")]
@@ -93,12 +90,12 @@ Form(form): Form<CreatePasswordForm>,
 	{
 		Ok(password) => axum::response::Html(
 			Layout {
-				company_name: COMPANY_NAME,
+				company_name: &config::get().site.company_name,
 				body: NewPasswordPage {
 					password,
 					prevlink: Some(format!("/admin/manage_user?uid={}", user.id))
 				},
-				impressum_link: IMPRESSUM,
+				impressum_link: &config::get().site.impressum,
 			}
 			.render_once()
 			.unwrap(),
@@ -144,11 +141,11 @@ pub(crate) async fn delete_password(
 	match backend.rm_password_for(&user, &form.label).await {
 		Ok(()) => axum::response::Html(
 			Layout {
-				company_name: COMPANY_NAME,
+				company_name: &config::get().site.company_name,
 				body: DeletedPasswordPage {
 					prevlink: Some(format!("/admin/manage_user?uid={}", user.id))
 				},
-				impressum_link: IMPRESSUM,
+				impressum_link: &config::get().site.impressum,
 			}
 			.render_once()
 			.unwrap(),
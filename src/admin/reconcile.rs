@@ -0,0 +1,87 @@
+/*
+  Copyright © 2022 nyantec GmbH <oss@nyantec.com>
+  Written by Vika Shleina <vsh@nyantec.com>
+  
+  Provided that these terms and disclaimer and all copyright notices
+  are retained or reproduced in an accompanying document, permission
+  is granted to deal in this work without restriction, including un‐
+  limited rights to use, publicly perform, distribute, sell, modify,
+  merge, give away, or sublicence.
+  
+  This work is provided "AS IS" and WITHOUT WARRANTY of any kind, to
+  the utmost extent permitted by applicable law, neither express nor
+  implied; without malicious intent or gross negligence. In no event
+  may a licensor, author or contributor be held liable for indirect,
+  direct, other damage, loss, or other issues arising in any way out
+  of dealing in the work, even if advised of the possibility of such
+  damage or existence of a defect, except proven that it results out
+  of said person's immediate fault when using the work as intended.
+ */
+use sailfish::TemplateOnce;
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse};
+use nyanpasswd::reconcile::{Change, Manifest, ReconcileError};
+
+use crate::{config, csrf::CsrfToken, Layout, Service};
+
+#[derive(sailfish::TemplateOnce)]
+#[template(path = "reconcile.stpl")]
+struct ReconcilePage {
+	reconcile: Option<&'static config::Reconcile>,
+	drift: Option<Result<Vec<Change>, String>>,
+	csrf_token: String,
+}
+
+/// What reconciling would change, if a file is configured.
+pub(super) async fn drift(backend: &Service) -> Option<Result<Vec<Change>, ReconcileError>> {
+	let reconcile = config::get().reconcile.as_ref()?;
+	Some(match Manifest::load(&reconcile.file) {
+		Ok(manifest) => backend.drift(&manifest).await,
+		Err(err) => Err(err),
+	})
+}
+
+async fn show_drift(State(backend): State<Arc<Service>>, CsrfToken(csrf_token): CsrfToken) -> axum::response::Response {
+	let drift = drift(&backend).await.map(|drift| drift.map_err(|err| err.to_string()));
+	axum::response::Html(
+		Layout {
+			company_name: &config::get().site.company_name,
+			impressum_link: &config::get().site.impressum,
+			body: ReconcilePage {
+				reconcile: config::get().reconcile.as_ref(),
+				drift,
+				csrf_token,
+			},
+		}
+		.render_once()
+		.unwrap(),
+	)
+	.into_response()
+}
+
+async fn apply(State(backend): State<Arc<Service>>) -> axum::response::Response {
+	let reconcile = match &config::get().reconcile {
+		Some(reconcile) => reconcile,
+		None => return StatusCode::NOT_FOUND.into_response(),
+	};
+	let result = match Manifest::load(&reconcile.file) {
+		Ok(manifest) => backend.reconcile(&manifest, false).await,
+		Err(err) => Err(err),
+	};
+	match result {
+		Ok(_) => (StatusCode::FOUND, [("Location", "/admin/reconcile/")]).into_response(),
+		Err(err) => (
+			StatusCode::INTERNAL_SERVER_ERROR,
+			[("Content-Type", "text/plain")],
+			err.to_string(),
+		)
+			.into_response(),
+	}
+}
+
+pub fn router(backend: Arc<Service>) -> axum::Router {
+	axum::Router::new()
+		.route("/", axum::routing::get(show_drift).post(apply))
+		.with_state(backend)
+}
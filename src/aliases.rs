@@ -0,0 +1,135 @@
+/*
+  Copyright © 2022 nyantec GmbH <oss@nyantec.com>
+  Written by Vika Shleina <vsh@nyantec.com>
+  
+  Provided that these terms and disclaimer and all copyright notices
+  are retained or reproduced in an accompanying document, permission
+  is granted to deal in this work without restriction, including un‐
+  limited rights to use, publicly perform, distribute, sell, modify,
+  merge, give away, or sublicence.
+  
+  This work is provided "AS IS" and WITHOUT WARRANTY of any kind, to
+  the utmost extent permitted by applicable law, neither express nor
+  implied; without malicious intent or gross negligence. In no event
+  may a licensor, author or contributor be held liable for indirect,
+  direct, other damage, loss, or other issues arising in any way out
+  of dealing in the work, even if advised of the possibility of such
+  damage or existence of a defect, except proven that it results out
+  of said person's immediate fault when using the work as intended.
+ */
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Form};
+use nyanpasswd::{SelfServiceAliasName, SelfServiceAliasResult};
+
+use crate::Service;
+
+#[derive(serde::Deserialize)]
+pub(crate) struct CreateAliasForm {
+	/// Empty if a random name should be generated.
+	template: String,
+	#[serde(default)]
+	word: String,
+}
+
+pub(crate) async fn create_alias(
+	State(backend): State<Arc<Service>>,
+	user: nyanpasswd::User,
+	Form(form): Form<CreateAliasForm>,
+) -> axum::response::Response {
+	let name = if form.template.is_empty() {
+		SelfServiceAliasName::Random
+	} else {
+		SelfServiceAliasName::Template {
+			template: &form.template,
+			word: &form.word,
+		}
+	};
+
+	match backend.create_self_service_alias(&user, name).await {
+		Ok(SelfServiceAliasResult::Created(_)) => (StatusCode::FOUND, [("Location", "/")]).into_response(),
+		Ok(SelfServiceAliasResult::LimitReached) => (
+			StatusCode::FORBIDDEN,
+			[("Content-Type", "text/plain")],
+			"You have reached your alias limit. Delete an alias or ask an administrator to raise the limit.",
+		)
+			.into_response(),
+		Ok(SelfServiceAliasResult::NameTaken) => (
+			StatusCode::CONFLICT,
+			[("Content-Type", "text/plain")],
+			"This alias name is already taken.",
+		)
+			.into_response(),
+		Ok(SelfServiceAliasResult::InvalidName) => (
+			StatusCode::BAD_REQUEST,
+			[("Content-Type", "text/plain")],
+			"Invalid alias name: only lowercase letters and digits are allowed, up to 64 characters in total.",
+		)
+			.into_response(),
+		Ok(SelfServiceAliasResult::UnknownTemplate) => (
+			StatusCode::BAD_REQUEST,
+			[("Content-Type", "text/plain")],
+			"This alias template is not approved by an administrator.",
+		)
+			.into_response(),
+		Err(err) => crate::sql_error(err),
+	}
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct ToggleAliasForm {
+	alias_name: String,
+	enabled: bool,
+}
+
+pub(crate) async fn toggle_alias(
+	State(backend): State<Arc<Service>>,
+	user: nyanpasswd::User,
+	Form(form): Form<ToggleAliasForm>,
+) -> axum::response::Response {
+	match backend.set_self_service_alias_enabled(&user, &form.alias_name, form.enabled).await {
+		Ok(()) => (StatusCode::FOUND, [("Location", "/")]).into_response(),
+		Err(err) => crate::sql_error(err),
+	}
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct DeleteAliasForm {
+	alias_name: String,
+}
+
+pub(crate) async fn delete_alias(
+	State(backend): State<Arc<Service>>,
+	user: nyanpasswd::User,
+	Form(form): Form<DeleteAliasForm>,
+) -> axum::response::Response {
+	match backend.rm_self_service_alias_for(&user, &form.alias_name).await {
+		Ok(()) => (StatusCode::FOUND, [("Location", "/")]).into_response(),
+		Err(err) => crate::sql_error(err),
+	}
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct LeaveAliasForm {
+	alias_name: String,
+	#[serde(default)]
+	domain: String,
+}
+
+pub(crate) async fn leave_alias(
+	State(backend): State<Arc<Service>>,
+	user: nyanpasswd::User,
+	Form(form): Form<LeaveAliasForm>,
+) -> axum::response::Response {
+	let domain = Some(form.domain.as_str()).filter(|d| !d.is_empty());
+	match backend.leave_alias(&user, &form.alias_name, domain).await {
+		Ok(true) => (StatusCode::FOUND, [("Location", "/")]).into_response(),
+		Ok(false) => (
+			StatusCode::FORBIDDEN,
+			[("Content-Type", "text/plain")],
+			"You can't leave this alias on your own. Ask an administrator to remove you from it.",
+		)
+			.into_response(),
+		Err(err) => crate::sql_error(err),
+	}
+}
@@ -20,51 +20,91 @@
 use std::sync::Arc;
 
 use axum::{
-	extract::State,
-	http::StatusCode,
+	extract::{ConnectInfo, State},
+	http::{header, HeaderValue, Request, StatusCode},
+	middleware::Next,
 	response::{IntoResponse, Response},
 	Json,
 };
+use utoipa::OpenApi;
 
+use crate::unix::{peer_has_permission, PeerCred, PeerRule};
 use crate::Service;
+use nyanpasswd::axum::{CertDn, CertDnExtractionError};
+use nyanpasswd::{ApiPermission, Authenticated, User, UserQuery};
 
-#[derive(serde::Deserialize)]
+#[derive(serde::Deserialize, utoipa::ToSchema)]
 struct AuthenticationForm {
 	user: String,
 	password: String,
 }
 
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct AuthenticationError {
+	error: nyanpasswd::AuthenticationFailure,
+}
+
 /// Check a user password and return one of the following responses:
 /// - `200 OK` - password is correct
 /// - `400 Bad Request` - this user is unknown
 /// - `403 Forbidden` - login disabled by administrator
 /// - `401 Unauthorized` - this password is either expired or it is not valid
 /// - `500 Internal Server Error` - service suffered an internal error
-async fn authenticate_user(State(db): State<Arc<Service>>, Json(form): Json<AuthenticationForm>) -> StatusCode {
-	use nyanpasswd::AuthenticationResult as Auth;
-
-	match db.verify_password(&form.user, &form.password).await {
-		Ok(result) => match result {
-			Auth::Ok => StatusCode::OK,
-			Auth::NoSuchUser => StatusCode::BAD_REQUEST,
-			Auth::LoginDisabled => StatusCode::FORBIDDEN,
-			Auth::IncorrectPassword => StatusCode::UNAUTHORIZED,
-		},
+///
+/// Except for internal errors, the reply body contains either the user and the label of the
+/// matched password, or the reason for rejection, so that clients don't need a separate lookup.
+#[utoipa::path(
+	post,
+	path = "/authenticate",
+	request_body = AuthenticationForm,
+	responses(
+		(status = 200, description = "Password is correct", body = Authenticated),
+		(status = 400, description = "This user is unknown", body = AuthenticationError),
+		(status = 401, description = "This password is either expired or it is not valid", body = AuthenticationError),
+		(status = 403, description = "Login disabled by administrator", body = AuthenticationError),
+		(status = 500, description = "Service suffered an internal error"),
+	)
+)]
+async fn authenticate_user(State(db): State<Arc<Service>>, Json(form): Json<AuthenticationForm>) -> Response {
+	use nyanpasswd::AuthenticationFailure as Failure;
+
+	match db.authenticate(&form.user, &form.password).await {
+		Ok(Ok(authenticated)) => (StatusCode::OK, Json(authenticated)).into_response(),
+		Ok(Err(failure)) => (
+			match failure {
+				Failure::NoSuchUser => StatusCode::BAD_REQUEST,
+				Failure::LoginDisabled => StatusCode::FORBIDDEN,
+				Failure::IncorrectPassword => StatusCode::UNAUTHORIZED,
+			},
+			Json(AuthenticationError { error: failure }),
+		)
+			.into_response(),
 		Err(err) => {
 			tracing::error!("Error verifying password: {}", err);
-			StatusCode::INTERNAL_SERVER_ERROR
+			StatusCode::INTERNAL_SERVER_ERROR.into_response()
 		}
 	}
 }
 
-#[derive(serde::Deserialize)]
+#[derive(serde::Deserialize, utoipa::ToSchema)]
 struct LookupForm {
 	user: String,
 }
 
-/// Look up a user in the database and return some info about it. Return 404 if the user does not exist.
+/// Look up a user in the database by their username or email address and return some info about it. Return 404 if the
+/// user does not exist or the address is on an unknown domain.
+#[utoipa::path(
+	post,
+	path = "/user_lookup",
+	request_body = LookupForm,
+	responses(
+		(status = 200, description = "User found", body = User),
+		(status = 404, description = "No such user"),
+		(status = 500, description = "Service suffered an internal error"),
+	)
+)]
 async fn lookup_user(State(db): State<Arc<Service>>, Json(form): Json<LookupForm>) -> Response {
-	match db.find_user_by_name(&form.user).await {
+	match db.find_user_by_address(&form.user).await {
 		Ok(Some(user)) => {
 			tracing::debug!("Replying with user data for {}: {:#?}", form.user, user);
 			axum::response::Json(user).into_response()
@@ -77,9 +117,344 @@ async fn lookup_user(State(db): State<Arc<Service>>, Json(form): Json<LookupForm
 	}
 }
 
-pub fn router(backend: Arc<Service>) -> axum::Router {
+/// List users matching the given filters, ordered by username. Every entry has the same shape as a `/user_lookup` reply.
+#[utoipa::path(
+	post,
+	path = "/users",
+	request_body = UserQuery,
+	responses(
+		(status = 200, description = "A page of users", body = [User]),
+		(status = 500, description = "Service suffered an internal error"),
+	)
+)]
+async fn list_users(State(db): State<Arc<Service>>, Json(query): Json<UserQuery>) -> Response {
+	match db.search_users(&query).await {
+		Ok(users) => axum::response::Json(users).into_response(),
+		Err(err) => {
+			tracing::error!("Error listing users: {}", err);
+			StatusCode::INTERNAL_SERVER_ERROR.into_response()
+		}
+	}
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+struct AliasLookupForm {
+	alias: String,
+}
+
+/// Resolve an alias into the users it delivers to. Return 404 if there are no (non-expired) users behind this alias.
+#[utoipa::path(
+	post,
+	path = "/alias_lookup",
+	request_body = AliasLookupForm,
+	responses(
+		(status = 200, description = "Users the alias delivers to", body = [User]),
+		(status = 404, description = "No such alias, or all of its destinations have expired"),
+		(status = 500, description = "Service suffered an internal error"),
+	)
+)]
+async fn lookup_alias(State(db): State<Arc<Service>>, Json(form): Json<AliasLookupForm>) -> Response {
+	match db.lookup_alias(&form.alias).await {
+		Ok(users) if users.is_empty() => StatusCode::NOT_FOUND.into_response(),
+		Ok(users) => {
+			tracing::debug!("Replying with destinations for alias {}: {:#?}", form.alias, users);
+			axum::response::Json(users).into_response()
+		}
+		Err(err) => {
+			tracing::error!("Error looking up alias: {}", err);
+			StatusCode::INTERNAL_SERVER_ERROR.into_response()
+		}
+	}
+}
+
+/// List names of aliases a user is a member of. Return 404 if the user does not exist.
+#[utoipa::path(
+	post,
+	path = "/user_aliases",
+	request_body = LookupForm,
+	responses(
+		(status = 200, description = "Names of aliases the user is a member of", body = [String]),
+		(status = 404, description = "No such user"),
+		(status = 500, description = "Service suffered an internal error"),
+	)
+)]
+async fn user_aliases(State(db): State<Arc<Service>>, Json(form): Json<LookupForm>) -> Response {
+	let user = match db.find_user_by_address(&form.user).await {
+		Ok(Some(user)) => user,
+		Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+		Err(err) => {
+			tracing::error!("Error looking up user: {}", err);
+			return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+		}
+	};
+
+	match db.list_aliases_for(&user).await {
+		Ok(aliases) => axum::response::Json(aliases).into_response(),
+		Err(err) => {
+			tracing::error!("Error listing aliases for user: {}", err);
+			StatusCode::INTERNAL_SERVER_ERROR.into_response()
+		}
+	}
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+struct ResolveRecipientForm {
+	recipient: String,
+}
+
+/// Resolve a local part into the final set of users that should receive mail sent to it, following alias shadowing
+/// rules. Return 404 if the mail should bounce.
+#[utoipa::path(
+	post,
+	path = "/resolve_recipient",
+	request_body = ResolveRecipientForm,
+	responses(
+		(status = 200, description = "Users that should receive the mail", body = [User]),
+		(status = 404, description = "The mail should bounce"),
+		(status = 500, description = "Service suffered an internal error"),
+	)
+)]
+async fn resolve_recipient(State(db): State<Arc<Service>>, Json(form): Json<ResolveRecipientForm>) -> Response {
+	match db.resolve_recipient(&form.recipient).await {
+		Ok(users) if users.is_empty() => StatusCode::NOT_FOUND.into_response(),
+		Ok(users) => {
+			tracing::debug!("Replying with delivery set for {}: {:#?}", form.recipient, users);
+			axum::response::Json(users).into_response()
+		}
+		Err(err) => {
+			tracing::error!("Error resolving recipient: {}", err);
+			StatusCode::INTERNAL_SERVER_ERROR.into_response()
+		}
+	}
+}
+
+struct SecuritySchemes;
+impl utoipa::Modify for SecuritySchemes {
+	fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+		use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+
+		let components = openapi.components.get_or_insert_with(Default::default);
+		components.add_security_scheme(
+			"api_token",
+			SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+		);
+	}
+}
+
+#[derive(OpenApi)]
+#[openapi(
+	servers((url = "/api/v1")),
+	modifiers(&SecuritySchemes),
+	security(("api_token" = [])),
+	paths(authenticate_user, lookup_user, list_users, lookup_alias, user_aliases, resolve_recipient),
+	components(schemas(
+		AuthenticationForm,
+		AuthenticationError,
+		LookupForm,
+		AliasLookupForm,
+		ResolveRecipientForm,
+		User,
+		UserQuery,
+		nyanpasswd::UserStatus,
+		Authenticated,
+		nyanpasswd::AuthenticationFailure,
+	))
+)]
+struct ApiDoc;
+
+async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+	Json(ApiDoc::openapi())
+}
+
+/// Mark a response as coming from a deprecated unversioned endpoint, pointing to its successor.
+async fn deprecated<B>(request: Request<B>, next: Next<B>) -> Response {
+	// Relative to the deprecated path, e.g. `/api/authenticate` -> `/api/v1/authenticate`.
+	let successor = HeaderValue::from_str(&format!("<v1{}>; rel=\"successor-version\"", request.uri().path()));
+	let mut response = next.run(request).await;
+	response.headers_mut().insert("Deprecation", HeaderValue::from_static("true"));
+	if let Ok(successor) = successor {
+		response.headers_mut().insert(header::LINK, successor);
+	}
+	response
+}
+
+/// How callers of the API are identified.
+#[derive(Clone)]
+pub enum Access {
+	/// Registered API consumers. If `allow_anonymous` is set, requests that don't identify an API
+	/// consumer are allowed to use every endpoint.
+	Consumers { allow_anonymous: bool },
+	/// Peers on a Unix socket, authorized by their credentials.
+	UnixPeers(&'static [PeerRule]),
+}
+
+#[derive(Clone)]
+struct Guard {
+	backend: Arc<Service>,
+	permission: ApiPermission,
+	access: Access,
+}
+
+/// Only let API consumers with the required permission through.
+///
+/// Consumers identify themselves with a bearer token, or with a TLS client certificate registered
+/// for them. Requests carrying neither are let through only if anonymous access is allowed.
+///
+/// On a Unix socket, the peer credentials of the calling process are checked instead.
+async fn authorize<B>(State(guard): State<Guard>, request: Request<B>, next: Next<B>) -> Response {
+	let (parts, body) = request.into_parts();
+	let allow_anonymous = match &guard.access {
+		Access::Consumers { allow_anonymous } => *allow_anonymous,
+		Access::UnixPeers(rules) => {
+			let Some(ConnectInfo(PeerCred(peer))) = parts.extensions.get::<ConnectInfo<PeerCred>>() else {
+				tracing::error!("Unix socket connection without peer credentials");
+				return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+			};
+			if !peer_has_permission(rules, peer, guard.permission) {
+				tracing::warn!(
+					"Unix peer uid={} gid={} lacks permission {}",
+					peer.uid(),
+					peer.gid(),
+					guard.permission.as_str()
+				);
+				return (
+					StatusCode::FORBIDDEN,
+					[("Content-Type", "text/plain")],
+					format!("Permission `{}` required", guard.permission.as_str()),
+				)
+					.into_response();
+			}
+			return next.run(Request::from_parts(parts, body)).await;
+		}
+	};
+	let bearer_token = parts
+		.headers
+		.get(header::AUTHORIZATION)
+		.and_then(|value| value.to_str().ok())
+		.and_then(|value| value.strip_prefix("Bearer "))
+		.map(str::to_owned);
+
+	let consumer = if let Some(token) = bearer_token {
+		match guard.backend.find_api_consumer_by_token(&token).await {
+			Ok(Some(consumer)) => Some(consumer),
+			Ok(None) => return unauthorized("Invalid API token"),
+			Err(err) => {
+				tracing::error!("Error looking up API consumer: {}", err);
+				return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+			}
+		}
+	} else {
+		match CertDn::from_request(&parts) {
+			Ok(cert_dn) => match cert_dn.parse() {
+				Ok(dn) => match guard.backend.find_api_consumer_by_cert_dn(&dn).await {
+					Ok(consumer) => consumer,
+					Err(err) => {
+						tracing::error!("Error looking up API consumer: {}", err);
+						return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+					}
+				},
+				Err(_) => None,
+			},
+			// Forged certificate headers mustn't fall back to anonymous access
+			Err(err @ CertDnExtractionError::UntrustedProxy) => return err.into_response(),
+			Err(_) => None,
+		}
+	};
+
+	match consumer {
+		Some(consumer) if consumer.has_permission(guard.permission) => {
+			tracing::debug!("API consumer {} authorized for {}", consumer.name, guard.permission.as_str());
+		}
+		Some(consumer) => {
+			tracing::warn!("API consumer {} lacks permission {}", consumer.name, guard.permission.as_str());
+			return (
+				StatusCode::FORBIDDEN,
+				[("Content-Type", "text/plain")],
+				format!("Permission `{}` required", guard.permission.as_str()),
+			)
+				.into_response();
+		}
+		None if allow_anonymous => {}
+		None => return unauthorized("API token or registered client certificate required"),
+	}
+
+	next.run(Request::from_parts(parts, body)).await
+}
+
+fn unauthorized(message: &'static str) -> Response {
+	(
+		StatusCode::UNAUTHORIZED,
+		[(header::WWW_AUTHENTICATE, "Bearer"), (header::CONTENT_TYPE, "text/plain")],
+		message,
+	)
+		.into_response()
+}
+
+fn v1(backend: &Arc<Service>, access: &Access) -> axum::Router<Arc<Service>> {
+	let require = |permission| {
+		axum::middleware::from_fn_with_state(
+			Guard {
+				backend: backend.clone(),
+				permission,
+				access: access.clone(),
+			},
+			authorize,
+		)
+	};
 	axum::Router::new()
-		.route("/authenticate", axum::routing::post(authenticate_user))
-		.route("/user_lookup", axum::routing::post(lookup_user))
+		.route(
+			"/authenticate",
+			axum::routing::post(authenticate_user).route_layer(require(ApiPermission::Authenticate)),
+		)
+		.route(
+			"/user_lookup",
+			axum::routing::post(lookup_user).route_layer(require(ApiPermission::LookupUsers)),
+		)
+		.route(
+			"/users",
+			axum::routing::post(list_users).route_layer(require(ApiPermission::ListUsers)),
+		)
+		.route(
+			"/alias_lookup",
+			axum::routing::post(lookup_alias).route_layer(require(ApiPermission::LookupAliases)),
+		)
+		.route(
+			"/user_aliases",
+			axum::routing::post(user_aliases).route_layer(require(ApiPermission::LookupUsers)),
+		)
+		.route(
+			"/resolve_recipient",
+			axum::routing::post(resolve_recipient).route_layer(require(ApiPermission::LookupAliases)),
+		)
+}
+
+/// Build the API router, authorizing callers according to `access`.
+pub fn router(backend: Arc<Service>, access: Access) -> axum::Router {
+	axum::Router::new()
+		.route("/v1/openapi.json", axum::routing::get(openapi))
+		.nest("/v1", v1(&backend, &access))
+		// Unversioned paths predate `/v1` and are kept for existing consumers.
+		.merge(v1(&backend, &access).layer(axum::middleware::from_fn(deprecated)))
 		.with_state(backend)
 }
+
+#[cfg(test)]
+mod test {
+	use utoipa::OpenApi;
+
+	/// The OpenAPI document is checked in, so that changes to the API are visible in review.
+	/// Run with `UPDATE_OPENAPI=1` to regenerate it after an intentional change.
+	#[test]
+	fn test_openapi_document() {
+		let path = concat!(env!("CARGO_MANIFEST_DIR"), "/openapi.json");
+		let document = super::ApiDoc::openapi().to_pretty_json().unwrap() + "\n";
+		if std::env::var_os("UPDATE_OPENAPI").is_some() {
+			std::fs::write(path, &document).unwrap();
+		}
+		assert_eq!(
+			std::fs::read_to_string(path).unwrap(),
+			document,
+			"API description changed, rerun with UPDATE_OPENAPI=1 if this is intentional"
+		);
+	}
+}
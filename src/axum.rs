@@ -17,30 +17,628 @@
   damage or existence of a defect, except proven that it results out
   of said person's immediate fault when using the work as intended.
  */
-use axum::extract::FromRequestParts;
-use axum::http::{request::Parts, StatusCode};
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::{request::Parts, HeaderMap, StatusCode};
 use axum::response::{IntoResponse, Response};
+use once_cell::sync::OnceCell;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 
 use super::{MigrationsDone, Service, User};
 
+/// Where the username of a client certificate's owner is taken from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdentitySource {
+	/// An attribute of the subject DN, e.g. `UID`, `CN` or `emailAddress`.
+	Attribute(String),
+	/// A subject alternative name. This requires the whole certificate, not just its subject DN.
+	SubjectAltName(SanKind),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SanKind {
+	Email,
+	Dns,
+	Uri,
+}
+
+impl std::str::FromStr for IdentitySource {
+	type Err = String;
+
+	/// Parse an attribute type like `UID`, or a SAN kind like `SAN:email`, `SAN:dns` or `SAN:uri`.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if let Some(kind) = s.strip_prefix("SAN:").or_else(|| s.strip_prefix("san:")) {
+			return match kind.to_ascii_lowercase().as_str() {
+				"email" => Ok(Self::SubjectAltName(SanKind::Email)),
+				"dns" => Ok(Self::SubjectAltName(SanKind::Dns)),
+				"uri" => Ok(Self::SubjectAltName(SanKind::Uri)),
+				_ => Err(format!("unsupported subject alternative name kind `{}`", kind)),
+			};
+		}
+		if s.is_empty() || !s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.') {
+			return Err(format!("`{}` is not an attribute type", s));
+		}
+		Ok(Self::Attribute(crate::dn::canonical_attribute_type(s).to_owned()))
+	}
+}
+
+impl std::fmt::Display for IdentitySource {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Attribute(name) => f.write_str(name),
+			Self::SubjectAltName(SanKind::Email) => f.write_str("SAN:email"),
+			Self::SubjectAltName(SanKind::Dns) => f.write_str("SAN:dns"),
+			Self::SubjectAltName(SanKind::Uri) => f.write_str("SAN:uri"),
+		}
+	}
+}
+
+/// A regex replacement applied to usernames taken from certificates, e.g. to strip a domain.
+#[derive(Debug, Clone)]
+pub struct Rewrite {
+	pub pattern: regex::Regex,
+	/// Replacement for the first match of `pattern`, which may refer to capture groups like `$1`.
+	pub replacement: String,
+}
+
+/// How client certificates are mapped to usernames.
+#[derive(Debug, Clone)]
+pub struct IdentityMapping {
+	pub source: IdentitySource,
+	/// Applied to the username in order. Rewrites whose pattern doesn't match have no effect.
+	pub rewrites: Vec<Rewrite>,
+}
+
+impl Default for IdentityMapping {
+	/// Use the `UID` attribute of the subject DN as it is.
+	fn default() -> Self {
+		Self {
+			source: IdentitySource::Attribute("UID".to_owned()),
+			rewrites: vec![],
+		}
+	}
+}
+
+impl IdentityMapping {
+	/// Find the username of the owner of a client certificate.
+	pub fn username(&self, cert: &CertDn) -> Result<String, IdentityError> {
+		let mut candidates: Vec<String> = match &self.source {
+			IdentitySource::Attribute(name) => cert.parse()?.values(name).map(str::to_owned).collect(),
+			IdentitySource::SubjectAltName(kind) => {
+				use x509_parser::extensions::GeneralName;
+
+				let der = cert.certificate_der().ok_or(IdentityError::NoCertificate)?;
+				let (_, certificate) =
+					x509_parser::parse_x509_certificate(der).map_err(|_| IdentityError::InvalidCertificate)?;
+				match certificate
+					.subject_alternative_name()
+					.map_err(|_| IdentityError::InvalidCertificate)?
+				{
+					Some(san) => san
+						.value
+						.general_names
+						.iter()
+						.filter_map(|name| match (kind, name) {
+							(SanKind::Email, GeneralName::RFC822Name(value))
+							| (SanKind::Dns, GeneralName::DNSName(value))
+							| (SanKind::Uri, GeneralName::URI(value)) => Some(value.to_string()),
+							_ => None,
+						})
+						.collect(),
+					None => vec![],
+				}
+			}
+		};
+		candidates.dedup();
+		let mut username = match candidates.len() {
+			0 => return Err(IdentityError::Missing(self.source.to_string())),
+			1 => candidates.pop().unwrap(),
+			_ => return Err(IdentityError::Ambiguous(self.source.to_string())),
+		};
+		for rewrite in &self.rewrites {
+			username = rewrite.pattern.replace(&username, &rewrite.replacement).into_owned();
+		}
+		if username.is_empty() {
+			return Err(IdentityError::Missing(self.source.to_string()));
+		}
+
+		Ok(username)
+	}
+}
+
 lazy_static::lazy_static! {
-	static ref DN_UID_REGEX: regex::Regex = regex::Regex::new(r#"UID ?= ?([a-z][a-z][a-z])"#).unwrap();
+	static ref DEFAULT_IDENTITY_MAPPING: IdentityMapping = IdentityMapping::default();
 }
 
-const ERROR_MESSAGE_TLS_PROXY_MISCONFIGURED: &str = "TLS-terminating reverse proxy is misconfigured: required headers not found.
+static IDENTITY_MAPPING: OnceCell<IdentityMapping> = OnceCell::new();
 
-Hint: if you use nginx, use:
+/// Use a different mapping from certificates to usernames than [`IdentityMapping::default`] for
+/// [`CertDn::username`].
+///
+/// This can only be done once, before handling any requests. If a mapping was already set, the
+/// new one is returned back.
+pub fn set_identity_mapping(mapping: IdentityMapping) -> Result<(), IdentityMapping> {
+	IDENTITY_MAPPING.set(mapping)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum IdentityError {
+	#[error("Invalid subject DN in client certificate: {0}")]
+	InvalidDn(#[from] crate::dn::DnParseError),
+	#[error("No {0} in client certificate")]
+	Missing(String),
+	#[error("Client certificate has multiple different {0} values")]
+	Ambiguous(String),
+	#[error("The client certificate wasn't forwarded by the reverse proxy, only its subject DN")]
+	NoCertificate,
+	#[error("Client certificate could not be parsed")]
+	InvalidCertificate,
+}
+
+impl From<&IdentityError> for StatusCode {
+	fn from(err: &IdentityError) -> Self {
+		match err {
+			IdentityError::NoCertificate => StatusCode::INTERNAL_SERVER_ERROR,
+			_ => StatusCode::BAD_REQUEST,
+		}
+	}
+}
+
+/// How a TLS-terminating reverse proxy passes on client certificates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProxyHeaderFormat {
+	/// nginx: the verification result in `X-SSL-Verify`, the subject DN in `X-SSL-Client-Dn`, and
+	/// optionally the URL-encoded PEM certificate in `X-SSL-Client-Cert`.
+	#[default]
+	Nginx,
+	/// Traefik's `passTLSClientCert` middleware with `pem: true`: the URL-encoded certificate chain
+	/// in `X-Forwarded-Tls-Client-Cert`. Traefik doesn't say whether it verified the certificate, so
+	/// its `clientAuthType` must be `VerifyClientCertIfGiven` or `RequireAndVerifyClientCert`.
+	Traefik,
+	/// HAProxy: the base64-encoded DER certificate in `X-SSL-Client-Cert`, and the verification
+	/// result in `X-SSL-Client-Verify`.
+	Haproxy,
+}
+
+impl ProxyHeaderFormat {
+	/// What to tell the admin if the headers are missing.
+	fn configuration_hint(self) -> &'static str {
+		match self {
+			Self::Nginx => {
+				"Hint: if you use nginx, use:
     proxy_set_header X-SSL-Verify $ssl_client_verify;
     proxy_set_header X-SSL-Client-Dn $ssl_client_s_dn;
 to provide the necessary headers.
-";
+"
+			}
+			// Traefik omits the header if there is no certificate, so it is never missing
+			Self::Traefik => "",
+			Self::Haproxy => {
+				"Hint: use:
+    http-request set-header X-SSL-Client-Verify %[ssl_c_verify]
+    http-request set-header X-SSL-Client-Cert %[ssl_c_der,base64]
+to provide the necessary headers.
+"
+			}
+		}
+	}
+}
+
+impl std::str::FromStr for ProxyHeaderFormat {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_ascii_lowercase().as_str() {
+			"nginx" => Ok(Self::Nginx),
+			"traefik" => Ok(Self::Traefik),
+			"haproxy" => Ok(Self::Haproxy),
+			_ => Err(format!("unknown reverse proxy `{}`, expected nginx, traefik or haproxy", s)),
+		}
+	}
+}
+
+impl std::fmt::Display for ProxyHeaderFormat {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(match self {
+			Self::Nginx => "nginx",
+			Self::Traefik => "traefik",
+			Self::Haproxy => "haproxy",
+		})
+	}
+}
+
+static PROXY_HEADER_FORMAT: OnceCell<ProxyHeaderFormat> = OnceCell::new();
+
+/// Expect client certificates in a different format than nginx's in [`CertDn`].
+///
+/// This can only be done once, before handling any requests. If a format was already set, the
+/// new one is returned back.
+pub fn set_proxy_header_format(format: ProxyHeaderFormat) -> Result<(), ProxyHeaderFormat> {
+	PROXY_HEADER_FORMAT.set(format)
+}
+
+/// Every header a reverse proxy may pass client certificates in, whatever the [`ProxyHeaderFormat`].
+const CERTIFICATE_HEADERS: &[&str] = &[
+	"X-SSL-Verify",
+	"X-SSL-Client-Dn",
+	"X-SSL-Client-Cert",
+	"X-SSL-Client-Verify",
+	"X-Forwarded-Tls-Client-Cert",
+];
+
+/// The header a reverse proxy sends [`TrustedProxies::secret`] in.
+pub const PROXY_SECRET_HEADER: &str = "X-Proxy-Secret";
+
+/// An IP network, like `10.0.0.0/8`, or a single address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+	address: IpAddr,
+	prefix: u8,
+}
+
+/// Turn an IPv4-mapped IPv6 address back into the IPv4 address it maps.
+fn canonical(address: IpAddr) -> IpAddr {
+	match address {
+		IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(address, IpAddr::V4),
+		IpAddr::V4(_) => address,
+	}
+}
+
+impl IpNetwork {
+	pub fn contains(&self, address: IpAddr) -> bool {
+		// A dual-stack socket sees IPv4 peers as IPv4-mapped IPv6 addresses
+		match (self.address, canonical(address)) {
+			(IpAddr::V4(network), IpAddr::V4(address)) => {
+				let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+				u32::from(network) & mask == u32::from(address) & mask
+			}
+			(IpAddr::V6(network), IpAddr::V6(address)) => {
+				let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+				u128::from(network) & mask == u128::from(address) & mask
+			}
+			_ => false,
+		}
+	}
+}
+
+impl std::str::FromStr for IpNetwork {
+	type Err = String;
+
+	/// Parse an address with an optional prefix length, like `10.0.0.0/8`, `::1` or `fd00::/8`.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (address, prefix) = match s.split_once('/') {
+			Some((address, prefix)) => (address, Some(prefix)),
+			None => (s, None),
+		};
+		let address: IpAddr = address.parse().map_err(|_| format!("`{}` is not an IP address", address))?;
+		let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+		let prefix = match prefix {
+			Some(prefix) => match prefix.parse() {
+				Ok(prefix) if prefix <= max_prefix => prefix,
+				_ => return Err(format!("`{}` is not a valid prefix length", prefix)),
+			},
+			None => max_prefix,
+		};
+		Ok(Self { address, prefix })
+	}
+}
+
+impl std::fmt::Display for IpNetwork {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}/{}", self.address, self.prefix)
+	}
+}
+
+/// Which reverse proxies [`CertDn`] believes about client certificates.
+///
+/// If both networks and a secret are set, a proxy has to satisfy both.
+#[derive(Clone)]
+pub struct TrustedProxies {
+	/// Networks the proxy may connect from. If empty, any address is fine.
+	pub networks: Vec<IpNetwork>,
+	/// A secret the proxy has to send in the [`PROXY_SECRET_HEADER`] header.
+	pub secret: Option<String>,
+}
+
+impl std::fmt::Debug for TrustedProxies {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("TrustedProxies")
+			.field("networks", &self.networks)
+			.field("secret", &self.secret.as_ref().map(|_| "<redacted>"))
+			.finish()
+	}
+}
+
+impl Default for TrustedProxies {
+	/// Trust proxies running on the same host.
+	fn default() -> Self {
+		Self {
+			networks: vec!["127.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()],
+			secret: None,
+		}
+	}
+}
+
+impl TrustedProxies {
+	/// Check whether a request from `peer` with `headers` comes from a trusted proxy.
+	///
+	/// If the peer address isn't known, only the secret can be checked.
+	fn trusts(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> bool {
+		let address_trusted = self.networks.is_empty()
+			|| peer.map_or(false, |peer| self.networks.iter().any(|network| network.contains(peer)));
+		let secret_trusted = match &self.secret {
+			Some(secret) => headers
+				.get(PROXY_SECRET_HEADER)
+				.map_or(false, |value| constant_time_eq(value.as_bytes(), secret.as_bytes())),
+			None => true,
+		};
+		address_trusted && secret_trusted && (peer.is_some() || self.secret.is_some())
+	}
+}
+
+/// Compare secrets without leaking how much of them matched through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+lazy_static::lazy_static! {
+	static ref DEFAULT_TRUSTED_PROXIES: TrustedProxies = TrustedProxies::default();
+}
+
+static TRUSTED_PROXIES: OnceCell<TrustedProxies> = OnceCell::new();
+
+/// Trust different reverse proxies than [`TrustedProxies::default`] in [`CertDn`].
+///
+/// This can only be done once, before handling any requests. If trusted proxies were already set,
+/// the new ones are returned back.
+pub fn set_trusted_proxies(proxies: TrustedProxies) -> Result<(), TrustedProxies> {
+	TRUSTED_PROXIES.set(proxies)
+}
+
+/// The header selecting the user to pretend to be in [`DevMode`].
+pub const DEV_USER_HEADER: &str = "X-Dev-User";
+/// The cookie selecting the user to pretend to be in [`DevMode`], if there's no [`DEV_USER_HEADER`].
+pub const DEV_USER_COOKIE: &str = "dev_user";
 
-pub struct CertDn(String);
+/// Find the value of a cookie.
+pub fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+	headers
+		.get_all(axum::http::header::COOKIE)
+		.iter()
+		.filter_map(|value| value.to_str().ok())
+		.flat_map(|value| value.split(';'))
+		.filter_map(|cookie| cookie.trim().split_once('='))
+		.find(|(cookie, _)| *cookie == name)
+		.map(|(_, value)| value)
+}
+
+/// The cookie holding the token of a dashboard session opened without a client certificate, see
+/// [`Service::create_session`] and [`Service::create_oidc_session`].
+pub const SESSION_COOKIE: &str = "nyanpasswd_session";
+
+/// The token of the dashboard session a request was made in, if any.
+pub fn session_token(headers: &HeaderMap) -> Option<&str> {
+	cookie(headers, SESSION_COOKIE).filter(|token| !token.is_empty())
+}
+
+/// Fake client certificates, for development without a TLS-terminating reverse proxy.
+///
+/// This lets anyone be anyone, so it must never be enabled in production.
+#[derive(Debug, Clone)]
+pub struct DevMode {
+	/// The user to pretend to be if a request doesn't select one and has no client certificate.
+	pub default_user: String,
+}
+
+impl DevMode {
+	fn selected_user(&self, headers: &HeaderMap) -> Option<String> {
+		if let Some(user) = headers.get(DEV_USER_HEADER).and_then(|value| value.to_str().ok()) {
+			return Some(user.to_owned());
+		}
+		cookie(headers, DEV_USER_COOKIE).map(str::to_owned)
+	}
+
+	/// A subject DN the configured [`IdentityMapping`] maps back to `user`.
+	fn identity(&self, user: &str) -> CertDn {
+		let attribute = match &IDENTITY_MAPPING.get().unwrap_or(&DEFAULT_IDENTITY_MAPPING).source {
+			IdentitySource::Attribute(name) => name.clone(),
+			// Can't be faked without a certificate, and rejected when enabling dev mode
+			IdentitySource::SubjectAltName(_) => "UID".to_owned(),
+		};
+		let dn = crate::dn::DistinguishedName(vec![vec![crate::dn::Attribute {
+			name: attribute,
+			value: user.to_owned(),
+		}]]);
+		CertDn {
+			dn: dn.to_string(),
+			certificate: None,
+		}
+	}
+}
+
+static DEV_MODE: OnceCell<DevMode> = OnceCell::new();
+
+/// Let [`CertDn`] make up client certificates according to `dev`.
+///
+/// This can only be done once, before handling any requests. If dev mode was already enabled, the
+/// new settings are returned back.
+pub fn enable_dev_mode(dev: DevMode) -> Result<(), DevMode> {
+	DEV_MODE.set(dev)
+}
+
+/// A client certificate validated by a TLS listener of our own, instead of a reverse proxy.
+///
+/// If a request carries this extension, it is used by [`CertDn`], and the reverse proxy headers
+/// are ignored.
+#[derive(Clone, Debug)]
+pub struct VerifiedClientCertificate {
+	/// The DER-encoded certificate, or `None` if the client didn't present one.
+	pub certificate: Option<Vec<u8>>,
+}
+
+/// A client certificate we have in full, not only its subject DN.
+#[derive(Clone, Debug)]
+struct Certificate {
+	der: Vec<u8>,
+	serial: String,
+	fingerprint: String,
+}
+
+/// The client certificate the request was made with.
+#[derive(Clone, Debug)]
+pub struct CertDn {
+	dn: String,
+	certificate: Option<Certificate>,
+}
 impl CertDn {
-	/// Parse the UID out of a client certificate DN.
-	pub fn uid(&self) -> Option<&str> {
-		DN_UID_REGEX.captures(&self.0).map(|c| c.get(1).unwrap().as_str())
+	/// Take the subject DN, serial and fingerprint from a DER-encoded certificate.
+	fn from_der(der: Vec<u8>) -> Option<Self> {
+		use sha2::Digest;
+
+		let (_, certificate) = x509_parser::parse_x509_certificate(&der).ok()?;
+		let dn = crate::dn::DistinguishedName::from_x509(certificate.subject())?.to_string();
+		// Like `openssl x509 -serial`, without the leading zeroes DER needs for the sign
+		let serial = certificate.raw_serial();
+		let serial = &serial[serial.iter().position(|b| *b != 0).unwrap_or(serial.len().saturating_sub(1))..];
+		let serial = serial.iter().map(|b| format!("{:02X}", b)).collect();
+		let fingerprint = sha2::Sha256::digest(&der)
+			.iter()
+			.map(|b| format!("{:02X}", b))
+			.collect::<Vec<_>>()
+			.join(":");
+
+		Some(CertDn {
+			dn,
+			certificate: Some(Certificate {
+				der,
+				serial,
+				fingerprint,
+			}),
+		})
+	}
+	/// A certificate with the given details, or only a subject DN if `fingerprint` is empty.
+	#[cfg(test)]
+	pub(crate) fn for_test(dn: &str, serial: &str, fingerprint: &str) -> Self {
+		CertDn {
+			dn: dn.to_owned(),
+			certificate: (!fingerprint.is_empty()).then(|| Certificate {
+				der: vec![],
+				serial: serial.to_owned(),
+				fingerprint: fingerprint.to_owned(),
+			}),
+		}
+	}
+	/// The full subject DN of the client certificate.
+	pub fn as_str(&self) -> &str {
+		&self.dn
+	}
+	/// Parse the subject DN.
+	pub fn parse(&self) -> Result<crate::dn::DistinguishedName, crate::dn::DnParseError> {
+		self.dn.parse()
+	}
+	/// The whole client certificate, DER-encoded, if it's available.
+	pub fn certificate_der(&self) -> Option<&[u8]> {
+		self.certificate.as_ref().map(|cert| cert.der.as_slice())
+	}
+	/// The serial number of the client certificate in uppercase hex, like `1234ABCD`, if the whole
+	/// certificate is available.
+	pub fn serial(&self) -> Option<&str> {
+		self.certificate.as_ref().map(|cert| cert.serial.as_str())
+	}
+	/// The SHA-256 fingerprint of the client certificate in the same format as
+	/// `openssl x509 -fingerprint -sha256`, if the whole certificate is available.
+	pub fn fingerprint(&self) -> Option<&str> {
+		self.certificate.as_ref().map(|cert| cert.fingerprint.as_str())
+	}
+	/// Find the username of the certificate's owner, according to the configured
+	/// [`IdentityMapping`].
+	pub fn username(&self) -> Result<String, IdentityError> {
+		IDENTITY_MAPPING
+			.get()
+			.unwrap_or(&DEFAULT_IDENTITY_MAPPING)
+			.username(self)
+	}
+
+	/// Read the client certificate from headers set by a reverse proxy.
+	fn from_headers(headers: &HeaderMap, format: ProxyHeaderFormat) -> Result<Self, CertDnExtractionError> {
+		let header = |name: &str| headers.get(name).map(|s| String::from_utf8_lossy(s.as_bytes()));
+		let certificate = |name: &str| match headers.get(name).map(|value| value.as_bytes()) {
+			None | Some(b"") => Ok(None),
+			Some(value) => decode_forwarded_certificate(value)
+				.and_then(CertDn::from_der)
+				.map(Some)
+				.ok_or(CertDnExtractionError::InvalidCertificate),
+		};
+		match format {
+			ProxyHeaderFormat::Nginx => {
+				match header("X-SSL-Verify").as_deref() {
+					Some("SUCCESS") => {}
+					// We have no client certificate
+					Some("NONE") => return Err(CertDnExtractionError::NoTlsCert),
+					// Client certificate validation failed (e.g. it was revoked)
+					Some(failed) => return Err(CertDnExtractionError::CertValidationFailed(failed.to_owned())),
+					None => return Err(CertDnExtractionError::ReverseProxyMisconfigured(format)),
+				}
+				// Optional, only needed for subject alternative names, serials and fingerprints
+				if let Some(cert_dn) = certificate("X-SSL-Client-Cert")? {
+					return Ok(cert_dn);
+				}
+				match header("X-SSL-Client-Dn") {
+					Some(dn) => Ok(CertDn {
+						dn: dn.into_owned(),
+						certificate: None,
+					}),
+					None => Err(CertDnExtractionError::ReverseProxyMisconfigured(format)),
+				}
+			}
+			ProxyHeaderFormat::Traefik => certificate("X-Forwarded-Tls-Client-Cert")?.ok_or(CertDnExtractionError::NoTlsCert),
+			ProxyHeaderFormat::Haproxy => {
+				match header("X-SSL-Client-Verify").as_deref() {
+					Some("0") => {}
+					Some(code) => {
+						return Err(CertDnExtractionError::CertValidationFailed(format!(
+							"FAILED:verify error {}",
+							code
+						)))
+					}
+					None => return Err(CertDnExtractionError::ReverseProxyMisconfigured(format)),
+				}
+				certificate("X-SSL-Client-Cert")?.ok_or(CertDnExtractionError::NoTlsCert)
+			}
+		}
+	}
+}
+
+impl CertDn {
+	/// Find the client certificate of a request, from our own TLS listener or from a reverse proxy.
+	///
+	/// Unlike the extractor, this never stands in the identities of development mode, so it's
+	/// what the API uses to recognize its consumers.
+	pub fn from_request(parts: &Parts) -> Result<Self, CertDnExtractionError> {
+		if let Some(verified) = parts.extensions.get::<VerifiedClientCertificate>() {
+			let der = verified.certificate.clone().ok_or(CertDnExtractionError::NoTlsCert)?;
+			return CertDn::from_der(der).ok_or(CertDnExtractionError::InvalidCertificate);
+		}
+		let format = PROXY_HEADER_FORMAT.get().copied().unwrap_or_default();
+		let peer = parts
+			.extensions
+			.get::<ConnectInfo<SocketAddr>>()
+			.map(|ConnectInfo(addr)| addr.ip());
+		let trusted = TRUSTED_PROXIES
+			.get()
+			.unwrap_or(&DEFAULT_TRUSTED_PROXIES)
+			.trusts(peer, &parts.headers);
+		let headers = if trusted {
+			&parts.headers
+		} else if CERTIFICATE_HEADERS.iter().any(|name| parts.headers.contains_key(*name)) {
+			let peer = peer.map_or_else(|| "unknown peer".to_owned(), |peer| peer.to_string());
+			tracing::warn!("Rejecting client certificate headers from untrusted proxy {}", peer);
+			return Err(CertDnExtractionError::UntrustedProxy);
+		} else {
+			// Someone talking to us directly, not through the proxy
+			&HeaderMap::new()
+		};
+		CertDn::from_headers(headers, format)
 	}
 }
 
@@ -52,46 +650,54 @@ where
 	type Rejection = CertDnExtractionError;
 
 	async fn from_request_parts(parts: &mut Parts, _: &T) -> Result<Self, Self::Rejection> {
-		match parts
-			.headers
-			.get("X-SSL-Verify")
-			.map(|s| String::from_utf8_lossy(s.as_bytes()))
-			.as_deref()
-		{
-			Some("SUCCESS") => {}
-			// We have no client certificate
-			Some("NONE") => return Err(CertDnExtractionError::NoTlsCert),
-			// Client certificate validation failed (e.g. it was revoked)
-			Some(failed) => return Err(CertDnExtractionError::CertValidationFailed(failed.to_owned())),
-			None => {
-				#[cfg(debug_assertions)]
-				return Ok(CertDn(
-					"O = nyantec GmbH, CN = Vika Shleina, GN = Viktoriya, SN = Shleina, pseudonym = Vika, UID = vsh".to_string(),
-				));
-				#[cfg(not(debug_assertions))]
-				return Err(CertDnExtractionError::ReverseProxyMisconfigured);
+		let result = CertDn::from_request(parts);
+		let Some(dev) = DEV_MODE.get() else {
+			return result;
+		};
+		match (dev.selected_user(&parts.headers), result) {
+			(Some(user), _) => Ok(dev.identity(&user)),
+			(None, Err(CertDnExtractionError::NoTlsCert | CertDnExtractionError::ReverseProxyMisconfigured(_))) => {
+				Ok(dev.identity(&dev.default_user))
 			}
-		}
-		if let Some(ssl_client_s_dn) = parts
-			.headers
-			.get("X-SSL-Client-Dn")
-			.map(|s| String::from_utf8_lossy(s.as_bytes()))
-		{
-			return Ok(CertDn(ssl_client_s_dn.to_string()));
-		} else {
-			return Err(CertDnExtractionError::ReverseProxyMisconfigured);
+			(None, result) => result,
 		}
 	}
 }
 
+/// Decode a certificate forwarded by a reverse proxy.
+///
+/// This accepts URL-encoded PEM like nginx's `$ssl_client_escaped_cert`, the same without the
+/// `BEGIN`/`END` lines like Traefik sends it, and plain base64-encoded DER like HAProxy's
+/// `ssl_c_der,base64`. If there's a comma-separated chain, the first certificate is the client's.
+fn decode_forwarded_certificate(value: &[u8]) -> Option<Vec<u8>> {
+	use base64::Engine;
+
+	let value = percent_encoding::percent_decode(value).collect::<Vec<u8>>();
+	let first = value.split(|b| *b == b',').next()?;
+	if first.windows(10).any(|w| w == b"-----BEGIN") {
+		let (_, pem) = x509_parser::pem::parse_x509_pem(first).ok()?;
+		return Some(pem.contents);
+	}
+	let base64 = first
+		.iter()
+		.copied()
+		.filter(|b| !b.is_ascii_whitespace())
+		.collect::<Vec<u8>>();
+	base64::engine::general_purpose::STANDARD.decode(base64).ok()
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum CertDnExtractionError {
 	#[error("No TLS client certificate was provided")]
 	NoTlsCert,
 	#[error("Certificate validation {0}")]
 	CertValidationFailed(String),
-	#[error("Required headers `X-SSL-Verify` and/or `X-SSL-Client-Dn` not found")]
-	ReverseProxyMisconfigured,
+	#[error("Required {0} headers not found")]
+	ReverseProxyMisconfigured(ProxyHeaderFormat),
+	#[error("Client certificate could not be parsed")]
+	InvalidCertificate,
+	#[error("Client certificate headers are only accepted from trusted reverse proxies")]
+	UntrustedProxy,
 }
 
 impl From<&CertDnExtractionError> for StatusCode {
@@ -99,8 +705,10 @@ impl From<&CertDnExtractionError> for StatusCode {
 		use CertDnExtractionError::*;
 		match err {
 			CertValidationFailed(_) => StatusCode::FORBIDDEN,
-			ReverseProxyMisconfigured => StatusCode::INTERNAL_SERVER_ERROR,
+			ReverseProxyMisconfigured(_) => StatusCode::INTERNAL_SERVER_ERROR,
 			NoTlsCert => StatusCode::UNAUTHORIZED,
+			InvalidCertificate => StatusCode::BAD_REQUEST,
+			UntrustedProxy => StatusCode::FORBIDDEN,
 		}
 	}
 }
@@ -111,7 +719,11 @@ impl IntoResponse for CertDnExtractionError {
 			StatusCode::from(&self),
 			[("Content-Type", "text/plain")],
 			match &self {
-				Self::ReverseProxyMisconfigured => ERROR_MESSAGE_TLS_PROXY_MISCONFIGURED.to_string(),
+				Self::ReverseProxyMisconfigured(format) => format!(
+					"TLS-terminating reverse proxy is misconfigured: required {} headers not found.\n\n{}",
+					format,
+					format.configuration_hint()
+				),
 				_ => self.to_string(),
 			},
 		)
@@ -125,8 +737,16 @@ pub enum UserExtractionError {
 	Sql(#[from] sqlx::Error),
 	#[error("User not found in database")]
 	UserNotFound,
-	#[error("No UID field in TLS client certificate's Subject DN")]
-	NoUidFieldInCert,
+	#[error("This client certificate is not pinned for your account")]
+	CertificateNotPinned,
+	#[error("This client certificate was revoked")]
+	CertificateRevoked,
+	#[error("A client certificate of your account was revoked, and this one can't be told apart from it")]
+	CertificateUnidentified,
+	#[error("Your session has expired, please log in again")]
+	SessionExpired,
+	#[error("{0}")]
+	Identity(#[from] IdentityError),
 	#[error("Error parsing TLS client certificate data")]
 	Certificate(#[from] CertDnExtractionError),
 }
@@ -135,8 +755,9 @@ impl From<&UserExtractionError> for StatusCode {
 		use UserExtractionError::*;
 		match err {
 			Sql(_) => StatusCode::INTERNAL_SERVER_ERROR,
-			UserNotFound => StatusCode::UNAUTHORIZED,
-			NoUidFieldInCert => StatusCode::BAD_REQUEST,
+			UserNotFound | SessionExpired => StatusCode::UNAUTHORIZED,
+			CertificateNotPinned | CertificateRevoked | CertificateUnidentified => StatusCode::FORBIDDEN,
+			Identity(err) => StatusCode::from(err),
 			Certificate(err) => StatusCode::from(err),
 		}
 	}
@@ -152,33 +773,322 @@ impl FromRequestParts<Arc<Service<MigrationsDone>>> for User {
 	type Rejection = UserExtractionError;
 
 	async fn from_request_parts(parts: &mut Parts, db: &Arc<Service<MigrationsDone>>) -> Result<Self, Self::Rejection> {
-		match CertDn::from_request_parts(parts, db).await {
-			Ok(cert_dn) => match cert_dn.uid() {
-				Some(uid) => match db.find_user_by_name(uid).await {
-					Ok(Some(user)) => Ok(user),
-					Ok(None) => Err(UserExtractionError::UserNotFound),
-					Err(err) => Err(UserExtractionError::Sql(err)),
-				},
-				None => Err(UserExtractionError::NoUidFieldInCert),
-			},
-			#[cfg_attr(debug_assertions, allow(unused_variables))]
-			Err(err) => {
-				#[cfg(debug_assertions)]
-				return Ok(db.find_user_by_name("vsh").await?.unwrap());
-				#[cfg(not(debug_assertions))]
-				return Err(err.into());
+		// Without a client certificate, the user may have logged in with a passkey or an identity provider
+		let cert_dn = match CertDn::from_request_parts(parts, db).await {
+			Err(CertDnExtractionError::NoTlsCert) => {
+				let token = session_token(&parts.headers).ok_or(CertDnExtractionError::NoTlsCert)?;
+				return match db.find_session(token).await? {
+					Some(session) => Ok(session.user),
+					None => Err(UserExtractionError::SessionExpired),
+				};
 			}
+			result => result?,
+		};
+		let user = db
+			.find_user_by_name(&cert_dn.username()?)
+			.await?
+			.ok_or(UserExtractionError::UserNotFound)?;
+		match db.check_client_certificate(&user, &cert_dn).await? {
+			crate::CertificateCheck::Allowed => Ok(user),
+			crate::CertificateCheck::NotPinned => Err(UserExtractionError::CertificateNotPinned),
+			crate::CertificateCheck::Revoked => Err(UserExtractionError::CertificateRevoked),
+			crate::CertificateCheck::Unidentified => Err(UserExtractionError::CertificateUnidentified),
 		}
 	}
 }
 
 #[cfg(test)]
 mod test {
+	use super::{
+		CertDn, CertDnExtractionError, IdentityError, IdentityMapping, IdentitySource, IpNetwork, ProxyHeaderFormat,
+		DevMode, Rewrite, SanKind, TrustedProxies, session_token,
+	};
+	use axum::http::{HeaderMap, HeaderValue};
+	use std::net::IpAddr;
+
+	/// Self-signed, for `UID=vsh,CN=Vika Shleina,O=nyantec GmbH` with the SAN `vsh@nyantec.com`.
+	const VSH_CERT: &str = "-----BEGIN CERTIFICATE-----
+MIIB6TCCAY+gAwIBAgIEEjSrzTAKBggqhkjOPQQDAjBDMRUwEwYDVQQKDAxueWFu
+dGVjIEdtYkgxFTATBgNVBAMMDFZpa2EgU2hsZWluYTETMBEGCgmSJomT8ixkAQEM
+A3ZzaDAgFw0yNjEwMTgxODM0MjdaGA8yMTI2MDkyNDE4MzQyN1owQzEVMBMGA1UE
+CgwMbnlhbnRlYyBHbWJIMRUwEwYDVQQDDAxWaWthIFNobGVpbmExEzARBgoJkiaJ
+k/IsZAEBDAN2c2gwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAAQ3DDTKAf/PSGQs
+nlUdaGmWtyppIs/L0y3rn3j8m3FNEOWWwD+qwyPNd6IwX2CFBcMKtgV0WNTUHg4R
+SwzXlXVEo28wbTAdBgNVHQ4EFgQUU8JLT2ncYhChwVIiEO/wOvko3cEwHwYDVR0j
+BBgwFoAUU8JLT2ncYhChwVIiEO/wOvko3cEwDwYDVR0TAQH/BAUwAwEB/zAaBgNV
+HREEEzARgQ92c2hAbnlhbnRlYy5jb20wCgYIKoZIzj0EAwIDSAAwRQIhAOA1urZg
+nUcIE+GWpc4mlzf9FQCJ0n11cYrgzO1E5LvhAiAgtYOAw4lWLekwcTojyZ1TcID6
+uJhIDd6fd4XCQH6HKw==
+-----END CERTIFICATE-----";
+	const VSH_CERT_FINGERPRINT: &str =
+		"63:EE:D3:6D:BB:E9:5C:36:CC:35:6B:88:FA:53:4C:7B:6F:F0:71:E3:22:73:FE:BA:A7:AA:AF:83:F0:B1:73:24";
+
+	fn headers(headers: &[(&'static str, &str)]) -> HeaderMap {
+		headers
+			.iter()
+			.map(|(name, value)| (*name, HeaderValue::from_str(value).unwrap()))
+			.map(|(name, value)| (axum::http::HeaderName::from_static(name), value))
+			.collect()
+	}
+
+	/// The certificate as Traefik sends it: without the `BEGIN`/`END` lines and URL-encoded.
+	fn traefik_escaped(pem: &str) -> String {
+		let base64 = pem.lines().filter(|line| !line.starts_with("-----")).collect::<String>();
+		percent_encoding::utf8_percent_encode(&base64, percent_encoding::NON_ALPHANUMERIC).to_string()
+	}
+
+	fn cert_dn(dn: &str) -> CertDn {
+		CertDn {
+			dn: dn.to_owned(),
+			certificate: None,
+		}
+	}
+
 	#[test]
-	fn test_uid_regex() {
-		use super::DN_UID_REGEX;
+	fn test_default_identity_mapping() {
+		let mapping = IdentityMapping::default();
 		const VSH_DN: &str = "O = nyantec GmbH, CN = Vika Shleina, GN = Viktoriya, SN = Shleina, pseudonym = Vika, UID = vsh";
-		let captures = dbg!(DN_UID_REGEX.captures(VSH_DN).unwrap());
-		assert_eq!(captures.get(1).unwrap().as_str(), "vsh");
+		assert_eq!(mapping.username(&cert_dn(VSH_DN)).unwrap(), "vsh");
+		assert_eq!(mapping.username(&cert_dn("UID=vika.shleina,O=nyantec GmbH")).unwrap(), "vika.shleina");
+		// `UID=` inside another attribute's value is not a UID
+		assert!(matches!(
+			mapping.username(&cert_dn(r"CN=x\,UID=vsh,O=nyantec GmbH")),
+			Err(IdentityError::Missing(_))
+		));
+		assert!(matches!(
+			mapping.username(&cert_dn("UID=vsh+UID=mal,O=nyantec GmbH")),
+			Err(IdentityError::Ambiguous(_))
+		));
+		assert!(matches!(mapping.username(&cert_dn("UID")), Err(IdentityError::InvalidDn(_))));
+	}
+
+	#[test]
+	fn test_identity_mapping_rewrites() {
+		let mapping = IdentityMapping {
+			source: "emailAddress".parse().unwrap(),
+			rewrites: vec![
+				Rewrite {
+					pattern: regex::Regex::new(r"@nyantec\.com$").unwrap(),
+					replacement: String::new(),
+				},
+				Rewrite {
+					pattern: regex::Regex::new(r"^(.*)$").unwrap(),
+					replacement: "${1}".to_owned(),
+				},
+			],
+		};
+		assert_eq!(mapping.source, IdentitySource::Attribute("emailAddress".to_owned()));
+		assert_eq!(mapping.username(&cert_dn("E=vsh@nyantec.com,O=nyantec GmbH")).unwrap(), "vsh");
+		// Rewrites that don't match have no effect
+		assert_eq!(mapping.username(&cert_dn("emailAddress=vsh@example.com")).unwrap(), "vsh@example.com");
+	}
+
+	#[test]
+	fn test_identity_source() {
+		assert_eq!("uid".parse(), Ok(IdentitySource::Attribute("UID".to_owned())));
+		assert_eq!("2.5.4.3".parse(), Ok(IdentitySource::Attribute("CN".to_owned())));
+		assert_eq!("SAN:email".parse(), Ok(IdentitySource::SubjectAltName(SanKind::Email)));
+		assert!("SAN:ip".parse::<IdentitySource>().is_err());
+		assert!("U I D".parse::<IdentitySource>().is_err());
+		// SANs need the whole certificate
+		let mapping = IdentityMapping {
+			source: IdentitySource::SubjectAltName(SanKind::Email),
+			rewrites: vec![],
+		};
+		assert!(matches!(
+			mapping.username(&cert_dn("UID=vsh")),
+			Err(IdentityError::NoCertificate)
+		));
+	}
+
+	#[test]
+	fn test_nginx_headers() {
+		let cert_dn = CertDn::from_headers(
+			&headers(&[("x-ssl-verify", "SUCCESS"), ("x-ssl-client-dn", "UID=vsh,O=nyantec GmbH")]),
+			ProxyHeaderFormat::Nginx,
+		)
+		.unwrap();
+		assert_eq!(cert_dn.as_str(), "UID=vsh,O=nyantec GmbH");
+		assert_eq!(cert_dn.fingerprint(), None);
+
+		let escaped = percent_encoding::utf8_percent_encode(VSH_CERT, percent_encoding::NON_ALPHANUMERIC).to_string();
+		let cert_dn = CertDn::from_headers(
+			&headers(&[
+				("x-ssl-verify", "SUCCESS"),
+				("x-ssl-client-dn", "UID=vsh,CN=Vika Shleina,O=nyantec GmbH"),
+				("x-ssl-client-cert", &escaped),
+			]),
+			ProxyHeaderFormat::Nginx,
+		)
+		.unwrap();
+		assert_eq!(cert_dn.as_str(), "UID=vsh,CN=Vika Shleina,O=nyantec GmbH");
+		assert_eq!(cert_dn.serial(), Some("1234ABCD"));
+		assert_eq!(cert_dn.fingerprint(), Some(VSH_CERT_FINGERPRINT));
+		// Without the certificate, the DN is passed on as nginx wrote it, and only matches once parsed
+		let raw = CertDn::from_headers(
+			&headers(&[("x-ssl-verify", "SUCCESS"), ("x-ssl-client-dn", "UID = vsh, CN = Vika Shleina, O = nyantec GmbH")]),
+			ProxyHeaderFormat::Nginx,
+		)
+		.unwrap();
+		assert_ne!(raw.as_str(), cert_dn.as_str());
+		assert_eq!(raw.parse().unwrap().to_string(), cert_dn.as_str());
+
+		assert!(matches!(
+			CertDn::from_headers(&headers(&[("x-ssl-verify", "NONE")]), ProxyHeaderFormat::Nginx),
+			Err(CertDnExtractionError::NoTlsCert)
+		));
+		assert!(matches!(
+			CertDn::from_headers(&headers(&[("x-ssl-verify", "FAILED:revoked")]), ProxyHeaderFormat::Nginx),
+			Err(CertDnExtractionError::CertValidationFailed(_))
+		));
+		assert!(matches!(
+			CertDn::from_headers(&headers(&[]), ProxyHeaderFormat::Nginx),
+			Err(CertDnExtractionError::ReverseProxyMisconfigured(ProxyHeaderFormat::Nginx))
+		));
+	}
+
+	#[test]
+	fn test_traefik_headers() {
+		// A chain, of which only the first certificate is the client's
+		let chain = format!("{},{}", traefik_escaped(VSH_CERT), traefik_escaped(VSH_CERT));
+		let cert_dn = CertDn::from_headers(
+			&headers(&[("x-forwarded-tls-client-cert", &chain)]),
+			ProxyHeaderFormat::Traefik,
+		)
+		.unwrap();
+		assert_eq!(cert_dn.as_str(), "UID=vsh,CN=Vika Shleina,O=nyantec GmbH");
+		assert_eq!(cert_dn.fingerprint(), Some(VSH_CERT_FINGERPRINT));
+		// The nginx headers mean nothing here
+		assert!(matches!(
+			CertDn::from_headers(
+				&headers(&[("x-ssl-verify", "SUCCESS"), ("x-ssl-client-dn", "UID=vsh")]),
+				ProxyHeaderFormat::Traefik
+			),
+			Err(CertDnExtractionError::NoTlsCert)
+		));
+		assert!(matches!(
+			CertDn::from_headers(
+				&headers(&[("x-forwarded-tls-client-cert", "bm90IGEgY2VydGlmaWNhdGU=")]),
+				ProxyHeaderFormat::Traefik
+			),
+			Err(CertDnExtractionError::InvalidCertificate)
+		));
+	}
+
+	#[test]
+	fn test_haproxy_headers() {
+		let base64 = VSH_CERT.lines().filter(|line| !line.starts_with("-----")).collect::<String>();
+		let cert_dn = CertDn::from_headers(
+			&headers(&[("x-ssl-client-verify", "0"), ("x-ssl-client-cert", &base64)]),
+			ProxyHeaderFormat::Haproxy,
+		)
+		.unwrap();
+		assert_eq!(cert_dn.serial(), Some("1234ABCD"));
+		let mapping = IdentityMapping {
+			source: "SAN:email".parse().unwrap(),
+			rewrites: vec![],
+		};
+		assert_eq!(mapping.username(&cert_dn).unwrap(), "vsh@nyantec.com");
+
+		assert!(matches!(
+			CertDn::from_headers(
+				&headers(&[("x-ssl-client-verify", "0"), ("x-ssl-client-cert", "")]),
+				ProxyHeaderFormat::Haproxy
+			),
+			Err(CertDnExtractionError::NoTlsCert)
+		));
+		assert!(matches!(
+			CertDn::from_headers(
+				&headers(&[("x-ssl-client-verify", "23"), ("x-ssl-client-cert", &base64)]),
+				ProxyHeaderFormat::Haproxy
+			),
+			Err(CertDnExtractionError::CertValidationFailed(_))
+		));
+		assert!(matches!(
+			CertDn::from_headers(&headers(&[("x-ssl-client-cert", &base64)]), ProxyHeaderFormat::Haproxy),
+			Err(CertDnExtractionError::ReverseProxyMisconfigured(ProxyHeaderFormat::Haproxy))
+		));
+	}
+
+	#[test]
+	fn test_ip_network() {
+		let network: IpNetwork = "10.0.0.0/8".parse().unwrap();
+		assert!(network.contains("10.1.2.3".parse().unwrap()));
+		assert!(network.contains("::ffff:10.1.2.3".parse().unwrap()));
+		assert!(!network.contains("11.0.0.1".parse().unwrap()));
+		assert!(!network.contains("::1".parse().unwrap()));
+		let network: IpNetwork = "fd00::/8".parse().unwrap();
+		assert!(network.contains("fd12::1".parse().unwrap()));
+		assert!(!network.contains("fe80::1".parse().unwrap()));
+		let network: IpNetwork = "192.0.2.1".parse().unwrap();
+		assert_eq!(network.to_string(), "192.0.2.1/32");
+		assert!(network.contains("192.0.2.1".parse().unwrap()));
+		assert!(!network.contains("192.0.2.2".parse().unwrap()));
+		assert!("0.0.0.0/0".parse::<IpNetwork>().unwrap().contains("8.8.8.8".parse().unwrap()));
+
+		assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+		assert!("localhost".parse::<IpNetwork>().is_err());
+	}
+
+	#[test]
+	fn test_trusted_proxies() {
+		let localhost: Option<IpAddr> = Some("127.0.0.1".parse().unwrap());
+		let remote: Option<IpAddr> = Some("192.0.2.1".parse().unwrap());
+		let with_secret = headers(&[("x-proxy-secret", "hunter2")]);
+		let wrong_secret = headers(&[("x-proxy-secret", "hunter3")]);
+
+		let default = TrustedProxies::default();
+		assert!(default.trusts(localhost, &HeaderMap::new()));
+		assert!(default.trusts(Some("::1".parse().unwrap()), &HeaderMap::new()));
+		assert!(!default.trusts(remote, &with_secret));
+		assert!(!default.trusts(None, &HeaderMap::new()));
+
+		let secret_only = TrustedProxies {
+			networks: vec![],
+			secret: Some("hunter2".to_owned()),
+		};
+		assert!(secret_only.trusts(remote, &with_secret));
+		assert!(secret_only.trusts(None, &with_secret));
+		assert!(!secret_only.trusts(localhost, &wrong_secret));
+		assert!(!secret_only.trusts(localhost, &HeaderMap::new()));
+
+		let both = TrustedProxies {
+			networks: vec!["192.0.2.0/24".parse().unwrap()],
+			secret: Some("hunter2".to_owned()),
+		};
+		assert!(both.trusts(remote, &with_secret));
+		assert!(!both.trusts(remote, &HeaderMap::new()));
+		assert!(!both.trusts(localhost, &with_secret));
+	}
+
+	#[test]
+	fn test_dev_mode() {
+		let dev = DevMode {
+			default_user: "vsh".to_owned(),
+		};
+		assert_eq!(dev.selected_user(&HeaderMap::new()), None);
+		assert_eq!(dev.selected_user(&headers(&[("x-dev-user", "mvs")])).as_deref(), Some("mvs"));
+		assert_eq!(
+			dev.selected_user(&headers(&[("cookie", "theme=dark; dev_user=mvs")])).as_deref(),
+			Some("mvs")
+		);
+		// The header wins over the cookie
+		assert_eq!(
+			dev.selected_user(&headers(&[("cookie", "dev_user=mvs"), ("x-dev-user", "abc")])).as_deref(),
+			Some("abc")
+		);
+
+		let identity = dev.identity("mvs");
+		assert_eq!(identity.as_str(), "UID=mvs");
+		assert_eq!(IdentityMapping::default().username(&identity).unwrap(), "mvs");
+		// Usernames can't smuggle in other attributes
+		let identity = dev.identity("mvs,UID=vsh");
+		assert_eq!(IdentityMapping::default().username(&identity).unwrap(), "mvs,UID=vsh");
+	}
+	#[test]
+	fn test_session_token() {
+		assert_eq!(session_token(&headers(&[("cookie", "dev_user=vsh; nyanpasswd_session=abc")])), Some("abc"));
+		assert_eq!(session_token(&headers(&[("cookie", "nyanpasswd_session=")])), None);
+		assert_eq!(session_token(&headers(&[("cookie", "dev_user=vsh")])), None);
 	}
 }
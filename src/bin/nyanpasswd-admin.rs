@@ -0,0 +1,830 @@
+/*
+  Copyright © 2022 nyantec GmbH <oss@nyantec.com>
+  Written by Vika Shleina <vsh@nyantec.com>
+  
+  Provided that these terms and disclaimer and all copyright notices
+  are retained or reproduced in an accompanying document, permission
+  is granted to deal in this work without restriction, including un‐
+  limited rights to use, publicly perform, distribute, sell, modify,
+  merge, give away, or sublicence.
+  
+  This work is provided "AS IS" and WITHOUT WARRANTY of any kind, to
+  the utmost extent permitted by applicable law, neither express nor
+  implied; without malicious intent or gross negligence. In no event
+  may a licensor, author or contributor be held liable for indirect,
+  direct, other damage, loss, or other issues arising in any way out
+  of dealing in the work, even if advised of the possibility of such
+  damage or existence of a defect, except proven that it results out
+  of said person's immediate fault when using the work as intended.
+ */
+//! Command-line administration, for when the dashboard isn't an option: bootstrapping the first
+//! admin, fixing accounts without a browser at hand, or scripting.
+//!
+//! The database is the one named in the server's configuration, see [`nyanpasswd::config`].
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::str::FromStr;
+
+use chrono::{DateTime, FixedOffset};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use nyanpasswd::config::{Config, ConfigError};
+use nyanpasswd::reconcile::{Manifest, ReconcileError};
+use nyanpasswd::transfer::{Dataset, ImportError, ImportOptions};
+use nyanpasswd::{Alias, MigrationsDone, User, UserQuery, UserStatus};
+use sqlx::postgres::PgPoolOptions;
+use uuid::Uuid;
+
+type Service = nyanpasswd::Service<MigrationsDone>;
+
+#[derive(Parser)]
+#[command(name = "nyanpasswd-admin", about = "Manage nyanpasswd users, passwords and aliases")]
+struct Cli {
+	/// Print results as JSON instead of tables
+	#[arg(long, global = true)]
+	json: bool,
+	#[command(subcommand)]
+	command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+	/// Create, inspect and change users
+	#[command(subcommand)]
+	Users(UsersCommand),
+	/// Manage passwords users log into mail and other services with
+	#[command(subcommand)]
+	Passwords(PasswordsCommand),
+	/// Manage aliases administrators set up
+	#[command(subcommand)]
+	Aliases(AliasesCommand),
+	/// Inspect and apply database migrations
+	#[command(subcommand)]
+	Migrations(MigrationsCommand),
+	/// Review who can get in, and how
+	#[command(subcommand)]
+	Audit(AuditCommand),
+	/// Export users, passwords, domains and aliases
+	Export {
+		#[arg(long, value_enum, default_value = "json")]
+		format: Format,
+		/// File to write JSON to, or directory to write CSV files to. JSON goes to standard output
+		/// by default.
+		#[arg(long, short, required_if_eq("format", "csv"))]
+		output: Option<PathBuf>,
+	},
+	/// Make managed users and aliases match the reconciliation file
+	Reconcile {
+		/// Reconciliation file to use, instead of the configured one
+		#[arg(long)]
+		file: Option<PathBuf>,
+		/// Only show what would change
+		#[arg(long)]
+		dry_run: bool,
+	},
+	/// Make the database match a JSON export, in a single transaction
+	Import {
+		/// JSON export, or `-` to read standard input
+		file: PathBuf,
+		/// Only show what would change
+		#[arg(long)]
+		dry_run: bool,
+		/// Also remove whatever isn't in the export
+		#[arg(long)]
+		prune: bool,
+	},
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+	/// A single document that can be imported again
+	Json,
+	/// One file per table, for spreadsheets and other tools
+	Csv,
+}
+
+#[derive(Subcommand)]
+enum UsersCommand {
+	/// List users, ordered by username
+	List {
+		#[arg(long, value_enum, default_value = "active")]
+		status: Status,
+		/// Only list non-human users (`true`) or human ones (`false`)
+		#[arg(long)]
+		non_human: Option<bool>,
+		/// Only list users allowed (`true`) or not allowed (`false`) to log in
+		#[arg(long)]
+		login_allowed: Option<bool>,
+		/// Only list users whose name starts with this
+		#[arg(long)]
+		prefix: Option<String>,
+	},
+	/// Show a user along with their settings and aliases
+	Show { username: String },
+	/// Create a user
+	Create {
+		username: String,
+		/// Create a non-human user, whose passwords are managed by admins
+		#[arg(long)]
+		non_human: bool,
+		/// When the user expires: `never`, a date or an RFC 3339 timestamp
+		#[arg(long, default_value = "never")]
+		expires: Expiry,
+	},
+	/// Change a user's settings
+	Set {
+		username: String,
+		#[command(flatten)]
+		changes: UserChanges,
+	},
+}
+
+#[derive(Args)]
+#[group(required = true, multiple = true)]
+struct UserChanges {
+	/// Allow or disallow the user to log in
+	#[arg(long)]
+	login_allowed: Option<bool>,
+	/// When the user expires: `never`, a date or an RFC 3339 timestamp
+	#[arg(long)]
+	expires: Option<Expiry>,
+	/// How many self-service aliases the user may have
+	#[arg(long)]
+	alias_limit: Option<i32>,
+	/// Only let pinned client certificates into the dashboard
+	#[arg(long)]
+	require_pinned_certificate: Option<bool>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Status {
+	/// Users that haven't expired yet
+	Active,
+	/// Users past their expiry date
+	Expired,
+	/// All users
+	All,
+}
+
+impl From<Status> for UserStatus {
+	fn from(status: Status) -> Self {
+		match status {
+			Status::Active => UserStatus::Active,
+			Status::Expired => UserStatus::Expired,
+			Status::All => UserStatus::All,
+		}
+	}
+}
+
+/// An expiry date given on the command line.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Expiry(Option<DateTime<FixedOffset>>);
+
+impl FromStr for Expiry {
+	type Err = String;
+
+	/// Parse `never`, a date (meaning midnight UTC at its start) or an RFC 3339 timestamp.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if s == "never" {
+			return Ok(Expiry(None));
+		}
+		if let Ok(date) = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+			let midnight = date.and_hms_opt(0, 0, 0).expect("midnight exists");
+			return Ok(Expiry(Some(chrono::TimeZone::from_utc_datetime(&chrono::Utc, &midnight).into())));
+		}
+		DateTime::parse_from_rfc3339(s)
+			.map(|date| Expiry(Some(date)))
+			.map_err(|_| "expected `never`, a date like 2038-01-19, or an RFC 3339 timestamp".to_owned())
+	}
+}
+
+#[derive(Subcommand)]
+enum PasswordsCommand {
+	/// List a user's passwords
+	List { username: String },
+	/// Generate a password and print it. It can't be shown again later.
+	Create {
+		username: String,
+		label: String,
+		/// When the password expires: `never`, a date or an RFC 3339 timestamp
+		#[arg(long, default_value = "never")]
+		expires: Expiry,
+	},
+	/// Delete a password
+	Delete { username: String, label: String },
+}
+
+#[derive(Subcommand)]
+enum AliasesCommand {
+	/// List aliases and their members
+	List,
+	/// Make a user a member of an alias
+	Add {
+		alias: String,
+		username: String,
+		/// Only deliver mail to the alias on this domain
+		#[arg(long)]
+		domain: Option<String>,
+	},
+	/// Remove a user from an alias
+	Remove {
+		alias: String,
+		username: String,
+		#[arg(long)]
+		domain: Option<String>,
+	},
+	/// Show who receives mail sent to an address, after resolving aliases
+	Resolve { recipient: String },
+}
+
+#[derive(Subcommand)]
+enum MigrationsCommand {
+	/// List migrations and whether they were applied
+	Status,
+	/// Apply pending migrations
+	Run,
+}
+
+#[derive(Subcommand)]
+enum AuditCommand {
+	/// List users and passwords about to expire
+	Expiring {
+		/// How many days ahead to look
+		#[arg(long, default_value = "30")]
+		days: i64,
+	},
+	/// List client certificates users logged into the dashboard with
+	Certificates {
+		/// Only list certificates of this user
+		#[arg(long)]
+		user: Option<String>,
+	},
+	/// List open dashboard sessions of users who logged in with a passkey or single sign-on
+	Sessions,
+}
+
+#[derive(Debug, thiserror::Error)]
+enum Error {
+	#[error("Invalid configuration: {0}")]
+	Config(#[from] ConfigError),
+	#[error("Connection to the database failed: {0}")]
+	Connect(sqlx::Error),
+	#[error("The database has pending migrations, apply them with `nyanpasswd-admin migrations run` first")]
+	PendingMigrations,
+	#[error("No such user: {0}")]
+	NoSuchUser(String),
+	#[error("A user named {0} already exists")]
+	UsernameTaken(String),
+	#[error("{0} already has a password labelled {1}")]
+	LabelTaken(String, String),
+	#[error("No such domain: {0}")]
+	NoSuchDomain(String),
+	#[error("Invalid {0}")]
+	Invalid(&'static str),
+	#[error("Couldn't access {}: {}", .0.display(), .1)]
+	Io(PathBuf, std::io::Error),
+	#[error("Couldn't read the export: {0}")]
+	Parse(#[from] serde_json::Error),
+	#[error(transparent)]
+	Import(#[from] ImportError),
+	#[error("No reconciliation file is configured, pass one with --file")]
+	NoReconcileFile,
+	#[error(transparent)]
+	Reconcile(#[from] ReconcileError),
+	#[error("{0}, change the file and reconcile instead")]
+	Managed(String),
+	#[error("SQL layer error: {0}")]
+	Sql(sqlx::Error),
+}
+
+impl From<sqlx::Error> for Error {
+	fn from(err: sqlx::Error) -> Self {
+		match &err {
+			sqlx::Error::Database(db_err) if nyanpasswd::reconcile::is_managed_violation(&err) => {
+				Error::Managed(db_err.message().to_owned())
+			}
+			_ => Error::Sql(err),
+		}
+	}
+}
+
+/// Whether a query failed with the given SQLSTATE.
+fn sql_state_is(err: &sqlx::Error, state: &str) -> bool {
+	matches!(err, sqlx::Error::Database(err) if err.code().as_deref() == Some(state))
+}
+
+/// Turn constraint violations on values from the command line into readable errors.
+fn invalid(what: &'static str) -> impl FnOnce(sqlx::Error) -> Error {
+	move |err| {
+		// Check violations, and values too long for their column
+		if sql_state_is(&err, "23514") || sql_state_is(&err, "22001") {
+			Error::Invalid(what)
+		} else {
+			err.into()
+		}
+	}
+}
+
+fn format_date(date: Option<DateTime<FixedOffset>>) -> String {
+	match date {
+		Some(date) => date.format("%Y-%m-%d %H:%M %:z").to_string(),
+		None => "never".to_owned(),
+	}
+}
+
+/// Print rows as JSON, or as a table with the given columns.
+fn print_rows<T: serde::Serialize>(json: bool, rows: &[T], columns: &[&str], cells: impl Fn(&T) -> Vec<String>) {
+	if json {
+		println!("{}", serde_json::to_string_pretty(rows).expect("output is serializable"));
+		return;
+	}
+	let rows: Vec<Vec<String>> = std::iter::once(columns.iter().map(|column| column.to_string()).collect())
+		.chain(rows.iter().map(cells))
+		.collect();
+	let mut widths = vec![0; columns.len()];
+	for row in &rows {
+		for (width, cell) in widths.iter_mut().zip(row) {
+			*width = (*width).max(cell.chars().count());
+		}
+	}
+	for row in rows {
+		let line: Vec<String> = row.iter().zip(&widths).map(|(cell, width)| format!("{:width$}", cell)).collect();
+		println!("{}", line.join("  ").trim_end());
+	}
+}
+
+/// Print a single result as JSON, or `message` for humans.
+fn print_one<T: serde::Serialize>(json: bool, value: &T, message: impl FnOnce() -> String) {
+	if json {
+		println!("{}", serde_json::to_string_pretty(value).expect("output is serializable"));
+	} else {
+		println!("{}", message());
+	}
+}
+
+/// Look a user up by name. Unlike `find_user_by_name`, this finds expired users too, so that
+/// their expiry date can be extended.
+async fn find_user(backend: &Service, username: &str) -> Result<User, Error> {
+	// The exact match comes before longer names it's a prefix of
+	let query = UserQuery {
+		non_human: None,
+		login_allowed: None,
+		status: UserStatus::All,
+		username_prefix: Some(username.to_owned()),
+		after: None,
+		limit: None,
+	};
+	backend
+		.search_users(&query)
+		.await?
+		.into_iter()
+		.find(|user| user.username == username)
+		.ok_or_else(|| Error::NoSuchUser(username.to_owned()))
+}
+
+#[derive(serde::Serialize)]
+struct UserDetails {
+	#[serde(flatten)]
+	user: User,
+	alias_limit: i32,
+	require_pinned_certificate: bool,
+	aliases: Vec<String>,
+}
+
+async fn show_user(backend: &Service, json: bool, user: User) -> Result<(), Error> {
+	let (alias_limit, require_pinned_certificate, aliases) = futures::try_join!(
+		backend.get_alias_limit(user.id),
+		backend.get_require_pinned_certificate(user.id),
+		backend.list_aliases_for(&user),
+	)?;
+	let details = UserDetails { user, alias_limit, require_pinned_certificate, aliases };
+	print_one(json, &details, || {
+		let user = &details.user;
+		[
+			format!("ID:                         {}", user.id),
+			format!("Username:                   {}", user.username),
+			format!("Non-human:                  {}", user.non_human),
+			format!("Login allowed:              {}", user.login_allowed),
+			format!("Created:                    {}", format_date(Some(user.created_at))),
+			format!("Expires:                    {}", format_date(user.expires_at)),
+			format!("Alias limit:                {}", details.alias_limit),
+			format!("Require pinned certificate: {}", details.require_pinned_certificate),
+			format!("Aliases:                    {}", details.aliases.join(", ")),
+		]
+		.join("\n")
+	});
+
+	Ok(())
+}
+
+async fn users(backend: &Service, json: bool, command: UsersCommand) -> Result<(), Error> {
+	match command {
+		UsersCommand::List { status, non_human, login_allowed, prefix } => {
+			let mut query = UserQuery {
+				non_human,
+				login_allowed,
+				status: status.into(),
+				username_prefix: prefix,
+				..Default::default()
+			};
+			let mut users = Vec::new();
+			loop {
+				let page = backend.search_users(&query).await?;
+				let done = (page.len() as i64) < UserQuery::MAX_LIMIT;
+				query.after = page.last().map(|user| user.username.clone());
+				users.extend(page);
+				if done {
+					break;
+				}
+			}
+			print_rows(json, &users, &["USERNAME", "ID", "NON-HUMAN", "LOGIN ALLOWED", "EXPIRES"], |user| {
+				vec![
+					user.username.clone(),
+					user.id.to_string(),
+					user.non_human.to_string(),
+					user.login_allowed.to_string(),
+					format_date(user.expires_at),
+				]
+			});
+		}
+		UsersCommand::Show { username } => show_user(backend, json, find_user(backend, &username).await?).await?,
+		UsersCommand::Create { username, non_human, expires } => {
+			let id = match backend.create_user(&username, expires.0, non_human).await {
+				Ok(id) => id,
+				Err(err) if sql_state_is(&err, "23505") => return Err(Error::UsernameTaken(username)),
+				Err(err) => return Err(invalid("username")(err)),
+			};
+			let user = backend.get_user_by_id(id).await?.ok_or(Error::NoSuchUser(username))?;
+			print_one(json, &user, || format!("Created user {} ({})", user.username, user.id));
+		}
+		UsersCommand::Set { username, changes } => {
+			let user = find_user(backend, &username).await?;
+			if let Some(login_allowed) = changes.login_allowed {
+				backend.set_user_login_allowed(user.id, login_allowed).await?;
+			}
+			if let Some(Expiry(expires_at)) = changes.expires {
+				backend.set_user_expiry_date(user.id, expires_at).await?;
+			}
+			if let Some(alias_limit) = changes.alias_limit {
+				backend.set_alias_limit(user.id, alias_limit).await.map_err(invalid("alias limit"))?;
+			}
+			if let Some(require_pinned_certificate) = changes.require_pinned_certificate {
+				backend.set_require_pinned_certificate(user.id, require_pinned_certificate).await?;
+			}
+			show_user(backend, json, find_user(backend, &username).await?).await?;
+		}
+	}
+
+	Ok(())
+}
+
+#[derive(serde::Serialize)]
+struct PasswordInfo {
+	label: String,
+	created_at: DateTime<FixedOffset>,
+	expires_at: Option<DateTime<FixedOffset>>,
+}
+
+#[derive(serde::Serialize)]
+struct CreatedPassword<'a> {
+	username: &'a str,
+	label: &'a str,
+	password: &'a str,
+}
+
+async fn passwords(backend: &Service, json: bool, command: PasswordsCommand) -> Result<(), Error> {
+	match command {
+		PasswordsCommand::List { username } => {
+			let user = find_user(backend, &username).await?;
+			let passwords: Vec<PasswordInfo> = backend
+				.list_passwords_for(&user)
+				.await?
+				.into_iter()
+				.map(|password| PasswordInfo {
+					label: password.label,
+					created_at: password.created_at,
+					expires_at: password.expires_at,
+				})
+				.collect();
+			print_rows(json, &passwords, &["LABEL", "CREATED", "EXPIRES"], |password| {
+				vec![
+					password.label.clone(),
+					format_date(Some(password.created_at)),
+					format_date(password.expires_at),
+				]
+			});
+		}
+		PasswordsCommand::Create { username, label, expires } => {
+			let user = find_user(backend, &username).await?;
+			let password = match backend.new_password(&user, &label, expires.0).await {
+				Ok(password) => password,
+				Err(err) if sql_state_is(&err, "23505") => return Err(Error::LabelTaken(username, label)),
+				Err(err) => return Err(invalid("label")(err)),
+			};
+			// Just the password, so that it can be piped somewhere
+			print_one(
+				json,
+				&CreatedPassword { username: &username, label: &label, password: &password },
+				|| password.clone(),
+			);
+		}
+		PasswordsCommand::Delete { username, label } => {
+			let user = find_user(backend, &username).await?;
+			backend.rm_password_for(&user, &label).await?;
+			print_one(json, &serde_json::json!({}), || format!("Deleted password {} of {}", label, username));
+		}
+	}
+
+	Ok(())
+}
+
+#[derive(serde::Serialize)]
+struct AliasEntry {
+	alias_name: String,
+	domain: Option<String>,
+	/// Usernames of members.
+	members: Vec<String>,
+}
+
+async fn aliases(backend: &Service, json: bool, command: AliasesCommand) -> Result<(), Error> {
+	match command {
+		AliasesCommand::List => {
+			let (aliases, users) = futures::try_join!(backend.list_all_aliases(), backend.list_users())?;
+			let usernames: HashMap<Uuid, String> = users.into_iter().map(|user| (user.id, user.username)).collect();
+			let aliases: Vec<AliasEntry> = aliases
+				.into_iter()
+				.map(|(alias_name, domain, members)| AliasEntry {
+					alias_name,
+					domain,
+					members: members.iter().filter_map(|id| usernames.get(id).cloned()).collect(),
+				})
+				.collect();
+			print_rows(json, &aliases, &["ALIAS", "DOMAIN", "MEMBERS"], |alias| {
+				vec![
+					alias.alias_name.clone(),
+					alias.domain.clone().unwrap_or_else(|| "*".to_owned()),
+					alias.members.join(", "),
+				]
+			});
+		}
+		AliasesCommand::Add { alias, username, domain } => {
+			let user = find_user(backend, &username).await?;
+			let entry = Alias { alias_name: alias, destination: user.id, domain };
+			match backend.add_alias(&entry).await {
+				// Already a member
+				Err(err) if sql_state_is(&err, "23505") => {}
+				Err(err) if sql_state_is(&err, "23503") => {
+					return Err(Error::NoSuchDomain(entry.domain.unwrap_or_default()));
+				}
+				result => result.map_err(invalid("alias name"))?,
+			}
+			print_one(json, &entry, || format!("{} is a member of {}", username, entry.alias_name));
+		}
+		AliasesCommand::Remove { alias, username, domain } => {
+			let user = find_user(backend, &username).await?;
+			let entry = Alias { alias_name: alias, destination: user.id, domain };
+			backend.remove_alias(&entry).await?;
+			print_one(json, &entry, || format!("{} is not a member of {}", username, entry.alias_name));
+		}
+		AliasesCommand::Resolve { recipient } => {
+			let users = backend.resolve_recipient(&recipient).await?;
+			print_rows(json, &users, &["USERNAME", "ID"], |user| vec![user.username.clone(), user.id.to_string()]);
+		}
+	}
+
+	Ok(())
+}
+
+async fn migrations(backend: nyanpasswd::Service<nyanpasswd::Created>, json: bool, command: MigrationsCommand) -> Result<(), Error> {
+	let status = match command {
+		MigrationsCommand::Status => backend.migration_status().await?,
+		MigrationsCommand::Run => {
+			let pending = backend.migration_status().await?.iter().filter(|m| m.applied_at.is_none()).count();
+			let backend = backend.run_migrations().await?;
+			if !json {
+				println!("Applied {} migrations", pending);
+			}
+			backend.migration_status().await?
+		}
+	};
+	print_rows(json, &status, &["VERSION", "DESCRIPTION", "APPLIED"], |migration| {
+		vec![
+			migration.version.to_string(),
+			migration.description.clone(),
+			migration.applied_at.map(|date| format_date(Some(date))).unwrap_or_else(|| "pending".to_owned()),
+		]
+	});
+
+	Ok(())
+}
+
+#[derive(serde::Serialize)]
+struct CertificateEntry {
+	username: String,
+	#[serde(flatten)]
+	certificate: nyanpasswd::ClientCertificate,
+}
+
+async fn audit(backend: &Service, json: bool, command: AuditCommand) -> Result<(), Error> {
+	match command {
+		AuditCommand::Expiring { days } => {
+			let before = chrono::Utc::now() + chrono::Duration::days(days);
+			let expiring = backend.list_expiring(before.into()).await?;
+			print_rows(json, &expiring, &["USERNAME", "PASSWORD", "EXPIRES"], |expiring| {
+				vec![
+					expiring.username.clone(),
+					expiring.password_label.clone().unwrap_or_else(|| "(user)".to_owned()),
+					format_date(Some(expiring.expires_at)),
+				]
+			});
+		}
+		AuditCommand::Certificates { user } => {
+			let certificates = match &user {
+				Some(username) => backend.list_client_certificates_for(find_user(backend, username).await?.id).await?,
+				None => backend.list_all_client_certificates().await?,
+			};
+			let usernames: HashMap<Uuid, String> =
+				backend.list_users().await?.into_iter().map(|user| (user.id, user.username)).collect();
+			let certificates: Vec<CertificateEntry> = certificates
+				.into_iter()
+				.map(|certificate| CertificateEntry {
+					username: usernames.get(&certificate.userid).cloned().unwrap_or_default(),
+					certificate,
+				})
+				.collect();
+			print_rows(
+				json,
+				&certificates,
+				&["USERNAME", "SUBJECT", "SERIAL", "PINNED", "REVOKED", "LAST SEEN"],
+				|entry| {
+					vec![
+						entry.username.clone(),
+						entry.certificate.subject.clone(),
+						entry.certificate.serial.clone(),
+						entry.certificate.pinned.to_string(),
+						entry.certificate.revoked.to_string(),
+						format_date(Some(entry.certificate.last_seen)),
+					]
+				},
+			);
+		}
+		AuditCommand::Sessions => {
+			let sessions = backend.list_sessions().await?;
+			print_rows(json, &sessions, &["USERNAME", "METHOD", "PASSKEY", "CREATED", "EXPIRES"], |session| {
+				vec![
+					session.username.clone(),
+					match session.method {
+						nyanpasswd::LoginMethod::Passkey => "passkey".to_owned(),
+						nyanpasswd::LoginMethod::Oidc => "oidc".to_owned(),
+					},
+					session.passkey.clone().unwrap_or_default(),
+					format_date(Some(session.created_at)),
+					format_date(Some(session.expires_at)),
+				]
+			});
+		}
+	}
+
+	Ok(())
+}
+
+async fn export(backend: &Service, format: Format, output: Option<PathBuf>) -> Result<(), Error> {
+	let dataset = backend.export().await?;
+	match (format, output) {
+		(Format::Json, None) => {
+			let mut stdout = std::io::stdout().lock();
+			serde_json::to_writer_pretty(&mut stdout, &dataset)?;
+			writeln!(stdout).map_err(|err| Error::Io("standard output".into(), err))?;
+		}
+		(Format::Json, Some(path)) => {
+			let json = serde_json::to_string_pretty(&dataset)? + "\n";
+			std::fs::write(&path, json).map_err(|err| Error::Io(path, err))?;
+		}
+		(Format::Csv, Some(dir)) => dataset.write_csv(&dir).map_err(|err| Error::Io(dir, err))?,
+		(Format::Csv, None) => unreachable!("clap requires an output directory"),
+	}
+
+	Ok(())
+}
+
+async fn import(backend: &Service, json: bool, file: PathBuf, options: ImportOptions) -> Result<(), Error> {
+	let input = if file.as_os_str() == "-" {
+		let mut input = String::new();
+		std::io::stdin()
+			.read_to_string(&mut input)
+			.map_err(|err| Error::Io("standard input".into(), err))?;
+		input
+	} else {
+		std::fs::read_to_string(&file).map_err(|err| Error::Io(file, err))?
+	};
+	let dataset: Dataset = serde_json::from_str(&input)?;
+	let changes = backend.import(&dataset, options).await?;
+	print_changes(json, &changes, options.dry_run);
+
+	Ok(())
+}
+
+/// Print what an import or reconciliation changed.
+fn print_changes<T: serde::Serialize + std::fmt::Display>(json: bool, changes: &[T], dry_run: bool) {
+	print_one(json, &changes, || {
+		let mut lines: Vec<String> = changes.iter().map(ToString::to_string).collect();
+		lines.push(match (changes.len(), dry_run) {
+			(0, _) => "Nothing to change".to_owned(),
+			(count, true) => format!("Dry run, {} changes not applied", count),
+			(count, false) => format!("Applied {} changes", count),
+		});
+		lines.join("\n")
+	});
+}
+
+async fn reconcile(backend: &Service, json: bool, file: PathBuf, dry_run: bool) -> Result<(), Error> {
+	let manifest = Manifest::load(&file)?;
+	let changes = backend.reconcile(&manifest, dry_run).await?;
+	print_changes(json, &changes, dry_run);
+
+	Ok(())
+}
+
+async fn run(cli: Cli) -> Result<(), Error> {
+	let config = Config::load()?;
+	let db = PgPoolOptions::new()
+		.max_connections(config.database.max_connections.min(4))
+		.connect(&config.database.url)
+		.await
+		.map_err(Error::Connect)?;
+	let backend = nyanpasswd::Service::new(db);
+	if let Command::Migrations(command) = cli.command {
+		return migrations(backend, cli.json, command).await;
+	}
+	// Migrating is left to the `migrations` command, so that an older server isn't surprised by
+	// a newer schema
+	let backend = backend.check_migrations().await?.map_err(|_| Error::PendingMigrations)?;
+
+	match cli.command {
+		Command::Users(command) => users(&backend, cli.json, command).await,
+		Command::Passwords(command) => passwords(&backend, cli.json, command).await,
+		Command::Aliases(command) => aliases(&backend, cli.json, command).await,
+		Command::Audit(command) => audit(&backend, cli.json, command).await,
+		Command::Export { format, output } => export(&backend, format, output).await,
+		Command::Reconcile { file, dry_run } => {
+			let file = file
+				.or_else(|| config.reconcile.as_ref().map(|reconcile| reconcile.file.clone()))
+				.ok_or(Error::NoReconcileFile)?;
+			reconcile(&backend, cli.json, file, dry_run).await
+		}
+		Command::Import { file, dry_run, prune } => import(&backend, cli.json, file, ImportOptions { prune, dry_run }).await,
+		Command::Migrations(_) => unreachable!("handled above"),
+	}
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+	// Only errors by default, so that they don't get mixed up with the output
+	tracing_subscriber::fmt()
+		.with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+		.with_writer(std::io::stderr)
+		.init();
+
+	match run(Cli::parse()).await {
+		Ok(()) => ExitCode::SUCCESS,
+		Err(err) => {
+			eprintln!("nyanpasswd-admin: {}", err);
+			ExitCode::FAILURE
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::{Cli, Expiry};
+	use clap::{CommandFactory, Parser};
+
+	#[test]
+	fn test_cli() {
+		Cli::command().debug_assert();
+		// Changing a user without saying what to change is a mistake
+		assert!(Cli::try_parse_from(["nyanpasswd-admin", "users", "set", "vsh"]).is_err());
+		assert!(Cli::try_parse_from(["nyanpasswd-admin", "users", "set", "vsh", "--login-allowed", "false"]).is_ok());
+		assert!(Cli::try_parse_from(["nyanpasswd-admin", "--json", "users", "list", "--status", "expired"]).is_ok());
+		// CSV is several files, so it can't go to standard output
+		assert!(Cli::try_parse_from(["nyanpasswd-admin", "export", "--format", "csv"]).is_err());
+		assert!(Cli::try_parse_from(["nyanpasswd-admin", "export", "--format", "csv", "-o", "backup"]).is_ok());
+		assert!(Cli::try_parse_from(["nyanpasswd-admin", "import", "-", "--dry-run", "--prune"]).is_ok());
+		assert!(Cli::try_parse_from(["nyanpasswd-admin", "reconcile", "--dry-run"]).is_ok());
+	}
+
+	#[test]
+	fn test_expiry() {
+		assert_eq!("never".parse::<Expiry>(), Ok(Expiry(None)));
+		assert_eq!(
+			"2038-01-19".parse::<Expiry>().unwrap().0.unwrap().to_rfc3339(),
+			"2038-01-19T00:00:00+00:00"
+		);
+		assert_eq!(
+			"2038-01-19T03:14:07+01:00".parse::<Expiry>().unwrap().0.unwrap().to_rfc3339(),
+			"2038-01-19T03:14:07+01:00"
+		);
+		assert!("tomorrow".parse::<Expiry>().is_err());
+	}
+}
@@ -0,0 +1,1121 @@
+/*
+  Copyright © 2022 nyantec GmbH <oss@nyantec.com>
+  Written by Vika Shleina <vsh@nyantec.com>
+  
+  Provided that these terms and disclaimer and all copyright notices
+  are retained or reproduced in an accompanying document, permission
+  is granted to deal in this work without restriction, including un‐
+  limited rights to use, publicly perform, distribute, sell, modify,
+  merge, give away, or sublicence.
+  
+  This work is provided "AS IS" and WITHOUT WARRANTY of any kind, to
+  the utmost extent permitted by applicable law, neither express nor
+  implied; without malicious intent or gross negligence. In no event
+  may a licensor, author or contributor be held liable for indirect,
+  direct, other damage, loss, or other issues arising in any way out
+  of dealing in the work, even if advised of the possibility of such
+  damage or existence of a defect, except proven that it results out
+  of said person's immediate fault when using the work as intended.
+ */
+//! Configuration, loaded from a TOML file and the environment at startup.
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use once_cell::sync::OnceCell;
+
+use crate::tls::TlsFiles;
+use crate::unix::PeerRule;
+
+/// Where the configuration file is read from, unless `NYANPASSWD_CONFIG` says otherwise.
+const DEFAULT_PATH: &str = "/etc/nyanpasswd/config.toml";
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// The configuration the server was started with.
+///
+/// # Panics
+/// If called before [`init`].
+pub fn get() -> &'static Config {
+	CONFIG.get().expect("configuration not loaded")
+}
+
+/// Make the configuration available through [`get`].
+pub fn init(config: Config) {
+	if CONFIG.set(config).is_err() {
+		panic!("configuration loaded twice");
+	}
+}
+
+#[derive(Debug)]
+pub struct Config {
+	pub site: Site,
+	pub database: Database,
+	pub http: Http,
+	pub admin: Admin,
+	pub mail: Mail,
+	pub certificates: Certificates,
+	pub api: Api,
+	/// If set, the TCP listener terminates TLS itself, instead of relying on a reverse proxy.
+	pub tls: Option<TlsFiles>,
+	/// If set, users can log into the dashboard with passkeys, too.
+	pub passkeys: Option<Passkeys>,
+	/// If set, users can log into the dashboard through an OpenID Connect provider, too.
+	pub oidc: Option<Oidc>,
+	/// If set, non-human users and aliases are managed by a file, see [`crate::reconcile`].
+	pub reconcile: Option<Reconcile>,
+	/// If set, requests without a client certificate get a fake one. Only for development.
+	pub dev: Option<crate::axum::DevMode>,
+}
+
+#[derive(Debug)]
+pub struct Site {
+	pub company_name: String,
+	/// Link to the legal notice shown in the footer.
+	pub impressum: String,
+}
+
+pub struct Database {
+	pub url: String,
+	pub max_connections: u32,
+}
+
+// The URL may contain a password, so keep it out of the logs.
+impl std::fmt::Debug for Database {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("Database")
+			.field("url", &"<redacted>")
+			.field("max_connections", &self.max_connections)
+			.finish()
+	}
+}
+
+#[derive(Debug)]
+pub struct Http {
+	/// Address of the TCP listener serving the dashboard.
+	pub listen: SocketAddr,
+}
+
+#[derive(Debug)]
+pub struct Admin {
+	/// Certificate UIDs of users allowed to use the admin dashboard.
+	pub uids: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Mail {
+	/// Domains registered on startup, in addition to those added in the admin dashboard.
+	pub domains: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Certificates {
+	/// How client certificates are mapped to usernames.
+	pub identity: crate::axum::IdentityMapping,
+	/// How the reverse proxy passes on client certificates.
+	pub proxy_format: crate::axum::ProxyHeaderFormat,
+	/// Which reverse proxies are believed about client certificates.
+	pub trusted_proxies: crate::axum::TrustedProxies,
+}
+
+#[derive(Debug)]
+pub struct Api {
+	pub allow_anonymous: bool,
+	/// Whether the TCP listener serves the API, besides the dashboard.
+	pub tcp: bool,
+	pub socket: Option<ApiSocket>,
+	/// API consumers registered on startup, e.g. by a NixOS module.
+	pub consumers: Vec<ApiConsumer>,
+}
+
+#[derive(Debug)]
+pub struct Passkeys {
+	pub relying_party: crate::webauthn::RelyingParty,
+	/// How long logging in with a passkey lasts.
+	pub session_lifetime: chrono::Duration,
+	/// Whether passkeys let admins into the admin dashboard.
+	pub admin: bool,
+}
+
+#[derive(Debug)]
+pub struct Oidc {
+	pub settings: crate::oidc::Settings,
+	/// Shown on the login button.
+	pub provider_name: String,
+	/// How long logging in through the identity provider lasts.
+	pub session_lifetime: chrono::Duration,
+	/// Whether the identity provider lets admins into the admin dashboard.
+	pub admin: bool,
+}
+
+#[derive(Debug)]
+pub struct Reconcile {
+	pub file: PathBuf,
+	/// Whether the server reconciles before it starts serving.
+	pub on_startup: bool,
+}
+
+/// An API consumer declared in the configuration, with a token read from a file.
+pub struct ApiConsumer {
+	pub name: String,
+	pub token: String,
+	pub permissions: Vec<crate::ApiPermission>,
+}
+
+// The token is a secret, so keep it out of the logs.
+impl std::fmt::Debug for ApiConsumer {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("ApiConsumer")
+			.field("name", &self.name)
+			.field("token", &"<redacted>")
+			.field("permissions", &self.permissions)
+			.finish()
+	}
+}
+
+/// A Unix socket to serve the API on.
+#[derive(Debug)]
+pub struct ApiSocket {
+	pub path: PathBuf,
+	pub mode: u32,
+	pub group: Option<u32>,
+	pub peers: Vec<PeerRule>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+	#[error("could not read {0}: {1}")]
+	Read(PathBuf, std::io::Error),
+	#[error("could not parse {0}: {1}")]
+	Parse(PathBuf, toml::de::Error),
+	#[error("{0}: {1}")]
+	Invalid(&'static str, String),
+	#[error("environment variable {0} is not valid UTF-8")]
+	NotUnicode(String),
+}
+
+// The file format. Everything is optional here so that environment
+// variables can fill in the gaps; validation happens in `RawConfig::validate`.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawConfig {
+	site: RawSite,
+	database: RawDatabase,
+	http: RawHttp,
+	admin: RawAdmin,
+	mail: RawMail,
+	certificates: RawCertificates,
+	api: RawApi,
+	tls: RawTls,
+	passkeys: RawPasskeys,
+	oidc: RawOidc,
+	reconcile: RawReconcile,
+	dev: RawDev,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawSite {
+	company_name: Option<String>,
+	impressum: Option<String>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawDatabase {
+	url: Option<String>,
+	url_file: Option<PathBuf>,
+	max_connections: Option<u32>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawHttp {
+	listen: Option<String>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawAdmin {
+	uids: Vec<String>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawMail {
+	domains: Vec<String>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawCertificates {
+	proxy_format: Option<String>,
+	trusted_proxies: Option<Vec<String>>,
+	proxy_secret: Option<String>,
+	proxy_secret_file: Option<PathBuf>,
+	username_attribute: Option<String>,
+	username_rewrites: Vec<RawRewrite>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRewrite {
+	pattern: String,
+	replacement: String,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawApi {
+	allow_anonymous: bool,
+	/// Defaults to `true`.
+	tcp: Option<bool>,
+	socket: Option<PathBuf>,
+	socket_mode: Option<String>,
+	socket_group: Option<String>,
+	socket_peers: Vec<String>,
+	consumers: Vec<RawApiConsumer>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawApiConsumer {
+	name: String,
+	token_file: PathBuf,
+	permissions: Vec<crate::ApiPermission>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawTls {
+	certificate: Option<PathBuf>,
+	key: Option<PathBuf>,
+	client_ca: Option<PathBuf>,
+	client_crl: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawPasskeys {
+	enable: bool,
+	origin: Option<String>,
+	rp_id: Option<String>,
+	session_hours: Option<u32>,
+	admin: bool,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawOidc {
+	enable: bool,
+	issuer: Option<String>,
+	client_id: Option<String>,
+	client_secret: Option<String>,
+	client_secret_file: Option<PathBuf>,
+	origin: Option<String>,
+	username_claim: Option<String>,
+	scopes: Option<Vec<String>>,
+	provider_name: Option<String>,
+	ca_file: Option<PathBuf>,
+	session_hours: Option<u32>,
+	admin: bool,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawReconcile {
+	file: Option<PathBuf>,
+	on_startup: Option<bool>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawDev {
+	enable: bool,
+	user: Option<String>,
+}
+
+/// Read an environment variable, or the contents of the file named by `<name>_FILE`.
+///
+/// The file variant is meant for secrets, which shouldn't be visible in the process environment.
+fn env_var(name: &str) -> Result<Option<String>, ConfigError> {
+	match std::env::var(name) {
+		Ok(value) => return Ok(Some(value)),
+		Err(std::env::VarError::NotUnicode(_)) => return Err(ConfigError::NotUnicode(name.to_owned())),
+		Err(std::env::VarError::NotPresent) => {}
+	}
+	match std::env::var_os(format!("{}_FILE", name)) {
+		Some(path) => read_secret(Path::new(&path)).map(Some),
+		None => Ok(None),
+	}
+}
+
+fn read_secret(path: &Path) -> Result<String, ConfigError> {
+	std::fs::read_to_string(path)
+		.map(|secret| secret.trim_end_matches('\n').to_owned())
+		.map_err(|err| ConfigError::Read(path.to_owned(), err))
+}
+
+/// The host name of a web origin like `https://mail.nyantec.com:8443`.
+///
+/// Browsers only allow passkeys on HTTPS, except for `localhost`.
+fn origin_host(origin: &str) -> Option<&str> {
+	let (scheme, authority) = origin.split_once("://")?;
+	let host = match authority.rsplit_once(':') {
+		Some((host, port)) if port.parse::<u16>().is_ok() => host,
+		_ => authority,
+	};
+	if host.is_empty() || host.contains(['/', ':', '@']) {
+		return None;
+	}
+	match scheme {
+		"https" => Some(host),
+		"http" if host == "localhost" => Some(host),
+		_ => None,
+	}
+}
+
+/// Whether secrets may be sent to a URL: it has to use HTTPS, unless it points at this machine.
+fn secure_url(url: &str) -> bool {
+	match url.parse::<hyper::Uri>() {
+		Ok(uri) => match (uri.scheme_str(), uri.host()) {
+			(Some("https"), Some(_)) => true,
+			(Some("http"), Some(host)) => ["localhost", "127.0.0.1", "[::1]"].contains(&host),
+			_ => false,
+		},
+		Err(_) => false,
+	}
+}
+
+impl RawConfig {
+	/// Let environment variables override settings from the file.
+	fn apply_env(&mut self) -> Result<(), ConfigError> {
+		if let Some(name) = env_var("COMPANY_NAME")? {
+			self.site.company_name = Some(name);
+		}
+		if let Some(impressum) = env_var("IMPRESSUM")? {
+			self.site.impressum = Some(impressum);
+		}
+		if let Some(url) = env_var("DATABASE_URL")? {
+			self.database.url = Some(url);
+			self.database.url_file = None;
+		}
+		if let Some(max_connections) = env_var("DATABASE_MAX_CONNECTIONS")? {
+			self.database.max_connections = Some(
+				max_connections
+					.parse()
+					.map_err(|err| ConfigError::Invalid("DATABASE_MAX_CONNECTIONS", format!("{}", err)))?,
+			);
+		}
+		if let Some(listen) = env_var("LISTEN_ADDRESS")? {
+			self.http.listen = Some(listen);
+		}
+		if let Some(uids) = env_var("ADMIN_UIDS")? {
+			self.admin.uids = uids.split_whitespace().map(str::to_owned).collect();
+		}
+		if let Some(domains) = env_var("MAIL_DOMAINS")? {
+			self.mail.domains = domains.split_whitespace().map(str::to_owned).collect();
+		}
+		if let Some(format) = env_var("CERT_PROXY_FORMAT")? {
+			self.certificates.proxy_format = Some(format);
+		}
+		if let Some(proxies) = env_var("CERT_TRUSTED_PROXIES")? {
+			self.certificates.trusted_proxies = Some(proxies.split_whitespace().map(str::to_owned).collect());
+		}
+		if let Some(secret) = env_var("CERT_PROXY_SECRET")? {
+			self.certificates.proxy_secret = Some(secret);
+			self.certificates.proxy_secret_file = None;
+		}
+		if let Some(attribute) = env_var("CERT_USERNAME_ATTRIBUTE")? {
+			self.certificates.username_attribute = Some(attribute);
+		}
+		if let Some(allow_anonymous) = env_var("API_ALLOW_ANONYMOUS")? {
+			self.api.allow_anonymous = allow_anonymous == "true";
+		}
+		if let Some(tcp) = env_var("API_TCP")? {
+			self.api.tcp = Some(tcp == "true");
+		}
+		if let Some(socket) = env_var("API_SOCKET")? {
+			self.api.socket = Some(socket.into());
+		}
+		if let Some(mode) = env_var("API_SOCKET_MODE")? {
+			self.api.socket_mode = Some(mode);
+		}
+		if let Some(group) = env_var("API_SOCKET_GROUP")? {
+			self.api.socket_group = Some(group);
+		}
+		if let Some(peers) = env_var("API_SOCKET_PEERS")? {
+			self.api.socket_peers = peers.split_whitespace().map(str::to_owned).collect();
+		}
+		if let Some(certificate) = env_var("TLS_CERTIFICATE")? {
+			self.tls.certificate = Some(certificate.into());
+		}
+		if let Some(key) = env_var("TLS_KEY")? {
+			self.tls.key = Some(key.into());
+		}
+		if let Some(client_ca) = env_var("TLS_CLIENT_CA")? {
+			self.tls.client_ca = Some(client_ca.into());
+		}
+		if let Some(client_crl) = env_var("TLS_CLIENT_CRL")? {
+			self.tls.client_crl = Some(client_crl.into());
+		}
+		if let Some(enable) = env_var("PASSKEYS_ENABLE")? {
+			self.passkeys.enable = enable == "true";
+		}
+		if let Some(origin) = env_var("PASSKEYS_ORIGIN")? {
+			self.passkeys.origin = Some(origin);
+		}
+		if let Some(rp_id) = env_var("PASSKEYS_RP_ID")? {
+			self.passkeys.rp_id = Some(rp_id);
+		}
+		if let Some(hours) = env_var("PASSKEYS_SESSION_HOURS")? {
+			self.passkeys.session_hours = Some(
+				hours
+					.parse()
+					.map_err(|err| ConfigError::Invalid("PASSKEYS_SESSION_HOURS", format!("{}", err)))?,
+			);
+		}
+		if let Some(admin) = env_var("PASSKEYS_ADMIN")? {
+			self.passkeys.admin = admin == "true";
+		}
+		if let Some(enable) = env_var("OIDC_ENABLE")? {
+			self.oidc.enable = enable == "true";
+		}
+		if let Some(issuer) = env_var("OIDC_ISSUER")? {
+			self.oidc.issuer = Some(issuer);
+		}
+		if let Some(client_id) = env_var("OIDC_CLIENT_ID")? {
+			self.oidc.client_id = Some(client_id);
+		}
+		if let Some(secret) = env_var("OIDC_CLIENT_SECRET")? {
+			self.oidc.client_secret = Some(secret);
+			self.oidc.client_secret_file = None;
+		}
+		if let Some(origin) = env_var("OIDC_ORIGIN")? {
+			self.oidc.origin = Some(origin);
+		}
+		if let Some(claim) = env_var("OIDC_USERNAME_CLAIM")? {
+			self.oidc.username_claim = Some(claim);
+		}
+		if let Some(scopes) = env_var("OIDC_SCOPES")? {
+			self.oidc.scopes = Some(scopes.split_whitespace().map(str::to_owned).collect());
+		}
+		if let Some(name) = env_var("OIDC_PROVIDER_NAME")? {
+			self.oidc.provider_name = Some(name);
+		}
+		if let Some(ca_file) = env_var("OIDC_CA_FILE")? {
+			self.oidc.ca_file = Some(ca_file.into());
+		}
+		if let Some(hours) = env_var("OIDC_SESSION_HOURS")? {
+			self.oidc.session_hours = Some(
+				hours
+					.parse()
+					.map_err(|err| ConfigError::Invalid("OIDC_SESSION_HOURS", format!("{}", err)))?,
+			);
+		}
+		if let Some(admin) = env_var("OIDC_ADMIN")? {
+			self.oidc.admin = admin == "true";
+		}
+		if let Some(file) = env_var("RECONCILE_FILE")? {
+			self.reconcile.file = Some(file.into());
+		}
+		if let Some(on_startup) = env_var("RECONCILE_ON_STARTUP")? {
+			self.reconcile.on_startup = Some(on_startup == "true");
+		}
+		if let Some(enable) = env_var("DEV_MODE")? {
+			self.dev.enable = enable == "true";
+		}
+		if let Some(user) = env_var("DEV_USER")? {
+			self.dev.user = Some(user);
+		}
+
+		Ok(())
+	}
+
+	fn validate(self) -> Result<Config, ConfigError> {
+		let database_url = match (self.database.url, self.database.url_file) {
+			(Some(_), Some(_)) => {
+				return Err(ConfigError::Invalid(
+					"database",
+					"only one of `url` and `url_file` may be set".to_owned(),
+				))
+			}
+			(Some(url), None) => url,
+			(None, Some(path)) => read_secret(&path)?,
+			(None, None) => {
+				return Err(ConfigError::Invalid(
+					"database.url",
+					"not set (set it, `database.url_file`, DATABASE_URL or DATABASE_URL_FILE)".to_owned(),
+				))
+			}
+		};
+		let max_connections = self.database.max_connections.unwrap_or(5);
+		if max_connections == 0 {
+			return Err(ConfigError::Invalid("database.max_connections", "must be at least 1".to_owned()));
+		}
+
+		let listen = match self.http.listen {
+			Some(listen) => listen
+				.parse()
+				.map_err(|err| ConfigError::Invalid("http.listen", format!("`{}`: {}", listen, err)))?,
+			None => SocketAddr::from(([127, 0, 0, 1], 3000)),
+		};
+
+		let domains = self.mail.domains;
+		if let Some(domain) = domains.iter().find(|domain| domain.is_empty() || domain.contains('@')) {
+			return Err(ConfigError::Invalid("mail.domains", format!("`{}` is not a domain", domain)));
+		}
+
+		let proxy_format = match self.certificates.proxy_format {
+			Some(format) => format
+				.parse()
+				.map_err(|err| ConfigError::Invalid("certificates.proxy_format", err))?,
+			None => Default::default(),
+		};
+		let secret = match (self.certificates.proxy_secret, self.certificates.proxy_secret_file) {
+			(Some(_), Some(_)) => {
+				return Err(ConfigError::Invalid(
+					"certificates",
+					"only one of `proxy_secret` and `proxy_secret_file` may be set".to_owned(),
+				))
+			}
+			(Some(secret), None) => Some(secret),
+			(None, Some(path)) => Some(read_secret(&path)?),
+			(None, None) => None,
+		};
+		let trusted_proxies = match self.certificates.trusted_proxies {
+			Some(networks) => {
+				let networks = networks
+					.iter()
+					.map(|network| network.parse())
+					.collect::<Result<Vec<_>, _>>()
+					.map_err(|err| ConfigError::Invalid("certificates.trusted_proxies", err))?;
+				if networks.is_empty() && secret.is_none() {
+					return Err(ConfigError::Invalid(
+						"certificates.trusted_proxies",
+						"may only be empty if `proxy_secret` is set".to_owned(),
+					));
+				}
+				crate::axum::TrustedProxies { networks, secret }
+			}
+			None => crate::axum::TrustedProxies {
+				secret,
+				..Default::default()
+			},
+		};
+		let identity = crate::axum::IdentityMapping {
+			source: match self.certificates.username_attribute {
+				Some(attribute) => attribute
+					.parse()
+					.map_err(|err| ConfigError::Invalid("certificates.username_attribute", err))?,
+				None => crate::axum::IdentityMapping::default().source,
+			},
+			rewrites: self
+				.certificates
+				.username_rewrites
+				.into_iter()
+				.map(|rewrite| {
+					Ok(crate::axum::Rewrite {
+						pattern: regex::Regex::new(&rewrite.pattern)
+							.map_err(|err| ConfigError::Invalid("certificates.username_rewrites", err.to_string()))?,
+						replacement: rewrite.replacement,
+					})
+				})
+				.collect::<Result<_, _>>()?,
+		};
+
+		let socket = match self.api.socket {
+			Some(path) => Some(ApiSocket {
+				path,
+				mode: match self.api.socket_mode {
+					Some(mode) => u32::from_str_radix(&mode, 8)
+						.ok()
+						.filter(|mode| *mode <= 0o777)
+						.ok_or_else(|| ConfigError::Invalid("api.socket_mode", format!("`{}` is not an octal mode", mode)))?,
+					None => 0o660,
+				},
+				group: self
+					.api
+					.socket_group
+					.map(|group| crate::unix::resolve_group(&group))
+					.transpose()
+					.map_err(|err| ConfigError::Invalid("api.socket_group", err.to_string()))?,
+				peers: self
+					.api
+					.socket_peers
+					.iter()
+					.map(|rule| PeerRule::parse(rule))
+					.collect::<Result<_, _>>()
+					.map_err(|err| ConfigError::Invalid("api.socket_peers", err.to_string()))?,
+			}),
+			None if self.api.socket_mode.is_some() || self.api.socket_group.is_some() || !self.api.socket_peers.is_empty() => {
+				return Err(ConfigError::Invalid(
+					"api.socket",
+					"not set, but other socket settings are".to_owned(),
+				))
+			}
+			None => None,
+		};
+		let tcp = self.api.tcp.unwrap_or(true);
+		if !tcp && socket.is_none() {
+			return Err(ConfigError::Invalid("api.tcp", "disabled, but `api.socket` isn't set either".to_owned()));
+		}
+
+		let mut consumers: Vec<ApiConsumer> = Vec::new();
+		for consumer in self.api.consumers {
+			if consumers.iter().any(|c| c.name == consumer.name) {
+				return Err(ConfigError::Invalid("api.consumers", format!("`{}` is declared twice", consumer.name)));
+			}
+			let token = read_secret(&consumer.token_file)?;
+			if token.len() < 16 {
+				return Err(ConfigError::Invalid(
+					"api.consumers",
+					format!("the token of `{}` must be at least 16 characters long", consumer.name),
+				));
+			}
+			consumers.push(ApiConsumer {
+				name: consumer.name,
+				token,
+				permissions: consumer.permissions,
+			});
+		}
+
+		let tls = match self.tls {
+			RawTls {
+				certificate: Some(certificate),
+				key: Some(key),
+				client_ca: Some(client_ca),
+				client_crl,
+			} => Some(TlsFiles {
+				certificate,
+				key,
+				client_ca,
+				client_crl,
+			}),
+			RawTls {
+				certificate: None,
+				key: None,
+				client_ca: None,
+				client_crl: None,
+			} => None,
+			_ => {
+				return Err(ConfigError::Invalid(
+					"tls",
+					"`certificate`, `key` and `client_ca` must all be set to enable TLS".to_owned(),
+				))
+			}
+		};
+
+		let company_name = self.site.company_name.unwrap_or_else(|| "nyantec GmbH".to_owned());
+
+		let passkeys = match self.passkeys {
+			RawPasskeys { enable: false, .. } => None,
+			RawPasskeys { enable: true, origin: None, .. } => {
+				return Err(ConfigError::Invalid("passkeys.origin", "must be set to enable passkeys".to_owned()))
+			}
+			RawPasskeys {
+				enable: true,
+				origin: Some(origin),
+				rp_id,
+				session_hours,
+				admin,
+			} => {
+				let host = origin_host(&origin).ok_or_else(|| {
+					ConfigError::Invalid(
+						"passkeys.origin",
+						format!("`{}` is not an origin like `https://mail.nyantec.com`", origin),
+					)
+				})?;
+				let rp_id = rp_id.unwrap_or_else(|| host.to_owned());
+				if host != rp_id && !host.ends_with(&format!(".{}", rp_id)) {
+					return Err(ConfigError::Invalid(
+						"passkeys.rp_id",
+						format!("`{}` is neither `{}` nor a parent domain of it", rp_id, host),
+					));
+				}
+				let session_hours = session_hours.unwrap_or(12);
+				if session_hours == 0 {
+					return Err(ConfigError::Invalid("passkeys.session_hours", "must be at least 1".to_owned()));
+				}
+				Some(Passkeys {
+					relying_party: crate::webauthn::RelyingParty {
+						id: rp_id,
+						origin,
+						name: company_name.clone(),
+					},
+					session_lifetime: chrono::Duration::hours(session_hours.into()),
+					admin,
+				})
+			}
+		};
+
+		let oidc = match self.oidc {
+			RawOidc { enable: false, .. } => None,
+			RawOidc {
+				enable: true,
+				issuer,
+				client_id,
+				client_secret,
+				client_secret_file,
+				origin,
+				username_claim,
+				scopes,
+				provider_name,
+				ca_file,
+				session_hours,
+				admin,
+			} => {
+				let issuer = issuer
+					.ok_or_else(|| ConfigError::Invalid("oidc.issuer", "must be set to enable OpenID Connect".to_owned()))?;
+				if !secure_url(&issuer) {
+					return Err(ConfigError::Invalid("oidc.issuer", format!("`{}` is not an HTTPS URL", issuer)));
+				}
+				let client_id = client_id
+					.ok_or_else(|| ConfigError::Invalid("oidc.client_id", "must be set to enable OpenID Connect".to_owned()))?;
+				let client_secret = match (client_secret, client_secret_file) {
+					(Some(_), Some(_)) => {
+						return Err(ConfigError::Invalid(
+							"oidc",
+							"only one of `client_secret` and `client_secret_file` may be set".to_owned(),
+						))
+					}
+					(Some(secret), None) => Some(secret),
+					(None, Some(path)) => Some(read_secret(&path)?),
+					(None, None) => None,
+				};
+				let origin = origin
+					.ok_or_else(|| ConfigError::Invalid("oidc.origin", "must be set to enable OpenID Connect".to_owned()))?;
+				if origin_host(&origin).is_none() {
+					return Err(ConfigError::Invalid(
+						"oidc.origin",
+						format!("`{}` is not an origin like `https://mail.nyantec.com`", origin),
+					));
+				}
+				let session_hours = session_hours.unwrap_or(12);
+				if session_hours == 0 {
+					return Err(ConfigError::Invalid("oidc.session_hours", "must be at least 1".to_owned()));
+				}
+				Some(Oidc {
+					settings: crate::oidc::Settings {
+						issuer,
+						client_id,
+						client_secret,
+						redirect_uri: format!("{}/oidc/callback", origin),
+						scopes: scopes.unwrap_or_else(|| vec!["profile".to_owned()]),
+						username_claim: username_claim.unwrap_or_else(|| "preferred_username".to_owned()),
+						ca_file,
+					},
+					provider_name: provider_name.unwrap_or_else(|| "single sign-on".to_owned()),
+					session_lifetime: chrono::Duration::hours(session_hours.into()),
+					admin,
+				})
+			}
+		};
+
+		let reconcile = match self.reconcile {
+			RawReconcile { file: Some(file), on_startup } => Some(Reconcile {
+				file,
+				on_startup: on_startup.unwrap_or(true),
+			}),
+			RawReconcile { file: None, on_startup: None } => None,
+			RawReconcile { file: None, .. } => {
+				return Err(ConfigError::Invalid("reconcile.file", "must be set to reconcile on startup".to_owned()))
+			}
+		};
+
+		let dev = match self.dev {
+			RawDev { enable: false, .. } => None,
+			RawDev { enable: true, user: None } => {
+				return Err(ConfigError::Invalid("dev.user", "must be set in dev mode".to_owned()))
+			}
+			RawDev {
+				enable: true,
+				user: Some(user),
+			} => {
+				if let crate::axum::IdentitySource::SubjectAltName(_) = identity.source {
+					return Err(ConfigError::Invalid(
+						"dev.enable",
+						"dev mode can't fake subject alternative names, use a DN attribute as `certificates.username_attribute`"
+							.to_owned(),
+					));
+				}
+				Some(crate::axum::DevMode { default_user: user })
+			}
+		};
+
+		Ok(Config {
+			site: Site {
+				company_name,
+				impressum: self
+					.site
+					.impressum
+					.unwrap_or_else(|| "https://nyantec.com/impressum/".to_owned()),
+			},
+			database: Database {
+				url: database_url,
+				max_connections,
+			},
+			http: Http { listen },
+			admin: Admin { uids: self.admin.uids },
+			mail: Mail { domains },
+			certificates: Certificates {
+				identity,
+				proxy_format,
+				trusted_proxies,
+			},
+			api: Api {
+				allow_anonymous: self.api.allow_anonymous,
+				tcp,
+				socket,
+				consumers,
+			},
+			tls,
+			passkeys,
+			oidc,
+			reconcile,
+			dev,
+		})
+	}
+}
+
+impl Config {
+	/// Load the configuration file named by `NYANPASSWD_CONFIG`, applying environment overrides.
+	///
+	/// If `NYANPASSWD_CONFIG` isn't set and there is no file at the default location, the
+	/// configuration comes from the environment alone.
+	pub fn load() -> Result<Self, ConfigError> {
+		let (path, required) = match std::env::var_os("NYANPASSWD_CONFIG") {
+			Some(path) => (PathBuf::from(path), true),
+			None => (PathBuf::from(DEFAULT_PATH), false),
+		};
+		let mut raw = match std::fs::read_to_string(&path) {
+			Ok(contents) => Self::parse(&path, &contents)?,
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound && !required => RawConfig::default(),
+			Err(err) => return Err(ConfigError::Read(path, err)),
+		};
+		raw.apply_env()?;
+		raw.validate()
+	}
+
+	fn parse(path: &Path, contents: &str) -> Result<RawConfig, ConfigError> {
+		toml::from_str(contents).map_err(|err| ConfigError::Parse(path.to_owned(), err))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::{Config, ConfigError};
+	use std::path::Path;
+
+	#[test]
+	fn test_parse_config() {
+		let token_file = std::env::temp_dir().join(format!("nyanpasswd-test-token-{}", std::process::id()));
+		std::fs::write(&token_file, "0123456789abcdef0123\n").unwrap();
+		let config = Config::parse(
+			Path::new("config.toml"),
+			&r#"
+			[site]
+			company_name = "Example Inc."
+
+			[database]
+			url = "postgres://localhost/mail"
+			max_connections = 10
+
+			[http]
+			listen = "[::1]:8080"
+
+			[admin]
+			uids = ["vsh"]
+
+			[mail]
+			domains = ["nyantec.com", "Example.ORG"]
+
+			[certificates]
+			proxy_format = "HAProxy"
+			trusted_proxies = ["10.0.0.0/8", "fd00::/8"]
+			proxy_secret = "hunter2"
+			username_attribute = "emailAddress"
+			username_rewrites = [{ pattern = "@nyantec\\.com$", replacement = "" }]
+
+			[api]
+			tcp = false
+			socket = "/run/nyanpasswd/api.sock"
+			socket_mode = "600"
+			socket_peers = ["user:0=authenticate"]
+
+			[[api.consumers]]
+			name = "dovecot2"
+			token_file = "{token_file}"
+			permissions = ["authenticate", "lookup_users"]
+
+			[tls]
+			certificate = "/etc/nyanpasswd/cert.pem"
+			key = "/etc/nyanpasswd/key.pem"
+			client_ca = "/etc/nyanpasswd/ca.pem"
+
+			[passkeys]
+			enable = true
+			origin = "https://mail.nyantec.com"
+			rp_id = "nyantec.com"
+
+			[oidc]
+			enable = true
+			issuer = "https://id.nyantec.com/realms/nyantec"
+			client_id = "nyanpasswd"
+			client_secret = "hunter3"
+			origin = "https://mail.nyantec.com"
+			username_claim = "uid"
+
+			[reconcile]
+			file = "/etc/nyanpasswd/managed.toml"
+			"#
+			.replace("{token_file}", token_file.to_str().unwrap()),
+		)
+		.unwrap()
+		.validate()
+		.unwrap();
+
+		assert_eq!(config.site.company_name, "Example Inc.");
+		assert_eq!(config.site.impressum, "https://nyantec.com/impressum/");
+		assert_eq!(config.database.max_connections, 10);
+		assert_eq!(config.http.listen.to_string(), "[::1]:8080");
+		assert_eq!(config.admin.uids, ["vsh"]);
+		assert_eq!(config.mail.domains, ["nyantec.com", "Example.ORG"]);
+		assert_eq!(config.certificates.proxy_format, crate::axum::ProxyHeaderFormat::Haproxy);
+		assert_eq!(config.certificates.trusted_proxies.networks[1].to_string(), "fd00::/8");
+		assert_eq!(config.certificates.trusted_proxies.secret.as_deref(), Some("hunter2"));
+		assert!(!format!("{:?}", config.certificates).contains("hunter2"));
+		assert_eq!(config.certificates.identity.source.to_string(), "emailAddress");
+		assert_eq!(config.certificates.identity.rewrites[0].pattern.as_str(), r"@nyantec\.com$");
+		assert!(!config.api.tcp);
+		let socket = config.api.socket.unwrap();
+		assert_eq!(socket.mode, 0o600);
+		assert_eq!(socket.peers.len(), 1);
+		let consumer = &config.api.consumers[0];
+		assert_eq!(consumer.name, "dovecot2");
+		assert_eq!(consumer.token, "0123456789abcdef0123");
+		assert_eq!(consumer.permissions, [crate::ApiPermission::Authenticate, crate::ApiPermission::LookupUsers]);
+		assert!(!format!("{:?}", config.api.consumers).contains("0123456789"));
+		std::fs::remove_file(token_file).unwrap();
+		let tls = config.tls.unwrap();
+		assert_eq!(tls.client_ca.to_str(), Some("/etc/nyanpasswd/ca.pem"));
+		assert!(tls.client_crl.is_none());
+		let passkeys = config.passkeys.unwrap();
+		assert_eq!(passkeys.relying_party.id, "nyantec.com");
+		assert_eq!(passkeys.relying_party.name, "Example Inc.");
+		assert_eq!(passkeys.session_lifetime, chrono::Duration::hours(12));
+		assert!(!passkeys.admin);
+		let oidc = config.oidc.unwrap();
+		assert_eq!(oidc.settings.redirect_uri, "https://mail.nyantec.com/oidc/callback");
+		assert_eq!(oidc.settings.username_claim, "uid");
+		assert_eq!(oidc.settings.scopes, ["profile"]);
+		assert_eq!(oidc.provider_name, "single sign-on");
+		assert!(!format!("{:?}", oidc).contains("hunter3"));
+		let reconcile = config.reconcile.unwrap();
+		assert_eq!(reconcile.file.to_str(), Some("/etc/nyanpasswd/managed.toml"));
+		assert!(reconcile.on_startup);
+		assert!(config.dev.is_none());
+		// The database URL may contain a password
+		assert!(!format!("{:?}", config.database).contains("postgres://"));
+	}
+
+	#[test]
+	fn test_invalid_config() {
+		let parse = |contents| Config::parse(Path::new("config.toml"), contents).and_then(|raw| raw.validate());
+
+		assert!(matches!(parse("[database]\nurl = 5"), Err(ConfigError::Parse(..))));
+		assert!(matches!(parse("[databse]\nurl = \"postgres://\""), Err(ConfigError::Parse(..))));
+		assert!(matches!(parse(""), Err(ConfigError::Invalid("database.url", _))));
+		assert!(matches!(
+			parse("[database]\nurl = \"postgres://\"\n[http]\nlisten = \"localhost\""),
+			Err(ConfigError::Invalid("http.listen", _))
+		));
+		assert!(matches!(
+			parse("[database]\nurl = \"postgres://\"\n[certificates]\nproxy_format = \"apache\""),
+			Err(ConfigError::Invalid("certificates.proxy_format", _))
+		));
+		assert!(matches!(
+			parse("[database]\nurl = \"postgres://\"\n[certificates]\ntrusted_proxies = [\"10.0.0.0/40\"]"),
+			Err(ConfigError::Invalid("certificates.trusted_proxies", _))
+		));
+		assert!(matches!(
+			parse("[database]\nurl = \"postgres://\"\n[certificates]\ntrusted_proxies = []"),
+			Err(ConfigError::Invalid("certificates.trusted_proxies", _))
+		));
+		assert!(matches!(
+			parse("[database]\nurl = \"postgres://\"\n[reconcile]\non_startup = true"),
+			Err(ConfigError::Invalid("reconcile.file", _))
+		));
+		assert!(matches!(
+			parse("[database]\nurl = \"postgres://\"\n[passkeys]\nenable = true"),
+			Err(ConfigError::Invalid("passkeys.origin", _))
+		));
+		assert!(matches!(
+			parse("[database]\nurl = \"postgres://\"\n[passkeys]\nenable = true\norigin = \"http://mail.nyantec.com\""),
+			Err(ConfigError::Invalid("passkeys.origin", _))
+		));
+		assert!(matches!(
+			parse("[database]\nurl = \"postgres://\"\n[passkeys]\nenable = true\norigin = \"https://mail.nyantec.com\"\nrp_id = \"tec.com\""),
+			Err(ConfigError::Invalid("passkeys.rp_id", _))
+		));
+		assert!(matches!(
+			parse("[database]\nurl = \"postgres://\"\n[dev]\nenable = true"),
+			Err(ConfigError::Invalid("dev.user", _))
+		));
+		assert!(matches!(
+			parse("[database]\nurl = \"postgres://\"\n[certificates]\nusername_attribute = \"SAN:email\"\n[dev]\nenable = true\nuser = \"vsh\""),
+			Err(ConfigError::Invalid("dev.enable", _))
+		));
+		assert!(matches!(
+			parse("[database]\nurl = \"postgres://\"\n[certificates]\nusername_attribute = \"SAN:ip\""),
+			Err(ConfigError::Invalid("certificates.username_attribute", _))
+		));
+		assert!(matches!(
+			parse(
+				"[database]\nurl = \"postgres://\"\n[[certificates.username_rewrites]]\npattern = \"(\"\nreplacement = \"\""
+			),
+			Err(ConfigError::Invalid("certificates.username_rewrites", _))
+		));
+		assert!(matches!(
+			parse("[database]\nurl = \"postgres://\"\n[mail]\ndomains = [\"vsh@nyantec.com\"]"),
+			Err(ConfigError::Invalid("mail.domains", _))
+		));
+		assert!(matches!(
+			parse("[database]\nurl = \"postgres://\"\n[api]\ntcp = false"),
+			Err(ConfigError::Invalid("api.tcp", _))
+		));
+		assert!(parse("[database]\nurl = \"postgres://\"\n[api]\nsocket = \"/tmp/sock\"").unwrap().api.tcp);
+		assert!(matches!(
+			parse("[database]\nurl = \"postgres://\"\n[api]\nsocket_mode = \"600\""),
+			Err(ConfigError::Invalid("api.socket", _))
+		));
+		assert!(matches!(
+			parse("[database]\nurl = \"postgres://\"\n[api]\nsocket = \"/tmp/sock\"\nsocket_mode = \"rw\""),
+			Err(ConfigError::Invalid("api.socket_mode", _))
+		));
+		assert!(matches!(
+			parse("[database]\nurl = \"postgres://\"\n[[api.consumers]]\nname = \"dovecot2\"\ntoken_file = \"/nonexistent\"\npermissions = []"),
+			Err(ConfigError::Read(..))
+		));
+		assert!(matches!(
+			parse("[database]\nurl = \"postgres://\"\n[[api.consumers]]\nname = \"dovecot2\"\ntoken_file = \"/dev/null\"\npermissions = []"),
+			Err(ConfigError::Invalid("api.consumers", _))
+		));
+		assert!(matches!(
+			parse("[database]\nurl = \"postgres://\"\n[tls]\ncertificate = \"cert.pem\""),
+			Err(ConfigError::Invalid("tls", _))
+		));
+		assert!(matches!(
+			parse("[database]\nurl = \"postgres://\"\n[oidc]\nenable = true\nclient_id = \"nyanpasswd\"\norigin = \"https://mail.nyantec.com\""),
+			Err(ConfigError::Invalid("oidc.issuer", _))
+		));
+		assert!(matches!(
+			parse("[database]\nurl = \"postgres://\"\n[oidc]\nenable = true\nclient_id = \"nyanpasswd\"\norigin = \"https://mail.nyantec.com\"\nissuer = \"http://id.nyantec.com\""),
+			Err(ConfigError::Invalid("oidc.issuer", _))
+		));
+		assert!(parse("[database]\nurl = \"postgres://\"\n[oidc]\nenable = true\nclient_id = \"nyanpasswd\"\norigin = \"https://mail.nyantec.com\"\nissuer = \"http://localhost:8080/realms/test\"").is_ok());
+		assert!(matches!(
+			parse("[database]\nurl = \"postgres://\"\n[oidc]\nenable = true\nclient_id = \"nyanpasswd\"\norigin = \"https://mail.nyantec.com\"\nissuer = \"https://id.nyantec.com\"\nclient_secret = \"a\"\nclient_secret_file = \"b\""),
+			Err(ConfigError::Invalid("oidc", _))
+		));
+	}
+}
@@ -0,0 +1,286 @@
+/*
+  Copyright © 2022 nyantec GmbH <oss@nyantec.com>
+  Written by Vika Shleina <vsh@nyantec.com>
+  
+  Provided that these terms and disclaimer and all copyright notices
+  are retained or reproduced in an accompanying document, permission
+  is granted to deal in this work without restriction, including un‐
+  limited rights to use, publicly perform, distribute, sell, modify,
+  merge, give away, or sublicence.
+  
+  This work is provided "AS IS" and WITHOUT WARRANTY of any kind, to
+  the utmost extent permitted by applicable law, neither express nor
+  implied; without malicious intent or gross negligence. In no event
+  may a licensor, author or contributor be held liable for indirect,
+  direct, other damage, loss, or other issues arising in any way out
+  of dealing in the work, even if advised of the possibility of such
+  damage or existence of a defect, except proven that it results out
+  of said person's immediate fault when using the work as intended.
+ */
+//! Protection against cross-site request forgery for the dashboard.
+//!
+//! Client certificates are sent with every request, including ones other sites make the browser
+//! send, so every form carries a token that only our own pages know. Tokens are bound to the
+//! client certificate and signed with a key that's generated at startup, so they don't need to be
+//! stored anywhere.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::body::Body;
+use axum::extract::FromRequestParts;
+use axum::http::{header, request::Parts, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use hmac::{Hmac, Mac};
+use nyanpasswd::axum::{session_token, CertDn};
+use once_cell::sync::OnceCell;
+use rand::RngCore;
+
+/// The form field carrying the token.
+pub const FIELD: &str = "csrf_token";
+/// The header carrying the token, for requests that aren't form submissions.
+pub const HEADER: &str = "X-CSRF-Token";
+/// How long a page may be left open before its forms stop working.
+const MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+/// The most we buffer to look for the token, the same as axum's default body limit.
+const BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+static KEY: OnceCell<[u8; 32]> = OnceCell::new();
+
+fn keyed_mac(message: &str) -> Hmac<sha2::Sha256> {
+	let key = KEY.get_or_init(|| {
+		let mut key = [0; 32];
+		rand::thread_rng().fill_bytes(&mut key);
+		key
+	});
+	let mut mac = Hmac::<sha2::Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+	mac.update(message.as_bytes());
+	mac
+}
+
+fn mac(timestamp: u64, nonce: &str, identity: &str) -> Hmac<sha2::Sha256> {
+	keyed_mac(&format!("{}.{}.{}", timestamp, nonce, identity))
+}
+
+/// Sign `message` with the key CSRF tokens are signed with, for other state handed to clients.
+///
+/// Messages must not start with a digit, so they can't be mistaken for CSRF tokens.
+pub(crate) fn sign(message: &str) -> String {
+	hex(&keyed_mac(message).finalize().into_bytes())
+}
+
+/// Check a signature made by [`sign`].
+pub(crate) fn verify_signature(message: &str, signature: &str) -> bool {
+	unhex(signature).map_or(false, |signature| keyed_mac(message).verify_slice(&signature).is_ok())
+}
+
+fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+	if hex.len() % 2 != 0 {
+		return None;
+	}
+	(0..hex.len())
+		.step_by(2)
+		.map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+		.collect()
+}
+
+/// Make a token for forms submitted with the client certificate `identity`.
+fn generate(identity: &str, now: u64) -> String {
+	let mut nonce = [0; 16];
+	rand::thread_rng().fill_bytes(&mut nonce);
+	let nonce = hex(&nonce);
+	let mac = hex(&mac(now, &nonce, identity).finalize().into_bytes());
+	format!("{}.{}.{}", now, nonce, mac)
+}
+
+fn verify(token: &str, identity: &str, now: u64) -> bool {
+	let mut parts = token.splitn(3, '.');
+	let (Some(timestamp), Some(nonce), Some(signature)) = (parts.next(), parts.next(), parts.next()) else {
+		return false;
+	};
+	let (Ok(timestamp), Some(signature)) = (timestamp.parse::<u64>(), unhex(signature)) else {
+		return false;
+	};
+	// Tokens from the future are as suspicious as old ones
+	if timestamp > now || now - timestamp > MAX_AGE.as_secs() {
+		return false;
+	}
+	mac(timestamp, nonce, identity).verify_slice(&signature).is_ok()
+}
+
+pub(crate) fn now() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// The identity tokens are bound to: the client certificate, or the session of a user who logged
+/// in with a passkey. Other requests are rejected by the handlers anyway, except for logging in,
+/// so they all share one.
+async fn identity(parts: &mut Parts) -> String {
+	match CertDn::from_request_parts(parts, &()).await {
+		Ok(cert_dn) => format!("certificate:{}", cert_dn.as_str()),
+		Err(_) => match session_token(&parts.headers) {
+			Some(token) => format!("session:{}", token),
+			None => String::new(),
+		},
+	}
+}
+
+/// A fresh token, to be put into the [`FIELD`] field of every form on a page.
+pub struct CsrfToken(pub String);
+
+#[async_trait::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for CsrfToken {
+	type Rejection = std::convert::Infallible;
+
+	async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+		Ok(CsrfToken(generate(&identity(parts).await, now())))
+	}
+}
+
+fn forbidden(message: &'static str) -> Response {
+	(StatusCode::FORBIDDEN, [(header::CONTENT_TYPE, "text/plain")], message).into_response()
+}
+
+/// Check that the `Origin` (or, failing that, `Referer`) of a request is this site.
+///
+/// Browsers send at least one of them with cross-site form submissions, unless told not to by
+/// the other site. In that case, the token still protects us.
+fn same_origin(parts: &Parts) -> bool {
+	if parts
+		.headers
+		.get("Sec-Fetch-Site")
+		.map_or(false, |site| site == "cross-site" || site == "same-site")
+	{
+		return false;
+	}
+	// Reverse proxies don't necessarily pass on the `Host` they were asked for. Other sites can't
+	// make browsers send either header, so both can be relied on.
+	let Some(host) = parts
+		.headers
+		.get("X-Forwarded-Host")
+		.or_else(|| parts.headers.get(header::HOST))
+		.and_then(|host| host.to_str().ok())
+		.map(|host| host.split(',').next().unwrap_or_default().trim())
+	else {
+		return true;
+	};
+	let origin = match (parts.headers.get(header::ORIGIN), parts.headers.get(header::REFERER)) {
+		(Some(origin), _) => origin,
+		(None, Some(referer)) => referer,
+		(None, None) => return true,
+	};
+	let Ok(origin) = origin.to_str() else {
+		return false;
+	};
+	// `null` is sent for opaque origins, like sandboxed frames
+	let Some((_scheme, rest)) = origin.split_once("://") else {
+		return false;
+	};
+	let origin_host = rest.split('/').next().unwrap_or_default();
+	origin_host.eq_ignore_ascii_case(host)
+}
+
+/// Buffer a request body of at most `limit` bytes.
+async fn read_body(body: Body, limit: usize) -> Result<hyper::body::Bytes, StatusCode> {
+	hyper::body::to_bytes(http_body::Limited::new(body, limit)).await.map_err(|err| {
+		if err.is::<http_body::LengthLimitError>() {
+			StatusCode::PAYLOAD_TOO_LARGE
+		} else {
+			StatusCode::BAD_REQUEST
+		}
+	})
+}
+
+/// Reject state-changing requests that don't come from one of our own forms.
+pub async fn protect(request: Request<Body>, next: Next<Body>) -> Response {
+	if matches!(*request.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+		return next.run(request).await;
+	}
+	let (mut parts, body) = request.into_parts();
+	if !same_origin(&parts) {
+		tracing::warn!("Rejecting cross-origin {} {}", parts.method, parts.uri);
+		return forbidden("Cross-origin requests are not allowed");
+	}
+	let body = match read_body(body, BODY_LIMIT).await {
+		Ok(body) => body,
+		Err(status) => return status.into_response(),
+	};
+	let token = match parts.headers.get(HEADER).and_then(|token| token.to_str().ok()) {
+		Some(token) => Some(token.to_owned()),
+		None => form_urlencoded::parse(&body)
+			.find(|(name, _)| name == FIELD)
+			.map(|(_, value)| value.into_owned()),
+	};
+	let identity = identity(&mut parts).await;
+	match token {
+		Some(token) if verify(&token, &identity, now()) => {}
+		_ => {
+			tracing::warn!("Rejecting {} {} without a valid CSRF token", parts.method, parts.uri);
+			return forbidden("The form has expired or was not submitted from this site. Please reload the page and try again.");
+		}
+	}
+
+	next.run(Request::from_parts(parts, Body::from(body))).await
+}
+
+#[cfg(test)]
+mod test {
+	use super::{generate, read_body, same_origin, verify, MAX_AGE};
+	use axum::http::{Request, StatusCode};
+	use axum::body::Body;
+
+	#[test]
+	fn test_tokens() {
+		let now = 1_700_000_000;
+		let token = generate("UID=vsh", now);
+		assert!(verify(&token, "UID=vsh", now));
+		assert!(verify(&token, "UID=vsh", now + 60));
+		// Bound to the client certificate
+		assert!(!verify(&token, "UID=mvs", now));
+		// Expiring
+		assert!(!verify(&token, "UID=vsh", now + MAX_AGE.as_secs() + 1));
+		assert!(!verify(&token, "UID=vsh", now - 1));
+		// Tokens are different every time
+		assert_ne!(token, generate("UID=vsh", now));
+
+		let (timestamp, rest) = token.split_once('.').unwrap();
+		let later = format!("{}.{}", timestamp.parse::<u64>().unwrap() + 1, rest);
+		assert!(!verify(&later, "UID=vsh", now + 1));
+		assert!(!verify("", "UID=vsh", now));
+		assert!(!verify("1.2.3", "UID=vsh", now));
+		assert!(!verify(&format!("{}x", token), "UID=vsh", now));
+	}
+	#[tokio::test]
+	async fn test_read_body() {
+		assert_eq!(read_body(Body::from("csrf_token=1.2.3"), 16).await.unwrap(), "csrf_token=1.2.3");
+		assert_eq!(read_body(Body::from("csrf_token=1.2.34"), 16).await.unwrap_err(), StatusCode::PAYLOAD_TOO_LARGE);
+	}
+	#[test]
+	fn test_same_origin() {
+		let check = |headers: &[(&str, &str)]| {
+			let mut request = Request::post("/create_password");
+			for (name, value) in headers {
+				request = request.header(*name, *value);
+			}
+			same_origin(&request.body(()).unwrap().into_parts().0)
+		};
+		assert!(check(&[("Host", "mail.nyantec.com")]));
+		assert!(check(&[("Host", "mail.nyantec.com"), ("Origin", "https://mail.nyantec.com")]));
+		assert!(check(&[("Host", "mail.nyantec.com"), ("Referer", "https://mail.nyantec.com/admin/")]));
+		assert!(check(&[("Host", "mail.nyantec.com"), ("Sec-Fetch-Site", "same-origin")]));
+		assert!(check(&[
+			("Host", "127.0.0.1:3000"),
+			("X-Forwarded-Host", "mail.nyantec.com"),
+			("Origin", "https://mail.nyantec.com")
+		]));
+		assert!(!check(&[("Host", "127.0.0.1:3000"), ("Origin", "https://mail.nyantec.com")]));
+		assert!(!check(&[("Host", "mail.nyantec.com"), ("Origin", "https://evil.example")]));
+		assert!(!check(&[("Host", "mail.nyantec.com"), ("Origin", "null")]));
+		assert!(!check(&[("Host", "mail.nyantec.com"), ("Referer", "https://mail.nyantec.com.evil.example/")]));
+		assert!(!check(&[("Host", "mail.nyantec.com"), ("Sec-Fetch-Site", "cross-site")]));
+		assert!(!check(&[("Host", "mail.nyantec.com"), ("Sec-Fetch-Site", "same-site")]));
+	}
+}
@@ -0,0 +1,446 @@
+/*
+  Copyright © 2022 nyantec GmbH <oss@nyantec.com>
+  Written by Vika Shleina <vsh@nyantec.com>
+  
+  Provided that these terms and disclaimer and all copyright notices
+  are retained or reproduced in an accompanying document, permission
+  is granted to deal in this work without restriction, including un‐
+  limited rights to use, publicly perform, distribute, sell, modify,
+  merge, give away, or sublicence.
+  
+  This work is provided "AS IS" and WITHOUT WARRANTY of any kind, to
+  the utmost extent permitted by applicable law, neither express nor
+  implied; without malicious intent or gross negligence. In no event
+  may a licensor, author or contributor be held liable for indirect,
+  direct, other damage, loss, or other issues arising in any way out
+  of dealing in the work, even if advised of the possibility of such
+  damage or existence of a defect, except proven that it results out
+  of said person's immediate fault when using the work as intended.
+ */
+//! Distinguished names, as defined in [RFC 4514](https://www.rfc-editor.org/rfc/rfc4514).
+use std::fmt::Write;
+
+/// Short names of well-known attribute types, with their aliases and OIDs.
+const ATTRIBUTE_TYPES: &[(&str, &[&str], &str)] = &[
+	("CN", &["commonName"], "2.5.4.3"),
+	("SN", &["surname"], "2.5.4.4"),
+	("serialNumber", &[], "2.5.4.5"),
+	("C", &["countryName"], "2.5.4.6"),
+	("L", &["localityName"], "2.5.4.7"),
+	("ST", &["stateOrProvinceName", "S"], "2.5.4.8"),
+	("STREET", &["streetAddress"], "2.5.4.9"),
+	("O", &["organizationName"], "2.5.4.10"),
+	("OU", &["organizationalUnitName"], "2.5.4.11"),
+	("title", &[], "2.5.4.12"),
+	("GN", &["givenName", "G"], "2.5.4.42"),
+	("pseudonym", &[], "2.5.4.65"),
+	("UID", &["userid"], "0.9.2342.19200300.100.1.1"),
+	("DC", &["domainComponent"], "0.9.2342.19200300.100.1.25"),
+	("emailAddress", &["E", "email"], "1.2.840.113549.1.9.1"),
+];
+
+/// Find the canonical short name of an attribute type given by name, alias or OID.
+///
+/// Unknown attribute types are returned as they are.
+pub fn canonical_attribute_type(name: &str) -> &str {
+	let oid = name
+		.strip_prefix("OID.")
+		.or_else(|| name.strip_prefix("oid."))
+		.unwrap_or(name);
+	ATTRIBUTE_TYPES
+		.iter()
+		.find(|(short, aliases, known_oid)| {
+			short.eq_ignore_ascii_case(name)
+				|| aliases.iter().any(|alias| alias.eq_ignore_ascii_case(name))
+				|| *known_oid == oid
+		})
+		.map(|(short, _, _)| *short)
+		.unwrap_or(name)
+}
+
+/// A single `type=value` pair of a distinguished name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attribute {
+	/// The attribute type, using the short name if it is a well-known one (e.g. `CN`).
+	pub name: String,
+	pub value: String,
+}
+
+/// A parsed distinguished name.
+///
+/// Relative distinguished names are kept in the order they appear in the string, which, per
+/// RFC 4514, is the reverse of the order in the certificate: the most specific one comes first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DistinguishedName(pub Vec<Vec<Attribute>>);
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum DnParseError {
+	#[error("expected `=` after attribute type at offset {0}")]
+	ExpectedEquals(usize),
+	#[error("invalid attribute type at offset {0}")]
+	InvalidAttributeType(usize),
+	#[error("invalid escape sequence at offset {0}")]
+	InvalidEscape(usize),
+	#[error("invalid hex-encoded value at offset {0}")]
+	InvalidHexValue(usize),
+	#[error("unterminated quoted value starting at offset {0}")]
+	UnterminatedQuote(usize),
+	#[error("unexpected `{1}` at offset {0}")]
+	UnexpectedCharacter(usize, char),
+	#[error("value is not valid UTF-8")]
+	InvalidUtf8,
+}
+
+struct Parser<'a> {
+	input: &'a str,
+	pos: usize,
+}
+
+impl Parser<'_> {
+	fn peek(&self) -> Option<char> {
+		self.input[self.pos..].chars().next()
+	}
+
+	fn bump(&mut self) -> Option<char> {
+		let c = self.peek()?;
+		self.pos += c.len_utf8();
+		Some(c)
+	}
+
+	fn skip_spaces(&mut self) {
+		while self.peek() == Some(' ') {
+			self.pos += 1;
+		}
+	}
+
+	fn hex_pair(&mut self) -> Option<u8> {
+		let pair = self.input.get(self.pos..self.pos + 2)?;
+		// `from_str_radix` alone would accept a leading `+`
+		if !pair.bytes().all(|b| b.is_ascii_hexdigit()) {
+			return None;
+		}
+		self.pos += 2;
+		u8::from_str_radix(pair, 16).ok()
+	}
+
+	fn attribute_type(&mut self) -> Result<String, DnParseError> {
+		let start = self.pos;
+		while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '-' || c == '.') {
+			self.pos += 1;
+		}
+		let name = &self.input[start..self.pos];
+		if name.is_empty() {
+			return Err(DnParseError::InvalidAttributeType(start));
+		}
+		Ok(canonical_attribute_type(name).to_owned())
+	}
+
+	fn attribute_value(&mut self) -> Result<String, DnParseError> {
+		match self.peek() {
+			Some('#') => self.hex_value(),
+			Some('"') => self.quoted_value(),
+			_ => self.plain_value(),
+		}
+	}
+
+	/// A BER-encoded value, e.g. `#0c03767368`.
+	fn hex_value(&mut self) -> Result<String, DnParseError> {
+		let start = self.pos;
+		self.pos += 1;
+		let mut bytes = vec![];
+		while matches!(self.peek(), Some(c) if c.is_ascii_hexdigit()) {
+			bytes.push(self.hex_pair().ok_or(DnParseError::InvalidHexValue(start))?);
+		}
+		decode_ber_string(&bytes).ok_or(DnParseError::InvalidHexValue(start))
+	}
+
+	/// A value in double quotes, as allowed by RFC 1779.
+	fn quoted_value(&mut self) -> Result<String, DnParseError> {
+		let start = self.pos;
+		self.pos += 1;
+		let mut value = vec![];
+		loop {
+			match self.bump() {
+				Some('"') => return String::from_utf8(value).map_err(|_| DnParseError::InvalidUtf8),
+				Some('\\') => self.escape(&mut value)?,
+				Some(c) => value.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+				None => return Err(DnParseError::UnterminatedQuote(start)),
+			}
+		}
+	}
+
+	fn plain_value(&mut self) -> Result<String, DnParseError> {
+		let mut value = vec![];
+		// Unescaped spaces at the end of a value aren't part of it
+		let mut significant_len = 0;
+		while let Some(c) = self.peek() {
+			match c {
+				',' | '+' | ';' => break,
+				'\\' => {
+					self.pos += 1;
+					self.escape(&mut value)?;
+					significant_len = value.len();
+				}
+				_ => {
+					self.pos += c.len_utf8();
+					value.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+					if c != ' ' {
+						significant_len = value.len();
+					}
+				}
+			}
+		}
+		value.truncate(significant_len);
+		String::from_utf8(value).map_err(|_| DnParseError::InvalidUtf8)
+	}
+
+	/// Handle the rest of an escape sequence, after the backslash.
+	fn escape(&mut self, value: &mut Vec<u8>) -> Result<(), DnParseError> {
+		let start = self.pos - 1;
+		if let Some(byte) = self.hex_pair() {
+			value.push(byte);
+			return Ok(());
+		}
+		match self.bump() {
+			Some(c) => {
+				value.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+				Ok(())
+			}
+			None => Err(DnParseError::InvalidEscape(start)),
+		}
+	}
+}
+
+/// Decode a BER-encoded string type. Anything else is rejected.
+fn decode_ber_string(bytes: &[u8]) -> Option<String> {
+	const UTF8_STRING: u8 = 0x0c;
+	const PRINTABLE_STRING: u8 = 0x13;
+	const T61_STRING: u8 = 0x14;
+	const IA5_STRING: u8 = 0x16;
+	const VISIBLE_STRING: u8 = 0x1a;
+
+	let (&tag, rest) = bytes.split_first()?;
+	if ![UTF8_STRING, PRINTABLE_STRING, T61_STRING, IA5_STRING, VISIBLE_STRING].contains(&tag) {
+		return None;
+	}
+	let (&len, rest) = rest.split_first()?;
+	let (len, rest) = if len < 0x80 {
+		(len as usize, rest)
+	} else {
+		let octets = (len & 0x7f) as usize;
+		if octets == 0 || octets > std::mem::size_of::<usize>() || rest.len() < octets {
+			return None;
+		}
+		let (len, rest) = rest.split_at(octets);
+		(len.iter().fold(0usize, |acc, b| (acc << 8) | *b as usize), rest)
+	};
+	if rest.len() != len {
+		return None;
+	}
+	String::from_utf8(rest.to_vec()).ok()
+}
+
+impl std::str::FromStr for DistinguishedName {
+	type Err = DnParseError;
+
+	/// Parse a DN in the RFC 4514 format, e.g. `UID=vsh,CN=Vika Shleina,O=nyantec GmbH`.
+	///
+	/// For compatibility with older formats, spaces around separators, `;` as a separator and
+	/// quoted values are accepted too, e.g. `O = nyantec GmbH, CN = "Shleina, Vika"`.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut parser = Parser { input: s, pos: 0 };
+		let mut rdns = vec![];
+		parser.skip_spaces();
+		if parser.peek().is_none() {
+			return Ok(Self(rdns));
+		}
+		let mut rdn = vec![];
+		loop {
+			parser.skip_spaces();
+			let name = parser.attribute_type()?;
+			parser.skip_spaces();
+			if parser.bump() != Some('=') {
+				return Err(DnParseError::ExpectedEquals(parser.pos));
+			}
+			parser.skip_spaces();
+			let value = parser.attribute_value()?;
+			rdn.push(Attribute { name, value });
+			parser.skip_spaces();
+			match parser.bump() {
+				Some('+') => {}
+				Some(',' | ';') => rdns.push(std::mem::take(&mut rdn)),
+				None => {
+					rdns.push(rdn);
+					return Ok(Self(rdns));
+				}
+				Some(c) => return Err(DnParseError::UnexpectedCharacter(parser.pos - c.len_utf8(), c)),
+			}
+		}
+	}
+}
+
+impl std::fmt::Display for DistinguishedName {
+	/// Format the DN according to RFC 4514, escaping special characters.
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		for (i, rdn) in self.0.iter().enumerate() {
+			if i > 0 {
+				f.write_char(',')?;
+			}
+			for (j, attribute) in rdn.iter().enumerate() {
+				if j > 0 {
+					f.write_char('+')?;
+				}
+				write!(f, "{}=", attribute.name)?;
+				let last = attribute.value.chars().count().saturating_sub(1);
+				for (k, c) in attribute.value.chars().enumerate() {
+					match c {
+						'"' | '+' | ',' | ';' | '<' | '>' | '\\' => write!(f, "\\{}", c)?,
+						'#' if k == 0 => f.write_str("\\#")?,
+						' ' if k == 0 || k == last => f.write_str("\\ ")?,
+						'\0' => f.write_str("\\00")?,
+						c => f.write_char(c)?,
+					}
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+impl DistinguishedName {
+	/// Build a DN from the subject or issuer of a certificate.
+	///
+	/// Returns `None` if an attribute value isn't a string.
+	pub fn from_x509(name: &x509_parser::x509::X509Name<'_>) -> Option<Self> {
+		let mut rdns = name
+			.iter()
+			.map(|rdn| {
+				rdn.iter()
+					.map(|attribute| {
+						Some(Attribute {
+							name: canonical_attribute_type(&attribute.attr_type().to_id_string()).to_owned(),
+							value: attribute.as_str().ok()?.to_owned(),
+						})
+					})
+					.collect::<Option<Vec<_>>>()
+			})
+			.collect::<Option<Vec<_>>>()?;
+		// Certificates list the most general RDN first, RFC 4514 strings the most specific one
+		rdns.reverse();
+		Some(Self(rdns))
+	}
+
+	/// Iterate over all values of an attribute type, given by name, alias or OID.
+	pub fn values<'a>(&'a self, name: &str) -> impl Iterator<Item = &'a str> + 'a {
+		let name = canonical_attribute_type(name).to_owned();
+		self.0
+			.iter()
+			.flatten()
+			.filter(move |attribute| attribute.name.eq_ignore_ascii_case(&name))
+			.map(|attribute| attribute.value.as_str())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::{Attribute, DistinguishedName, DnParseError};
+
+	fn attr(name: &str, value: &str) -> Attribute {
+		Attribute {
+			name: name.to_owned(),
+			value: value.to_owned(),
+		}
+	}
+
+	#[test]
+	fn test_parse_rfc4514() {
+		let dn: DistinguishedName = "UID=vsh,CN=Vika Shleina,O=nyantec GmbH".parse().unwrap();
+		assert_eq!(
+			dn,
+			DistinguishedName(vec![
+				vec![attr("UID", "vsh")],
+				vec![attr("CN", "Vika Shleina")],
+				vec![attr("O", "nyantec GmbH")],
+			])
+		);
+		assert_eq!(dn.values("uid").collect::<Vec<_>>(), ["vsh"]);
+		assert_eq!(dn.values("0.9.2342.19200300.100.1.1").collect::<Vec<_>>(), ["vsh"]);
+		assert_eq!(dn.values("userid").collect::<Vec<_>>(), ["vsh"]);
+		assert!("".parse::<DistinguishedName>().unwrap().0.is_empty());
+	}
+
+	#[test]
+	fn test_parse_openssl_oneline() {
+		let dn: DistinguishedName =
+			"O = nyantec GmbH, CN = Vika Shleina, GN = Viktoriya, SN = Shleina, pseudonym = Vika, UID = vsh"
+				.parse()
+				.unwrap();
+		assert_eq!(dn.0.len(), 6);
+		assert_eq!(dn.values("UID").collect::<Vec<_>>(), ["vsh"]);
+		assert_eq!(dn.values("givenName").collect::<Vec<_>>(), ["Viktoriya"]);
+	}
+
+	#[test]
+	fn test_parse_escapes() {
+		// An attribute value pretending to contain another attribute mustn't be mistaken for it
+		let dn: DistinguishedName = r"CN=Mallory\, UID=vsh,O=Evil\+Co,UID=mal".parse().unwrap();
+		assert_eq!(dn.values("CN").collect::<Vec<_>>(), ["Mallory, UID=vsh"]);
+		assert_eq!(dn.values("O").collect::<Vec<_>>(), ["Evil+Co"]);
+		assert_eq!(dn.values("UID").collect::<Vec<_>>(), ["mal"]);
+
+		let dn: DistinguishedName = r#"CN=\"quoted\" \<x\> \;\\,OU=\#hash,O=\ spaces\ "#.parse().unwrap();
+		assert_eq!(dn.values("CN").collect::<Vec<_>>(), [r#""quoted" <x> ;\"#]);
+		assert_eq!(dn.values("OU").collect::<Vec<_>>(), ["#hash"]);
+		assert_eq!(dn.values("O").collect::<Vec<_>>(), [" spaces "]);
+
+		// Hex escapes encode UTF-8 bytes
+		let dn: DistinguishedName = r"CN=Lu\C4\8Di\C4\87,O=caf\c3\a9".parse().unwrap();
+		assert_eq!(dn.values("CN").collect::<Vec<_>>(), ["Lučić"]);
+		assert_eq!(dn.values("O").collect::<Vec<_>>(), ["café"]);
+
+		// BER-encoded values (a UTF8String here)
+		let dn: DistinguishedName = "UID=#0c03767368".parse().unwrap();
+		assert_eq!(dn.values("UID").collect::<Vec<_>>(), ["vsh"]);
+
+		let dn: DistinguishedName = r#"CN="Shleina, Vika",O=nyantec"#.parse().unwrap();
+		assert_eq!(dn.values("CN").collect::<Vec<_>>(), ["Shleina, Vika"]);
+	}
+
+	#[test]
+	fn test_parse_multivalued() {
+		let dn: DistinguishedName = "CN=Vika+UID=vsh,OU=Staff+OU=Admins,O=nyantec GmbH".parse().unwrap();
+		assert_eq!(
+			dn,
+			DistinguishedName(vec![
+				vec![attr("CN", "Vika"), attr("UID", "vsh")],
+				vec![attr("OU", "Staff"), attr("OU", "Admins")],
+				vec![attr("O", "nyantec GmbH")],
+			])
+		);
+		assert_eq!(dn.values("OU").collect::<Vec<_>>(), ["Staff", "Admins"]);
+	}
+
+	#[test]
+	fn test_parse_errors() {
+		assert_eq!("CN".parse::<DistinguishedName>(), Err(DnParseError::ExpectedEquals(2)));
+		assert_eq!("=vsh".parse::<DistinguishedName>(), Err(DnParseError::InvalidAttributeType(0)));
+		assert_eq!(r"CN=vsh\".parse::<DistinguishedName>(), Err(DnParseError::InvalidEscape(6)));
+		assert_eq!(r#"CN="vsh"#.parse::<DistinguishedName>(), Err(DnParseError::UnterminatedQuote(3)));
+		assert_eq!(r#"CN="vsh" x"#.parse::<DistinguishedName>(), Err(DnParseError::UnexpectedCharacter(9, 'x')));
+		assert_eq!("CN=#0c0376".parse::<DistinguishedName>(), Err(DnParseError::InvalidHexValue(3)));
+		assert_eq!(r"CN=\ff".parse::<DistinguishedName>(), Err(DnParseError::InvalidUtf8));
+	}
+
+	#[test]
+	fn test_format_roundtrip() {
+		for value in ["Mallory, UID=vsh", " leading", "trailing ", "#hash", r#"a"b\c<d>e;f+g"#, "plain"] {
+			let dn = DistinguishedName(vec![vec![attr("CN", value), attr("UID", "vsh")], vec![attr("O", "nyantec")]]);
+			let formatted = dn.to_string();
+			assert_eq!(formatted.parse::<DistinguishedName>().unwrap(), dn, "{}", formatted);
+		}
+		assert_eq!(
+			DistinguishedName(vec![vec![attr("CN", "Shleina, Vika")], vec![attr("O", "nyantec")]]).to_string(),
+			r"CN=Shleina\, Vika,O=nyantec"
+		);
+	}
+}
@@ -29,14 +29,74 @@ mod util {
 	use rand::{CryptoRng, Rng};
 
 	const PASSWORD_LENGTH: usize = 64;
+	const ALIAS_NAME_LENGTH: usize = 16;
+	const ALIAS_NAME_CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
 
 	pub(super) fn gen_password<R: Rng + CryptoRng>(rng: &mut R) -> String {
 		// SAFETY: the Alphanumeric distribution only produces ASCII alphanumeric characters, which are valid UTF-8
 		unsafe { String::from_utf8_unchecked(rng.sample_iter(&Alphanumeric).take(PASSWORD_LENGTH).collect::<Vec<u8>>()) }
 	}
+
+	/// Generate a random alias name that is safe to use as a local part.
+	pub(super) fn gen_alias_name<R: Rng + CryptoRng>(rng: &mut R) -> String {
+		(0..ALIAS_NAME_LENGTH)
+			.map(|_| ALIAS_NAME_CHARSET[rng.gen_range(0..ALIAS_NAME_CHARSET.len())] as char)
+			.collect()
+	}
+
+	/// Hash an API token for storage. Tokens are long random strings, so unlike
+	/// passwords they don't need a slow, salted hash.
+	pub(super) fn hash_token(token: &str) -> String {
+		use sha2::Digest;
+		format!("{:x}", sha2::Sha256::digest(token.as_bytes()))
+	}
+
+	/// Split an address into its local part and its domain, if present.
+	///
+	/// Domains are case-insensitive, so they are normalized to lowercase.
+	pub(super) fn split_address(address: &str) -> (&str, Option<String>) {
+		match address.rsplit_once('@') {
+			Some((local_part, domain)) => (local_part, Some(domain.to_lowercase())),
+			None => (address, None),
+		}
+	}
+
+	/// Treat empty strings (e.g. from an unselected HTML form field) as a missing value.
+	pub(super) fn empty_string_as_none<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+	where
+		D: serde::Deserializer<'de>,
+	{
+		Ok(<Option<String> as serde::Deserialize>::deserialize(deserializer)?.filter(|s| !s.is_empty()))
+	}
+
+	/// For `#[serde(default = "crate::util::yes")]` on flags that default to `true`.
+	pub(super) fn yes() -> bool {
+		true
+	}
+
+	/// Join an alias name and its domain, if it's scoped to one, into an address for display.
+	pub(super) fn address(alias_name: &str, domain: Option<&str>) -> String {
+		match domain {
+			Some(domain) => format!("{}@{}", alias_name, domain),
+			None => alias_name.to_owned(),
+		}
+	}
+
+	/// Format an expiry date for display.
+	pub(super) fn format_date(date: Option<chrono::DateTime<chrono::FixedOffset>>) -> String {
+		date.map(|date| date.to_rfc3339()).unwrap_or_else(|| "never".to_owned())
+	}
 }
 
 pub mod axum;
+pub mod config;
+pub mod dn;
+pub mod oidc;
+pub mod reconcile;
+pub mod tls;
+pub mod transfer;
+pub mod unix;
+pub mod webauthn;
 
 #[derive(sqlx::FromRow, Debug)]
 pub struct Password {
@@ -47,20 +107,354 @@ pub struct Password {
 	pub expires_at: Option<chrono::DateTime<chrono::FixedOffset>>,
 }
 
-#[derive(sqlx::FromRow, Debug, serde::Serialize)]
+#[derive(sqlx::FromRow, Debug, serde::Serialize, utoipa::ToSchema)]
 pub struct User {
 	pub id: Uuid,
 	pub username: String,
 	pub login_allowed: bool,
 	pub created_at: chrono::DateTime<chrono::FixedOffset>,
 	pub expires_at: Option<chrono::DateTime<chrono::FixedOffset>>,
-	pub non_human: bool
+	pub non_human: bool,
+	/// Whether the user is managed by the reconciliation file, and can only be changed by reconciling.
+	pub managed: bool,
 }
 
 #[derive(sqlx::FromRow, Debug, serde::Serialize, serde::Deserialize)]
 pub struct Alias {
 	pub alias_name: String,
 	pub destination: Uuid,
+	/// Domain this alias is valid on. Unscoped aliases are valid on all domains.
+	#[serde(default, deserialize_with = "util::empty_string_as_none")]
+	pub domain: Option<String>,
+}
+
+/// Which users to list depending on their expiry date.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum UserStatus {
+	/// Users that haven't expired yet.
+	#[default]
+	Active,
+	/// Users past their expiry date.
+	Expired,
+	/// All users.
+	All,
+}
+
+/// Filters for listing users. Omitted filters match everything.
+///
+/// Results are ordered by username. To fetch the next page, pass the
+/// username of the last user on the current page as `after`.
+#[derive(Debug, Default, serde::Deserialize, utoipa::ToSchema)]
+#[serde(default)]
+pub struct UserQuery {
+	pub non_human: Option<bool>,
+	pub login_allowed: Option<bool>,
+	pub status: UserStatus,
+	pub username_prefix: Option<String>,
+	pub after: Option<String>,
+	/// Maximum number of users to return. Defaults to, and is capped at, 1000.
+	pub limit: Option<i64>,
+}
+
+impl UserQuery {
+	pub const MAX_LIMIT: i64 = 1000;
+}
+
+/// Settings shared by all entries of an alias.
+#[derive(sqlx::FromRow, Debug)]
+pub struct AliasInfo {
+	pub alias_name: String,
+	pub domain: Option<String>,
+	pub description: String,
+	/// Whether members may remove themselves from the alias.
+	pub self_removable: bool,
+}
+
+/// An alias that delivers mail to a certain user.
+#[derive(Debug)]
+pub struct AliasMembership {
+	pub alias_name: String,
+	pub domain: Option<String>,
+	pub description: String,
+	pub self_removable: bool,
+	/// The name of the nested alias through which the alias reaches
+	/// the user, or `None` if the user is a direct member.
+	pub via: Option<String>,
+}
+
+/// What an API consumer is allowed to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiPermission {
+	/// Verify passwords.
+	Authenticate,
+	/// Look up single users and their aliases.
+	LookupUsers,
+	/// List and search all users.
+	ListUsers,
+	/// Resolve aliases and recipients.
+	LookupAliases,
+}
+
+impl ApiPermission {
+	pub const ALL: [Self; 4] = [Self::Authenticate, Self::LookupUsers, Self::ListUsers, Self::LookupAliases];
+
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Self::Authenticate => "authenticate",
+			Self::LookupUsers => "lookup_users",
+			Self::ListUsers => "list_users",
+			Self::LookupAliases => "lookup_aliases",
+		}
+	}
+}
+
+/// A service that is allowed to use the API.
+#[derive(sqlx::FromRow, Debug)]
+pub struct ApiConsumer {
+	pub id: Uuid,
+	pub name: String,
+	/// Subject DN of a TLS client certificate identifying this consumer.
+	pub cert_dn: Option<String>,
+	pub permissions: Vec<String>,
+	pub created_at: chrono::DateTime<chrono::FixedOffset>,
+}
+
+impl ApiConsumer {
+	pub fn has_permission(&self, permission: ApiPermission) -> bool {
+		self.permissions.iter().any(|p| p == permission.as_str())
+	}
+}
+
+/// A TLS client certificate a user logged into the dashboard with.
+#[derive(sqlx::FromRow, Debug, serde::Serialize)]
+pub struct ClientCertificate {
+	pub userid: Uuid,
+	/// SHA-256 fingerprint, as returned by [`axum::CertDn::fingerprint`].
+	pub fingerprint: String,
+	pub serial: String,
+	pub subject: String,
+	pub pinned: bool,
+	pub revoked: bool,
+	pub first_seen: chrono::DateTime<chrono::FixedOffset>,
+	pub last_seen: chrono::DateTime<chrono::FixedOffset>,
+}
+
+/// Whether a client certificate may be used to access the dashboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertificateCheck {
+	Allowed,
+	/// The user only allows pinned certificates, and this one isn't, or we don't have the whole
+	/// certificate to tell.
+	NotPinned,
+	/// An admin revoked the certificate.
+	Revoked,
+	/// An admin revoked one of the user's certificates, and we don't have the whole certificate
+	/// to tell whether it's this one.
+	Unidentified,
+}
+
+/// A passkey a user can log into the dashboard with.
+#[derive(sqlx::FromRow, Debug)]
+pub struct Passkey {
+	pub credential_id: Vec<u8>,
+	pub userid: Uuid,
+	pub name: String,
+	/// COSE-encoded, see [`webauthn::RelyingParty::verify_assertion`].
+	pub public_key: Vec<u8>,
+	pub sign_count: i64,
+	pub created_at: chrono::DateTime<chrono::FixedOffset>,
+	pub last_used: Option<chrono::DateTime<chrono::FixedOffset>>,
+}
+
+/// How a dashboard session was opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LoginMethod {
+	Passkey,
+	Oidc,
+}
+
+/// A dashboard session, opened without a client certificate.
+#[derive(Debug)]
+pub struct Session {
+	pub user: User,
+	pub method: LoginMethod,
+}
+
+#[derive(sqlx::FromRow)]
+struct SessionRow {
+	#[sqlx(flatten)]
+	user: User,
+	/// Sessions opened through an OpenID Connect provider have no passkey.
+	passkey: bool,
+}
+
+/// An open dashboard session, see [`Service::list_sessions`].
+#[derive(Debug, serde::Serialize)]
+pub struct SessionInfo {
+	pub username: String,
+	pub method: LoginMethod,
+	/// Name of the passkey the session was opened with.
+	pub passkey: Option<String>,
+	pub created_at: chrono::DateTime<chrono::FixedOffset>,
+	pub expires_at: chrono::DateTime<chrono::FixedOffset>,
+}
+
+/// A user or password about to expire, see [`Service::list_expiring`].
+#[derive(sqlx::FromRow, Debug, serde::Serialize)]
+pub struct Expiring {
+	pub username: String,
+	/// Label of the expiring password, or `None` if the user itself expires.
+	pub password_label: Option<String>,
+	pub expires_at: chrono::DateTime<chrono::FixedOffset>,
+}
+
+/// A migration shipped with this version, see [`Service::migration_status`].
+#[derive(Debug, serde::Serialize)]
+pub struct MigrationStatus {
+	pub version: i64,
+	pub description: String,
+	/// When the migration was applied, or `None` if it's pending.
+	pub applied_at: Option<chrono::DateTime<chrono::FixedOffset>>,
+}
+
+/// An alias created by a user for themselves.
+#[derive(sqlx::FromRow, Debug)]
+pub struct SelfServiceAlias {
+	pub alias_name: String,
+	pub owner: Uuid,
+	pub enabled: bool,
+	pub created_at: chrono::DateTime<chrono::FixedOffset>,
+}
+
+/// How the name of a new self-service alias should be chosen.
+#[derive(Debug)]
+pub enum SelfServiceAliasName<'a> {
+	/// Generate a random name.
+	Random,
+	/// Fill in an admin-approved template.
+	///
+	/// `<username>` in the template is replaced with the owner's
+	/// username, and `<word>` with the word of the user's choice.
+	Template { template: &'a str, word: &'a str },
+}
+
+pub enum SelfServiceAliasResult {
+	Created(String),
+	LimitReached,
+	NameTaken,
+	InvalidName,
+	UnknownTemplate,
+}
+
+/// What a single name resolves to, before expanding nested aliases.
+#[derive(Debug)]
+pub enum RecipientMatch {
+	/// An alias entry matched. The alias name may be a pattern.
+	Alias {
+		alias_name: String,
+		domain: Option<String>,
+		destinations: Vec<User>,
+	},
+	/// A user with this name exists and isn't shadowed by an alias.
+	User(User),
+	/// Nothing matched, so mail to this name should bounce.
+	NoMatch,
+}
+
+/// A user an alias delivers to, along with the alias entry that matched.
+#[derive(sqlx::FromRow)]
+struct AliasDestination {
+	alias_name: String,
+	domain: Option<String>,
+	#[sqlx(flatten)]
+	user: User,
+}
+
+impl AliasDestination {
+	/// Query all alias entries matching a name on a certain domain,
+	/// including patterns. Disabled aliases and expired users are
+	/// filtered out.
+	async fn query<'c, E: sqlx::PgExecutor<'c>>(executor: E, name: &str, domain: Option<&str>) -> sqlx::Result<Vec<Self>> {
+		sqlx::query_as::<_, AliasDestination>(
+			"SELECT aliases.alias_name, aliases.domain, userdb.* FROM mailpasswd.aliases INNER JOIN mailpasswd.userdb ON destination = userdb.id WHERE (alias_name = $1 OR (right(alias_name, 1) = '*' AND starts_with($1, left(alias_name, -1)))) AND (aliases.domain IS NULL OR aliases.domain = $2) AND aliases.enabled AND (userdb.expires_at IS NULL OR userdb.expires_at > now()) ORDER BY userdb.username",
+		)
+		.bind(name)
+		.bind(domain)
+		.fetch_all(executor)
+		.await
+	}
+
+	/// Pick the most specific alias entry out of the candidates.
+	///
+	/// Longer alias names (i.e. longer pattern prefixes) win, and for
+	/// the same name, aliases scoped to a domain take precedence over
+	/// unscoped ones.
+	fn most_specific(candidates: Vec<Self>) -> Option<RecipientMatch> {
+		let key = |c: &Self| (c.alias_name.len(), c.domain.is_some());
+		let best = candidates.iter().max_by_key(|c| key(c))?;
+		let (alias_name, domain) = (best.alias_name.clone(), best.domain.clone());
+
+		Some(RecipientMatch::Alias {
+			destinations: candidates
+				.into_iter()
+				.filter(|c| c.alias_name == alias_name && c.domain == domain)
+				.map(|c| c.user)
+				.collect(),
+			alias_name,
+			domain,
+		})
+	}
+}
+
+/// Strip the subaddress (e.g. `+tag`) from a local part, if present.
+fn strip_subaddress(local_part: &str) -> Option<&str> {
+	local_part.split_once('+').map(|(base, _)| base).filter(|base| !base.is_empty())
+}
+
+/// Match a single name on a domain against aliases and users.
+///
+/// Exact matches always win: an alias with this exact name shadows a
+/// user, and a user shadows any pattern. If the name carries a
+/// subaddress, the name without it is tried next. Patterns are only
+/// considered if nothing else matched, with the longest prefix
+/// winning over shorter ones and catch-alls.
+async fn match_recipient(conn: &mut sqlx::PgConnection, name: &str, domain: Option<&str>) -> sqlx::Result<RecipientMatch> {
+	async fn find_user(conn: &mut sqlx::PgConnection, name: &str) -> sqlx::Result<Option<User>> {
+		sqlx::query_as::<_, User>("SELECT * FROM mailpasswd.userdb WHERE username = $1 AND (expires_at IS NULL OR expires_at > now())")
+			.bind(name)
+			.fetch_optional(conn)
+			.await
+	}
+
+	let (exact, patterns): (Vec<_>, Vec<_>) = AliasDestination::query(&mut *conn, name, domain)
+		.await?
+		.into_iter()
+		.partition(|c| c.alias_name == name);
+	if let Some(alias) = AliasDestination::most_specific(exact) {
+		return Ok(alias);
+	}
+	if let Some(user) = find_user(conn, name).await? {
+		return Ok(RecipientMatch::User(user));
+	}
+
+	if let Some(base) = strip_subaddress(name) {
+		let exact = AliasDestination::query(&mut *conn, base, domain)
+			.await?
+			.into_iter()
+			.filter(|c| c.alias_name == base)
+			.collect();
+		if let Some(alias) = AliasDestination::most_specific(exact) {
+			return Ok(alias);
+		}
+		if let Some(user) = find_user(conn, base).await? {
+			return Ok(RecipientMatch::User(user));
+		}
+	}
+
+	Ok(AliasDestination::most_specific(patterns).unwrap_or(RecipientMatch::NoMatch))
 }
 
 static MIGRATOR: sqlx::migrate::Migrator = sqlx::migrate!();
@@ -76,8 +470,7 @@ mod sealed {
 	impl InitState for super::MigrationsDone {}
 	impl InitState for super::Created {}
 }
-use sealed::Created;
-pub use sealed::MigrationsDone;
+pub use sealed::{Created, MigrationsDone};
 
 #[derive(Clone)]
 pub struct Service<S: sealed::InitState> {
@@ -102,6 +495,55 @@ pub enum AuthenticationResult {
 	IncorrectPassword,
 }
 
+/// A successful authentication, see [`Service::authenticate`].
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+pub struct Authenticated {
+	pub user: User,
+	/// Label of the password that matched.
+	pub password_label: String,
+}
+
+/// Reason an authentication attempt was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthenticationFailure {
+	/// The user doesn't exist, has expired, or is on an unknown domain.
+	NoSuchUser,
+	LoginDisabled,
+	/// No password of this user matched, or the matching password has expired.
+	IncorrectPassword,
+}
+
+impl<S: sealed::InitState> Service<S> {
+	/// List the migrations shipped with this version, and when they were applied.
+	#[tracing::instrument]
+	pub async fn migration_status(&self) -> sqlx::Result<Vec<MigrationStatus>> {
+		// sqlx creates its bookkeeping table when running the first migration
+		let applied: std::collections::HashMap<i64, chrono::DateTime<chrono::FixedOffset>> =
+			if sqlx::query_scalar::<_, bool>("SELECT to_regclass('_sqlx_migrations') IS NOT NULL")
+				.fetch_one(&self.db)
+				.await?
+			{
+				sqlx::query_as("SELECT version, installed_on FROM _sqlx_migrations WHERE success")
+					.fetch_all(&self.db)
+					.await?
+					.into_iter()
+					.collect()
+			} else {
+				Default::default()
+			};
+
+		Ok(MIGRATOR
+			.iter()
+			.map(|migration| MigrationStatus {
+				version: migration.version,
+				description: migration.description.to_string(),
+				applied_at: applied.get(&migration.version).copied(),
+			})
+			.collect())
+	}
+}
+
 impl Service<Created> {
 	pub fn new(db: sqlx::PgPool) -> Self {
 		Self {
@@ -121,6 +563,24 @@ impl Service<Created> {
 			argon2: self.argon2,
 		})
 	}
+
+	/// Use the database as it is, if all migrations were applied already.
+	///
+	/// Unlike [`Self::run_migrations`], this never changes the schema under a server that still
+	/// runs an older version. If there are pending migrations, the service is returned unchanged.
+	#[tracing::instrument]
+	pub async fn check_migrations(self) -> sqlx::Result<Result<Service<MigrationsDone>, Self>> {
+		if self.migration_status().await?.iter().any(|migration| migration.applied_at.is_none()) {
+			return Ok(Err(self));
+		}
+
+		Ok(Ok(Service::<MigrationsDone> {
+			_migrations: std::marker::PhantomData::<MigrationsDone>,
+			db: self.db,
+			argon2: self.argon2,
+		}))
+	}
+
 }
 
 impl Service<MigrationsDone> {
@@ -154,8 +614,8 @@ impl Service<MigrationsDone> {
 	#[tracing::instrument]
 	pub async fn rm_password_for(&self, user: &User, label: &str) -> sqlx::Result<()> {
 		sqlx::query("DELETE FROM mailpasswd.passdb WHERE userid = $1 AND label = $2")
-			.bind(&user.id)
-			.bind(&label)
+			.bind(user.id)
+			.bind(label)
 			.execute(&self.db)
 			.await?;
 
@@ -180,9 +640,30 @@ impl Service<MigrationsDone> {
 			.await
 	}
 
-	/// Verify a password for a user identified by their username.
+	/// Verify a password for a user identified by their username or email address.
+	///
+	/// Addresses on unknown domains are treated as unknown users.
 	#[tracing::instrument(skip(password))]
 	pub async fn verify_password(&self, user: &str, password: &str) -> sqlx::Result<AuthenticationResult> {
+		Ok(match self.authenticate(user, password).await? {
+			Ok(_) => AuthenticationResult::Ok,
+			Err(AuthenticationFailure::NoSuchUser) => AuthenticationResult::NoSuchUser,
+			Err(AuthenticationFailure::LoginDisabled) => AuthenticationResult::LoginDisabled,
+			Err(AuthenticationFailure::IncorrectPassword) => AuthenticationResult::IncorrectPassword,
+		})
+	}
+	/// Verify a password like [`Service::verify_password`], returning the user and the label
+	/// of the password that matched on success.
+	#[tracing::instrument(skip(password))]
+	pub async fn authenticate(
+		&self,
+		user: &str,
+		password: &str,
+	) -> sqlx::Result<Result<Authenticated, AuthenticationFailure>> {
+		let user = match self.parse_address(user).await? {
+			Some((local_part, _)) => local_part,
+			None => return Ok(Err(AuthenticationFailure::NoSuchUser)),
+		};
 		// First, wrap things in a transaction. This is because we need extreme granularity in
 		// errors that might be hard to do in a single SELECT statement, but with multiple SELECT
 		// statements, we need consistency. This is provided by REPEATABLE READ transaction
@@ -194,47 +675,47 @@ impl Service<MigrationsDone> {
 			.execute(&mut txn)
 			.await?;
 		// First, check if user exists and is allowed to log in.
-		match sqlx::query_as::<_, (bool,)>("SELECT login_allowed FROM mailpasswd.userdb WHERE username = $1")
-			.bind(user)
-			.fetch_optional(&mut txn)
-			.await
+		let user = match sqlx::query_as::<_, User>(
+			"SELECT * FROM mailpasswd.userdb WHERE username = $1 AND (expires_at IS NULL OR expires_at > now())",
+		)
+		.bind(user)
+		.fetch_optional(&mut txn)
+		.await?
 		{
-			Ok(Some((login_allowed,))) => {
-				if !login_allowed {
-					return Ok(AuthenticationResult::LoginDisabled);
-				}
-			}
-			Ok(None) => return Ok(AuthenticationResult::NoSuchUser),
-			Err(err) => return Err(err),
+			Some(user) if !user.login_allowed => return Ok(Err(AuthenticationFailure::LoginDisabled)),
+			Some(user) => user,
+			None => return Ok(Err(AuthenticationFailure::NoSuchUser)),
 		};
 
-		let mut stream = sqlx::query_scalar::<_, String>(
-			"SELECT passdb.hash FROM mailpasswd.passdb INNER JOIN mailpasswd.userdb ON userid = userdb.id WHERE userdb.username = $1",
+		let mut stream = sqlx::query_as::<_, (String, String)>(
+			"SELECT label, hash FROM mailpasswd.passdb WHERE userid = $1 AND (expires_at IS NULL OR expires_at > now())",
 		)
-		.bind(user)
-		.fetch_many(&self.db);
+		.bind(user.id)
+		.fetch_many(&mut txn);
 
+		let mut matched = None;
 		while let Some(result) = stream.next().await {
 			match result {
-				Ok(sqlx::Either::Right(hash)) => {
+				Ok(sqlx::Either::Right((label, hash))) => {
 					if self
 						.argon2
 						.verify_password(password.as_bytes(), &PasswordHash::new(&hash).expect("hash should be valid"))
 						.is_ok()
 					{
-						txn.commit().await?;
-						return Ok(AuthenticationResult::Ok);
+						matched = Some(label);
+						break;
 					}
 				}
-				Err(err) => {
-					txn.commit().await?;
-					return Err(err);
-				}
+				Err(err) => return Err(err),
 				Ok(sqlx::Either::Left(_query_result)) => {}
 			}
 		}
+		drop(stream);
 		txn.commit().await?;
-		Ok(AuthenticationResult::IncorrectPassword)
+		Ok(match matched {
+			Some(password_label) => Ok(Authenticated { user, password_label }),
+			None => Err(AuthenticationFailure::IncorrectPassword),
+		})
 	}
 	/// Resolve a user by its username.
 	///
@@ -242,11 +723,21 @@ impl Service<MigrationsDone> {
 	/// consumers should use [`get_user_by_id`][] instead.
 	#[tracing::instrument]
 	pub async fn find_user_by_name(&self, username: &str) -> sqlx::Result<Option<User>> {
-		sqlx::query_as::<_, User>("SELECT * FROM mailpasswd.userdb WHERE username = $1 AND (CASE WHEN expires_at != null THEN expires_at > now() ELSE true END)")
+		sqlx::query_as::<_, User>("SELECT * FROM mailpasswd.userdb WHERE username = $1 AND (expires_at IS NULL OR expires_at > now())")
 			.bind(username)
 			.fetch_optional(&self.db)
 			.await
 	}
+	/// Resolve a user by its username or email address.
+	///
+	/// Addresses on unknown domains are treated as unknown users.
+	#[tracing::instrument]
+	pub async fn find_user_by_address(&self, address: &str) -> sqlx::Result<Option<User>> {
+		match self.parse_address(address).await? {
+			Some((local_part, _)) => self.find_user_by_name(local_part).await,
+			None => Ok(None),
+		}
+	}
 	/// Find a user by its static neverchanging UUID.
 	#[tracing::instrument]
 	pub async fn get_user_by_id(&self, uuid: Uuid) -> sqlx::Result<Option<User>> {
@@ -260,6 +751,25 @@ impl Service<MigrationsDone> {
 	pub async fn list_users(&self) -> sqlx::Result<Vec<User>> {
 		sqlx::query_as::<_, User>("SELECT * FROM mailpasswd.userdb ORDER BY username").fetch_all(&self.db).await
 	}
+	/// List users matching the filters, one page at a time.
+	#[tracing::instrument]
+	pub async fn search_users(&self, query: &UserQuery) -> sqlx::Result<Vec<User>> {
+		sqlx::query_as::<_, User>(
+			"SELECT * FROM mailpasswd.userdb WHERE ($1::BOOL IS NULL OR non_human = $1) AND ($2::BOOL IS NULL OR login_allowed = $2) AND ($3::BOOL IS NULL OR $3 = (expires_at IS NOT NULL AND expires_at <= now())) AND ($4::TEXT IS NULL OR starts_with(username, $4)) AND ($5::TEXT IS NULL OR username > $5) ORDER BY username LIMIT $6",
+		)
+		.bind(query.non_human)
+		.bind(query.login_allowed)
+		.bind(match query.status {
+			UserStatus::Active => Some(false),
+			UserStatus::Expired => Some(true),
+			UserStatus::All => None,
+		})
+		.bind(query.username_prefix.as_deref())
+		.bind(query.after.as_deref())
+		.bind(query.limit.unwrap_or(UserQuery::MAX_LIMIT).clamp(0, UserQuery::MAX_LIMIT))
+		.fetch_all(&self.db)
+		.await
+	}
 	/// Create a new user.
 	#[tracing::instrument]
 	pub async fn create_user(
@@ -285,6 +795,36 @@ impl Service<MigrationsDone> {
 
 		Ok(())
 	}
+	/// Allow or disallow a user to log in.
+	#[tracing::instrument]
+	pub async fn set_user_login_allowed(&self, user: Uuid, allowed: bool) -> sqlx::Result<()> {
+		sqlx::query("UPDATE mailpasswd.userdb SET login_allowed = $2 WHERE id = $1")
+			.bind(user)
+			.bind(allowed)
+			.execute(&self.db)
+			.await?;
+
+		Ok(())
+	}
+	/// List users and passwords that haven't expired yet, but will before `before`, soonest first.
+	///
+	/// Passwords of users that expired already aren't included.
+	#[tracing::instrument]
+	pub async fn list_expiring(&self, before: chrono::DateTime<chrono::FixedOffset>) -> sqlx::Result<Vec<Expiring>> {
+		sqlx::query_as::<_, Expiring>(
+			"SELECT username, NULL::varchar AS password_label, expires_at FROM mailpasswd.userdb
+			 WHERE expires_at > now() AND expires_at <= $1
+			 UNION ALL
+			 SELECT userdb.username, passdb.label, passdb.expires_at
+			 FROM mailpasswd.passdb JOIN mailpasswd.userdb ON userdb.id = passdb.userid
+			 WHERE passdb.expires_at > now() AND passdb.expires_at <= $1
+			 AND (userdb.expires_at IS NULL OR userdb.expires_at > now())
+			 ORDER BY expires_at",
+		)
+		.bind(before)
+		.fetch_all(&self.db)
+		.await
+	}
 	/// Set user expiry date.
 	#[tracing::instrument]
 	pub async fn set_user_expiry_date(
@@ -301,74 +841,850 @@ impl Service<MigrationsDone> {
 		Ok(())
 	}
 
-	pub async fn add_alias(&self, alias: &Alias) -> sqlx::Result<()> {
-		sqlx::query("INSERT INTO mailpasswd.aliases (alias_name, destination) VALUES ($1, $2)")
-			.bind(alias.alias_name.as_str())
-			.bind(alias.destination)
+	#[tracing::instrument]
+	pub async fn list_api_consumers(&self) -> sqlx::Result<Vec<ApiConsumer>> {
+		sqlx::query_as::<_, ApiConsumer>("SELECT * FROM mailpasswd.api_consumers ORDER BY name")
+			.fetch_all(&self.db)
+			.await
+	}
+	/// Register a new API consumer, returning its bearer token.
+	///
+	/// Only a hash of the token is stored, so it can't be retrieved later.
+	#[tracing::instrument]
+	pub async fn create_api_consumer(
+		&self,
+		name: &str,
+		cert_dn: Option<&dn::DistinguishedName>,
+		permissions: &[ApiPermission],
+	) -> sqlx::Result<String> {
+		let token = util::gen_password(&mut rand::thread_rng());
+		sqlx::query("INSERT INTO mailpasswd.api_consumers (name, cert_dn, permissions, token_hash) VALUES ($1, $2, $3, $4)")
+			.bind(name)
+			// Stored in RFC 4514 form, the way DNs of client certificates are compared against it
+			.bind(cert_dn.map(ToString::to_string))
+			.bind(permissions.iter().map(ApiPermission::as_str).collect::<Vec<_>>())
+			.bind(util::hash_token(&token))
 			.execute(&self.db)
 			.await?;
-
-		Ok(())
+		Ok(token)
 	}
-	pub async fn remove_alias(&self, alias: &Alias) -> sqlx::Result<()> {
-		sqlx::query("DELETE FROM mailpasswd.aliases WHERE alias_name = $1 AND destination = $2")
-			.bind(alias.alias_name.as_str())
-			.bind(alias.destination)
+	/// Register an API consumer with a token chosen elsewhere, e.g. by
+	/// a deployment tool, or update its token and permissions if a
+	/// consumer with this name exists.
+	#[tracing::instrument(skip(token))]
+	pub async fn register_api_consumer(&self, name: &str, token: &str, permissions: &[ApiPermission]) -> sqlx::Result<()> {
+		sqlx::query(
+			"INSERT INTO mailpasswd.api_consumers (name, permissions, token_hash) VALUES ($1, $2, $3)
+			 ON CONFLICT (name) DO UPDATE SET permissions = EXCLUDED.permissions, token_hash = EXCLUDED.token_hash",
+		)
+		.bind(name)
+		.bind(permissions.iter().map(ApiPermission::as_str).collect::<Vec<_>>())
+		.bind(util::hash_token(token))
+		.execute(&self.db)
+		.await
+		.map(|_| ())
+	}
+	/// Replace the bearer token of an API consumer, invalidating the old one.
+	#[tracing::instrument]
+	pub async fn reset_api_consumer_token(&self, id: Uuid) -> sqlx::Result<String> {
+		let token = util::gen_password(&mut rand::thread_rng());
+		sqlx::query("UPDATE mailpasswd.api_consumers SET token_hash = $2 WHERE id = $1")
+			.bind(id)
+			.bind(util::hash_token(&token))
 			.execute(&self.db)
 			.await?;
-
-		Ok(())
+		Ok(token)
 	}
-	pub async fn list_all_aliases(&self) -> sqlx::Result<Vec<(String, Vec<Uuid>)>> {
-		sqlx::query_as::<_, (String, Vec<Uuid>)>("SELECT alias_name, array_agg(destination) FROM mailpasswd.aliases GROUP BY alias_name ORDER BY alias_name")
-			.fetch_all(&self.db)
+	#[tracing::instrument]
+	pub async fn set_api_consumer_permissions(&self, id: Uuid, permissions: &[ApiPermission]) -> sqlx::Result<()> {
+		sqlx::query("UPDATE mailpasswd.api_consumers SET permissions = $2 WHERE id = $1")
+			.bind(id)
+			.bind(permissions.iter().map(ApiPermission::as_str).collect::<Vec<_>>())
+			.execute(&self.db)
 			.await
+			.map(|_| ())
 	}
-}
-
-#[cfg(test)]
-mod test {
-	use super::AuthenticationResult;
-	use futures::{StreamExt, TryStreamExt};
-
-	fn create_service(pool: sqlx::PgPool) -> crate::Service<super::MigrationsDone> {
-		// Note: you are DEFINITELY NOT SUPPOSED to be creating this
-		// object like that! SQLx test harness automatically applies
-		// migrations, so we don't need to run them a second time.
-		//
-		// Since the typestate field is private, you won't be able to
-		// do it in external code anyway.
-		crate::Service::<super::MigrationsDone> {
-			_migrations: std::marker::PhantomData::<super::MigrationsDone>,
-			db: pool,
-			argon2: argon2::Argon2::new(argon2::Algorithm::Argon2i, Default::default(), Default::default()),
-		}
+	#[tracing::instrument]
+	pub async fn rm_api_consumer(&self, id: Uuid) -> sqlx::Result<()> {
+		sqlx::query("DELETE FROM mailpasswd.api_consumers WHERE id = $1")
+			.bind(id)
+			.execute(&self.db)
+			.await
+			.map(|_| ())
+	}
+	#[tracing::instrument(skip(token))]
+	pub async fn find_api_consumer_by_token(&self, token: &str) -> sqlx::Result<Option<ApiConsumer>> {
+		sqlx::query_as::<_, ApiConsumer>("SELECT * FROM mailpasswd.api_consumers WHERE token_hash = $1")
+			.bind(util::hash_token(token))
+			.fetch_optional(&self.db)
+			.await
+	}
+	#[tracing::instrument]
+	pub async fn find_api_consumer_by_cert_dn(&self, cert_dn: &dn::DistinguishedName) -> sqlx::Result<Option<ApiConsumer>> {
+		sqlx::query_as::<_, ApiConsumer>("SELECT * FROM mailpasswd.api_consumers WHERE cert_dn = $1")
+			.bind(cert_dn.to_string())
+			.fetch_optional(&self.db)
+			.await
 	}
 
-	#[sqlx::test]
-	async fn smoke_test(pool: sqlx::PgPool) -> sqlx::Result<()> {
-		let svc = create_service(pool);
+	/// Record that a user presented a client certificate, and check whether they may use it.
+	///
+	/// Certificates are only recorded if we have the whole certificate, not just its subject DN.
+	#[tracing::instrument(skip(cert), fields(subject = cert.as_str()))]
+	pub async fn check_client_certificate(&self, user: &User, cert: &axum::CertDn) -> sqlx::Result<CertificateCheck> {
+		let require_pinned = self.get_require_pinned_certificate(user.id).await?;
+		let (Some(fingerprint), Some(serial)) = (cert.fingerprint(), cert.serial()) else {
+			let any_revoked = sqlx::query_scalar::<_, bool>(
+				"SELECT EXISTS(SELECT 1 FROM mailpasswd.client_certificates WHERE userid = $1 AND revoked)",
+			)
+			.bind(user.id)
+			.fetch_one(&self.db)
+			.await?;
 
-		let uuid = svc.create_user("vsh", None, false).await?;
-		let user = svc.find_user_by_name("vsh").await?.unwrap();
-		assert_eq!(user.id, uuid);
+			return Ok(if any_revoked {
+				CertificateCheck::Unidentified
+			} else if require_pinned {
+				CertificateCheck::NotPinned
+			} else {
+				CertificateCheck::Allowed
+			});
+		};
+		let (pinned, revoked) = sqlx::query_as::<_, (bool, bool)>(
+			"INSERT INTO mailpasswd.client_certificates (userid, fingerprint, serial, subject) VALUES ($1, $2, $3, $4)
+			 ON CONFLICT (userid, fingerprint) DO UPDATE SET last_seen = now()
+			 RETURNING pinned, revoked",
+		)
+		.bind(user.id)
+		.bind(fingerprint)
+		.bind(serial)
+		.bind(cert.as_str())
+		.fetch_one(&self.db)
+		.await?;
 
-		// Check that no passwords are defined
-		assert_eq!(svc.list_passwords_for(&user).await?.len(), 0);
-		assert!(matches!(
-			svc.verify_password(&user.username, "AAAAAAAA").await?,
-			AuthenticationResult::IncorrectPassword
-		));
-		// Generate a password and ensure it matches
-		let password = svc.new_password(&user, "longiflorum", None).await?;
-		assert_eq!(svc.list_passwords_for(&user).await?.len(), 1);
-		assert!(matches!(
-			svc.verify_password(&user.username, &password).await?,
-			AuthenticationResult::Ok
-		));
-		// Ensure something else isn't accepted
-		assert!(matches!(
-			svc.verify_password(&user.username, "AAAAAAAA").await?,
+		Ok(if revoked {
+			CertificateCheck::Revoked
+		} else if require_pinned && !pinned {
+			CertificateCheck::NotPinned
+		} else {
+			CertificateCheck::Allowed
+		})
+	}
+	#[tracing::instrument]
+	pub async fn list_client_certificates_for(&self, user: Uuid) -> sqlx::Result<Vec<ClientCertificate>> {
+		sqlx::query_as::<_, ClientCertificate>(
+			"SELECT * FROM mailpasswd.client_certificates WHERE userid = $1 ORDER BY last_seen DESC",
+		)
+		.bind(user)
+		.fetch_all(&self.db)
+		.await
+	}
+	/// List client certificates of all users, most recently seen first.
+	#[tracing::instrument]
+	pub async fn list_all_client_certificates(&self) -> sqlx::Result<Vec<ClientCertificate>> {
+		sqlx::query_as::<_, ClientCertificate>("SELECT * FROM mailpasswd.client_certificates ORDER BY last_seen DESC")
+			.fetch_all(&self.db)
+			.await
+	}
+	/// Check whether only pinned certificates let the user into the dashboard.
+	#[tracing::instrument]
+	pub async fn get_require_pinned_certificate(&self, user: Uuid) -> sqlx::Result<bool> {
+		sqlx::query_scalar::<_, bool>("SELECT require_pinned_certificate FROM mailpasswd.userdb WHERE id = $1")
+			.bind(user)
+			.fetch_one(&self.db)
+			.await
+	}
+	#[tracing::instrument]
+	pub async fn toggle_require_pinned_certificate(&self, user: Uuid) -> sqlx::Result<()> {
+		sqlx::query("UPDATE mailpasswd.userdb SET require_pinned_certificate = NOT require_pinned_certificate WHERE id = $1")
+			.bind(user)
+			.execute(&self.db)
+			.await?;
+
+		Ok(())
+	}
+	#[tracing::instrument]
+	pub async fn set_require_pinned_certificate(&self, user: Uuid, require: bool) -> sqlx::Result<()> {
+		sqlx::query("UPDATE mailpasswd.userdb SET require_pinned_certificate = $2 WHERE id = $1")
+			.bind(user)
+			.bind(require)
+			.execute(&self.db)
+			.await?;
+
+		Ok(())
+	}
+	#[tracing::instrument]
+	pub async fn set_client_certificate_pinned(&self, user: Uuid, fingerprint: &str, pinned: bool) -> sqlx::Result<()> {
+		sqlx::query("UPDATE mailpasswd.client_certificates SET pinned = $3 WHERE userid = $1 AND fingerprint = $2")
+			.bind(user)
+			.bind(fingerprint)
+			.bind(pinned)
+			.execute(&self.db)
+			.await?;
+
+		Ok(())
+	}
+	/// Revoke a certificate's access to the dashboard, or restore it.
+	///
+	/// This takes effect immediately, unlike publishing a new CRL.
+	#[tracing::instrument]
+	pub async fn set_client_certificate_revoked(&self, user: Uuid, fingerprint: &str, revoked: bool) -> sqlx::Result<()> {
+		sqlx::query("UPDATE mailpasswd.client_certificates SET revoked = $3 WHERE userid = $1 AND fingerprint = $2")
+			.bind(user)
+			.bind(fingerprint)
+			.bind(revoked)
+			.execute(&self.db)
+			.await?;
+
+		Ok(())
+	}
+
+	#[tracing::instrument(skip(passkey))]
+	pub async fn add_passkey(&self, user: &User, name: &str, passkey: &webauthn::NewPasskey) -> sqlx::Result<()> {
+		sqlx::query(
+			"INSERT INTO mailpasswd.passkeys (credential_id, userid, name, public_key, sign_count) VALUES ($1, $2, $3, $4, $5)",
+		)
+		.bind(&passkey.credential_id)
+		.bind(user.id)
+		.bind(name)
+		.bind(&passkey.public_key)
+		.bind(i64::from(passkey.sign_count))
+		.execute(&self.db)
+		.await?;
+
+		Ok(())
+	}
+	#[tracing::instrument]
+	pub async fn list_passkeys_for(&self, user: &User) -> sqlx::Result<Vec<Passkey>> {
+		sqlx::query_as::<_, Passkey>("SELECT * FROM mailpasswd.passkeys WHERE userid = $1 ORDER BY name")
+			.bind(user.id)
+			.fetch_all(&self.db)
+			.await
+	}
+	#[tracing::instrument]
+	pub async fn find_passkey(&self, credential_id: &[u8]) -> sqlx::Result<Option<Passkey>> {
+		sqlx::query_as::<_, Passkey>("SELECT * FROM mailpasswd.passkeys WHERE credential_id = $1")
+			.bind(credential_id)
+			.fetch_optional(&self.db)
+			.await
+	}
+	/// Delete a passkey, ending all sessions opened with it.
+	#[tracing::instrument]
+	pub async fn rm_passkey_for(&self, user: &User, name: &str) -> sqlx::Result<()> {
+		sqlx::query("DELETE FROM mailpasswd.passkeys WHERE userid = $1 AND name = $2")
+			.bind(user.id)
+			.bind(name)
+			.execute(&self.db)
+			.await?;
+
+		Ok(())
+	}
+	/// Open a dashboard session for the owner of a passkey they just used, and return its token.
+	///
+	/// Only a hash of the token is stored, like for API consumers.
+	#[tracing::instrument(skip(passkey), fields(user = %passkey.userid))]
+	pub async fn create_session(&self, passkey: &Passkey, sign_count: u32, lifetime: chrono::Duration) -> sqlx::Result<String> {
+		let mut txn = self.db.begin().await?;
+		sqlx::query("UPDATE mailpasswd.passkeys SET sign_count = $2, last_used = now() WHERE credential_id = $1")
+			.bind(&passkey.credential_id)
+			.bind(i64::from(sign_count))
+			.execute(&mut txn)
+			.await?;
+		let token = Self::insert_session(&mut txn, passkey.userid, Some(&passkey.credential_id), lifetime).await?;
+		txn.commit().await?;
+
+		Ok(token)
+	}
+	/// Open a dashboard session for a user the OpenID Connect provider vouched for, and return its token.
+	#[tracing::instrument(skip(user), fields(user = %user.username))]
+	pub async fn create_oidc_session(&self, user: &User, lifetime: chrono::Duration) -> sqlx::Result<String> {
+		let mut txn = self.db.begin().await?;
+		let token = Self::insert_session(&mut txn, user.id, None, lifetime).await?;
+		txn.commit().await?;
+
+		Ok(token)
+	}
+	async fn insert_session(
+		txn: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+		userid: Uuid,
+		credential_id: Option<&[u8]>,
+		lifetime: chrono::Duration,
+	) -> sqlx::Result<String> {
+		let token = util::gen_password(&mut rand::thread_rng());
+		sqlx::query("DELETE FROM mailpasswd.sessions WHERE expires_at <= now()")
+			.execute(&mut *txn)
+			.await?;
+		sqlx::query("INSERT INTO mailpasswd.sessions (token_hash, userid, credential_id, expires_at) VALUES ($1, $2, $3, $4)")
+			.bind(util::hash_token(&token))
+			.bind(userid)
+			.bind(credential_id)
+			.bind(chrono::Utc::now() + lifetime)
+			.execute(&mut *txn)
+			.await?;
+
+		Ok(token)
+	}
+	/// Find a session, unless it or its user expired.
+	#[tracing::instrument(skip(token))]
+	pub async fn find_session(&self, token: &str) -> sqlx::Result<Option<Session>> {
+		let row = sqlx::query_as::<_, SessionRow>(
+			"SELECT userdb.*, sessions.credential_id IS NOT NULL AS passkey
+			 FROM mailpasswd.sessions JOIN mailpasswd.userdb ON userdb.id = sessions.userid
+			 WHERE token_hash = $1 AND sessions.expires_at > now() AND (userdb.expires_at IS NULL OR userdb.expires_at > now())",
+		)
+		.bind(util::hash_token(token))
+		.fetch_optional(&self.db)
+		.await?;
+
+		Ok(row.map(|row| Session {
+			user: row.user,
+			method: if row.passkey { LoginMethod::Passkey } else { LoginMethod::Oidc },
+		}))
+	}
+	#[tracing::instrument(skip(token))]
+	pub async fn rm_session(&self, token: &str) -> sqlx::Result<()> {
+		sqlx::query("DELETE FROM mailpasswd.sessions WHERE token_hash = $1")
+			.bind(util::hash_token(token))
+			.execute(&self.db)
+			.await?;
+
+		Ok(())
+	}
+	/// List dashboard sessions that haven't expired yet, oldest first.
+	#[tracing::instrument]
+	pub async fn list_sessions(&self) -> sqlx::Result<Vec<SessionInfo>> {
+		let rows = sqlx::query_as::<_, (String, Option<String>, chrono::DateTime<chrono::FixedOffset>, chrono::DateTime<chrono::FixedOffset>)>(
+			"SELECT userdb.username, passkeys.name, sessions.created_at, sessions.expires_at
+			 FROM mailpasswd.sessions
+			 JOIN mailpasswd.userdb ON userdb.id = sessions.userid
+			 LEFT JOIN mailpasswd.passkeys ON passkeys.credential_id = sessions.credential_id
+			 WHERE sessions.expires_at > now()
+			 ORDER BY sessions.created_at",
+		)
+		.fetch_all(&self.db)
+		.await?;
+
+		Ok(rows
+			.into_iter()
+			.map(|(username, passkey, created_at, expires_at)| SessionInfo {
+				username,
+				method: if passkey.is_some() { LoginMethod::Passkey } else { LoginMethod::Oidc },
+				passkey,
+				created_at,
+				expires_at,
+			})
+			.collect())
+	}
+	/// End all sessions opened with a certain login method, and return how many there were.
+	#[tracing::instrument]
+	pub async fn rm_sessions(&self, method: LoginMethod) -> sqlx::Result<u64> {
+		Ok(sqlx::query("DELETE FROM mailpasswd.sessions WHERE (credential_id IS NOT NULL) = $1")
+			.bind(method == LoginMethod::Passkey)
+			.execute(&self.db)
+			.await?
+			.rows_affected())
+	}
+
+	/// List all domains we accept addresses on.
+	#[tracing::instrument]
+	pub async fn list_domains(&self) -> sqlx::Result<Vec<String>> {
+		sqlx::query_scalar::<_, String>("SELECT name FROM mailpasswd.domains ORDER BY name").fetch_all(&self.db).await
+	}
+	/// Register a new domain.
+	#[tracing::instrument]
+	pub async fn add_domain(&self, domain: &str) -> sqlx::Result<()> {
+		sqlx::query("INSERT INTO mailpasswd.domains (name) VALUES ($1)")
+			.bind(domain.to_lowercase())
+			.execute(&self.db)
+			.await?;
+
+		Ok(())
+	}
+	/// Register a domain unless it's known already, e.g. one from the configuration file.
+	#[tracing::instrument]
+	pub async fn register_domain(&self, domain: &str) -> sqlx::Result<()> {
+		sqlx::query("INSERT INTO mailpasswd.domains (name) VALUES ($1) ON CONFLICT DO NOTHING")
+			.bind(domain.to_lowercase())
+			.execute(&self.db)
+			.await?;
+
+		Ok(())
+	}
+	/// Remove a domain. This fails if there are still aliases scoped to it.
+	#[tracing::instrument]
+	pub async fn remove_domain(&self, domain: &str) -> sqlx::Result<()> {
+		sqlx::query("DELETE FROM mailpasswd.domains WHERE name = $1")
+			.bind(domain.to_lowercase())
+			.execute(&self.db)
+			.await?;
+
+		Ok(())
+	}
+	/// Split an address into its local part and its domain, checking
+	/// that the domain is known. Returns `None` for unknown domains.
+	///
+	/// Bare local parts are accepted as-is.
+	async fn parse_address<'a>(&self, address: &'a str) -> sqlx::Result<Option<(&'a str, Option<String>)>> {
+		match self::util::split_address(address) {
+			(local_part, None) => Ok(Some((local_part, None))),
+			(local_part, Some(domain)) => {
+				if sqlx::query_scalar::<_, bool>("SELECT EXISTS(SELECT 1 FROM mailpasswd.domains WHERE name = $1)")
+					.bind(&domain)
+					.fetch_one(&self.db)
+					.await?
+				{
+					Ok(Some((local_part, Some(domain))))
+				} else {
+					Ok(None)
+				}
+			}
+		}
+	}
+
+	pub async fn add_alias(&self, alias: &Alias) -> sqlx::Result<()> {
+		sqlx::query("INSERT INTO mailpasswd.aliases (alias_name, destination, domain) VALUES ($1, $2, $3)")
+			.bind(alias.alias_name.as_str())
+			.bind(alias.destination)
+			.bind(alias.domain.as_deref())
+			.execute(&self.db)
+			.await?;
+
+		Ok(())
+	}
+	pub async fn remove_alias(&self, alias: &Alias) -> sqlx::Result<()> {
+		sqlx::query("DELETE FROM mailpasswd.aliases WHERE alias_name = $1 AND destination = $2 AND domain IS NOT DISTINCT FROM $3")
+			.bind(alias.alias_name.as_str())
+			.bind(alias.destination)
+			.bind(alias.domain.as_deref())
+			.execute(&self.db)
+			.await?;
+
+		Ok(())
+	}
+	/// List all aliases managed by administrators, grouped by their name and domain.
+	///
+	/// Self-service aliases are listed separately, see [`Self::list_all_self_service_aliases`].
+	pub async fn list_all_aliases(&self) -> sqlx::Result<Vec<(String, Option<String>, Vec<Uuid>)>> {
+		sqlx::query_as::<_, (String, Option<String>, Vec<Uuid>)>(
+			"SELECT alias_name, domain, array_agg(destination) FROM mailpasswd.aliases WHERE owner IS NULL GROUP BY alias_name, domain ORDER BY alias_name, domain NULLS FIRST",
+		)
+		.fetch_all(&self.db)
+		.await
+	}
+	/// Resolve an alias, given as a bare name or a full address, into
+	/// the users it delivers to.
+	///
+	/// Expired users are filtered out, as they should be treated as
+	/// if they don't exist. Addresses on unknown domains don't resolve
+	/// to anything.
+	///
+	/// Pattern aliases are taken into account, but never apply to names
+	/// of existing users.
+	#[tracing::instrument]
+	pub async fn lookup_alias(&self, address: &str) -> sqlx::Result<Vec<User>> {
+		match self.match_recipient(address).await? {
+			RecipientMatch::Alias { destinations, .. } => Ok(destinations),
+			RecipientMatch::User(_) | RecipientMatch::NoMatch => Ok(vec![]),
+		}
+	}
+	/// Find out what a name or a full address directly matches,
+	/// without expanding nested aliases.
+	///
+	/// See [`Self::resolve_recipient`] for the full resolution.
+	#[tracing::instrument]
+	pub async fn match_recipient(&self, address: &str) -> sqlx::Result<RecipientMatch> {
+		match self.parse_address(address).await? {
+			Some((local_part, domain)) => match_recipient(&mut *self.db.acquire().await?, local_part, domain.as_deref()).await,
+			None => Ok(RecipientMatch::NoMatch),
+		}
+	}
+	/// Resolve a local part or a full email address into the final set
+	/// of users the mail should be delivered to.
+	///
+	/// This follows the same rules Postfix applies to our virtual alias
+	/// maps:
+	///  - if an alias with this name exists, it shadows the user with
+	///    the same name, and mail goes to the alias destinations instead;
+	///  - subaddresses and pattern aliases are matched as described in
+	///    [`Self::match_recipient`];
+	///  - destinations are expanded recursively, since they can be
+	///    shadowed by aliases themselves;
+	///  - a shadowed user that is a member of its own alias receives a
+	///    copy directly, without being expanded again;
+	///  - otherwise, the mail is delivered to the user itself.
+	///
+	/// Expired users are treated as if they don't exist. Every name is
+	/// only expanded once, so alias loops terminate instead of
+	/// producing duplicates. Nested names are expanded on the same
+	/// domain. An empty result means the mail should bounce, which is
+	/// also the case for addresses on unknown domains.
+	#[tracing::instrument]
+	pub async fn resolve_recipient(&self, address: &str) -> sqlx::Result<Vec<User>> {
+		let (local_part, domain) = match self.parse_address(address).await? {
+			Some(parsed) => parsed,
+			None => return Ok(vec![]),
+		};
+		// See `verify_password` on why we need a transaction here.
+		let mut txn = self.db.begin().await?;
+		sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY")
+			.execute(&mut txn)
+			.await?;
+
+		let mut recipients: Vec<User> = Vec::new();
+		let mut expanded: std::collections::HashSet<String> = std::collections::HashSet::new();
+		let mut queue: std::collections::VecDeque<String> = std::collections::VecDeque::from([local_part.to_owned()]);
+
+		while let Some(name) = queue.pop_front() {
+			if !expanded.insert(name.clone()) {
+				continue;
+			}
+
+			let destinations = match match_recipient(&mut txn, &name, domain.as_deref()).await? {
+				RecipientMatch::Alias { destinations, .. } => destinations,
+				RecipientMatch::User(user) => {
+					if !recipients.iter().any(|r| r.id == user.id) {
+						recipients.push(user);
+					}
+					continue;
+				}
+				RecipientMatch::NoMatch => continue,
+			};
+
+			let base = strip_subaddress(&name).unwrap_or(&name);
+			for user in destinations {
+				if user.username == base {
+					// The shadowed user is a member of the alias.
+					if !recipients.iter().any(|r| r.id == user.id) {
+						recipients.push(user);
+					}
+				} else {
+					queue.push_back(user.username);
+				}
+			}
+		}
+		txn.commit().await?;
+
+		Ok(recipients)
+	}
+	/// List all aliases the user is a destination of.
+	///
+	/// Aliases scoped to a domain are returned as full addresses.
+	#[tracing::instrument]
+	pub async fn list_aliases_for(&self, user: &User) -> sqlx::Result<Vec<String>> {
+		sqlx::query_scalar::<_, String>(
+			"SELECT CASE WHEN domain IS NULL THEN alias_name ELSE alias_name || '@' || domain END FROM mailpasswd.aliases WHERE destination = $1 ORDER BY alias_name, domain NULLS FIRST",
+		)
+		.bind(user.id)
+		.fetch_all(&self.db)
+		.await
+	}
+
+	/// List settings of all aliases that have them.
+	#[tracing::instrument]
+	pub async fn list_alias_info(&self) -> sqlx::Result<Vec<AliasInfo>> {
+		sqlx::query_as::<_, AliasInfo>("SELECT * FROM mailpasswd.alias_info ORDER BY alias_name, domain NULLS FIRST")
+			.fetch_all(&self.db)
+			.await
+	}
+	/// Set the description of an alias, and whether its members may
+	/// remove themselves from it.
+	#[tracing::instrument]
+	pub async fn set_alias_info(&self, info: &AliasInfo) -> sqlx::Result<()> {
+		let mut txn = self.db.begin().await?;
+		sqlx::query("DELETE FROM mailpasswd.alias_info WHERE alias_name = $1 AND domain IS NOT DISTINCT FROM $2")
+			.bind(&info.alias_name)
+			.bind(info.domain.as_deref())
+			.execute(&mut txn)
+			.await?;
+		sqlx::query("INSERT INTO mailpasswd.alias_info (alias_name, domain, description, self_removable) VALUES ($1, $2, $3, $4)")
+			.bind(&info.alias_name)
+			.bind(info.domain.as_deref())
+			.bind(&info.description)
+			.bind(info.self_removable)
+			.execute(&mut txn)
+			.await?;
+		txn.commit().await?;
+
+		Ok(())
+	}
+	/// List all aliases managed by administrators that currently
+	/// deliver mail to the user, including ones that reach the user
+	/// through nested aliases.
+	///
+	/// Only aliases the user actually receives mail from are returned,
+	/// following the same rules as [`Self::resolve_recipient`] on the
+	/// domain the alias is scoped to. Direct memberships come first.
+	#[tracing::instrument]
+	pub async fn list_alias_memberships_for(&self, user: &User) -> sqlx::Result<Vec<AliasMembership>> {
+		// `candidates` walks up from the user's own aliases through
+		// aliases containing a user shadowed by them, `expand` then
+		// resolves every candidate like `resolve_recipient` would. Rows
+		// in `expand` are final if the name is a shadowed member of the
+		// alias it was expanded from.
+		Ok(sqlx::query_as::<_, (String, Option<String>, Option<String>, Option<bool>, Option<String>)>(
+			"WITH RECURSIVE candidates(alias_name, domain, via) AS (
+				SELECT alias_name, domain, NULL::VARCHAR FROM mailpasswd.aliases WHERE destination = $1 AND enabled AND owner IS NULL
+				UNION
+				SELECT parent.alias_name, parent.domain, candidates.alias_name FROM candidates
+					INNER JOIN mailpasswd.userdb ON userdb.username = candidates.alias_name
+					INNER JOIN mailpasswd.aliases parent ON parent.destination = userdb.id
+					WHERE parent.enabled AND parent.owner IS NULL AND (candidates.domain IS NULL OR parent.domain IS NULL OR parent.domain = candidates.domain)
+			), expand(alias_name, domain, name, final) AS (
+				SELECT DISTINCT alias_name, domain, alias_name::VARCHAR, false FROM candidates
+				UNION
+				SELECT expand.alias_name, expand.domain, match.username, match.username = coalesce(nullif(split_part(expand.name, '+', 1), ''), expand.name)
+					FROM expand CROSS JOIN LATERAL mailpasswd.match_alias(expand.name, coalesce(expand.domain, '')) AS match(username)
+					WHERE NOT expand.final
+			)
+			SELECT membership.alias_name, membership.domain, alias_info.description, alias_info.self_removable, membership.via
+			FROM (SELECT DISTINCT ON (alias_name, domain) * FROM candidates ORDER BY alias_name, domain, via NULLS FIRST) membership
+			LEFT JOIN mailpasswd.alias_info ON alias_info.alias_name = membership.alias_name AND alias_info.domain IS NOT DISTINCT FROM membership.domain
+			WHERE EXISTS(
+				SELECT 1 FROM expand WHERE expand.alias_name = membership.alias_name AND expand.domain IS NOT DISTINCT FROM membership.domain AND expand.name = $2
+					AND (expand.final OR NOT EXISTS(SELECT 1 FROM mailpasswd.match_alias($2, coalesce(expand.domain, ''))))
+			) AND EXISTS(SELECT 1 FROM mailpasswd.userdb WHERE id = $1 AND (expires_at IS NULL OR expires_at > now()))
+			ORDER BY membership.via IS NOT NULL, membership.alias_name, membership.domain NULLS FIRST",
+		)
+		.bind(user.id)
+		.bind(&user.username)
+		.fetch_all(&self.db)
+		.await?
+		.into_iter()
+		.map(|(alias_name, domain, description, self_removable, via)| AliasMembership {
+			alias_name,
+			domain,
+			description: description.unwrap_or_default(),
+			self_removable: self_removable.unwrap_or_default(),
+			via,
+		})
+		.collect())
+	}
+	/// Remove the user from an alias they are a direct member of, if
+	/// the alias allows that.
+	///
+	/// Returns `false` if the user isn't a direct member or the alias
+	/// isn't marked as self-removable.
+	#[tracing::instrument]
+	pub async fn leave_alias(&self, user: &User, alias_name: &str, domain: Option<&str>) -> sqlx::Result<bool> {
+		Ok(sqlx::query(
+			"DELETE FROM mailpasswd.aliases WHERE alias_name = $1 AND domain IS NOT DISTINCT FROM $2 AND destination = $3 AND owner IS NULL AND EXISTS(SELECT 1 FROM mailpasswd.alias_info WHERE alias_info.alias_name = aliases.alias_name AND alias_info.domain IS NOT DISTINCT FROM aliases.domain AND self_removable)",
+		)
+		.bind(alias_name)
+		.bind(domain)
+		.bind(user.id)
+		.execute(&self.db)
+		.await?
+		.rows_affected()
+			> 0)
+	}
+
+	/// List admin-approved templates for self-service alias names.
+	#[tracing::instrument]
+	pub async fn list_alias_templates(&self) -> sqlx::Result<Vec<String>> {
+		sqlx::query_scalar::<_, String>("SELECT template FROM mailpasswd.alias_templates ORDER BY template")
+			.fetch_all(&self.db)
+			.await
+	}
+	/// Approve a template for self-service alias names. It must contain
+	/// a `<word>` placeholder, and may contain a `<username>` one.
+	#[tracing::instrument]
+	pub async fn add_alias_template(&self, template: &str) -> sqlx::Result<()> {
+		sqlx::query("INSERT INTO mailpasswd.alias_templates (template) VALUES ($1)")
+			.bind(template)
+			.execute(&self.db)
+			.await?;
+
+		Ok(())
+	}
+	/// Revoke approval of a template. Existing aliases are left as-is.
+	#[tracing::instrument]
+	pub async fn remove_alias_template(&self, template: &str) -> sqlx::Result<()> {
+		sqlx::query("DELETE FROM mailpasswd.alias_templates WHERE template = $1")
+			.bind(template)
+			.execute(&self.db)
+			.await?;
+
+		Ok(())
+	}
+	/// Get the number of self-service aliases a user may have.
+	#[tracing::instrument]
+	pub async fn get_alias_limit(&self, user: Uuid) -> sqlx::Result<i32> {
+		sqlx::query_scalar::<_, i32>("SELECT alias_limit FROM mailpasswd.userdb WHERE id = $1")
+			.bind(user)
+			.fetch_one(&self.db)
+			.await
+	}
+	/// Set the number of self-service aliases a user may have.
+	///
+	/// Lowering the limit doesn't remove existing aliases.
+	#[tracing::instrument]
+	pub async fn set_alias_limit(&self, user: Uuid, limit: i32) -> sqlx::Result<()> {
+		sqlx::query("UPDATE mailpasswd.userdb SET alias_limit = $2 WHERE id = $1")
+			.bind(user)
+			.bind(limit)
+			.execute(&self.db)
+			.await?;
+
+		Ok(())
+	}
+	/// Create a self-service alias pointing to the user.
+	///
+	/// The alias name must not be taken by any other alias or user, so
+	/// that users can't intercept mail not intended for them.
+	#[tracing::instrument]
+	pub async fn create_self_service_alias(
+		&self,
+		user: &User,
+		name: SelfServiceAliasName<'_>,
+	) -> sqlx::Result<SelfServiceAliasResult> {
+		let mut txn = self.db.begin().await?;
+		// Serialize creation of self-service aliases, since the checks
+		// below can't be expressed as constraints. Reading aliases is
+		// still allowed in the meantime.
+		sqlx::query("LOCK TABLE mailpasswd.aliases IN SHARE ROW EXCLUSIVE MODE")
+			.execute(&mut txn)
+			.await?;
+
+		let alias_name = match name {
+			SelfServiceAliasName::Random => self::util::gen_alias_name(&mut rand::rngs::OsRng),
+			SelfServiceAliasName::Template { template, word } => {
+				if !sqlx::query_scalar::<_, bool>("SELECT EXISTS(SELECT 1 FROM mailpasswd.alias_templates WHERE template = $1)")
+					.bind(template)
+					.fetch_one(&mut txn)
+					.await?
+				{
+					return Ok(SelfServiceAliasResult::UnknownTemplate);
+				}
+				if word.is_empty() || !word.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit()) {
+					return Ok(SelfServiceAliasResult::InvalidName);
+				}
+
+				template.replace("<username>", &user.username).replace("<word>", word)
+			}
+		};
+		if alias_name.len() > 64 {
+			return Ok(SelfServiceAliasResult::InvalidName);
+		}
+
+		let (count, limit) = sqlx::query_as::<_, (i64, i32)>(
+			"SELECT (SELECT count(*) FROM mailpasswd.aliases WHERE owner = $1), alias_limit FROM mailpasswd.userdb WHERE id = $1",
+		)
+		.bind(user.id)
+		.fetch_one(&mut txn)
+		.await?;
+		if count >= limit as i64 {
+			return Ok(SelfServiceAliasResult::LimitReached);
+		}
+
+		// Self-service aliases are unscoped, so the name must not be
+		// used by anything else already, including patterns and users
+		// receiving subaddressed mail. Scoped aliases with this name
+		// are considered taken as well.
+		if sqlx::query_scalar::<_, bool>("SELECT EXISTS(SELECT 1 FROM mailpasswd.aliases WHERE alias_name = $1)")
+			.bind(&alias_name)
+			.fetch_one(&mut txn)
+			.await? || !matches!(match_recipient(&mut txn, &alias_name, None).await?, RecipientMatch::NoMatch)
+		{
+			return Ok(SelfServiceAliasResult::NameTaken);
+		}
+
+		sqlx::query("INSERT INTO mailpasswd.aliases (alias_name, destination, owner) VALUES ($1, $2, $2)")
+			.bind(&alias_name)
+			.bind(user.id)
+			.execute(&mut txn)
+			.await?;
+		txn.commit().await?;
+
+		Ok(SelfServiceAliasResult::Created(alias_name))
+	}
+	/// List self-service aliases owned by the user.
+	#[tracing::instrument]
+	pub async fn list_self_service_aliases_for(&self, user: &User) -> sqlx::Result<Vec<SelfServiceAlias>> {
+		sqlx::query_as::<_, SelfServiceAlias>(
+			"SELECT alias_name, owner, enabled, created_at FROM mailpasswd.aliases WHERE owner = $1 ORDER BY created_at",
+		)
+		.bind(user.id)
+		.fetch_all(&self.db)
+		.await
+	}
+	/// List self-service aliases of all users.
+	#[tracing::instrument]
+	pub async fn list_all_self_service_aliases(&self) -> sqlx::Result<Vec<SelfServiceAlias>> {
+		sqlx::query_as::<_, SelfServiceAlias>(
+			"SELECT alias_name, owner, enabled, created_at FROM mailpasswd.aliases WHERE owner IS NOT NULL ORDER BY alias_name",
+		)
+		.fetch_all(&self.db)
+		.await
+	}
+	/// Enable or disable a self-service alias owned by the user.
+	///
+	/// Mail sent to disabled aliases bounces.
+	#[tracing::instrument]
+	pub async fn set_self_service_alias_enabled(&self, user: &User, alias_name: &str, enabled: bool) -> sqlx::Result<()> {
+		sqlx::query("UPDATE mailpasswd.aliases SET enabled = $3 WHERE owner = $1 AND alias_name = $2")
+			.bind(user.id)
+			.bind(alias_name)
+			.bind(enabled)
+			.execute(&self.db)
+			.await?;
+
+		Ok(())
+	}
+	/// Irreversibly remove a self-service alias owned by the user.
+	#[tracing::instrument]
+	pub async fn rm_self_service_alias_for(&self, user: &User, alias_name: &str) -> sqlx::Result<()> {
+		sqlx::query("DELETE FROM mailpasswd.aliases WHERE owner = $1 AND alias_name = $2")
+			.bind(user.id)
+			.bind(alias_name)
+			.execute(&self.db)
+			.await?;
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::AuthenticationResult;
+	use futures::{StreamExt, TryStreamExt};
+
+	pub(crate) fn create_service(pool: sqlx::PgPool) -> crate::Service<super::MigrationsDone> {
+		// Note: you are DEFINITELY NOT SUPPOSED to be creating this
+		// object like that! SQLx test harness automatically applies
+		// migrations, so we don't need to run them a second time.
+		//
+		// Since the typestate field is private, you won't be able to
+		// do it in external code anyway.
+		crate::Service::<super::MigrationsDone> {
+			_migrations: std::marker::PhantomData::<super::MigrationsDone>,
+			db: pool,
+			argon2: argon2::Argon2::new(argon2::Algorithm::Argon2i, Default::default(), Default::default()),
+		}
+	}
+
+	#[sqlx::test]
+	async fn smoke_test(pool: sqlx::PgPool) -> sqlx::Result<()> {
+		let svc = create_service(pool);
+
+		let uuid = svc.create_user("vsh", None, false).await?;
+		let user = svc.find_user_by_name("vsh").await?.unwrap();
+		assert_eq!(user.id, uuid);
+
+		// Check that no passwords are defined
+		assert_eq!(svc.list_passwords_for(&user).await?.len(), 0);
+		assert!(matches!(
+			svc.verify_password(&user.username, "AAAAAAAA").await?,
+			AuthenticationResult::IncorrectPassword
+		));
+		// Generate a password and ensure it matches
+		let password = svc.new_password(&user, "longiflorum", None).await?;
+		assert_eq!(svc.list_passwords_for(&user).await?.len(), 1);
+		assert!(matches!(
+			svc.verify_password(&user.username, &password).await?,
+			AuthenticationResult::Ok
+		));
+		// Ensure something else isn't accepted
+		assert!(matches!(
+			svc.verify_password(&user.username, "AAAAAAAA").await?,
 			AuthenticationResult::IncorrectPassword
 		));
 		// Ensure non-unique labels are rejected for the same user
@@ -433,6 +1749,287 @@ mod test {
 		Ok(())
 	}
 
+	#[sqlx::test]
+	async fn test_set_login_allowed(pool: sqlx::PgPool) -> sqlx::Result<()> {
+		let svc = create_service(pool);
+
+		let uuid = svc.create_user("vsh", None, false).await?;
+		let user = svc.get_user_by_id(uuid).await?.unwrap();
+		let password = svc.new_password(&user, "longiflorum", None).await?;
+		// Setting the flag is idempotent, unlike toggling it
+		svc.set_user_login_allowed(uuid, false).await?;
+		svc.set_user_login_allowed(uuid, false).await?;
+		assert!(!svc.get_user_by_id(uuid).await?.unwrap().login_allowed);
+		assert!(matches!(
+			svc.verify_password("vsh", &password).await?,
+			AuthenticationResult::LoginDisabled
+		));
+		svc.set_user_login_allowed(uuid, true).await?;
+		svc.set_user_login_allowed(uuid, true).await?;
+		assert!(svc.get_user_by_id(uuid).await?.unwrap().login_allowed);
+		assert!(matches!(
+			svc.verify_password("vsh", &password).await?,
+			AuthenticationResult::Ok
+		));
+
+		Ok(())
+	}
+
+	#[sqlx::test]
+	async fn test_authenticate(pool: sqlx::PgPool) -> sqlx::Result<()> {
+		use super::AuthenticationFailure;
+		let svc = create_service(pool.clone());
+
+		let uuid = svc.create_user("vsh", None, false).await?;
+		let user = svc.find_user_by_name("vsh").await?.unwrap();
+		let password = svc.new_password(&user, "longiflorum", None).await?;
+		let another_password = svc.new_password(&user, "tigrinum", None).await?;
+
+		let authenticated = svc.authenticate("vsh", &another_password).await?.unwrap();
+		assert_eq!(authenticated.user.id, uuid);
+		assert_eq!(authenticated.password_label, "tigrinum");
+		assert_eq!(
+			svc.authenticate("vsh", "AAAAAAAA").await?.unwrap_err(),
+			AuthenticationFailure::IncorrectPassword
+		);
+		assert_eq!(
+			svc.authenticate("mvs", &password).await?.unwrap_err(),
+			AuthenticationFailure::NoSuchUser
+		);
+
+		// Expired passwords don't match anymore
+		sqlx::query("UPDATE mailpasswd.passdb SET created_at = now() - interval '2 days', expires_at = now() - interval '1 day' WHERE label = 'longiflorum'")
+			.execute(&pool)
+			.await?;
+		assert_eq!(
+			svc.authenticate("vsh", &password).await?.unwrap_err(),
+			AuthenticationFailure::IncorrectPassword
+		);
+
+		// Expired users don't exist
+		svc.set_user_expiry_date(uuid, Some(chrono::Utc::now().into())).await?;
+		assert_eq!(
+			svc.authenticate("vsh", &another_password).await?.unwrap_err(),
+			AuthenticationFailure::NoSuchUser
+		);
+
+		Ok(())
+	}
+
+	#[sqlx::test]
+	async fn test_api_consumers(pool: sqlx::PgPool) -> sqlx::Result<()> {
+		use super::ApiPermission;
+		let svc = create_service(pool);
+
+		let token = svc.create_api_consumer("dovecot", None, &[ApiPermission::Authenticate]).await?;
+		let dn = |s: &str| s.parse::<super::dn::DistinguishedName>().unwrap();
+		svc.create_api_consumer("radicale", Some(&dn("CN = radicale, O = nyantec GmbH")), &[]).await?;
+
+		let dovecot = svc.find_api_consumer_by_token(&token).await?.unwrap();
+		assert_eq!(dovecot.name, "dovecot");
+		assert!(dovecot.has_permission(ApiPermission::Authenticate));
+		assert!(!dovecot.has_permission(ApiPermission::LookupUsers));
+		assert!(svc.find_api_consumer_by_token("AAAAAAAA").await?.is_none());
+
+		// However the DN was written, it matches the RFC 4514 form certificates are turned into
+		let radicale = svc.find_api_consumer_by_cert_dn(&dn("CN=radicale,O=nyantec GmbH")).await?.unwrap();
+		assert!(radicale.permissions.is_empty());
+		assert_eq!(radicale.cert_dn.as_deref(), Some("CN=radicale,O=nyantec GmbH"));
+		assert!(svc.find_api_consumer_by_cert_dn(&dn("CN=radicale")).await?.is_none());
+		svc.set_api_consumer_permissions(radicale.id, &[ApiPermission::Authenticate, ApiPermission::LookupUsers])
+			.await?;
+		let radicale = svc.find_api_consumer_by_cert_dn(&dn("commonName=radicale;organizationName=nyantec GmbH")).await?.unwrap();
+		assert!(radicale.has_permission(ApiPermission::LookupUsers));
+
+		// Resetting a token invalidates the old one
+		let new_token = svc.reset_api_consumer_token(dovecot.id).await?;
+		assert!(svc.find_api_consumer_by_token(&token).await?.is_none());
+		assert_eq!(svc.find_api_consumer_by_token(&new_token).await?.unwrap().id, dovecot.id);
+
+		svc.rm_api_consumer(dovecot.id).await?;
+		assert_eq!(svc.list_api_consumers().await?.len(), 1);
+
+		// Registering again replaces the token and permissions
+		svc.register_api_consumer("postfix", "first-token-from-a-file", &[ApiPermission::LookupAliases]).await?;
+		svc.register_api_consumer("postfix", "second-token-from-a-file", &[ApiPermission::Authenticate]).await?;
+		assert!(svc.find_api_consumer_by_token("first-token-from-a-file").await?.is_none());
+		let postfix = svc.find_api_consumer_by_token("second-token-from-a-file").await?.unwrap();
+		assert!(postfix.has_permission(ApiPermission::Authenticate));
+		assert!(!postfix.has_permission(ApiPermission::LookupAliases));
+		assert_eq!(svc.list_api_consumers().await?.len(), 2);
+
+		Ok(())
+	}
+
+	#[sqlx::test]
+	async fn test_client_certificates(pool: sqlx::PgPool) -> sqlx::Result<()> {
+		use super::CertificateCheck;
+		let svc = create_service(pool);
+
+		svc.create_user("vsh", None, false).await?;
+		let user = svc.find_user_by_name("vsh").await?.unwrap();
+		let laptop = crate::axum::CertDn::for_test("UID=vsh,CN=laptop", "01", "AA:BB");
+		let phone = crate::axum::CertDn::for_test("UID=vsh,CN=phone", "02", "CC:DD");
+		let subject_only = crate::axum::CertDn::for_test("UID=vsh", "", "");
+
+		// Anything goes until pinning is required
+		assert_eq!(svc.check_client_certificate(&user, &laptop).await?, CertificateCheck::Allowed);
+		assert_eq!(svc.check_client_certificate(&user, &phone).await?, CertificateCheck::Allowed);
+		assert_eq!(svc.check_client_certificate(&user, &subject_only).await?, CertificateCheck::Allowed);
+		let certificates = svc.list_client_certificates_for(user.id).await?;
+		assert_eq!(certificates.len(), 2);
+		assert!(certificates.iter().any(|cert| cert.subject == "UID=vsh,CN=laptop" && cert.serial == "01"));
+		svc.create_user("mvs", None, false).await?;
+		let mvs = svc.find_user_by_name("mvs").await?.unwrap();
+		svc.check_client_certificate(&mvs, &crate::axum::CertDn::for_test("UID=mvs", "03", "EE:FF")).await?;
+		assert_eq!(svc.list_all_client_certificates().await?.len(), 3);
+
+		svc.set_client_certificate_pinned(user.id, "AA:BB", true).await?;
+		svc.toggle_require_pinned_certificate(user.id).await?;
+		assert!(svc.get_require_pinned_certificate(user.id).await?);
+		assert_eq!(svc.check_client_certificate(&user, &laptop).await?, CertificateCheck::Allowed);
+		assert_eq!(svc.check_client_certificate(&user, &phone).await?, CertificateCheck::NotPinned);
+		// Without the whole certificate, we can't tell whether it's pinned
+		assert_eq!(svc.check_client_certificate(&user, &subject_only).await?, CertificateCheck::NotPinned);
+
+		// Revoking beats pinning
+		svc.set_client_certificate_revoked(user.id, "AA:BB", true).await?;
+		assert_eq!(svc.check_client_certificate(&user, &laptop).await?, CertificateCheck::Revoked);
+		svc.toggle_require_pinned_certificate(user.id).await?;
+		assert_eq!(svc.check_client_certificate(&user, &laptop).await?, CertificateCheck::Revoked);
+		assert_eq!(svc.check_client_certificate(&user, &phone).await?, CertificateCheck::Allowed);
+		// Without the whole certificate, it might be the revoked one
+		assert_eq!(svc.check_client_certificate(&user, &subject_only).await?, CertificateCheck::Unidentified);
+		svc.set_require_pinned_certificate(user.id, true).await?;
+		svc.set_require_pinned_certificate(user.id, true).await?;
+		assert!(svc.get_require_pinned_certificate(user.id).await?);
+		assert_eq!(svc.check_client_certificate(&user, &phone).await?, CertificateCheck::NotPinned);
+
+		Ok(())
+	}
+
+	#[sqlx::test]
+	async fn test_passkeys(pool: sqlx::PgPool) -> sqlx::Result<()> {
+		let svc = create_service(pool);
+
+		svc.create_user("vsh", None, false).await?;
+		let user = svc.find_user_by_name("vsh").await?.unwrap();
+		let passkey = crate::webauthn::NewPasskey {
+			credential_id: b"phone".to_vec(),
+			public_key: b"key".to_vec(),
+			sign_count: 1,
+		};
+		svc.add_passkey(&user, "Phone", &passkey).await?;
+		// Names are unique per user
+		assert!(svc.add_passkey(&user, "Phone", &passkey).await.is_err());
+		let passkeys = svc.list_passkeys_for(&user).await?;
+		assert_eq!(passkeys.len(), 1);
+		assert_eq!(passkeys[0].name, "Phone");
+		assert!(passkeys[0].last_used.is_none());
+
+		let passkey = svc.find_passkey(b"phone").await?.unwrap();
+		let token = svc.create_session(&passkey, 2, chrono::Duration::hours(1)).await?;
+		assert_eq!(svc.find_passkey(b"phone").await?.unwrap().sign_count, 2);
+		assert_eq!(svc.find_session(&token).await?.unwrap().user.id, user.id);
+		assert!(svc.find_session("hunter2").await?.is_none());
+		svc.rm_session(&token).await?;
+		assert!(svc.find_session(&token).await?.is_none());
+
+		let expired = svc.create_session(&passkey, 3, chrono::Duration::hours(-1)).await?;
+		assert!(svc.find_session(&expired).await?.is_none());
+
+		// Deleting the passkey logs out
+		let token = svc.create_session(&passkey, 4, chrono::Duration::hours(1)).await?;
+		svc.rm_passkey_for(&user, "Phone").await?;
+		assert!(svc.find_passkey(b"phone").await?.is_none());
+		assert!(svc.find_session(&token).await?.is_none());
+
+		Ok(())
+	}
+
+	#[sqlx::test]
+	async fn test_oidc_sessions(pool: sqlx::PgPool) -> sqlx::Result<()> {
+		let svc = create_service(pool);
+
+		svc.create_user("vsh", None, false).await?;
+		let user = svc.find_user_by_name("vsh").await?.unwrap();
+		svc.add_passkey(
+			&user,
+			"Phone",
+			&crate::webauthn::NewPasskey {
+				credential_id: b"phone".to_vec(),
+				public_key: b"key".to_vec(),
+				sign_count: 0,
+			},
+		)
+		.await?;
+		let passkey = svc.find_passkey(b"phone").await?.unwrap();
+		let passkey_session = svc.create_session(&passkey, 1, chrono::Duration::hours(1)).await?;
+		let oidc_session = svc.create_oidc_session(&user, chrono::Duration::hours(1)).await?;
+		let session = svc.find_session(&oidc_session).await?.unwrap();
+		assert_eq!(session.user.id, user.id);
+		assert_eq!(session.method, crate::LoginMethod::Oidc);
+		assert_eq!(svc.find_session(&passkey_session).await?.unwrap().method, crate::LoginMethod::Passkey);
+		let sessions = svc.list_sessions().await?;
+		assert_eq!(sessions.len(), 2);
+		assert!(sessions.iter().all(|session| session.username == "vsh"));
+		assert!(sessions
+			.iter()
+			.any(|session| session.method == crate::LoginMethod::Passkey && session.passkey.as_deref() == Some("Phone")));
+		assert!(sessions.iter().any(|session| session.method == crate::LoginMethod::Oidc && session.passkey.is_none()));
+
+		// Disabling a login method ends only its sessions
+		assert_eq!(svc.rm_sessions(crate::LoginMethod::Oidc).await?, 1);
+		assert!(svc.find_session(&oidc_session).await?.is_none());
+		assert!(svc.find_session(&passkey_session).await?.is_some());
+
+		Ok(())
+	}
+
+	#[sqlx::test]
+	async fn test_expiring(pool: sqlx::PgPool) -> sqlx::Result<()> {
+		let svc = create_service(pool);
+		let in_days = |days| chrono::DateTime::<chrono::FixedOffset>::from(chrono::Utc::now() + chrono::Duration::days(days));
+
+		let vsh = svc.create_user("vsh", Some(in_days(10)), false).await?;
+		let vsh = svc.get_user_by_id(vsh).await?.unwrap();
+		svc.new_password(&vsh, "thunderbird", Some(in_days(5))).await?;
+		svc.new_password(&vsh, "phone", Some(in_days(60))).await?;
+		svc.new_password(&vsh, "laptop", None).await?;
+		svc.create_user("mvs", Some(in_days(60)), false).await?;
+		// Passwords of expired users don't matter anymore
+		let old = svc.create_user("old", None, false).await?;
+		let old = svc.get_user_by_id(old).await?.unwrap();
+		svc.new_password(&old, "imap", Some(in_days(5))).await?;
+		svc.set_user_expiry_date(old.id, Some(chrono::Utc::now().into())).await?;
+
+		let expiring = svc.list_expiring(in_days(30)).await?;
+		assert_eq!(
+			expiring
+				.iter()
+				.map(|e| (e.username.as_str(), e.password_label.as_deref()))
+				.collect::<Vec<_>>(),
+			vec![("vsh", Some("thunderbird")), ("vsh", None)]
+		);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrations = false)]
+	async fn test_migrations(pool: sqlx::PgPool) -> sqlx::Result<()> {
+		let svc = super::Service::new(pool);
+		let status = svc.migration_status().await?;
+		assert!(!status.is_empty());
+		assert!(status.iter().all(|migration| migration.applied_at.is_none()));
+		let svc = svc.check_migrations().await?.expect_err("migrations are pending");
+
+		let svc = svc.run_migrations().await?;
+		assert!(svc.migration_status().await?.iter().all(|migration| migration.applied_at.is_some()));
+		assert!(super::Service::new(svc.db).check_migrations().await?.is_ok());
+
+		Ok(())
+	}
+
 	#[sqlx::test]
 	async fn test_non_existent_user(pool: sqlx::PgPool) -> sqlx::Result<()> {
 		let svc = create_service(pool);
@@ -441,6 +2038,47 @@ mod test {
 		Ok(())
 	}
 
+	#[sqlx::test]
+	async fn test_search_users(pool: sqlx::PgPool) -> sqlx::Result<()> {
+		use super::{UserQuery, UserStatus};
+		let svc = create_service(pool);
+
+		svc.create_user("vsh", None, false).await?;
+		let mvs = svc.create_user("mvs", None, false).await?;
+		svc.create_user("mak", None, false).await?;
+		svc.create_user("gitlab", None, true).await?;
+		svc.toggle_user_login_allowed(mvs).await?;
+		let expired = svc.create_user("mxx", None, false).await?;
+		svc.set_user_expiry_date(expired, Some(chrono::Utc::now().into())).await?;
+
+		let svc = &svc;
+		let search = |query: UserQuery| async move {
+			svc.search_users(&query)
+				.await
+				.map(|users| users.into_iter().map(|user| user.username).collect::<Vec<_>>())
+		};
+		assert_eq!(search(UserQuery::default()).await?, vec!["gitlab", "mak", "mvs", "vsh"]);
+		assert_eq!(
+			search(UserQuery { status: UserStatus::All, ..Default::default() }).await?,
+			vec!["gitlab", "mak", "mvs", "mxx", "vsh"]
+		);
+		assert_eq!(search(UserQuery { status: UserStatus::Expired, ..Default::default() }).await?, vec!["mxx"]);
+		assert_eq!(search(UserQuery { non_human: Some(true), ..Default::default() }).await?, vec!["gitlab"]);
+		assert_eq!(search(UserQuery { login_allowed: Some(false), ..Default::default() }).await?, vec!["mvs"]);
+		assert_eq!(
+			search(UserQuery { username_prefix: Some("m".to_owned()), ..Default::default() }).await?,
+			vec!["mak", "mvs"]
+		);
+		// Pagination
+		assert_eq!(search(UserQuery { limit: Some(2), ..Default::default() }).await?, vec!["gitlab", "mak"]);
+		assert_eq!(
+			search(UserQuery { limit: Some(2), after: Some("mak".to_owned()), ..Default::default() }).await?,
+			vec!["mvs", "vsh"]
+		);
+
+		Ok(())
+	}
+
 	#[sqlx::test]
 	async fn test_aliases(pool: sqlx::PgPool) -> sqlx::Result<()> {
 		let svc = create_service(pool);
@@ -454,11 +2092,384 @@ mod test {
 			svc.add_alias(&super::Alias {
 				alias_name: "ops".to_string(),
 				destination: *user,
+				domain: None,
+			})
+			.await?;
+		}
+
+		assert_eq!(svc.list_all_aliases().await?, vec![("ops".to_owned(), None, users.to_vec())]);
+
+		Ok(())
+	}
+
+	#[sqlx::test]
+	async fn test_alias_lookup(pool: sqlx::PgPool) -> sqlx::Result<()> {
+		let svc = create_service(pool);
+
+		let vsh = svc.create_user("vsh", None, false).await?;
+		let mvs = svc.create_user("mvs", None, false).await?;
+		for (alias_name, destination) in [("ops", vsh), ("ops", mvs), ("abuse", vsh)] {
+			svc.add_alias(&super::Alias {
+				alias_name: alias_name.to_owned(),
+				destination,
+				domain: None,
+			})
+			.await?;
+		}
+
+		let ops = svc.lookup_alias("ops").await?;
+		assert_eq!(
+			ops.iter().map(|user| user.username.as_str()).collect::<Vec<_>>(),
+			vec!["mvs", "vsh"]
+		);
+		assert!(svc.lookup_alias("postmaster").await?.is_empty());
+
+		let user = svc.get_user_by_id(vsh).await?.unwrap();
+		assert_eq!(svc.list_aliases_for(&user).await?, vec!["abuse".to_owned(), "ops".to_owned()]);
+
+		// Expired users must disappear from aliases
+		svc.set_user_expiry_date(mvs, Some(chrono::Utc::now().into())).await?;
+		let ops = svc.lookup_alias("ops").await?;
+		assert_eq!(ops.len(), 1);
+		assert_eq!(ops[0].id, vsh);
+
+		Ok(())
+	}
+
+	#[sqlx::test]
+	async fn test_resolve_recipient(pool: sqlx::PgPool) -> sqlx::Result<()> {
+		let svc = create_service(pool);
+
+		let vsh = svc.create_user("vsh", None, false).await?;
+		let mvs = svc.create_user("mvs", None, false).await?;
+		let mak = svc.create_user("mak", None, false).await?;
+		let usernames = |users: Vec<super::User>| users.into_iter().map(|user| user.username).collect::<Vec<_>>();
+
+		// Plain users resolve to themselves, unknown names bounce
+		assert_eq!(usernames(svc.resolve_recipient("vsh").await?), vec!["vsh"]);
+		assert!(svc.resolve_recipient("nobody").await?.is_empty());
+
+		for (alias_name, destination) in [
+			// `mvs` is shadowed, but keeps a copy
+			("mvs", mvs),
+			("mvs", mak),
+			// `ops` contains a shadowed user, which gets expanded
+			("ops", vsh),
+			("ops", mvs),
+			// Loops must terminate
+			("loop", mak),
+			("mak", mak),
+			("mak", vsh),
+		] {
+			svc.add_alias(&super::Alias {
+				alias_name: alias_name.to_owned(),
+				destination,
+				domain: None,
+			})
+			.await?;
+		}
+
+		assert_eq!(usernames(svc.resolve_recipient("mvs").await?), vec!["mvs", "mak", "vsh"]);
+		assert_eq!(usernames(svc.resolve_recipient("ops").await?), vec!["mvs", "vsh", "mak"]);
+		assert_eq!(usernames(svc.resolve_recipient("loop").await?), vec!["mak", "vsh"]);
+
+		// Expired users are treated as nonexistent
+		svc.set_user_expiry_date(vsh, Some(chrono::Utc::now().into())).await?;
+		assert!(svc.resolve_recipient("vsh").await?.is_empty());
+		assert!(svc.find_user_by_name("vsh").await?.is_none());
+		assert_eq!(usernames(svc.resolve_recipient("ops").await?), vec!["mvs", "mak"]);
+
+		Ok(())
+	}
+
+	#[sqlx::test]
+	async fn test_domains(pool: sqlx::PgPool) -> sqlx::Result<()> {
+		let svc = create_service(pool);
+
+		let vsh = svc.create_user("vsh", None, false).await?;
+		let mvs = svc.create_user("mvs", None, false).await?;
+		let user = svc.get_user_by_id(vsh).await?.unwrap();
+		let password = svc.new_password(&user, "longiflorum", None).await?;
+		svc.add_domain("nyantec.com").await?;
+		svc.add_domain("Example.ORG").await?;
+		assert_eq!(svc.list_domains().await?, vec!["example.org", "nyantec.com"]);
+		// Registering on startup leaves known domains alone
+		assert!(svc.add_domain("nyantec.com").await.is_err());
+		svc.register_domain("NYANTEC.com").await?;
+		svc.register_domain("nyantec.de").await?;
+		assert_eq!(svc.list_domains().await?, vec!["example.org", "nyantec.com", "nyantec.de"]);
+		svc.remove_domain("nyantec.de").await?;
+
+		// Users are valid on all known domains, and nowhere else
+		assert_eq!(svc.find_user_by_address("vsh").await?.unwrap().id, vsh);
+		assert_eq!(svc.find_user_by_address("vsh@nyantec.com").await?.unwrap().id, vsh);
+		assert_eq!(svc.find_user_by_address("vsh@EXAMPLE.org").await?.unwrap().id, vsh);
+		assert!(svc.find_user_by_address("vsh@evil.example").await?.is_none());
+		assert!(matches!(
+			svc.verify_password("vsh@nyantec.com", &password).await?,
+			AuthenticationResult::Ok
+		));
+		assert!(matches!(
+			svc.verify_password("vsh@evil.example", &password).await?,
+			AuthenticationResult::NoSuchUser
+		));
+
+		// Scoped aliases take precedence over unscoped ones on their domain
+		for (domain, destination) in [(None, vsh), (Some("example.org"), mvs)] {
+			svc.add_alias(&super::Alias {
+				alias_name: "ops".to_owned(),
+				destination,
+				domain: domain.map(str::to_owned),
+			})
+			.await?;
+		}
+		let ids = |users: Vec<super::User>| users.into_iter().map(|user| user.id).collect::<Vec<_>>();
+		assert_eq!(ids(svc.lookup_alias("ops").await?), vec![vsh]);
+		assert_eq!(ids(svc.lookup_alias("ops@nyantec.com").await?), vec![vsh]);
+		assert_eq!(ids(svc.lookup_alias("ops@example.org").await?), vec![mvs]);
+		assert!(svc.lookup_alias("ops@evil.example").await?.is_empty());
+		assert_eq!(ids(svc.resolve_recipient("ops@example.org").await?), vec![mvs]);
+		assert!(svc.resolve_recipient("ops@evil.example").await?.is_empty());
+
+		let user = svc.get_user_by_id(mvs).await?.unwrap();
+		assert_eq!(svc.list_aliases_for(&user).await?, vec!["ops@example.org".to_owned()]);
+
+		// Domains with aliases scoped to them can't be removed
+		svc.remove_domain("example.org").await.unwrap_err();
+		svc.remove_alias(&super::Alias {
+			alias_name: "ops".to_owned(),
+			destination: mvs,
+			domain: Some("example.org".to_owned()),
+		})
+		.await?;
+		svc.remove_domain("example.org").await?;
+		assert_eq!(svc.list_domains().await?, vec!["nyantec.com"]);
+
+		Ok(())
+	}
+
+	#[sqlx::test]
+	async fn test_pattern_aliases(pool: sqlx::PgPool) -> sqlx::Result<()> {
+		let svc = create_service(pool);
+
+		let vsh = svc.create_user("vsh", None, false).await?;
+		let mvs = svc.create_user("mvs", None, false).await?;
+		let mak = svc.create_user("mak", None, false).await?;
+		svc.add_domain("nyantec.com").await?;
+		svc.add_domain("example.org").await?;
+		for (alias_name, domain, destination) in [
+			("*", Some("example.org"), mak),
+			("invoice-*", None, mvs),
+			("invoice-2023-*", None, vsh),
+			("ops", None, mvs),
+		] {
+			svc.add_alias(&super::Alias {
+				alias_name: alias_name.to_owned(),
+				destination,
+				domain: domain.map(str::to_owned),
+			})
+			.await?;
+		}
+		// Wildcards are only allowed at the end
+		svc.add_alias(&super::Alias {
+			alias_name: "in*voice".to_owned(),
+			destination: vsh,
+			domain: None,
+		})
+		.await
+		.unwrap_err();
+
+		let usernames = |users: Vec<super::User>| users.into_iter().map(|user| user.username).collect::<Vec<_>>();
+		// Longest prefix wins
+		assert_eq!(usernames(svc.lookup_alias("invoice-42").await?), vec!["mvs"]);
+		assert_eq!(usernames(svc.lookup_alias("invoice-2023-42").await?), vec!["vsh"]);
+		// Catch-alls only apply on their domain, and never to existing users or exact aliases
+		assert_eq!(usernames(svc.lookup_alias("random@example.org").await?), vec!["mak"]);
+		assert!(svc.lookup_alias("random@nyantec.com").await?.is_empty());
+		assert!(svc.lookup_alias("vsh@example.org").await?.is_empty());
+		assert_eq!(usernames(svc.resolve_recipient("vsh@example.org").await?), vec!["vsh"]);
+		assert_eq!(usernames(svc.resolve_recipient("ops@example.org").await?), vec!["mvs"]);
+		assert_eq!(usernames(svc.resolve_recipient("invoice-1@example.org").await?), vec!["mvs"]);
+		// Subaddresses resolve to the user or alias without them
+		assert_eq!(usernames(svc.resolve_recipient("vsh+lists").await?), vec!["vsh"]);
+		assert_eq!(usernames(svc.resolve_recipient("ops+alerts@example.org").await?), vec!["mvs"]);
+		assert!(matches!(
+			svc.match_recipient("vsh+lists@example.org").await?,
+			super::RecipientMatch::User(user) if user.id == vsh
+		));
+		assert!(matches!(
+			svc.match_recipient("nobody+lists@example.org").await?,
+			super::RecipientMatch::Alias { alias_name, domain: Some(_), .. } if alias_name == "*"
+		));
+		assert!(matches!(
+			svc.match_recipient("nobody+lists").await?,
+			super::RecipientMatch::NoMatch
+		));
+
+		// The lookup function for Postfix follows the same rules, but leaves users to Postfix
+		for (name, domain, expected) in [
+			("invoice-42", "nyantec.com", vec!["mvs"]),
+			("invoice-2023-42", "nyantec.com", vec!["vsh"]),
+			("random", "example.org", vec!["mak"]),
+			("random", "nyantec.com", vec![]),
+			("vsh", "example.org", vec![]),
+			("vsh+lists", "example.org", vec![]),
+			("ops+alerts", "example.org", vec!["mvs"]),
+			("nobody+lists", "example.org", vec!["mak"]),
+			("", "example.org", vec![]),
+		] {
+			let usernames: Vec<String> = sqlx::query_scalar("SELECT * FROM mailpasswd.match_alias($1, $2)")
+				.bind(name)
+				.bind(domain)
+				.fetch_all(&svc.db)
+				.await?;
+			assert_eq!(usernames, expected, "{name}@{domain}");
+		}
+
+		Ok(())
+	}
+
+	#[sqlx::test]
+	async fn test_self_service_aliases(pool: sqlx::PgPool) -> sqlx::Result<()> {
+		use super::{SelfServiceAliasName, SelfServiceAliasResult};
+		let svc = create_service(pool);
+
+		let vsh = svc.create_user("vsh", None, false).await?;
+		svc.create_user("mvs", None, false).await?;
+		let user = svc.get_user_by_id(vsh).await?.unwrap();
+		svc.add_alias_template("<username>-<word>").await?;
+		// Templates without a word are rejected
+		svc.add_alias_template("<username>").await.unwrap_err();
+		let template = |word| SelfServiceAliasName::Template {
+			template: "<username>-<word>",
+			word,
+		};
+
+		// No aliases are allowed by default
+		assert!(matches!(
+			svc.create_self_service_alias(&user, SelfServiceAliasName::Random).await?,
+			SelfServiceAliasResult::LimitReached
+		));
+		svc.set_alias_limit(vsh, 2).await?;
+		assert_eq!(svc.get_alias_limit(vsh).await?, 2);
+
+		let random = match svc.create_self_service_alias(&user, SelfServiceAliasName::Random).await? {
+			SelfServiceAliasResult::Created(alias_name) => alias_name,
+			_ => panic!("random alias should be created"),
+		};
+		assert!(matches!(
+			svc.create_self_service_alias(&user, template("shop")).await?,
+			SelfServiceAliasResult::Created(alias_name) if alias_name == "vsh-shop"
+		));
+		assert!(matches!(
+			svc.create_self_service_alias(&user, template("news")).await?,
+			SelfServiceAliasResult::LimitReached
+		));
+		svc.set_alias_limit(vsh, 10).await?;
+		assert!(matches!(
+			svc.create_self_service_alias(&user, template("shop")).await?,
+			SelfServiceAliasResult::NameTaken
+		));
+		assert!(matches!(
+			svc.create_self_service_alias(&user, template("Sh*p")).await?,
+			SelfServiceAliasResult::InvalidName
+		));
+		// Names covered by patterns are taken as well
+		let mvs = svc.find_user_by_name("mvs").await?.unwrap().id;
+		svc.add_alias(&super::Alias {
+			alias_name: "vsh-invoice*".to_owned(),
+			destination: mvs,
+			domain: None,
+		})
+		.await?;
+		assert!(matches!(
+			svc.create_self_service_alias(&user, template("invoice2023")).await?,
+			SelfServiceAliasResult::NameTaken
+		));
+		assert!(matches!(
+			svc.create_self_service_alias(&user, SelfServiceAliasName::Template { template: "<word>", word: "mvs" }).await?,
+			SelfServiceAliasResult::UnknownTemplate
+		));
+
+		let aliases = svc.list_self_service_aliases_for(&user).await?;
+		assert_eq!(
+			aliases.iter().map(|a| a.alias_name.as_str()).collect::<Vec<_>>(),
+			vec![random.as_str(), "vsh-shop"]
+		);
+		assert_eq!(svc.list_all_self_service_aliases().await?.len(), 2);
+		assert_eq!(svc.list_all_aliases().await?.len(), 1);
+		// Users created later can't take the name either
+		assert!(matches!(
+			svc.create_user("vsh-shop", None, false).await,
+			Err(sqlx::Error::Database(err)) if err.code().as_deref() == Some("23505")
+		));
+		assert_eq!(svc.lookup_alias("vsh-shop").await?[0].id, vsh);
+
+		// Disabled aliases bounce
+		svc.set_self_service_alias_enabled(&user, "vsh-shop", false).await?;
+		assert!(svc.lookup_alias("vsh-shop").await?.is_empty());
+		assert!(svc.resolve_recipient("vsh-shop").await?.is_empty());
+		svc.set_self_service_alias_enabled(&user, "vsh-shop", true).await?;
+		assert_eq!(svc.resolve_recipient("vsh-shop").await?[0].id, vsh);
+
+		svc.rm_self_service_alias_for(&user, "vsh-shop").await?;
+		assert_eq!(svc.list_self_service_aliases_for(&user).await?.len(), 1);
+		assert!(svc.lookup_alias("vsh-shop").await?.is_empty());
+
+		Ok(())
+	}
+
+	#[sqlx::test]
+	async fn test_alias_memberships(pool: sqlx::PgPool) -> sqlx::Result<()> {
+		let svc = create_service(pool);
+
+		let vsh = svc.create_user("vsh", None, false).await?;
+		let mvs = svc.create_user("mvs", None, false).await?;
+		let lead = svc.create_user("ops-lead", None, false).await?;
+		let user = svc.get_user_by_id(vsh).await?.unwrap();
+		svc.add_domain("example.org").await?;
+		for (alias_name, destination, domain) in [
+			("ops-lead", vsh, None),
+			("ops", lead, None),
+			("ops", mvs, None),
+			// `mvs` isn't shadowed, so this doesn't reach `vsh`
+			("abuse", mvs, None),
+			("lists", vsh, None),
+			// `vsh` is shadowed on this domain, so mail to `team` goes to `mvs`
+			("vsh", mvs, Some("example.org")),
+			("team", vsh, Some("example.org")),
+		] {
+			svc.add_alias(&super::Alias {
+				alias_name: alias_name.to_owned(),
+				destination,
+				domain: domain.map(str::to_owned),
 			})
 			.await?;
 		}
+		svc.set_alias_info(&super::AliasInfo {
+			alias_name: "lists".to_owned(),
+			domain: None,
+			description: "Mailing lists".to_owned(),
+			self_removable: true,
+		})
+		.await?;
+
+		let memberships = svc.list_alias_memberships_for(&user).await?;
+		assert_eq!(
+			memberships
+				.iter()
+				.map(|m| (m.alias_name.as_str(), m.via.as_deref(), m.description.as_str()))
+				.collect::<Vec<_>>(),
+			vec![("lists", None, "Mailing lists"), ("ops-lead", None, ""), ("ops", Some("ops-lead"), "")]
+		);
 
-		assert_eq!(svc.list_all_aliases().await?, vec![("ops".to_owned(), users.to_vec())]);
+		// Only self-removable aliases can be left
+		assert!(!svc.leave_alias(&user, "ops-lead", None).await?);
+		assert!(svc.leave_alias(&user, "lists", None).await?);
+		assert!(!svc.leave_alias(&user, "lists", None).await?);
+		assert_eq!(svc.list_alias_memberships_for(&user).await?.len(), 2);
+		// Settings go away with the last member
+		assert!(svc.list_alias_info().await?.is_empty());
 
 		Ok(())
 	}
@@ -0,0 +1,320 @@
+/*
+  Copyright © 2022 nyantec GmbH <oss@nyantec.com>
+  Written by Vika Shleina <vsh@nyantec.com>
+  
+  Provided that these terms and disclaimer and all copyright notices
+  are retained or reproduced in an accompanying document, permission
+  is granted to deal in this work without restriction, including un‐
+  limited rights to use, publicly perform, distribute, sell, modify,
+  merge, give away, or sublicence.
+  
+  This work is provided "AS IS" and WITHOUT WARRANTY of any kind, to
+  the utmost extent permitted by applicable law, neither express nor
+  implied; without malicious intent or gross negligence. In no event
+  may a licensor, author or contributor be held liable for indirect,
+  direct, other damage, loss, or other issues arising in any way out
+  of dealing in the work, even if advised of the possibility of such
+  damage or existence of a defect, except proven that it results out
+  of said person's immediate fault when using the work as intended.
+ */
+//! Logging into the dashboard without a client certificate, with a passkey (see
+//! [`crate::passkeys`]) or through an OpenID Connect provider.
+//!
+//! Either way opens a session, kept in a cookie, which the [`nyanpasswd::User`] extractor accepts
+//! instead of a certificate.
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+	extract::{Query, State},
+	http::{header, HeaderMap, StatusCode},
+	response::{AppendHeaders, IntoResponse},
+};
+use nyanpasswd::axum::{cookie, session_token, SESSION_COOKIE};
+use nyanpasswd::oidc::{Client, OidcError};
+use once_cell::sync::OnceCell;
+use sailfish::TemplateOnce;
+
+use crate::{config, csrf::CsrfToken, Layout, Service};
+
+/// The cookie tying a login through the identity provider to the browser that started it.
+const STATE_COOKIE: &str = "nyanpasswd_oidc_state";
+/// How long users may take to log in at the identity provider.
+const LOGIN_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// A login through the identity provider, kept by the browser in the state cookie, so that anyone
+/// starting to log in doesn't cost us memory.
+#[derive(Debug, PartialEq, Eq)]
+struct PendingLogin {
+	state: String,
+	nonce: String,
+	code_verifier: String,
+}
+
+/// The value of the state cookie for `login`, signed so that it can't be made up.
+fn state_cookie_value(login: &PendingLogin, expires: u64) -> String {
+	let message = format!("{}.{}.{}.{}", expires, login.state, login.nonce, login.code_verifier);
+	let signature = crate::csrf::sign(&format!("oidc-login.{}", message));
+	format!("{}.{}", message, signature)
+}
+
+/// Take the login with the given `state` out of the state cookie `cookie`, if it hasn't expired.
+fn check_state_cookie(cookie: &str, state: &str, now: u64) -> Result<PendingLogin, LoginError> {
+	let Some((message, signature)) = cookie.rsplit_once('.') else {
+		return Err(LoginError::UnknownLogin);
+	};
+	let mut parts = message.splitn(4, '.');
+	let (Some(expires), Some(issued), Some(nonce), Some(code_verifier)) =
+		(parts.next(), parts.next(), parts.next(), parts.next())
+	else {
+		return Err(LoginError::UnknownLogin);
+	};
+	let Ok(expires_at) = expires.parse::<u64>() else {
+		return Err(LoginError::UnknownLogin);
+	};
+	if issued != state
+		|| expires_at <= now
+		|| !crate::csrf::verify_signature(&format!("oidc-login.{}", message), signature)
+	{
+		return Err(LoginError::UnknownLogin);
+	}
+	Ok(PendingLogin {
+		state: issued.to_owned(),
+		nonce: nonce.to_owned(),
+		code_verifier: code_verifier.to_owned(),
+	})
+}
+
+static OIDC_CLIENT: OnceCell<Client> = OnceCell::new();
+
+/// Set up logging in through the identity provider.
+pub(crate) fn init_oidc(settings: nyanpasswd::oidc::Settings) -> Result<(), OidcError> {
+	if OIDC_CLIENT.set(Client::new(settings)?).is_err() {
+		panic!("OpenID Connect client set up twice");
+	}
+	Ok(())
+}
+
+fn oidc() -> Result<(&'static config::Oidc, &'static Client), LoginError> {
+	match (config::get().oidc.as_ref(), OIDC_CLIENT.get()) {
+		(Some(settings), Some(client)) => Ok((settings, client)),
+		_ => Err(LoginError::Disabled),
+	}
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum LoginError {
+	#[error("Logging in this way is not enabled")]
+	Disabled,
+	#[error("The login expired or was started in another browser, please try again")]
+	UnknownLogin,
+	#[error("The identity provider did not log you in: {0}")]
+	Refused(String),
+	#[error("Logging in through the identity provider failed: {0}")]
+	Oidc(#[from] OidcError),
+	#[error("There is no account for {0}")]
+	UnknownUser(String),
+	#[error("Non-human users cannot use the dashboard.")]
+	NonHuman,
+	#[error("SQL layer error: {0}")]
+	Sql(#[from] sqlx::Error),
+}
+
+impl IntoResponse for LoginError {
+	fn into_response(self) -> axum::response::Response {
+		(
+			match &self {
+				Self::Disabled => StatusCode::NOT_FOUND,
+				Self::UnknownLogin => StatusCode::BAD_REQUEST,
+				Self::Refused(_) | Self::UnknownUser(_) | Self::NonHuman => StatusCode::FORBIDDEN,
+				Self::Oidc(_) => StatusCode::BAD_GATEWAY,
+				Self::Sql(_) => StatusCode::INTERNAL_SERVER_ERROR,
+			},
+			[("Content-Type", "text/plain")],
+			self.to_string(),
+		)
+			.into_response()
+	}
+}
+
+#[derive(TemplateOnce)]
+#[template(path = "login.stpl")]
+struct LoginPage {
+	passkeys: bool,
+	/// The name of the identity provider, if users can log in through one.
+	oidc: Option<&'static str>,
+	csrf_token: String,
+}
+
+#[derive(TemplateOnce)]
+#[template(path = "logged_in.stpl")]
+struct LoggedInPage {}
+
+pub(crate) async fn login_page(CsrfToken(csrf_token): CsrfToken) -> Result<axum::response::Response, LoginError> {
+	let config = config::get();
+	if config.passkeys.is_none() && config.oidc.is_none() {
+		return Err(LoginError::Disabled);
+	}
+	Ok(axum::response::Html(
+		Layout {
+			company_name: &config.site.company_name,
+			impressum_link: &config.site.impressum,
+			body: LoginPage {
+				passkeys: config.passkeys.is_some(),
+				oidc: config.oidc.as_ref().map(|oidc| oidc.provider_name.as_str()),
+				csrf_token,
+			},
+		}
+		.render_once()
+		.unwrap(),
+	)
+	.into_response())
+}
+
+/// The `Set-Cookie` header value for a new session.
+pub(crate) fn session_cookie(token: &str, lifetime: chrono::Duration) -> String {
+	format!(
+		"{}={}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Strict",
+		SESSION_COOKIE,
+		token,
+		lifetime.num_seconds()
+	)
+}
+
+pub(crate) async fn begin_oidc_login() -> Result<axum::response::Response, LoginError> {
+	let (_, client) = oidc()?;
+	let authorization = client.begin().await?;
+	let login = PendingLogin {
+		state: authorization.state,
+		nonce: authorization.nonce,
+		code_verifier: authorization.code_verifier,
+	};
+	let expires = crate::csrf::now() + LOGIN_LIFETIME.as_secs();
+
+	// The identity provider sends the user back with a top-level navigation, which only brings
+	// along cookies that aren't strictly same-site
+	Ok((
+		StatusCode::FOUND,
+		[
+			(header::LOCATION, authorization.url),
+			(
+				header::SET_COOKIE,
+				format!(
+					"{}={}; Path=/oidc; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+					STATE_COOKIE,
+					state_cookie_value(&login, expires),
+					LOGIN_LIFETIME.as_secs()
+				),
+			),
+		],
+	)
+		.into_response())
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct Callback {
+	state: Option<String>,
+	code: Option<String>,
+	error: Option<String>,
+	error_description: Option<String>,
+}
+
+pub(crate) async fn finish_oidc_login(
+	State(backend): State<Arc<Service>>,
+	headers: HeaderMap,
+	Query(callback): Query<Callback>,
+) -> Result<axum::response::Response, LoginError> {
+	let (settings, client) = oidc()?;
+	// Only the browser that started logging in can finish, so nobody can be tricked into using
+	// someone else's account
+	let (Some(state), Some(state_cookie)) = (callback.state.as_deref(), cookie(&headers, STATE_COOKIE)) else {
+		return Err(LoginError::UnknownLogin);
+	};
+	let login = check_state_cookie(state_cookie, state, crate::csrf::now())?;
+	let code = match (callback.code, callback.error, callback.error_description) {
+		(Some(code), None, _) => code,
+		(_, Some(error), Some(description)) => return Err(LoginError::Refused(format!("{}: {}", error, description))),
+		(_, Some(error), None) => return Err(LoginError::Refused(error)),
+		(None, None, _) => return Err(LoginError::Refused("no authorization code".to_owned())),
+	};
+	let username = client
+		.finish(&code, &login.nonce, &login.code_verifier)
+		.await
+		.map_err(|err| {
+			tracing::warn!("Rejecting login through the identity provider: {}", err);
+			err
+		})?;
+	let user = backend
+		.find_user_by_name(&username)
+		.await?
+		.ok_or(LoginError::UnknownUser(username))?;
+	if user.non_human {
+		return Err(LoginError::NonHuman);
+	}
+	let token = backend.create_oidc_session(&user, settings.session_lifetime).await?;
+
+	Ok((
+		AppendHeaders([
+			(header::SET_COOKIE, session_cookie(&token, settings.session_lifetime)),
+			(
+				header::SET_COOKIE,
+				format!("{}=; Path=/oidc; Max-Age=0; HttpOnly; Secure; SameSite=Lax", STATE_COOKIE),
+			),
+		]),
+		axum::response::Html(
+			Layout {
+				company_name: &config::get().site.company_name,
+				impressum_link: &config::get().site.impressum,
+				body: LoggedInPage {},
+			}
+			.render_once()
+			.unwrap(),
+		),
+	)
+		.into_response())
+}
+
+pub(crate) async fn logout(
+	State(backend): State<Arc<Service>>,
+	headers: HeaderMap,
+) -> Result<axum::response::Response, LoginError> {
+	if let Some(token) = session_token(&headers) {
+		backend.rm_session(token).await?;
+	}
+	Ok((
+		StatusCode::FOUND,
+		[
+			(header::LOCATION, "/login".to_owned()),
+			(
+				header::SET_COOKIE,
+				format!("{}=; Path=/; Max-Age=0; HttpOnly; Secure; SameSite=Strict", SESSION_COOKIE),
+			),
+		],
+	)
+		.into_response())
+}
+
+#[cfg(test)]
+mod test {
+	use super::{check_state_cookie, state_cookie_value, PendingLogin};
+
+	#[test]
+	fn test_state_cookie() {
+		let now = 1_700_000_000;
+		let login = PendingLogin {
+			state: "c3RhdGU".to_owned(),
+			nonce: "bm9uY2U".to_owned(),
+			code_verifier: "dmVyaWZpZXI".to_owned(),
+		};
+		let cookie = state_cookie_value(&login, now + 60);
+		assert_eq!(check_state_cookie(&cookie, &login.state, now).unwrap(), login);
+		// Expired
+		assert!(check_state_cookie(&cookie, &login.state, now + 60).is_err());
+		// Not the login the identity provider sent back
+		assert!(check_state_cookie(&cookie, "b3RoZXI", now).is_err());
+		// Tampered with
+		assert!(check_state_cookie(&cookie.replacen("bm9uY2U", "b3RoZXI", 1), &login.state, now).is_err());
+		assert!(check_state_cookie(&cookie.replacen(&(now + 60).to_string(), &(now + 600).to_string(), 1), &login.state, now).is_err());
+		assert!(check_state_cookie("", &login.state, now).is_err());
+	}
+}
@@ -26,12 +26,12 @@ use axum::{
 };
 use sailfish::TemplateOnce;
 use sqlx::postgres::PgPoolOptions;
+use nyanpasswd::{config, tls, unix};
 use std::sync::Arc;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-const COMPANY_NAME: &str = "nyantec GmbH";
-const IMPRESSUM: &str = "https://nyantec.com/impressum/";
 const STYLE_CSS: &str = include_str!("../style.css");
+const PASSKEYS_JS: &str = include_str!("../passkeys.js");
 
 #[derive(TemplateOnce)]
 #[template(path = "layout.stpl")]
@@ -47,6 +47,15 @@ struct MainPage {
 	is_admin: bool,
 	user: nyanpasswd::User,
 	passwords: Vec<nyanpasswd::Password>,
+	aliases: Vec<nyanpasswd::SelfServiceAlias>,
+	alias_templates: Vec<String>,
+	alias_limit: i32,
+	memberships: Vec<nyanpasswd::AliasMembership>,
+	/// `None` if passkeys are disabled.
+	passkeys: Option<Vec<nyanpasswd::Passkey>>,
+	/// Whether the user logged in with a passkey or through the identity provider.
+	logged_in_with_session: bool,
+	csrf_token: String,
 }
 
 #[derive(TemplateOnce)]
@@ -66,8 +75,22 @@ type Service = nyanpasswd::Service<nyanpasswd::MigrationsDone>;
 async fn mainpage(
 	State(backend): State<Arc<Service>>,
 	admin: Option<admin::Admin>,
-	user: nyanpasswd::User,
+	certificate: Option<nyanpasswd::axum::CertDn>,
+	user: Result<nyanpasswd::User, nyanpasswd::axum::UserExtractionError>,
+	csrf::CsrfToken(csrf_token): csrf::CsrfToken,
 ) -> axum::response::Response {
+	use nyanpasswd::axum::{CertDnExtractionError, UserExtractionError};
+
+	let user = match user {
+		Ok(user) => user,
+		// Users who can't present a certificate may be able to log in another way
+		Err(UserExtractionError::Certificate(CertDnExtractionError::NoTlsCert) | UserExtractionError::SessionExpired)
+			if config::get().passkeys.is_some() || config::get().oidc.is_some() =>
+		{
+			return (StatusCode::FOUND, [("Location", "/login")]).into_response()
+		}
+		Err(err) => return err.into_response(),
+	};
 	// This is protection against administrators being too powerful.
 	//
 	// Marking a user as non-human disallows dashboard access by design,
@@ -81,25 +104,45 @@ async fn mainpage(
 		)
 			.into_response();
 	}
+	let (passwords, aliases, alias_templates, alias_limit, memberships, passkeys) = match futures::try_join!(
+		backend.list_passwords_for(&user),
+		backend.list_self_service_aliases_for(&user),
+		backend.list_alias_templates(),
+		backend.get_alias_limit(user.id),
+		backend.list_alias_memberships_for(&user),
+		async {
+			match config::get().passkeys {
+				Some(_) => backend.list_passkeys_for(&user).await.map(Some),
+				None => Ok(None),
+			}
+		},
+	) {
+		Ok(data) => data,
+		Err(err) => {
+			return (
+				StatusCode::INTERNAL_SERVER_ERROR,
+				[("Content-Type", "text/plain")],
+				format!("SQL layer error: {}", err),
+			)
+				.into_response()
+		}
+	};
 	axum::response::Html(
 		Layout {
-			company_name: COMPANY_NAME,
+			company_name: &config::get().site.company_name,
 			body: MainPage {
 				is_admin: admin.is_some(),
-				passwords: match backend.list_passwords_for(&user).await {
-					Ok(passwords) => passwords,
-					Err(err) => {
-						return (
-							StatusCode::INTERNAL_SERVER_ERROR,
-							[("Content-Type", "text/plain")],
-							format!("SQL layer error: {}", err),
-						)
-							.into_response()
-					}
-				},
+				passwords,
+				aliases,
+				alias_templates,
+				alias_limit,
+				memberships,
+				passkeys,
+				logged_in_with_session: certificate.is_none(),
 				user,
+				csrf_token,
 			},
-			impressum_link: IMPRESSUM,
+			impressum_link: &config::get().site.impressum,
 		}
 		.render_once()
 		.unwrap(),
@@ -120,9 +163,9 @@ async fn delete_password(
 	match backend.rm_password_for(&user, &form.label).await {
 		Ok(()) => axum::response::Html(
 			Layout {
-				company_name: COMPANY_NAME,
+				company_name: &config::get().site.company_name,
 				body: DeletedPasswordPage { prevlink: None },
-				impressum_link: IMPRESSUM,
+				impressum_link: &config::get().site.impressum,
 			}
 			.render_once()
 			.unwrap(),
@@ -178,9 +221,9 @@ async fn create_password(
 	{
 		Ok(password) => axum::response::Html(
 			Layout {
-				company_name: COMPANY_NAME,
+				company_name: &config::get().site.company_name,
 				body: NewPasswordPage { password, prevlink: None },
-				impressum_link: IMPRESSUM,
+				impressum_link: &config::get().site.impressum,
 			}
 			.render_once()
 			.unwrap(),
@@ -198,12 +241,38 @@ async fn create_password(
 async fn static_file_handler(Path(filename): Path<String>) -> axum::response::Response {
 	match filename.as_str() {
 		"style.css" => (StatusCode::OK, [("Content-Type", "text/css")], STYLE_CSS).into_response(),
+		"passkeys.js" => (StatusCode::OK, [("Content-Type", "text/javascript")], PASSKEYS_JS).into_response(),
 		_ => StatusCode::NOT_FOUND.into_response(),
 	}
 }
 
+/// Respond to a query of a form handler failing.
+///
+/// The database refuses changes to users and aliases managed by the reconciliation file, which
+/// is the admin's to fix, not ours.
+fn sql_error(err: sqlx::Error) -> axum::response::Response {
+	match &err {
+		sqlx::Error::Database(db_err) if nyanpasswd::reconcile::is_managed_violation(&err) => (
+			StatusCode::CONFLICT,
+			[("Content-Type", "text/plain")],
+			format!("{}, change the file and reconcile instead", db_err.message()),
+		)
+			.into_response(),
+		_ => (
+			StatusCode::INTERNAL_SERVER_ERROR,
+			[("Content-Type", "text/plain")],
+			format!("SQL layer error: {}", err),
+		)
+			.into_response(),
+	}
+}
+
 mod admin;
+mod aliases;
 mod api;
+mod csrf;
+mod login;
+mod passkeys;
 
 #[tokio::main]
 async fn main() -> Result<(), hyper::Error> {
@@ -212,16 +281,45 @@ async fn main() -> Result<(), hyper::Error> {
 		.with(tracing_subscriber::fmt::layer().json())
 		.init();
 
-	let backend = match nyanpasswd::Service::new({
-		let database_url = match std::env::var("DATABASE_URL") {
-			Ok(val) => {
-				tracing::info!("Got database URL: {}", val);
-				val
-			}
-			Err(err) => panic!("DATABASE_URL not set or invalid: {}", err),
-		};
+	let config = match config::Config::load() {
+		Ok(config) => config,
+		Err(err) => {
+			eprintln!("Invalid configuration: {}", err);
+			std::process::exit(1);
+		}
+	};
+	tracing::info!("Loaded configuration: {:?}", config);
+	nyanpasswd::axum::set_identity_mapping(config.certificates.identity.clone()).expect("identity mapping was already set");
+	nyanpasswd::axum::set_proxy_header_format(config.certificates.proxy_format).expect("proxy header format was already set");
+	nyanpasswd::axum::set_trusted_proxies(config.certificates.trusted_proxies.clone()).expect("trusted proxies were already set");
+	if let Some(dev) = &config.dev {
+		// Logging may be filtered, so this goes to stderr as well
+		let warning = format!(
+			"DEV MODE IS ENABLED. Anyone can log in as anyone, without a client certificate, as `{}` by default or \
+			 as any other user by sending the `{}` header or `{}` cookie. NEVER use this in production!",
+			dev.default_user,
+			nyanpasswd::axum::DEV_USER_HEADER,
+			nyanpasswd::axum::DEV_USER_COOKIE
+		);
+		eprintln!("\n{}\n{}\n{}\n", "!".repeat(80), warning, "!".repeat(80));
+		tracing::warn!("{}", warning);
+		nyanpasswd::axum::enable_dev_mode(dev.clone()).expect("dev mode was already enabled");
+	}
+	if let Some(oidc) = &config.oidc {
+		if let Err(err) = login::init_oidc(oidc.settings.clone()) {
+			eprintln!("Invalid configuration: {}", err);
+			std::process::exit(1);
+		}
+	}
+	config::init(config);
+	let config = config::get();
 
-		match PgPoolOptions::new().max_connections(5).connect(&database_url).await {
+	let backend = match nyanpasswd::Service::new({
+		match PgPoolOptions::new()
+			.max_connections(config.database.max_connections)
+			.connect(&config.database.url)
+			.await
+		{
 			Ok(db) => {
 				tracing::info!("Connected to the database: {:?}", db);
 				db
@@ -239,15 +337,145 @@ async fn main() -> Result<(), hyper::Error> {
 		Err(err) => panic!("Database migrations failed: {}", err),
 	};
 
-	let app = axum::Router::new()
+	// Sessions shouldn't outlive the login method they were opened with
+	for (method, enabled) in [
+		(nyanpasswd::LoginMethod::Passkey, config.passkeys.is_some()),
+		(nyanpasswd::LoginMethod::Oidc, config.oidc.is_some()),
+	] {
+		if enabled {
+			continue;
+		}
+		match backend.rm_sessions(method).await {
+			Ok(0) => {}
+			Ok(ended) => tracing::info!("Ended {} sessions opened with {:?}, which is disabled", ended, method),
+			Err(err) => panic!("Ending sessions failed: {}", err),
+		}
+	}
+
+	for domain in &config.mail.domains {
+		if let Err(err) = backend.register_domain(domain).await {
+			panic!("Registering domain {} failed: {}", domain, err);
+		}
+	}
+
+	for consumer in &config.api.consumers {
+		match backend.register_api_consumer(&consumer.name, &consumer.token, &consumer.permissions).await {
+			Ok(()) => tracing::info!("Registered API consumer {}", consumer.name),
+			Err(err) => panic!("Registering API consumer {} failed: {}", consumer.name, err),
+		}
+	}
+
+	if let Some(reconcile) = config.reconcile.as_ref().filter(|reconcile| reconcile.on_startup) {
+		let result = match nyanpasswd::reconcile::Manifest::load(&reconcile.file) {
+			Ok(manifest) => backend.reconcile(&manifest, false).await,
+			Err(err) => Err(err),
+		};
+		match result {
+			Ok(changes) => tracing::info!("Reconciled with {}, {} changes", reconcile.file.display(), changes.len()),
+			Err(err) => panic!("Reconciling failed: {}", err),
+		}
+	}
+
+	if config.api.allow_anonymous {
+		tracing::warn!("Anonymous API access is allowed, anyone who can reach the API can use it without authentication");
+	}
+
+	let api_socket = match &config.api.socket {
+		Some(socket) => {
+			let accept = match unix::UnixAccept::bind(&socket.path, socket.mode, socket.group) {
+				Ok(accept) => accept,
+				Err(err) => panic!("Binding the API socket at {:?} failed: {}", socket.path, err),
+			};
+			tracing::info!("Serving the API on {:?}", socket.path);
+			let api = axum::Router::new().nest_service(
+				"/api",
+				api::router(backend.clone(), api::Access::UnixPeers(&socket.peers)),
+			);
+			Some(hyper::server::Server::builder(accept).serve(api.into_make_service_with_connect_info::<unix::PeerCred>()))
+		}
+		None => None,
+	};
+
+	let mut app = axum::Router::new()
 		.route("/", axum::routing::get(mainpage))
 		.route("/delete_password", axum::routing::post(delete_password))
 		.route("/create_password", axum::routing::post(create_password))
+		.route("/aliases/create", axum::routing::post(aliases::create_alias))
+		.route("/aliases/toggle", axum::routing::post(aliases::toggle_alias))
+		.route("/aliases/delete", axum::routing::post(aliases::delete_alias))
+		.route("/aliases/leave", axum::routing::post(aliases::leave_alias))
+		.route("/login", axum::routing::get(login::login_page))
+		.route("/logout", axum::routing::post(login::logout))
+		.route("/oidc/login", axum::routing::get(login::begin_oidc_login))
+		.route("/oidc/callback", axum::routing::get(login::finish_oidc_login))
+		.route("/passkeys/login/begin", axum::routing::post(passkeys::begin_login))
+		.route("/passkeys/login/finish", axum::routing::post(passkeys::finish_login))
+		.route("/passkeys/register/begin", axum::routing::post(passkeys::begin_registration))
+		.route("/passkeys/register/finish", axum::routing::post(passkeys::finish_registration))
+		.route("/passkeys/delete", axum::routing::post(passkeys::delete_passkey))
 		.route("/static/:filename", axum::routing::get(static_file_handler))
-		.nest_service("/admin", admin::router(backend.clone()))
-		.nest_service("/api", api::router(backend.clone()))
-		.with_state(backend);
+		// The API only accepts JSON, which other sites can't send, so it's nested afterwards.
+		// The admin router protects its own forms, since it also serves a JSON API.
+		.layer(axum::middleware::from_fn(csrf::protect))
+		.nest_service("/admin", admin::router(backend.clone()));
+	if config.api.tcp {
+		app = app.nest_service(
+			"/api",
+			api::router(
+				backend.clone(),
+				api::Access::Consumers {
+					allow_anonymous: config.api.allow_anonymous,
+				},
+			),
+		);
+	}
+	let app = app.with_state(backend);
+
+	let server: std::pin::Pin<Box<dyn std::future::Future<Output = Result<(), hyper::Error>>>> = match &config.tls {
+		Some(files) => {
+			let listener = match tokio::net::TcpListener::bind(&config.http.listen).await {
+				Ok(listener) => listener,
+				Err(err) => panic!("Binding to {} failed: {}", config.http.listen, err),
+			};
+			let accept = match tls::TlsAccept::new(listener, files.clone()) {
+				Ok(accept) => accept,
+				Err(err) => panic!("Setting up TLS failed: {}", err),
+			};
+			tracing::info!("Serving TLS on {}", config.http.listen);
+			let app = app.layer(axum::middleware::from_fn(tls::forward_client_certificate));
+			Box::pin(
+				hyper::server::Server::builder(accept)
+					.serve(app.into_make_service_with_connect_info::<tls::ClientCertificate>()),
+			)
+		}
+		// The peer address is needed to check whether certificate headers come from a trusted proxy
+		None => Box::pin(
+			hyper::server::Server::bind(&config.http.listen)
+				.serve(app.into_make_service_with_connect_info::<std::net::SocketAddr>()),
+		),
+	};
+	match api_socket {
+		Some(api_server) => futures::try_join!(server, api_server).map(|_| ()),
+		None => server.await,
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use axum::http::StatusCode;
 
-	let addr = std::net::SocketAddr::from(([127, 0, 0, 1], 3000));
-	hyper::server::Server::bind(&addr).serve(app.into_make_service()).await
+	#[sqlx::test(migrations = false)]
+	async fn test_sql_error(pool: sqlx::PgPool) {
+		let err = sqlx::query(
+			"DO $$ BEGIN
+				RAISE EXCEPTION 'user gitlab is managed by the reconciliation file' USING ERRCODE = '23M01';
+			END $$",
+		)
+		.execute(&pool)
+		.await
+		.unwrap_err();
+		assert_eq!(super::sql_error(err).status(), StatusCode::CONFLICT);
+		let err = sqlx::query("SELECT 1 / 0").execute(&pool).await.unwrap_err();
+		assert_eq!(super::sql_error(err).status(), StatusCode::INTERNAL_SERVER_ERROR);
+	}
 }
@@ -0,0 +1,817 @@
+/*
+  Copyright © 2022 nyantec GmbH <oss@nyantec.com>
+  Written by Vika Shleina <vsh@nyantec.com>
+  
+  Provided that these terms and disclaimer and all copyright notices
+  are retained or reproduced in an accompanying document, permission
+  is granted to deal in this work without restriction, including un‐
+  limited rights to use, publicly perform, distribute, sell, modify,
+  merge, give away, or sublicence.
+  
+  This work is provided "AS IS" and WITHOUT WARRANTY of any kind, to
+  the utmost extent permitted by applicable law, neither express nor
+  implied; without malicious intent or gross negligence. In no event
+  may a licensor, author or contributor be held liable for indirect,
+  direct, other damage, loss, or other issues arising in any way out
+  of dealing in the work, even if advised of the possibility of such
+  damage or existence of a defect, except proven that it results out
+  of said person's immediate fault when using the work as intended.
+ */
+//! An OpenID Connect relying party, for logging into the dashboard through an identity provider.
+//!
+//! This is the authorization code flow with PKCE. ID tokens are checked as OpenID Connect Core
+//! 1.0, section 3.1.3.7 describes, signature included, even though they come straight from the
+//! token endpoint. Only asymmetric signatures are accepted, so that the client secret can't be
+//! used to forge them.
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use hyper::{body::HttpBody, header, Body, Request};
+use serde_json::Value;
+use sha2::Digest;
+use tokio_rustls::rustls;
+
+/// How long a request to the identity provider may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Anything the identity provider sends us is small, so bigger responses are a mistake.
+const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+/// How far our clock may be behind the identity provider's, in seconds.
+const LEEWAY: i64 = 60;
+/// An ID token signed with an unknown key makes us fetch the keys again, but not more often than this.
+const KEYS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum OidcError {
+	#[error("Could not load CA certificates from {0}: {1}")]
+	CaFile(PathBuf, std::io::Error),
+	#[error("Request to {0} failed: {1}")]
+	Request(String, String),
+	#[error("Unexpected response from {0}: {1}")]
+	Response(String, String),
+	#[error("The identity provider calls itself {0}, which is not the configured issuer")]
+	Issuer(String),
+	#[error("Malformed ID token")]
+	MalformedToken,
+	#[error("ID tokens signed with {0} are not supported")]
+	Algorithm(String),
+	#[error("The identity provider has no key to verify the ID token with")]
+	UnknownKey,
+	#[error("The ID token signature is invalid")]
+	Signature,
+	#[error("The ID token's `{0}` claim is invalid")]
+	Claim(&'static str),
+	#[error("The ID token has no `{0}` claim to take the username from")]
+	Username(String),
+}
+
+/// How we're registered with the identity provider.
+#[derive(Clone)]
+pub struct Settings {
+	/// The issuer identifier, which `/.well-known/openid-configuration` is found under.
+	pub issuer: String,
+	pub client_id: String,
+	/// `None` for a public client, which only has PKCE to rely on.
+	pub client_secret: Option<String>,
+	/// Where the identity provider sends users back to.
+	pub redirect_uri: String,
+	/// Scopes to ask for besides `openid`.
+	pub scopes: Vec<String>,
+	/// The ID token claim holding the username.
+	pub username_claim: String,
+	/// CA certificates to trust for the identity provider, instead of the system's.
+	pub ca_file: Option<PathBuf>,
+}
+
+// The client secret shouldn't end up in the logs.
+impl std::fmt::Debug for Settings {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("Settings")
+			.field("issuer", &self.issuer)
+			.field("client_id", &self.client_id)
+			.field("client_secret", &self.client_secret.as_ref().map(|_| "<redacted>"))
+			.field("redirect_uri", &self.redirect_uri)
+			.field("scopes", &self.scopes)
+			.field("username_claim", &self.username_claim)
+			.field("ca_file", &self.ca_file)
+			.finish()
+	}
+}
+
+/// A login in progress. Everything but the URL has to be kept until the user comes back.
+#[derive(Debug)]
+pub struct Authorization {
+	/// Where to send the user.
+	pub url: String,
+	pub state: String,
+	pub nonce: String,
+	pub code_verifier: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Metadata {
+	issuer: String,
+	authorization_endpoint: String,
+	token_endpoint: String,
+	jwks_uri: String,
+}
+
+#[derive(serde::Deserialize)]
+struct TokenResponse {
+	id_token: String,
+}
+
+#[derive(serde::Deserialize)]
+struct ErrorResponse {
+	error: String,
+	error_description: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct JwsHeader {
+	alg: String,
+	kid: Option<String>,
+	crit: Option<Value>,
+}
+
+/// The JWS algorithms we can verify.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Algorithm {
+	Rs256,
+	Rs384,
+	Rs512,
+	Ps256,
+	Ps384,
+	Ps512,
+	Es256,
+	Es384,
+	EdDsa,
+}
+
+impl Algorithm {
+	const ALL: [Algorithm; 9] = [
+		Self::Rs256,
+		Self::Rs384,
+		Self::Rs512,
+		Self::Ps256,
+		Self::Ps384,
+		Self::Ps512,
+		Self::Es256,
+		Self::Es384,
+		Self::EdDsa,
+	];
+
+	fn name(self) -> &'static str {
+		match self {
+			Self::Rs256 => "RS256",
+			Self::Rs384 => "RS384",
+			Self::Rs512 => "RS512",
+			Self::Ps256 => "PS256",
+			Self::Ps384 => "PS384",
+			Self::Ps512 => "PS512",
+			Self::Es256 => "ES256",
+			Self::Es384 => "ES384",
+			Self::EdDsa => "EdDSA",
+		}
+	}
+
+	fn key_type(self) -> &'static str {
+		match self {
+			Self::Rs256 | Self::Rs384 | Self::Rs512 | Self::Ps256 | Self::Ps384 | Self::Ps512 => "RSA",
+			Self::Es256 | Self::Es384 => "EC",
+			Self::EdDsa => "OKP",
+		}
+	}
+}
+
+impl std::str::FromStr for Algorithm {
+	type Err = OidcError;
+
+	fn from_str(name: &str) -> Result<Self, Self::Err> {
+		Self::ALL
+			.into_iter()
+			.find(|algorithm| algorithm.name() == name)
+			.ok_or_else(|| OidcError::Algorithm(name.to_owned()))
+	}
+}
+
+/// A public key from the identity provider's key set.
+#[derive(Debug, serde::Deserialize)]
+struct Jwk {
+	kty: String,
+	kid: Option<String>,
+	#[serde(rename = "use")]
+	usage: Option<String>,
+	alg: Option<String>,
+	crv: Option<String>,
+	n: Option<String>,
+	e: Option<String>,
+	x: Option<String>,
+	y: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct KeySet {
+	keys: Vec<Jwk>,
+}
+
+impl Jwk {
+	/// Whether this key may have made a signature, going by its metadata alone.
+	fn suits(&self, kid: Option<&str>, algorithm: Algorithm) -> bool {
+		self.kty == algorithm.key_type()
+			&& self.usage.as_deref().map_or(true, |usage| usage == "sig")
+			&& self.alg.as_deref().map_or(true, |alg| alg == algorithm.name())
+			&& (kid.is_none() || self.kid.as_deref() == kid)
+	}
+
+	fn verify(&self, algorithm: Algorithm, message: &[u8], signature: &[u8]) -> bool {
+		use ring::signature;
+
+		let component = |value: &Option<String>| value.as_deref().and_then(decode);
+		let curve = |expected: &str| self.crv.as_deref() == Some(expected);
+		let rsa = |parameters: &'static signature::RsaParameters| match (component(&self.n), component(&self.e)) {
+			(Some(n), Some(e)) => signature::RsaPublicKeyComponents { n, e }
+				.verify(parameters, message, signature)
+				.is_ok(),
+			_ => false,
+		};
+		// JWS signatures are r and s concatenated, which ring calls "fixed"
+		let ecdsa = |parameters: &'static signature::EcdsaVerificationAlgorithm| match (component(&self.x), component(&self.y)) {
+			(Some(x), Some(y)) => {
+				let point = [&[4][..], &x, &y].concat();
+				signature::UnparsedPublicKey::new(parameters, point)
+					.verify(message, signature)
+					.is_ok()
+			}
+			_ => false,
+		};
+		match algorithm {
+			Algorithm::Rs256 => rsa(&signature::RSA_PKCS1_2048_8192_SHA256),
+			Algorithm::Rs384 => rsa(&signature::RSA_PKCS1_2048_8192_SHA384),
+			Algorithm::Rs512 => rsa(&signature::RSA_PKCS1_2048_8192_SHA512),
+			Algorithm::Ps256 => rsa(&signature::RSA_PSS_2048_8192_SHA256),
+			Algorithm::Ps384 => rsa(&signature::RSA_PSS_2048_8192_SHA384),
+			Algorithm::Ps512 => rsa(&signature::RSA_PSS_2048_8192_SHA512),
+			Algorithm::Es256 => curve("P-256") && ecdsa(&signature::ECDSA_P256_SHA256_FIXED),
+			Algorithm::Es384 => curve("P-384") && ecdsa(&signature::ECDSA_P384_SHA384_FIXED),
+			Algorithm::EdDsa => {
+				curve("Ed25519")
+					&& component(&self.x).map_or(false, |x| {
+						signature::UnparsedPublicKey::new(&signature::ED25519, x)
+							.verify(message, signature)
+							.is_ok()
+					})
+			}
+		}
+	}
+}
+
+struct Keys {
+	keys: Vec<Jwk>,
+	fetched: Option<Instant>,
+}
+
+fn encode(data: &[u8]) -> String {
+	base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(data)
+}
+
+fn decode(data: &str) -> Option<Vec<u8>> {
+	base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(data).ok()
+}
+
+/// A value nobody can guess, for `state`, `nonce` and the PKCE code verifier.
+fn random() -> String {
+	encode(&rand::random::<[u8; 32]>())
+}
+
+/// Check the claims of an ID token, except for the username.
+fn check_claims(
+	claims: &serde_json::Map<String, Value>,
+	issuer: &str,
+	client_id: &str,
+	nonce: &str,
+	now: i64,
+) -> Result<(), OidcError> {
+	if claims.get("iss").and_then(Value::as_str) != Some(issuer) {
+		return Err(OidcError::Claim("iss"));
+	}
+	let audiences = match claims.get("aud") {
+		Some(Value::String(audience)) => vec![audience.as_str()],
+		Some(Value::Array(audiences)) => audiences.iter().filter_map(Value::as_str).collect(),
+		_ => vec![],
+	};
+	if !audiences.contains(&client_id) {
+		return Err(OidcError::Claim("aud"));
+	}
+	// With several audiences, the token may have been issued to one of the others
+	if let Some(authorized_party) = claims.get("azp") {
+		if authorized_party.as_str() != Some(client_id) {
+			return Err(OidcError::Claim("azp"));
+		}
+	}
+	match claims.get("exp").and_then(Value::as_f64) {
+		Some(expires) if expires as i64 + LEEWAY > now => {}
+		_ => return Err(OidcError::Claim("exp")),
+	}
+	if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+		return Err(OidcError::Claim("nonce"));
+	}
+
+	Ok(())
+}
+
+type HttpClient = hyper::Client<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>>;
+
+/// A client of one identity provider.
+///
+/// The provider's metadata is discovered on first use, and its keys are fetched when needed.
+pub struct Client {
+	settings: Settings,
+	http: HttpClient,
+	metadata: tokio::sync::OnceCell<Metadata>,
+	keys: tokio::sync::Mutex<Keys>,
+}
+
+impl std::fmt::Debug for Client {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("Client").field("settings", &self.settings).finish_non_exhaustive()
+	}
+}
+
+impl Client {
+	pub fn new(settings: Settings) -> Result<Self, OidcError> {
+		let mut roots = rustls::RootCertStore::empty();
+		match &settings.ca_file {
+			Some(path) => {
+				let error = |err| OidcError::CaFile(path.clone(), err);
+				let pem = std::fs::read(path).map_err(error)?;
+				let certificates = rustls_pemfile::certs(&mut pem.as_slice()).map_err(error)?;
+				if roots.add_parsable_certificates(&certificates).0 == 0 {
+					return Err(error(std::io::Error::new(
+						std::io::ErrorKind::InvalidData,
+						"no valid certificates",
+					)));
+				}
+			}
+			// Without any, only an identity provider on plain HTTP can be used, which is fine for testing
+			None => match rustls_native_certs::load_native_certs() {
+				Ok(certificates) => {
+					roots.add_parsable_certificates(&certificates.into_iter().map(|certificate| certificate.0).collect::<Vec<_>>());
+				}
+				Err(err) => tracing::warn!("Could not load the system's CA certificates: {}", err),
+			},
+		}
+		let tls = rustls::ClientConfig::builder()
+			.with_safe_defaults()
+			.with_root_certificates(roots)
+			.with_no_client_auth();
+		let connector = hyper_rustls::HttpsConnectorBuilder::new()
+			.with_tls_config(tls)
+			.https_or_http()
+			.enable_http1()
+			.build();
+
+		Ok(Self {
+			settings,
+			http: hyper::Client::builder().build(connector),
+			metadata: tokio::sync::OnceCell::new(),
+			keys: tokio::sync::Mutex::new(Keys {
+				keys: Vec::new(),
+				fetched: None,
+			}),
+		})
+	}
+
+	async fn send<T: serde::de::DeserializeOwned>(&self, request: Request<Body>) -> Result<T, OidcError> {
+		let url = request.uri().to_string();
+		let response = async {
+			let response = self.http.request(request).await?;
+			let status = response.status();
+			let mut body = response.into_body();
+			let mut data = Vec::new();
+			while let Some(chunk) = body.data().await {
+				data.extend_from_slice(&chunk?);
+				if data.len() > MAX_RESPONSE_SIZE {
+					break;
+				}
+			}
+			Ok::<_, hyper::Error>((status, data))
+		};
+		let (status, data) = tokio::time::timeout(REQUEST_TIMEOUT, response)
+			.await
+			.map_err(|_| OidcError::Request(url.clone(), "timed out".to_owned()))?
+			.map_err(|err| OidcError::Request(url.clone(), err.to_string()))?;
+		if data.len() > MAX_RESPONSE_SIZE {
+			return Err(OidcError::Response(url, "too large".to_owned()));
+		}
+		if !status.is_success() {
+			// The token endpoint explains what's wrong
+			return Err(OidcError::Response(
+				url,
+				match serde_json::from_slice::<ErrorResponse>(&data) {
+					Ok(ErrorResponse {
+						error,
+						error_description: Some(description),
+					}) => format!("{}: {}", error, description),
+					Ok(ErrorResponse { error, .. }) => error,
+					Err(_) => status.to_string(),
+				},
+			));
+		}
+		serde_json::from_slice(&data).map_err(|err| OidcError::Response(url, err.to_string()))
+	}
+
+	async fn get<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T, OidcError> {
+		let request = Request::get(url)
+			.header(header::ACCEPT, "application/json")
+			.body(Body::empty())
+			.map_err(|err| OidcError::Request(url.to_owned(), err.to_string()))?;
+		self.send(request).await
+	}
+
+	async fn metadata(&self) -> Result<&Metadata, OidcError> {
+		self.metadata
+			.get_or_try_init(|| async {
+				let issuer = &self.settings.issuer;
+				let url = format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'));
+				let metadata: Metadata = self.get(&url).await?;
+				if &metadata.issuer != issuer {
+					return Err(OidcError::Issuer(metadata.issuer));
+				}
+				// Nothing may be less secure than the issuer itself
+				for endpoint in [&metadata.authorization_endpoint, &metadata.token_endpoint, &metadata.jwks_uri] {
+					if issuer.starts_with("https://") && !endpoint.starts_with("https://") {
+						return Err(OidcError::Response(url, format!("{} is not an HTTPS URL", endpoint)));
+					}
+				}
+				tracing::info!("Discovered the identity provider: {:?}", metadata);
+				Ok(metadata)
+			})
+			.await
+	}
+
+	/// Start logging in. The user is to be sent to the returned URL.
+	pub async fn begin(&self) -> Result<Authorization, OidcError> {
+		let endpoint = &self.metadata().await?.authorization_endpoint;
+		let (state, nonce, code_verifier) = (random(), random(), random());
+		let scopes = std::iter::once("openid")
+			.chain(self.settings.scopes.iter().map(String::as_str).filter(|scope| *scope != "openid"))
+			.collect::<Vec<_>>()
+			.join(" ");
+		let query = form_urlencoded::Serializer::new(String::new())
+			.append_pair("response_type", "code")
+			.append_pair("client_id", &self.settings.client_id)
+			.append_pair("redirect_uri", &self.settings.redirect_uri)
+			.append_pair("scope", &scopes)
+			.append_pair("state", &state)
+			.append_pair("nonce", &nonce)
+			.append_pair("code_challenge", &encode(&sha2::Sha256::digest(code_verifier.as_bytes())))
+			.append_pair("code_challenge_method", "S256")
+			.finish();
+
+		Ok(Authorization {
+			url: format!("{}{}{}", endpoint, if endpoint.contains('?') { '&' } else { '?' }, query),
+			state,
+			nonce,
+			code_verifier,
+		})
+	}
+
+	/// Redeem the code the user came back with, and return their username.
+	pub async fn finish(&self, code: &str, nonce: &str, code_verifier: &str) -> Result<String, OidcError> {
+		let endpoint = &self.metadata().await?.token_endpoint;
+		// The serializer can't be held across an await
+		let request = {
+			let mut form = form_urlencoded::Serializer::new(String::new());
+			form.append_pair("grant_type", "authorization_code")
+				.append_pair("code", code)
+				.append_pair("redirect_uri", &self.settings.redirect_uri)
+				.append_pair("code_verifier", code_verifier);
+			let mut request = Request::post(endpoint)
+				.header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+				.header(header::ACCEPT, "application/json");
+			match &self.settings.client_secret {
+				// `client_secret_basic`, which every identity provider supports
+				Some(secret) => {
+					let credentials = format!(
+						"{}:{}",
+						form_urlencoded::byte_serialize(self.settings.client_id.as_bytes()).collect::<String>(),
+						form_urlencoded::byte_serialize(secret.as_bytes()).collect::<String>()
+					);
+					request = request.header(
+						header::AUTHORIZATION,
+						format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials)),
+					);
+				}
+				None => {
+					form.append_pair("client_id", &self.settings.client_id);
+				}
+			}
+			request.body(Body::from(form.finish()))
+		}
+		.map_err(|err| OidcError::Request(endpoint.to_owned(), err.to_string()))?;
+		let response: TokenResponse = self.send(request).await?;
+
+		let claims = self.verify_id_token(&response.id_token, nonce).await?;
+		match claims.get(&self.settings.username_claim) {
+			Some(Value::String(username)) if !username.is_empty() => Ok(username.clone()),
+			_ => Err(OidcError::Username(self.settings.username_claim.clone())),
+		}
+	}
+
+	async fn verify_id_token(&self, token: &str, nonce: &str) -> Result<serde_json::Map<String, Value>, OidcError> {
+		let (message, signature) = token.rsplit_once('.').ok_or(OidcError::MalformedToken)?;
+		let (header, claims) = message.split_once('.').ok_or(OidcError::MalformedToken)?;
+		let header: JwsHeader = decode(header)
+			.and_then(|header| serde_json::from_slice(&header).ok())
+			.ok_or(OidcError::MalformedToken)?;
+		let claims: serde_json::Map<String, Value> = decode(claims)
+			.and_then(|claims| serde_json::from_slice(&claims).ok())
+			.ok_or(OidcError::MalformedToken)?;
+		let signature = decode(signature).ok_or(OidcError::MalformedToken)?;
+		// We don't know any extensions
+		if header.crit.is_some() {
+			return Err(OidcError::MalformedToken);
+		}
+		let algorithm = header.alg.parse()?;
+		self.verify_signature(header.kid.as_deref(), algorithm, message.as_bytes(), &signature)
+			.await?;
+		check_claims(
+			&claims,
+			&self.settings.issuer,
+			&self.settings.client_id,
+			nonce,
+			chrono::Utc::now().timestamp(),
+		)?;
+
+		Ok(claims)
+	}
+
+	async fn verify_signature(
+		&self,
+		kid: Option<&str>,
+		algorithm: Algorithm,
+		message: &[u8],
+		signature: &[u8],
+	) -> Result<(), OidcError> {
+		let jwks_uri = &self.metadata().await?.jwks_uri;
+		let mut keys = self.keys.lock().await;
+		// Identity providers rotate their keys, so a key we don't know may be a new one
+		if !keys.keys.iter().any(|key| key.suits(kid, algorithm))
+			&& keys.fetched.map_or(true, |fetched| fetched.elapsed() >= KEYS_REFRESH_INTERVAL)
+		{
+			let key_set: KeySet = self.get(jwks_uri).await?;
+			*keys = Keys {
+				keys: key_set.keys,
+				fetched: Some(Instant::now()),
+			};
+		}
+		let mut candidates = keys.keys.iter().filter(|key| key.suits(kid, algorithm)).peekable();
+		if candidates.peek().is_none() {
+			return Err(OidcError::UnknownKey);
+		}
+		match candidates.any(|key| key.verify(algorithm, message, signature)) {
+			true => Ok(()),
+			false => Err(OidcError::Signature),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::{check_claims, encode, random, Client, OidcError, Settings};
+	use axum::{
+		extract::State,
+		http::{HeaderMap, StatusCode},
+		routing::{get, post},
+		Form, Json,
+	};
+	use base64::Engine;
+	use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+	use serde_json::{json, Value};
+	use sha2::Digest;
+	use std::collections::HashMap;
+	use std::sync::{Arc, Mutex};
+
+	/// A local identity provider, handing out ID tokens with whatever claims a test wants.
+	struct MockIdp {
+		issuer: String,
+		key: EcdsaKeyPair,
+		claims: Mutex<Value>,
+		/// The PKCE challenge of the login in progress.
+		code_challenge: Mutex<String>,
+	}
+
+	impl MockIdp {
+		fn sign(&self, kid: &str, claims: &Value) -> String {
+			let message = format!(
+				"{}.{}",
+				encode(json!({ "alg": "ES256", "kid": kid }).to_string().as_bytes()),
+				encode(claims.to_string().as_bytes())
+			);
+			let signature = self.key.sign(&ring::rand::SystemRandom::new(), message.as_bytes()).unwrap();
+			format!("{}.{}", message, encode(signature.as_ref()))
+		}
+
+		fn claims(&self, nonce: &str) -> Value {
+			let now = chrono::Utc::now().timestamp();
+			json!({
+				"iss": self.issuer,
+				"sub": "8c3b6e4e-5f4b-4bde-9b0e-0e0d8f0c2a41",
+				"aud": "nyanpasswd",
+				"exp": now + 300,
+				"iat": now,
+				"nonce": nonce,
+				"preferred_username": "vsh",
+			})
+		}
+	}
+
+	async fn token(
+		State(idp): State<Arc<MockIdp>>,
+		headers: HeaderMap,
+		Form(form): Form<HashMap<String, String>>,
+	) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+		let error = |status, error: &str| Err((status, Json(json!({ "error": error, "error_description": "Mock says no" }))));
+		let credentials = base64::engine::general_purpose::STANDARD.encode("nyanpasswd:hunter2");
+		if headers.get("authorization").and_then(|value| value.to_str().ok()) != Some(&format!("Basic {}", credentials)) {
+			return error(StatusCode::UNAUTHORIZED, "invalid_client");
+		}
+		let field = |name: &str| form.get(name).map(String::as_str).unwrap_or_default();
+		if field("grant_type") != "authorization_code"
+			|| field("code") != "letmein"
+			|| field("redirect_uri") != "https://mail.nyantec.com/oidc/callback"
+			|| encode(&sha2::Sha256::digest(field("code_verifier"))) != *idp.code_challenge.lock().unwrap()
+		{
+			return error(StatusCode::BAD_REQUEST, "invalid_grant");
+		}
+		Ok(Json(json!({
+			"access_token": "opaque",
+			"token_type": "Bearer",
+			"id_token": idp.sign("test", &idp.claims.lock().unwrap()),
+		})))
+	}
+
+	async fn start_idp() -> Arc<MockIdp> {
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let rng = ring::rand::SystemRandom::new();
+		let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+		let idp = Arc::new(MockIdp {
+			issuer: format!("http://{}/realms/test", listener.local_addr().unwrap()),
+			key: EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng).unwrap(),
+			claims: Mutex::new(Value::Null),
+			code_challenge: Mutex::new(String::new()),
+		});
+		let router = axum::Router::new()
+			.route(
+				"/realms/test/.well-known/openid-configuration",
+				get(|State(idp): State<Arc<MockIdp>>| async move {
+					Json(json!({
+						"issuer": idp.issuer,
+						"authorization_endpoint": format!("{}/auth", idp.issuer),
+						"token_endpoint": format!("{}/token", idp.issuer),
+						"jwks_uri": format!("{}/certs", idp.issuer),
+					}))
+				}),
+			)
+			.route(
+				"/realms/test/certs",
+				get(|State(idp): State<Arc<MockIdp>>| async move {
+					let point = idp.key.public_key().as_ref();
+					Json(json!({ "keys": [
+						{ "kty": "oct", "k": "aHVudGVyMg" },
+						{ "kty": "EC", "crv": "P-256", "kid": "test", "use": "sig", "x": encode(&point[1..33]), "y": encode(&point[33..]) },
+					] }))
+				}),
+			)
+			.route("/realms/test/token", post(token))
+			.with_state(idp.clone());
+		tokio::spawn(hyper::Server::from_tcp(listener).unwrap().serve(router.into_make_service()));
+		idp
+	}
+
+	fn settings(issuer: &str) -> Settings {
+		Settings {
+			issuer: issuer.to_owned(),
+			client_id: "nyanpasswd".to_owned(),
+			client_secret: Some("hunter2".to_owned()),
+			redirect_uri: "https://mail.nyantec.com/oidc/callback".to_owned(),
+			scopes: vec!["profile".to_owned()],
+			username_claim: "preferred_username".to_owned(),
+			ca_file: None,
+		}
+	}
+
+	#[tokio::test]
+	async fn test_login() {
+		let idp = start_idp().await;
+		let client = Client::new(settings(&idp.issuer)).unwrap();
+		let authorization = client.begin().await.unwrap();
+		let (endpoint, query) = authorization.url.split_once('?').unwrap();
+		assert_eq!(endpoint, format!("{}/auth", idp.issuer));
+		let query: HashMap<String, String> = form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+		assert_eq!(query["response_type"], "code");
+		assert_eq!(query["client_id"], "nyanpasswd");
+		assert_eq!(query["redirect_uri"], "https://mail.nyantec.com/oidc/callback");
+		assert_eq!(query["scope"], "openid profile");
+		assert_eq!(query["state"], authorization.state);
+		assert_eq!(query["nonce"], authorization.nonce);
+		assert_eq!(query["code_challenge_method"], "S256");
+
+		*idp.code_challenge.lock().unwrap() = query["code_challenge"].clone();
+		*idp.claims.lock().unwrap() = idp.claims(&authorization.nonce);
+		let finish = |nonce: &str, code_verifier: &str| {
+			let (client, nonce, code_verifier) = (&client, nonce.to_owned(), code_verifier.to_owned());
+			async move { client.finish("letmein", &nonce, &code_verifier).await }
+		};
+		assert_eq!(finish(&authorization.nonce, &authorization.code_verifier).await.unwrap(), "vsh");
+		// Only we know the code verifier, so nobody else can redeem the code
+		assert!(matches!(
+			finish(&authorization.nonce, &random()).await,
+			Err(OidcError::Response(_, reason)) if reason == "invalid_grant: Mock says no"
+		));
+		// The nonce ties the ID token to this login
+		assert!(matches!(
+			finish(&random(), &authorization.code_verifier).await,
+			Err(OidcError::Claim("nonce"))
+		));
+
+		idp.claims.lock().unwrap().as_object_mut().unwrap().remove("preferred_username");
+		assert!(matches!(
+			finish(&authorization.nonce, &authorization.code_verifier).await,
+			Err(OidcError::Username(claim)) if claim == "preferred_username"
+		));
+	}
+
+	#[tokio::test]
+	async fn test_id_token_signature() {
+		let idp = start_idp().await;
+		let client = Client::new(settings(&idp.issuer)).unwrap();
+		let claims = idp.claims("nonce");
+		let token = idp.sign("test", &claims);
+		assert!(client.verify_id_token(&token, "nonce").await.is_ok());
+
+		let (header, rest) = token.split_once('.').unwrap();
+		let (_, signature) = rest.split_once('.').unwrap();
+		let mut forged = claims.clone();
+		forged["preferred_username"] = json!("root");
+		let forged = encode(forged.to_string().as_bytes());
+		assert!(matches!(
+			client.verify_id_token(&format!("{}.{}.{}", header, forged, signature), "nonce").await,
+			Err(OidcError::Signature)
+		));
+		for alg in ["none", "HS256"] {
+			let header = encode(json!({ "alg": alg }).to_string().as_bytes());
+			assert!(matches!(
+				client.verify_id_token(&format!("{}.{}.", header, forged), "nonce").await,
+				Err(OidcError::Algorithm(name)) if name == alg
+			));
+		}
+		assert!(matches!(
+			client.verify_id_token(&idp.sign("rotated", &claims), "nonce").await,
+			Err(OidcError::UnknownKey)
+		));
+		assert!(matches!(
+			client.verify_id_token("not.a-token", "nonce").await,
+			Err(OidcError::MalformedToken)
+		));
+	}
+
+	#[tokio::test]
+	async fn test_discovery() {
+		let idp = start_idp().await;
+		// Issuers have to match exactly
+		let client = Client::new(settings(&format!("{}/", idp.issuer))).unwrap();
+		assert!(matches!(client.begin().await, Err(OidcError::Issuer(issuer)) if issuer == idp.issuer));
+	}
+
+	#[test]
+	fn test_check_claims() {
+		let check = |changes: Value, now: i64| {
+			let mut claims = json!({
+				"iss": "https://id.nyantec.com",
+				"aud": ["nyanpasswd", "mail"],
+				"azp": "nyanpasswd",
+				"exp": 1000,
+				"nonce": "nonce",
+			});
+			let claims = claims.as_object_mut().unwrap();
+			// `null` stands for leaving a claim out
+			for (claim, value) in changes.as_object().unwrap() {
+				match value {
+					Value::Null => claims.remove(claim),
+					value => claims.insert(claim.clone(), value.clone()),
+				};
+			}
+			check_claims(claims, "https://id.nyantec.com", "nyanpasswd", "nonce", now)
+		};
+		assert!(check(json!({}), 1000).is_ok());
+		assert!(check(json!({ "aud": "nyanpasswd", "azp": null }), 1000).is_ok());
+		assert!(matches!(check(json!({}), 1060), Err(OidcError::Claim("exp"))));
+		assert!(matches!(check(json!({ "exp": null }), 0), Err(OidcError::Claim("exp"))));
+		assert!(matches!(check(json!({ "iss": "https://evil.example" }), 0), Err(OidcError::Claim("iss"))));
+		assert!(matches!(check(json!({ "aud": ["mail"] }), 0), Err(OidcError::Claim("aud"))));
+		assert!(matches!(check(json!({ "azp": "mail" }), 0), Err(OidcError::Claim("azp"))));
+		assert!(matches!(check(json!({ "nonce": "other" }), 0), Err(OidcError::Claim("nonce"))));
+	}
+}
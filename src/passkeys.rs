@@ -0,0 +1,354 @@
+/*
+  Copyright © 2022 nyantec GmbH <oss@nyantec.com>
+  Written by Vika Shleina <vsh@nyantec.com>
+  
+  Provided that these terms and disclaimer and all copyright notices
+  are retained or reproduced in an accompanying document, permission
+  is granted to deal in this work without restriction, including un‐
+  limited rights to use, publicly perform, distribute, sell, modify,
+  merge, give away, or sublicence.
+  
+  This work is provided "AS IS" and WITHOUT WARRANTY of any kind, to
+  the utmost extent permitted by applicable law, neither express nor
+  implied; without malicious intent or gross negligence. In no event
+  may a licensor, author or contributor be held liable for indirect,
+  direct, other damage, loss, or other issues arising in any way out
+  of dealing in the work, even if advised of the possibility of such
+  damage or existence of a defect, except proven that it results out
+  of said person's immediate fault when using the work as intended.
+ */
+//! Logging into the dashboard with passkeys, for devices that can't hold a client certificate.
+//!
+//! Passkeys are registered while logged in with a client certificate. Logging in with one opens
+//! a session, see [`crate::login`].
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+	extract::State,
+	http::{header, HeaderMap, StatusCode},
+	response::IntoResponse,
+	Form, Json,
+};
+use nyanpasswd::axum::CertDn;
+use nyanpasswd::webauthn::{self, WebauthnError};
+use uuid::Uuid;
+
+use crate::{config, Service};
+
+/// How long a challenge can be answered. Browsers give up after [`webauthn::TIMEOUT`].
+const CHALLENGE_LIFETIME: Duration = Duration::from_secs(webauthn::TIMEOUT as u64 / 1000 + 60);
+/// Registration challenges are remembered until they're answered, so there's a limit on how many.
+const MAX_PENDING_CHALLENGES: usize = 10_000;
+/// The cookie a login challenge is handed out in.
+const LOGIN_COOKIE: &str = "nyanpasswd_passkey_login";
+
+struct PendingChallenge {
+	user: Uuid,
+	expires: Instant,
+}
+
+lazy_static::lazy_static! {
+	static ref CHALLENGES: Mutex<HashMap<Vec<u8>, PendingChallenge>> = Mutex::new(HashMap::new());
+}
+
+fn issue_challenge(user: Uuid) -> Result<Vec<u8>, PasskeyError> {
+	let mut pending = CHALLENGES.lock().unwrap();
+	let now = Instant::now();
+	pending.retain(|_, challenge| challenge.expires > now);
+	if pending.len() >= MAX_PENDING_CHALLENGES {
+		return Err(PasskeyError::TooManyChallenges);
+	}
+	let challenge = webauthn::challenge();
+	pending.insert(
+		challenge.clone(),
+		PendingChallenge {
+			user,
+			expires: now + CHALLENGE_LIFETIME,
+		},
+	);
+	Ok(challenge)
+}
+
+/// Take a challenge out of the pending ones, so that it can only be answered once.
+fn redeem_challenge(challenge: &str, user: Uuid) -> Result<Vec<u8>, PasskeyError> {
+	let challenge = webauthn::decode(challenge).ok_or(PasskeyError::UnknownChallenge)?;
+	match CHALLENGES.lock().unwrap().remove(&challenge) {
+		Some(pending) if pending.user == user && pending.expires > Instant::now() => Ok(challenge),
+		_ => Err(PasskeyError::UnknownChallenge),
+	}
+}
+
+/// Sign a login challenge expiring at `expires`, in seconds since the epoch, for [`LOGIN_COOKIE`].
+///
+/// Login challenges are handed out to anyone, so instead of remembering them, the browser keeps
+/// them until it answers.
+fn login_cookie_value(challenge: &str, expires: u64) -> String {
+	let signature = crate::csrf::sign(&format!("passkey-login.{}.{}", expires, challenge));
+	format!("{}.{}.{}", expires, challenge, signature)
+}
+
+/// Check that `challenge` is the one handed out in the login cookie `cookie`, and hasn't expired.
+fn check_login_cookie(cookie: &str, challenge: &str, now: u64) -> Result<Vec<u8>, PasskeyError> {
+	let mut parts = cookie.splitn(3, '.');
+	let (Some(expires), Some(issued), Some(signature)) = (parts.next(), parts.next(), parts.next()) else {
+		return Err(PasskeyError::UnknownChallenge);
+	};
+	let Ok(expires_at) = expires.parse::<u64>() else {
+		return Err(PasskeyError::UnknownChallenge);
+	};
+	if issued != challenge
+		|| expires_at <= now
+		|| !crate::csrf::verify_signature(&format!("passkey-login.{}.{}", expires, issued), signature)
+	{
+		return Err(PasskeyError::UnknownChallenge);
+	}
+	webauthn::decode(challenge).ok_or(PasskeyError::UnknownChallenge)
+}
+
+fn settings() -> Result<&'static config::Passkeys, PasskeyError> {
+	config::get().passkeys.as_ref().ok_or(PasskeyError::Disabled)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum PasskeyError {
+	#[error("Passkeys are not enabled")]
+	Disabled,
+	#[error("Passkeys can only be managed when logged in with a client certificate")]
+	CertificateRequired,
+	#[error("Too many passkey registrations in progress, please try again later")]
+	TooManyChallenges,
+	#[error("The request expired or was already used, please try again")]
+	UnknownChallenge,
+	#[error("Malformed {0}")]
+	Malformed(&'static str),
+	#[error("This passkey is not registered")]
+	UnknownPasskey,
+	#[error("A passkey with this name is already registered")]
+	NameTaken,
+	#[error("Passkey verification failed: {0}")]
+	Webauthn(#[from] WebauthnError),
+	#[error("{0}")]
+	User(#[from] nyanpasswd::axum::UserExtractionError),
+	#[error("SQL layer error: {0}")]
+	Sql(#[from] sqlx::Error),
+}
+
+impl IntoResponse for PasskeyError {
+	fn into_response(self) -> axum::response::Response {
+		(
+			match &self {
+				Self::Disabled => StatusCode::NOT_FOUND,
+				Self::CertificateRequired | Self::Webauthn(_) => StatusCode::FORBIDDEN,
+				Self::TooManyChallenges => StatusCode::SERVICE_UNAVAILABLE,
+				Self::UnknownChallenge | Self::Malformed(_) => StatusCode::BAD_REQUEST,
+				Self::UnknownPasskey => StatusCode::UNAUTHORIZED,
+				Self::NameTaken => StatusCode::CONFLICT,
+				Self::User(err) => StatusCode::from(err),
+				Self::Sql(_) => StatusCode::INTERNAL_SERVER_ERROR,
+			},
+			[("Content-Type", "text/plain")],
+			self.to_string(),
+		)
+			.into_response()
+	}
+}
+
+fn decode(field: &'static str, data: &str) -> Result<Vec<u8>, PasskeyError> {
+	webauthn::decode(data).ok_or(PasskeyError::Malformed(field))
+}
+
+type UserResult = Result<nyanpasswd::User, nyanpasswd::axum::UserExtractionError>;
+
+/// The user logged in with a client certificate, who may manage their passkeys.
+///
+/// Someone who got hold of a session shouldn't be able to add passkeys of their own.
+fn certificate_user(certificate: Option<CertDn>, user: UserResult) -> Result<nyanpasswd::User, PasskeyError> {
+	certificate.ok_or(PasskeyError::CertificateRequired)?;
+	Ok(user?)
+}
+
+pub(crate) async fn begin_registration(
+	State(backend): State<Arc<Service>>,
+	certificate: Option<CertDn>,
+	user: UserResult,
+) -> Result<Json<serde_json::Value>, PasskeyError> {
+	let settings = settings()?;
+	let user = certificate_user(certificate, user)?;
+	let passkeys = backend.list_passkeys_for(&user).await?;
+	let challenge = issue_challenge(user.id)?;
+
+	// Options for `navigator.credentials.create()`, with binary data encoded as in WebAuthn JSON
+	Ok(Json(serde_json::json!({
+		"challenge": webauthn::encode(&challenge),
+		"rp": { "id": settings.relying_party.id, "name": settings.relying_party.name },
+		"user": {
+			"id": webauthn::encode(user.id.as_bytes()),
+			"name": user.username,
+			"displayName": user.username,
+		},
+		"pubKeyCredParams": webauthn::ALGORITHMS
+			.iter()
+			.map(|alg| serde_json::json!({ "type": "public-key", "alg": alg }))
+			.collect::<Vec<_>>(),
+		"excludeCredentials": passkeys
+			.iter()
+			.map(|passkey| serde_json::json!({ "type": "public-key", "id": webauthn::encode(&passkey.credential_id) }))
+			.collect::<Vec<_>>(),
+		"authenticatorSelection": { "residentKey": "required", "userVerification": "required" },
+		"attestation": "none",
+		"timeout": webauthn::TIMEOUT,
+	})))
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RegistrationForm {
+	name: String,
+	challenge: String,
+	client_data_json: String,
+	attestation_object: String,
+}
+
+pub(crate) async fn finish_registration(
+	State(backend): State<Arc<Service>>,
+	certificate: Option<CertDn>,
+	user: UserResult,
+	Json(form): Json<RegistrationForm>,
+) -> Result<StatusCode, PasskeyError> {
+	let settings = settings()?;
+	let user = certificate_user(certificate, user)?;
+	let name = form.name.trim();
+	if name.is_empty() || name.chars().count() > 64 {
+		return Err(PasskeyError::Malformed("passkey name"));
+	}
+	let challenge = redeem_challenge(&form.challenge, user.id)?;
+	let passkey = settings.relying_party.verify_registration(
+		&challenge,
+		&decode("client data", &form.client_data_json)?,
+		&decode("attestation object", &form.attestation_object)?,
+	)?;
+	match backend.add_passkey(&user, name, &passkey).await {
+		Ok(()) => Ok(StatusCode::CREATED),
+		Err(sqlx::Error::Database(err)) if err.code().as_deref() == Some("23505") => Err(PasskeyError::NameTaken),
+		Err(err) => Err(err.into()),
+	}
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct DeletePasskeyForm {
+	name: String,
+}
+
+pub(crate) async fn delete_passkey(
+	State(backend): State<Arc<Service>>,
+	certificate: Option<CertDn>,
+	user: UserResult,
+	Form(form): Form<DeletePasskeyForm>,
+) -> Result<axum::response::Response, PasskeyError> {
+	settings()?;
+	let user = certificate_user(certificate, user)?;
+	backend.rm_passkey_for(&user, &form.name).await?;
+	Ok((StatusCode::FOUND, [("Location", "/")]).into_response())
+}
+
+pub(crate) async fn begin_login() -> Result<axum::response::Response, PasskeyError> {
+	let settings = settings()?;
+	let challenge = webauthn::encode(&webauthn::challenge());
+	let expires = crate::csrf::now() + CHALLENGE_LIFETIME.as_secs();
+
+	Ok((
+		[(
+			header::SET_COOKIE,
+			format!(
+				"{}={}; Path=/passkeys/login; Max-Age={}; HttpOnly; Secure; SameSite=Strict",
+				LOGIN_COOKIE,
+				login_cookie_value(&challenge, expires),
+				CHALLENGE_LIFETIME.as_secs()
+			),
+		)],
+		// Options for `navigator.credentials.get()`. Passkeys are discoverable, so the user doesn't
+		// have to tell who they are first.
+		Json(serde_json::json!({
+			"challenge": challenge,
+			"rpId": settings.relying_party.id,
+			"userVerification": "required",
+			"timeout": webauthn::TIMEOUT,
+		})),
+	)
+		.into_response())
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct LoginForm {
+	challenge: String,
+	credential_id: String,
+	client_data_json: String,
+	authenticator_data: String,
+	signature: String,
+}
+
+pub(crate) async fn finish_login(
+	State(backend): State<Arc<Service>>,
+	headers: HeaderMap,
+	Json(form): Json<LoginForm>,
+) -> Result<axum::response::Response, PasskeyError> {
+	let settings = settings()?;
+	let cookie = nyanpasswd::axum::cookie(&headers, LOGIN_COOKIE).ok_or(PasskeyError::UnknownChallenge)?;
+	let challenge = check_login_cookie(cookie, &form.challenge, crate::csrf::now())?;
+	let passkey = backend
+		.find_passkey(&decode("credential ID", &form.credential_id)?)
+		.await?
+		.ok_or(PasskeyError::UnknownPasskey)?;
+	let sign_count = settings
+		.relying_party
+		.verify_assertion(
+			&challenge,
+			&decode("client data", &form.client_data_json)?,
+			&decode("authenticator data", &form.authenticator_data)?,
+			&decode("signature", &form.signature)?,
+			&passkey.public_key,
+			passkey.sign_count as u32,
+		)
+		.map_err(|err| {
+			tracing::warn!("Rejecting login with passkey {:?}: {}", passkey.name, err);
+			err
+		})?;
+	let token = backend.create_session(&passkey, sign_count, settings.session_lifetime).await?;
+
+	Ok((
+		StatusCode::NO_CONTENT,
+		[
+			(header::SET_COOKIE, crate::login::session_cookie(&token, settings.session_lifetime)),
+			(
+				header::SET_COOKIE,
+				format!("{}=; Path=/passkeys/login; Max-Age=0; HttpOnly; Secure; SameSite=Strict", LOGIN_COOKIE),
+			),
+		],
+	)
+		.into_response())
+}
+
+#[cfg(test)]
+mod test {
+	use super::{check_login_cookie, login_cookie_value};
+
+	#[test]
+	fn test_login_cookie() {
+		let now = 1_700_000_000;
+		let challenge = nyanpasswd::webauthn::encode(&nyanpasswd::webauthn::challenge());
+		let cookie = login_cookie_value(&challenge, now + 60);
+		assert!(check_login_cookie(&cookie, &challenge, now).is_ok());
+		// Expired
+		assert!(check_login_cookie(&cookie, &challenge, now + 60).is_err());
+		// Not the challenge handed out
+		let other = nyanpasswd::webauthn::encode(&nyanpasswd::webauthn::challenge());
+		assert!(check_login_cookie(&cookie, &other, now).is_err());
+		// Tampered with
+		assert!(check_login_cookie(&login_cookie_value(&other, now + 60).replacen(&other, &challenge, 1), &challenge, now).is_err());
+		assert!(check_login_cookie(&cookie.replacen(&(now + 60).to_string(), &(now + 600).to_string(), 1), &challenge, now).is_err());
+		assert!(check_login_cookie("", &challenge, now).is_err());
+	}
+}
@@ -0,0 +1,660 @@
+/*
+  Copyright © 2022 nyantec GmbH <oss@nyantec.com>
+  Written by Vika Shleina <vsh@nyantec.com>
+  
+  Provided that these terms and disclaimer and all copyright notices
+  are retained or reproduced in an accompanying document, permission
+  is granted to deal in this work without restriction, including un‐
+  limited rights to use, publicly perform, distribute, sell, modify,
+  merge, give away, or sublicence.
+  
+  This work is provided "AS IS" and WITHOUT WARRANTY of any kind, to
+  the utmost extent permitted by applicable law, neither express nor
+  implied; without malicious intent or gross negligence. In no event
+  may a licensor, author or contributor be held liable for indirect,
+  direct, other damage, loss, or other issues arising in any way out
+  of dealing in the work, even if advised of the possibility of such
+  damage or existence of a defect, except proven that it results out
+  of said person's immediate fault when using the work as intended.
+ */
+//! Declarative management of non-human users and aliases.
+//!
+//! Deployments list the service accounts and aliases they need in a TOML file, and reconciling
+//! makes the database match it. Users and alias members created this way are marked as managed,
+//! and the database refuses changes to them, except from reconciling. Passwords of managed users
+//! are still created by admins, as they're secrets.
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, FixedOffset};
+
+use crate::util::{address, format_date};
+use crate::{MigrationsDone, Service};
+
+/// SQLSTATE of changes to managed users and aliases from outside of reconciling.
+pub const MANAGED_VIOLATION: &str = "23M01";
+
+/// Whether a query failed because it tried to change something managed by reconciling.
+pub fn is_managed_violation(err: &sqlx::Error) -> bool {
+	matches!(err, sqlx::Error::Database(err) if err.code().as_deref() == Some(MANAGED_VIOLATION))
+}
+
+/// The users and aliases that should exist, as read from the reconciliation file.
+#[derive(Debug, Default, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+	#[serde(default)]
+	pub users: Vec<ManagedUser>,
+	#[serde(default)]
+	pub aliases: Vec<ManagedAlias>,
+}
+
+/// A non-human user.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManagedUser {
+	pub username: String,
+	#[serde(default = "crate::util::yes")]
+	pub login_allowed: bool,
+	/// RFC 3339 timestamp, as a string.
+	#[serde(default)]
+	pub expires_at: Option<DateTime<FixedOffset>>,
+}
+
+/// An alias, along with all of its members.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManagedAlias {
+	pub name: String,
+	/// Domain the alias is valid on. Unscoped aliases are valid on all domains.
+	#[serde(default)]
+	pub domain: Option<String>,
+	/// Usernames of the members, who don't have to be managed themselves.
+	pub members: Vec<String>,
+	#[serde(default)]
+	pub description: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReconcileError {
+	#[error("Couldn't read {}: {}", .0.display(), .1)]
+	Read(PathBuf, std::io::Error),
+	#[error("Couldn't parse {}: {}", .0.display(), .1)]
+	Parse(PathBuf, toml::de::Error),
+	#[error("Invalid reconciliation file:\n  {}", .0.join("\n  "))]
+	Invalid(Vec<String>),
+	#[error("Applying `{change}` failed: {source}")]
+	Apply { change: Box<Change>, source: sqlx::Error },
+	#[error("SQL layer error: {0}")]
+	Sql(#[from] sqlx::Error),
+}
+
+impl Manifest {
+	pub fn load(path: &Path) -> Result<Self, ReconcileError> {
+		let contents = std::fs::read_to_string(path).map_err(|err| ReconcileError::Read(path.to_owned(), err))?;
+		toml::from_str(&contents).map_err(|err| ReconcileError::Parse(path.to_owned(), err))
+	}
+}
+
+/// A change reconciling makes to the database.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum Change {
+	CreateUser { user: ManagedUser },
+	/// Start managing a non-human user that was created by hand.
+	AdoptUser { username: String },
+	UpdateUser { before: ManagedUser, after: ManagedUser },
+	/// Stop managing a user that was removed from the file. Users are never deleted, so they're
+	/// disabled instead, and left for an admin to clean up.
+	ReleaseUser { username: String },
+	AddMember { alias_name: String, domain: Option<String>, username: String },
+	/// Start managing an alias member that was added by hand.
+	AdoptMember { alias_name: String, domain: Option<String>, username: String },
+	RemoveMember { alias_name: String, domain: Option<String>, username: String },
+	SetDescription { alias_name: String, domain: Option<String>, description: String },
+	RemoveAliasInfo { alias_name: String, domain: Option<String> },
+}
+
+impl fmt::Display for Change {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::CreateUser { user } => write!(f, "+ user {}", user.username),
+			Self::AdoptUser { username } => write!(f, "* user {}: start managing", username),
+			Self::UpdateUser { before, after } => {
+				let mut fields = Vec::new();
+				if before.login_allowed != after.login_allowed {
+					fields.push(format!("login_allowed {} -> {}", before.login_allowed, after.login_allowed));
+				}
+				if before.expires_at != after.expires_at {
+					fields.push(format!(
+						"expires_at {} -> {}",
+						format_date(before.expires_at),
+						format_date(after.expires_at)
+					));
+				}
+				write!(f, "~ user {}: {}", after.username, fields.join(", "))
+			}
+			Self::ReleaseUser { username } => write!(f, "- user {}: stop managing, disallow login", username),
+			Self::AddMember { alias_name, domain, username } => {
+				write!(f, "+ alias {} -> {}", address(alias_name, domain.as_deref()), username)
+			}
+			Self::AdoptMember { alias_name, domain, username } => write!(
+				f,
+				"* alias {} -> {}: start managing",
+				address(alias_name, domain.as_deref()),
+				username
+			),
+			Self::RemoveMember { alias_name, domain, username } => {
+				write!(f, "- alias {} -> {}", address(alias_name, domain.as_deref()), username)
+			}
+			Self::SetDescription { alias_name, domain, description } => write!(
+				f,
+				"~ alias settings {}: description {:?}",
+				address(alias_name, domain.as_deref()),
+				description
+			),
+			Self::RemoveAliasInfo { alias_name, domain } => {
+				write!(f, "- alias settings {}", address(alias_name, domain.as_deref()))
+			}
+		}
+	}
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+struct UserRow {
+	username: String,
+	login_allowed: bool,
+	expires_at: Option<DateTime<FixedOffset>>,
+	non_human: bool,
+	managed: bool,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+struct MemberRow {
+	alias_name: String,
+	domain: Option<String>,
+	username: String,
+	managed: bool,
+	self_service: bool,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+struct InfoRow {
+	alias_name: String,
+	domain: Option<String>,
+	description: String,
+	self_removable: bool,
+}
+
+/// What reconciling looks at in the database.
+#[derive(Debug, Default)]
+struct Current {
+	domains: Vec<String>,
+	users: Vec<UserRow>,
+	members: Vec<MemberRow>,
+	info: Vec<InfoRow>,
+}
+
+async fn read(conn: &mut sqlx::PgConnection) -> sqlx::Result<Current> {
+	Ok(Current {
+		domains: sqlx::query_scalar("SELECT name FROM mailpasswd.domains")
+			.fetch_all(&mut *conn)
+			.await?,
+		users: sqlx::query_as(
+			"SELECT username, login_allowed, expires_at, non_human, managed FROM mailpasswd.userdb ORDER BY username",
+		)
+		.fetch_all(&mut *conn)
+		.await?,
+		members: sqlx::query_as(
+			"SELECT alias_name, domain, username, aliases.managed, owner IS NOT NULL AS self_service
+			 FROM mailpasswd.aliases JOIN mailpasswd.userdb ON userdb.id = aliases.destination
+			 ORDER BY alias_name, domain NULLS FIRST, username",
+		)
+		.fetch_all(&mut *conn)
+		.await?,
+		info: sqlx::query_as("SELECT alias_name, domain, description, self_removable FROM mailpasswd.alias_info")
+			.fetch_all(&mut *conn)
+			.await?,
+	})
+}
+
+/// Find everything wrong with the manifest, given what's in the database.
+fn validate(manifest: &Manifest, current: &Current) -> Vec<String> {
+	let mut problems = Vec::new();
+	let mut usernames = BTreeSet::new();
+	for user in &manifest.users {
+		if !usernames.insert(user.username.as_str()) {
+			problems.push(format!("user {} is listed twice", user.username));
+		}
+		if let Some(existing) = current.users.iter().find(|existing| existing.username == user.username) {
+			if !existing.non_human && !existing.managed {
+				problems.push(format!("user {} is a human user, and can't be managed", user.username));
+			}
+		}
+	}
+
+	let mut aliases = BTreeSet::new();
+	for alias in &manifest.aliases {
+		let name = address(&alias.name, alias.domain.as_deref());
+		if !aliases.insert((alias.name.as_str(), alias.domain.as_deref())) {
+			problems.push(format!("alias {} is listed twice", name));
+		}
+		if alias.members.is_empty() {
+			problems.push(format!("alias {} has no members", name));
+		}
+		if let Some(domain) = &alias.domain {
+			// Domains are stored lowercased
+			if !current.domains.contains(domain) {
+				problems.push(format!("alias {} is on an unknown domain", name));
+			}
+		}
+		let self_service = current
+			.members
+			.iter()
+			.any(|row| row.self_service && row.alias_name == alias.name && row.domain == alias.domain);
+		if self_service {
+			problems.push(format!("alias {} is a self-service alias", name));
+		}
+		let mut members = BTreeSet::new();
+		for member in &alias.members {
+			if !members.insert(member) {
+				problems.push(format!("{} is listed twice as a member of alias {}", member, name));
+			}
+			if !usernames.contains(member.as_str()) && !current.users.iter().any(|user| &user.username == member) {
+				problems.push(format!("alias {} delivers to unknown user {}", name, member));
+			}
+		}
+	}
+
+	problems
+}
+
+/// Work out what needs to change for the database to match the manifest.
+fn plan(manifest: &Manifest, current: &Current) -> Vec<Change> {
+	let mut changes = Vec::new();
+
+	let users: HashMap<&str, &UserRow> = current.users.iter().map(|user| (user.username.as_str(), user)).collect();
+	for user in &manifest.users {
+		match users.get(user.username.as_str()) {
+			None => changes.push(Change::CreateUser { user: user.clone() }),
+			Some(existing) => {
+				if !existing.managed {
+					changes.push(Change::AdoptUser { username: user.username.clone() });
+				}
+				if existing.login_allowed != user.login_allowed || existing.expires_at != user.expires_at {
+					changes.push(Change::UpdateUser {
+						before: ManagedUser {
+							username: existing.username.clone(),
+							login_allowed: existing.login_allowed,
+							expires_at: existing.expires_at,
+						},
+						after: user.clone(),
+					});
+				}
+			}
+		}
+	}
+
+	let mut removals = Vec::new();
+	for alias in &manifest.aliases {
+		let rows: Vec<&MemberRow> = current
+			.members
+			.iter()
+			.filter(|row| row.alias_name == alias.name && row.domain == alias.domain)
+			.collect();
+		for member in &alias.members {
+			let (alias_name, domain, username) = (alias.name.clone(), alias.domain.clone(), member.clone());
+			match rows.iter().find(|row| &row.username == member) {
+				None => changes.push(Change::AddMember { alias_name, domain, username }),
+				Some(row) if !row.managed => changes.push(Change::AdoptMember { alias_name, domain, username }),
+				Some(_) => {}
+			}
+		}
+		// Managed aliases have exactly the members listed, even if others were added by hand before
+		for row in rows.iter().filter(|row| !alias.members.contains(&row.username)) {
+			removals.push(Change::RemoveMember {
+				alias_name: row.alias_name.clone(),
+				domain: row.domain.clone(),
+				username: row.username.clone(),
+			});
+		}
+		let info = current
+			.info
+			.iter()
+			.find(|info| info.alias_name == alias.name && info.domain == alias.domain);
+		// Members of managed aliases can't leave them, as reconciling would add them back
+		let up_to_date = match info {
+			Some(info) => info.description == alias.description && !info.self_removable,
+			None => alias.description.is_empty(),
+		};
+		if !up_to_date {
+			changes.push(Change::SetDescription {
+				alias_name: alias.name.clone(),
+				domain: alias.domain.clone(),
+				description: alias.description.clone(),
+			});
+		}
+	}
+
+	let declared = |alias_name: &str, domain: &Option<String>| {
+		manifest.aliases.iter().any(|alias| alias.name == alias_name && &alias.domain == domain)
+	};
+	let mut released = BTreeSet::new();
+	for row in current.members.iter().filter(|row| row.managed && !declared(&row.alias_name, &row.domain)) {
+		removals.push(Change::RemoveMember {
+			alias_name: row.alias_name.clone(),
+			domain: row.domain.clone(),
+			username: row.username.clone(),
+		});
+		released.insert((row.alias_name.as_str(), row.domain.as_deref()));
+	}
+	for info in &current.info {
+		if released.contains(&(info.alias_name.as_str(), info.domain.as_deref())) {
+			removals.push(Change::RemoveAliasInfo { alias_name: info.alias_name.clone(), domain: info.domain.clone() });
+		}
+	}
+	changes.extend(removals);
+
+	for user in current.users.iter().filter(|user| user.managed) {
+		if !manifest.users.iter().any(|declared| declared.username == user.username) {
+			changes.push(Change::ReleaseUser { username: user.username.clone() });
+		}
+	}
+
+	changes
+}
+
+async fn apply(conn: &mut sqlx::PgConnection, change: &Change) -> sqlx::Result<()> {
+	let query = match change {
+		Change::CreateUser { user } => sqlx::query(
+			"INSERT INTO mailpasswd.userdb (username, non_human, login_allowed, expires_at, managed)
+			 VALUES ($1, true, $2, $3, true)",
+		)
+		.bind(&user.username)
+		.bind(user.login_allowed)
+		.bind(user.expires_at),
+		Change::AdoptUser { username } => {
+			sqlx::query("UPDATE mailpasswd.userdb SET managed = true WHERE username = $1").bind(username)
+		}
+		Change::UpdateUser { after: user, .. } => {
+			sqlx::query("UPDATE mailpasswd.userdb SET login_allowed = $2, expires_at = $3 WHERE username = $1")
+				.bind(&user.username)
+				.bind(user.login_allowed)
+				.bind(user.expires_at)
+		}
+		Change::ReleaseUser { username } => {
+			sqlx::query("UPDATE mailpasswd.userdb SET managed = false, login_allowed = false WHERE username = $1").bind(username)
+		}
+		Change::AddMember { alias_name, domain, username } => sqlx::query(
+			"INSERT INTO mailpasswd.aliases (alias_name, domain, destination, managed)
+			 SELECT $2, $3, id, true FROM mailpasswd.userdb WHERE username = $1",
+		)
+		.bind(username)
+		.bind(alias_name)
+		.bind(domain.as_deref()),
+		Change::AdoptMember { alias_name, domain, username } => sqlx::query(
+			"UPDATE mailpasswd.aliases SET managed = true
+			 WHERE destination = (SELECT id FROM mailpasswd.userdb WHERE username = $1)
+			 AND alias_name = $2 AND domain IS NOT DISTINCT FROM $3",
+		)
+		.bind(username)
+		.bind(alias_name)
+		.bind(domain.as_deref()),
+		Change::RemoveMember { alias_name, domain, username } => sqlx::query(
+			"DELETE FROM mailpasswd.aliases
+			 WHERE destination = (SELECT id FROM mailpasswd.userdb WHERE username = $1)
+			 AND alias_name = $2 AND domain IS NOT DISTINCT FROM $3",
+		)
+		.bind(username)
+		.bind(alias_name)
+		.bind(domain.as_deref()),
+		Change::SetDescription { alias_name, domain, description } => {
+			sqlx::query("DELETE FROM mailpasswd.alias_info WHERE alias_name = $1 AND domain IS NOT DISTINCT FROM $2")
+				.bind(alias_name)
+				.bind(domain.as_deref())
+				.execute(&mut *conn)
+				.await?;
+			sqlx::query("INSERT INTO mailpasswd.alias_info (alias_name, domain, description) VALUES ($1, $2, $3)")
+				.bind(alias_name)
+				.bind(domain.as_deref())
+				.bind(description)
+		}
+		Change::RemoveAliasInfo { alias_name, domain } => {
+			sqlx::query("DELETE FROM mailpasswd.alias_info WHERE alias_name = $1 AND domain IS NOT DISTINCT FROM $2")
+				.bind(alias_name)
+				.bind(domain.as_deref())
+		}
+	};
+	query.execute(&mut *conn).await?;
+
+	Ok(())
+}
+
+impl Service<MigrationsDone> {
+	/// List the names and domains of aliases managed by reconciling.
+	pub async fn list_managed_aliases(&self) -> sqlx::Result<Vec<(String, Option<String>)>> {
+		sqlx::query_as("SELECT DISTINCT alias_name, domain FROM mailpasswd.aliases WHERE managed ORDER BY alias_name, domain")
+			.fetch_all(&self.db)
+			.await
+	}
+
+	/// List what reconciling would change, without changing anything.
+	#[tracing::instrument(skip(manifest))]
+	pub async fn drift(&self, manifest: &Manifest) -> Result<Vec<Change>, ReconcileError> {
+		let mut conn = self.db.acquire().await?;
+		let current = read(&mut conn).await?;
+		let problems = validate(manifest, &current);
+		if !problems.is_empty() {
+			return Err(ReconcileError::Invalid(problems));
+		}
+
+		Ok(plan(manifest, &current))
+	}
+
+	/// Make the managed users and aliases match the manifest, and return what was changed.
+	///
+	/// Everything is applied in one transaction. A dry run applies the changes too, and then
+	/// rolls back.
+	#[tracing::instrument(skip(manifest))]
+	pub async fn reconcile(&self, manifest: &Manifest, dry_run: bool) -> Result<Vec<Change>, ReconcileError> {
+		let mut txn = self.db.begin().await?;
+		sqlx::query("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE")
+			.execute(&mut txn)
+			.await?;
+		// Lets this transaction past the triggers protecting managed users and aliases
+		sqlx::query("SET LOCAL nyanpasswd.reconciling = 'on'")
+			.execute(&mut txn)
+			.await?;
+		let current = read(&mut txn).await?;
+		let problems = validate(manifest, &current);
+		if !problems.is_empty() {
+			return Err(ReconcileError::Invalid(problems));
+		}
+		let changes = plan(manifest, &current);
+		for change in &changes {
+			if let Err(source) = apply(&mut txn, change).await {
+				return Err(ReconcileError::Apply { change: Box::new(change.clone()), source });
+			}
+		}
+		if dry_run {
+			txn.rollback().await?;
+		} else {
+			txn.commit().await?;
+			for change in &changes {
+				tracing::info!("Reconciled: {}", change);
+			}
+		}
+
+		Ok(changes)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn manifest() -> Manifest {
+		toml::from_str(
+			r#"
+			[[users]]
+			username = "gitlab"
+
+			[[users]]
+			username = "backup"
+			login_allowed = false
+			expires_at = "2038-01-19T03:14:07Z"
+
+			[[aliases]]
+			name = "ops"
+			domain = "nyantec.com"
+			members = ["vsh", "gitlab"]
+			description = "Operations"
+			"#,
+		)
+		.unwrap()
+	}
+
+	fn user(username: &str, non_human: bool, managed: bool) -> UserRow {
+		UserRow { username: username.to_owned(), login_allowed: true, expires_at: None, non_human, managed }
+	}
+
+	#[test]
+	fn test_validate() {
+		let mut current = Current {
+			domains: vec!["nyantec.com".to_owned()],
+			users: vec![user("vsh", false, false)],
+			..Default::default()
+		};
+		assert!(validate(&manifest(), &current).is_empty());
+
+		let mut manifest = manifest();
+		manifest.users.push(manifest.users[0].clone());
+		manifest.users.push(ManagedUser { username: "vsh".to_owned(), login_allowed: true, expires_at: None });
+		manifest.aliases[0].members.push("nobody".to_owned());
+		manifest.aliases.push(ManagedAlias {
+			name: "cat".to_owned(),
+			domain: Some("Nyantec.com".to_owned()),
+			members: Vec::new(),
+			description: String::new(),
+		});
+		current.members.push(MemberRow {
+			alias_name: "cat".to_owned(),
+			domain: None,
+			username: "vsh".to_owned(),
+			managed: false,
+			self_service: true,
+		});
+		let problems = validate(&manifest, &current);
+		assert_eq!(problems.len(), 5, "{:#?}", problems);
+
+		// Self-service aliases are only unscoped
+		manifest.aliases[1].domain = None;
+		assert!(validate(&manifest, &current)
+			.iter()
+			.any(|problem| problem == "alias cat is a self-service alias"));
+	}
+
+	#[test]
+	fn test_plan() {
+		let manifest = manifest();
+		let mut current = Current {
+			domains: vec!["nyantec.com".to_owned()],
+			users: vec![user("vsh", false, false), user("gitlab", true, false), user("old", true, true)],
+			..Default::default()
+		};
+		let member = |username: &str, managed| MemberRow {
+			alias_name: "ops".to_owned(),
+			domain: Some("nyantec.com".to_owned()),
+			username: username.to_owned(),
+			managed,
+			self_service: false,
+		};
+		current.members.push(member("vsh", false));
+		current.members.push(member("old", false));
+		let changes: Vec<String> = plan(&manifest, &current).iter().map(ToString::to_string).collect();
+		assert_eq!(
+			changes,
+			[
+				"* user gitlab: start managing",
+				"+ user backup",
+				"* alias ops@nyantec.com -> vsh: start managing",
+				"+ alias ops@nyantec.com -> gitlab",
+				"~ alias settings ops@nyantec.com: description \"Operations\"",
+				"- alias ops@nyantec.com -> old",
+				"- user old: stop managing, disallow login",
+			]
+		);
+
+		// Dropping an alias from the file removes what reconciling added
+		let mut current = Current {
+			users: vec![user("gitlab", true, true)],
+			members: vec![MemberRow { managed: true, ..member("gitlab", true) }],
+			info: vec![InfoRow {
+				alias_name: "ops".to_owned(),
+				domain: Some("nyantec.com".to_owned()),
+				description: "Operations".to_owned(),
+				self_removable: false,
+			}],
+			..Default::default()
+		};
+		current.users[0].login_allowed = false;
+		let manifest = Manifest { users: vec![manifest.users[0].clone()], aliases: Vec::new() };
+		let changes: Vec<String> = plan(&manifest, &current).iter().map(ToString::to_string).collect();
+		assert_eq!(
+			changes,
+			[
+				"~ user gitlab: login_allowed false -> true",
+				"- alias ops@nyantec.com -> gitlab",
+				"- alias settings ops@nyantec.com",
+			]
+		);
+	}
+
+	#[sqlx::test]
+	async fn test_reconcile(pool: sqlx::PgPool) -> Result<(), ReconcileError> {
+		let svc = crate::test::create_service(pool);
+		svc.add_domain("nyantec.com").await?;
+		let vsh = svc.create_user("vsh", None, false).await?;
+		let manifest = manifest();
+
+		// A dry run changes nothing, so the drift stays
+		assert_eq!(svc.reconcile(&manifest, true).await?.len(), 5);
+		assert_eq!(svc.drift(&manifest).await?.len(), 5);
+		assert_eq!(svc.reconcile(&manifest, false).await?.len(), 5);
+		assert!(svc.drift(&manifest).await?.is_empty());
+		assert_eq!(svc.resolve_recipient("ops@nyantec.com").await?.len(), 2);
+		assert_eq!(svc.list_managed_aliases().await?, [("ops".to_owned(), Some("nyantec.com".to_owned()))]);
+
+		// Managed users and aliases can't be changed by hand
+		let gitlab = svc.find_user_by_name("gitlab").await?.unwrap();
+		assert!(gitlab.managed && gitlab.non_human);
+		assert!(is_managed_violation(&svc.set_user_login_allowed(gitlab.id, false).await.unwrap_err()));
+		let member = crate::Alias { alias_name: "ops".to_owned(), destination: vsh, domain: Some("nyantec.com".to_owned()) };
+		assert!(is_managed_violation(&svc.remove_alias(&member).await.unwrap_err()));
+		let info = crate::AliasInfo {
+			alias_name: "ops".to_owned(),
+			domain: Some("nyantec.com".to_owned()),
+			description: String::new(),
+			self_removable: true,
+		};
+		assert!(is_managed_violation(&svc.set_alias_info(&info).await.unwrap_err()));
+		// Other aliases and passwords of managed users still can
+		svc.add_alias(&crate::Alias { domain: None, ..member }).await?;
+		svc.new_password(&gitlab, "smtp", None).await?;
+		// Human users can't be taken over
+		let mut taken = manifest.clone();
+		taken.users.push(ManagedUser { username: "vsh".to_owned(), login_allowed: true, expires_at: None });
+		assert!(matches!(svc.drift(&taken).await, Err(ReconcileError::Invalid(_))));
+
+		// Users dropped from the file are disabled, but kept
+		let mut manifest = manifest;
+		manifest.users.retain(|user| user.username != "backup");
+		assert_eq!(svc.reconcile(&manifest, false).await?.len(), 1);
+		let backup = svc.find_user_by_name("backup").await?.unwrap();
+		assert!(!backup.managed && !backup.login_allowed);
+		svc.set_user_expiry_date(backup.id, None).await?;
+
+		Ok(())
+	}
+}
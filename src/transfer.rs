@@ -0,0 +1,962 @@
+/*
+  Copyright © 2022 nyantec GmbH <oss@nyantec.com>
+  Written by Vika Shleina <vsh@nyantec.com>
+  
+  Provided that these terms and disclaimer and all copyright notices
+  are retained or reproduced in an accompanying document, permission
+  is granted to deal in this work without restriction, including un‐
+  limited rights to use, publicly perform, distribute, sell, modify,
+  merge, give away, or sublicence.
+  
+  This work is provided "AS IS" and WITHOUT WARRANTY of any kind, to
+  the utmost extent permitted by applicable law, neither express nor
+  implied; without malicious intent or gross negligence. In no event
+  may a licensor, author or contributor be held liable for indirect,
+  direct, other damage, loss, or other issues arising in any way out
+  of dealing in the work, even if advised of the possibility of such
+  damage or existence of a defect, except proven that it results out
+  of said person's immediate fault when using the work as intended.
+ */
+//! Exporting the `mailpasswd` dataset, and importing it into another instance.
+//!
+//! The dataset covers users with their passwords, domains and aliases. Things tied to a particular
+//! instance, like API consumers, client certificates, passkeys and sessions, are left out. Users
+//! are referred to by username, so that a dataset can be imported where users have other IDs.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::path::Path;
+
+use chrono::{DateTime, FixedOffset};
+use uuid::Uuid;
+
+use crate::util::{address, format_date};
+use crate::{MigrationsDone, Service};
+
+/// Version of the dataset format, bumped on incompatible changes.
+pub const VERSION: u32 = 1;
+
+/// Everything in the `mailpasswd` schema that isn't tied to a particular instance.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Dataset {
+	/// Always [`VERSION`].
+	pub version: u32,
+	#[serde(default)]
+	pub domains: Vec<String>,
+	#[serde(default)]
+	pub users: Vec<UserEntry>,
+	#[serde(default)]
+	pub aliases: Vec<AliasEntry>,
+	#[serde(default)]
+	pub alias_info: Vec<AliasInfoEntry>,
+	#[serde(default)]
+	pub alias_templates: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserEntry {
+	/// Kept when creating the user, so that IDs survive moving to another instance. A new one is
+	/// generated if omitted.
+	#[serde(default)]
+	pub id: Option<Uuid>,
+	pub username: String,
+	#[serde(default = "crate::util::yes")]
+	pub login_allowed: bool,
+	#[serde(default)]
+	pub non_human: bool,
+	/// Defaults to the time of the import.
+	#[serde(default)]
+	pub created_at: Option<DateTime<FixedOffset>>,
+	#[serde(default)]
+	pub expires_at: Option<DateTime<FixedOffset>>,
+	#[serde(default)]
+	pub alias_limit: i32,
+	#[serde(default)]
+	pub require_pinned_certificate: bool,
+	/// Managed by the reconciliation file, see [`crate::reconcile`].
+	#[serde(default)]
+	pub managed: bool,
+	#[serde(default)]
+	pub passwords: Vec<PasswordEntry>,
+}
+
+impl UserEntry {
+	/// Whether anything that can be changed on an existing user differs.
+	fn settings_differ(&self, other: &UserEntry) -> bool {
+		self.login_allowed != other.login_allowed
+			|| self.non_human != other.non_human
+			|| self.expires_at != other.expires_at
+			|| self.alias_limit != other.alias_limit
+			|| self.require_pinned_certificate != other.require_pinned_certificate
+			|| self.managed != other.managed
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PasswordEntry {
+	pub label: String,
+	/// Argon2 hash in PHC string format.
+	pub hash: String,
+	/// Defaults to the time of the import.
+	#[serde(default)]
+	pub created_at: Option<DateTime<FixedOffset>>,
+	#[serde(default)]
+	pub expires_at: Option<DateTime<FixedOffset>>,
+}
+
+/// One member of an alias.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, sqlx::FromRow)]
+#[serde(deny_unknown_fields)]
+pub struct AliasEntry {
+	pub alias_name: String,
+	/// Domain the alias is valid on. Unscoped aliases are valid on all domains.
+	#[serde(default)]
+	pub domain: Option<String>,
+	/// Username of the member.
+	pub destination: String,
+	/// Self-service aliases belong to their destination, who created them.
+	#[serde(default)]
+	pub self_service: bool,
+	#[serde(default = "crate::util::yes")]
+	pub enabled: bool,
+	/// Defaults to the time of the import.
+	#[serde(default)]
+	pub created_at: Option<DateTime<FixedOffset>>,
+	/// Added by the reconciliation file, see [`crate::reconcile`].
+	#[serde(default)]
+	pub managed: bool,
+}
+
+impl AliasEntry {
+	fn key(&self) -> (&str, Option<&str>, &str) {
+		(&self.alias_name, self.domain.as_deref(), &self.destination)
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, sqlx::FromRow)]
+#[serde(deny_unknown_fields)]
+pub struct AliasInfoEntry {
+	pub alias_name: String,
+	#[serde(default)]
+	pub domain: Option<String>,
+	#[serde(default)]
+	pub description: String,
+	#[serde(default)]
+	pub self_removable: bool,
+}
+
+impl AliasInfoEntry {
+	fn key(&self) -> (&str, Option<&str>) {
+		(&self.alias_name, self.domain.as_deref())
+	}
+}
+
+/// A change an import makes to the database.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum Change {
+	AddDomain { domain: String },
+	/// The user's passwords are added by separate changes.
+	CreateUser { user: UserEntry },
+	UpdateUser { before: UserEntry, after: UserEntry },
+	AddPassword { username: String, password: PasswordEntry },
+	UpdatePassword { username: String, password: PasswordEntry },
+	AddAlias { alias: AliasEntry },
+	UpdateAlias { alias: AliasEntry },
+	SetAliasInfo { info: AliasInfoEntry },
+	AddAliasTemplate { template: String },
+	RemoveAliasTemplate { template: String },
+	RemoveAliasInfo { info: AliasInfoEntry },
+	RemoveAlias { alias: AliasEntry },
+	RemovePassword { username: String, label: String },
+	RemoveUser { username: String },
+	RemoveDomain { domain: String },
+}
+
+impl fmt::Display for Change {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::AddDomain { domain } => write!(f, "+ domain {}", domain),
+			Self::CreateUser { user } => {
+				write!(f, "+ user {}", user.username)?;
+				if user.non_human {
+					write!(f, " (non-human)")?;
+				}
+				if user.managed {
+					write!(f, " (managed)")?;
+				}
+				Ok(())
+			}
+			Self::UpdateUser { before, after } => {
+				let mut fields = Vec::new();
+				if before.login_allowed != after.login_allowed {
+					fields.push(format!("login_allowed {} -> {}", before.login_allowed, after.login_allowed));
+				}
+				if before.non_human != after.non_human {
+					fields.push(format!("non_human {} -> {}", before.non_human, after.non_human));
+				}
+				if before.expires_at != after.expires_at {
+					fields.push(format!(
+						"expires_at {} -> {}",
+						format_date(before.expires_at),
+						format_date(after.expires_at)
+					));
+				}
+				if before.alias_limit != after.alias_limit {
+					fields.push(format!("alias_limit {} -> {}", before.alias_limit, after.alias_limit));
+				}
+				if before.require_pinned_certificate != after.require_pinned_certificate {
+					fields.push(format!(
+						"require_pinned_certificate {} -> {}",
+						before.require_pinned_certificate, after.require_pinned_certificate
+					));
+				}
+				if before.managed != after.managed {
+					fields.push(format!("managed {} -> {}", before.managed, after.managed));
+				}
+				write!(f, "~ user {}: {}", after.username, fields.join(", "))
+			}
+			Self::AddPassword { username, password } => write!(f, "+ password {}/{}", username, password.label),
+			Self::UpdatePassword { username, password } => write!(f, "~ password {}/{}", username, password.label),
+			Self::AddAlias { alias } => {
+				write!(f, "+ alias {} -> {}", address(&alias.alias_name, alias.domain.as_deref()), alias.destination)?;
+				if alias.managed {
+					write!(f, " (managed)")?;
+				}
+				Ok(())
+			}
+			Self::UpdateAlias { alias } => write!(
+				f,
+				"~ alias {} -> {}: self_service {}, enabled {}, managed {}",
+				address(&alias.alias_name, alias.domain.as_deref()),
+				alias.destination,
+				alias.self_service,
+				alias.enabled,
+				alias.managed
+			),
+			Self::SetAliasInfo { info } => write!(f, "~ alias settings {}", address(&info.alias_name, info.domain.as_deref())),
+			Self::AddAliasTemplate { template } => write!(f, "+ alias template {}", template),
+			Self::RemoveAliasTemplate { template } => write!(f, "- alias template {}", template),
+			Self::RemoveAliasInfo { info } => write!(f, "- alias settings {}", address(&info.alias_name, info.domain.as_deref())),
+			Self::RemoveAlias { alias } => write!(
+				f,
+				"- alias {} -> {}",
+				address(&alias.alias_name, alias.domain.as_deref()),
+				alias.destination
+			),
+			Self::RemovePassword { username, label } => write!(f, "- password {}/{}", username, label),
+			Self::RemoveUser { username } => write!(f, "- user {}", username),
+			Self::RemoveDomain { domain } => write!(f, "- domain {}", domain),
+		}
+	}
+}
+
+/// Work out what needs to change for `current` to become `desired`.
+///
+/// Unless `prune` is set, things missing from `desired` are left alone. Changes are ordered so
+/// that they can be applied one after another.
+pub fn diff(current: &Dataset, desired: &Dataset, prune: bool) -> Vec<Change> {
+	let mut changes = Vec::new();
+	let mut removals = Vec::new();
+
+	let current_domains: BTreeSet<&String> = current.domains.iter().collect();
+	let desired_domains: BTreeSet<&String> = desired.domains.iter().collect();
+	for domain in desired_domains.difference(&current_domains) {
+		changes.push(Change::AddDomain { domain: domain.to_string() });
+	}
+	if prune {
+		for domain in current_domains.difference(&desired_domains) {
+			removals.push(Change::RemoveDomain { domain: domain.to_string() });
+		}
+	}
+
+	let current_users: HashMap<&str, &UserEntry> = current.users.iter().map(|user| (user.username.as_str(), user)).collect();
+	for user in &desired.users {
+		let settings = UserEntry { passwords: Vec::new(), ..user.clone() };
+		let current_passwords: HashMap<&str, &PasswordEntry> = match current_users.get(user.username.as_str()) {
+			Some(existing) => {
+				if existing.settings_differ(user) {
+					changes.push(Change::UpdateUser {
+						before: UserEntry { passwords: Vec::new(), ..(*existing).clone() },
+						after: UserEntry { id: existing.id, created_at: existing.created_at, ..settings },
+					});
+				}
+				existing.passwords.iter().map(|password| (password.label.as_str(), password)).collect()
+			}
+			None => {
+				changes.push(Change::CreateUser { user: settings });
+				HashMap::new()
+			}
+		};
+		for password in &user.passwords {
+			match current_passwords.get(password.label.as_str()) {
+				None => changes.push(Change::AddPassword { username: user.username.clone(), password: password.clone() }),
+				Some(existing) if existing.hash != password.hash || existing.expires_at != password.expires_at => {
+					changes.push(Change::UpdatePassword { username: user.username.clone(), password: password.clone() })
+				}
+				Some(_) => {}
+			}
+		}
+		if prune {
+			let desired_labels: BTreeSet<&str> = user.passwords.iter().map(|password| password.label.as_str()).collect();
+			for label in current_passwords.keys().filter(|label| !desired_labels.contains(*label)) {
+				removals.push(Change::RemovePassword { username: user.username.clone(), label: label.to_string() });
+			}
+		}
+	}
+
+	let current_aliases: HashMap<_, &AliasEntry> = current.aliases.iter().map(|alias| (alias.key(), alias)).collect();
+	for alias in &desired.aliases {
+		match current_aliases.get(&alias.key()) {
+			None => changes.push(Change::AddAlias { alias: alias.clone() }),
+			Some(existing)
+				if existing.self_service != alias.self_service
+					|| existing.enabled != alias.enabled
+					|| existing.managed != alias.managed =>
+			{
+				changes.push(Change::UpdateAlias { alias: alias.clone() })
+			}
+			Some(_) => {}
+		}
+	}
+
+	let current_info: HashMap<_, &AliasInfoEntry> = current.alias_info.iter().map(|info| (info.key(), info)).collect();
+	for info in &desired.alias_info {
+		if current_info.get(&info.key()) != Some(&info) {
+			changes.push(Change::SetAliasInfo { info: info.clone() });
+		}
+	}
+
+	let current_templates: BTreeSet<&String> = current.alias_templates.iter().collect();
+	let desired_templates: BTreeSet<&String> = desired.alias_templates.iter().collect();
+	for template in desired_templates.difference(&current_templates) {
+		changes.push(Change::AddAliasTemplate { template: template.to_string() });
+	}
+
+	if prune {
+		for template in current_templates.difference(&desired_templates) {
+			removals.push(Change::RemoveAliasTemplate { template: template.to_string() });
+		}
+		let desired_info: BTreeSet<_> = desired.alias_info.iter().map(AliasInfoEntry::key).collect();
+		for info in current.alias_info.iter().filter(|info| !desired_info.contains(&info.key())) {
+			removals.push(Change::RemoveAliasInfo { info: info.clone() });
+		}
+		let desired_aliases: BTreeSet<_> = desired.aliases.iter().map(AliasEntry::key).collect();
+		for alias in current.aliases.iter().filter(|alias| !desired_aliases.contains(&alias.key())) {
+			removals.push(Change::RemoveAlias { alias: alias.clone() });
+		}
+		let desired_users: BTreeSet<&str> = desired.users.iter().map(|user| user.username.as_str()).collect();
+		for user in current.users.iter().filter(|user| !desired_users.contains(user.username.as_str())) {
+			for password in &user.passwords {
+				removals.push(Change::RemovePassword { username: user.username.clone(), label: password.label.clone() });
+			}
+			removals.push(Change::RemoveUser { username: user.username.clone() });
+		}
+	}
+
+	// Whatever refers to something has to go before it
+	removals.sort_by_key(|change| match change {
+		Change::RemoveAliasTemplate { .. } | Change::RemoveAliasInfo { .. } => 0,
+		Change::RemoveAlias { .. } => 1,
+		Change::RemovePassword { .. } => 2,
+		Change::RemoveUser { .. } => 3,
+		_ => 4,
+	});
+	changes.extend(removals);
+	changes
+}
+
+/// Find everything wrong with a dataset that's about to be imported into `current`.
+fn validate(current: &Dataset, desired: &Dataset, prune: bool) -> Vec<String> {
+	let mut problems = Vec::new();
+	if desired.version != VERSION {
+		problems.push(format!("unsupported version {}, expected {}", desired.version, VERSION));
+	}
+	fn duplicates<'a, K: Ord + fmt::Debug + 'a>(what: &str, keys: impl Iterator<Item = K>, problems: &mut Vec<String>) {
+		let mut counts = BTreeMap::new();
+		for key in keys {
+			*counts.entry(key).or_insert(0) += 1;
+		}
+		for (key, _) in counts.into_iter().filter(|(_, count)| *count > 1) {
+			problems.push(format!("duplicate {} {:?}", what, key));
+		}
+	}
+	duplicates("domain", desired.domains.iter(), &mut problems);
+	for domain in desired.domains.iter().filter(|domain| **domain != domain.to_lowercase()) {
+		// Addresses are lowercased before looking up their domain
+		problems.push(format!("domain {} isn't lowercase", domain));
+	}
+	duplicates("user", desired.users.iter().map(|user| &user.username), &mut problems);
+	duplicates("user ID", desired.users.iter().filter_map(|user| user.id), &mut problems);
+	duplicates("alias member", desired.aliases.iter().map(AliasEntry::key), &mut problems);
+	duplicates("alias settings", desired.alias_info.iter().map(AliasInfoEntry::key), &mut problems);
+	duplicates("alias template", desired.alias_templates.iter(), &mut problems);
+
+	for user in &desired.users {
+		duplicates(
+			&format!("password of {}", user.username),
+			user.passwords.iter().map(|password| &password.label),
+			&mut problems,
+		);
+		for password in &user.passwords {
+			// Hashes are trusted to be well-formed when checking passwords
+			let valid = argon2::PasswordHash::new(&password.hash).map_or(false, |hash| hash.algorithm.as_str().starts_with("argon2"));
+			if !valid {
+				problems.push(format!("password {}/{} isn't an Argon2 hash in PHC format", user.username, password.label));
+			}
+		}
+	}
+
+	// What's not in the dataset only stays around without pruning
+	let kept = |in_dataset: bool, in_database: bool| in_dataset || (!prune && in_database);
+	let user_exists = |username: &str| {
+		kept(
+			desired.users.iter().any(|user| user.username == username),
+			current.users.iter().any(|user| user.username == username),
+		)
+	};
+	let domain_exists = |domain: &Option<String>| match domain {
+		None => true,
+		Some(domain) => kept(desired.domains.contains(domain), current.domains.contains(domain)),
+	};
+	for alias in &desired.aliases {
+		let name = address(&alias.alias_name, alias.domain.as_deref());
+		if !user_exists(&alias.destination) {
+			problems.push(format!("alias {} delivers to unknown user {}", name, alias.destination));
+		}
+		if !domain_exists(&alias.domain) {
+			problems.push(format!("alias {} is on an unknown domain", name));
+		}
+		if alias.self_service && alias.domain.is_some() {
+			problems.push(format!("self-service alias {} can't be scoped to a domain", name));
+		}
+	}
+	for info in &desired.alias_info {
+		if !domain_exists(&info.domain) {
+			let name = address(&info.alias_name, info.domain.as_deref());
+			problems.push(format!("settings of alias {} are on an unknown domain", name));
+		}
+	}
+
+	problems
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImportError {
+	#[error("Invalid dataset:\n  {}", .0.join("\n  "))]
+	Invalid(Vec<String>),
+	#[error("Applying `{change}` failed: {source}")]
+	Apply { change: Box<Change>, source: sqlx::Error },
+	#[error("SQL layer error: {0}")]
+	Sql(#[from] sqlx::Error),
+}
+
+/// How an import treats the database.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ImportOptions {
+	/// Remove whatever isn't in the dataset, instead of leaving it alone.
+	pub prune: bool,
+	/// Roll back instead of committing, to see what would change.
+	pub dry_run: bool,
+}
+
+async fn read(conn: &mut sqlx::PgConnection) -> sqlx::Result<Dataset> {
+	let domains = sqlx::query_scalar::<_, String>("SELECT name FROM mailpasswd.domains ORDER BY name")
+		.fetch_all(&mut *conn)
+		.await?;
+	type UserRow = (Uuid, String, bool, bool, DateTime<FixedOffset>, Option<DateTime<FixedOffset>>, i32, bool, bool);
+	let mut users: Vec<UserEntry> = sqlx::query_as::<_, UserRow>(
+		"SELECT id, username, login_allowed, non_human, created_at, expires_at, alias_limit, require_pinned_certificate, managed
+		 FROM mailpasswd.userdb ORDER BY username",
+	)
+	.fetch_all(&mut *conn)
+	.await?
+	.into_iter()
+	.map(
+		|(id, username, login_allowed, non_human, created_at, expires_at, alias_limit, require_pinned_certificate, managed)| {
+			UserEntry {
+				id: Some(id),
+				username,
+				login_allowed,
+				non_human,
+				created_at: Some(created_at),
+				expires_at,
+				alias_limit,
+				require_pinned_certificate,
+				managed,
+				passwords: Vec::new(),
+			}
+		},
+	)
+	.collect();
+	let passwords = sqlx::query_as::<_, (Uuid, String, String, DateTime<FixedOffset>, Option<DateTime<FixedOffset>>)>(
+		"SELECT userid, label, hash, created_at, expires_at FROM mailpasswd.passdb ORDER BY label",
+	)
+	.fetch_all(&mut *conn)
+	.await?;
+	let mut by_id: HashMap<Option<Uuid>, &mut UserEntry> = users.iter_mut().map(|user| (user.id, user)).collect();
+	for (userid, label, hash, created_at, expires_at) in passwords {
+		if let Some(user) = by_id.get_mut(&Some(userid)) {
+			user.passwords.push(PasswordEntry { label, hash, created_at: Some(created_at), expires_at });
+		}
+	}
+	let aliases = sqlx::query_as::<_, AliasEntry>(
+		"SELECT alias_name, domain, username AS destination, owner IS NOT NULL AS self_service, enabled, aliases.created_at,
+		 aliases.managed
+		 FROM mailpasswd.aliases JOIN mailpasswd.userdb ON userdb.id = aliases.destination
+		 ORDER BY alias_name, domain NULLS FIRST, username",
+	)
+	.fetch_all(&mut *conn)
+	.await?;
+	let alias_info = sqlx::query_as::<_, AliasInfoEntry>(
+		"SELECT alias_name, domain, description, self_removable FROM mailpasswd.alias_info ORDER BY alias_name, domain NULLS FIRST",
+	)
+	.fetch_all(&mut *conn)
+	.await?;
+	let alias_templates = sqlx::query_scalar::<_, String>("SELECT template FROM mailpasswd.alias_templates ORDER BY template")
+		.fetch_all(&mut *conn)
+		.await?;
+
+	Ok(Dataset { version: VERSION, domains, users, aliases, alias_info, alias_templates })
+}
+
+async fn apply(conn: &mut sqlx::PgConnection, change: &Change) -> sqlx::Result<()> {
+	let query = match change {
+		Change::AddDomain { domain } => sqlx::query("INSERT INTO mailpasswd.domains (name) VALUES ($1)").bind(domain),
+		Change::CreateUser { user } => sqlx::query(
+			"INSERT INTO mailpasswd.userdb
+			 (id, username, login_allowed, non_human, created_at, expires_at, alias_limit, require_pinned_certificate, managed)
+			 VALUES (COALESCE($1, gen_random_uuid()), $2, $3, $4, COALESCE($5, now()), $6, $7, $8, $9)",
+		)
+		.bind(user.id)
+		.bind(&user.username)
+		.bind(user.login_allowed)
+		.bind(user.non_human)
+		.bind(user.created_at)
+		.bind(user.expires_at)
+		.bind(user.alias_limit)
+		.bind(user.require_pinned_certificate)
+		.bind(user.managed),
+		Change::UpdateUser { after: user, .. } => sqlx::query(
+			"UPDATE mailpasswd.userdb SET login_allowed = $2, non_human = $3, expires_at = $4, alias_limit = $5,
+			 require_pinned_certificate = $6, managed = $7 WHERE username = $1",
+		)
+		.bind(&user.username)
+		.bind(user.login_allowed)
+		.bind(user.non_human)
+		.bind(user.expires_at)
+		.bind(user.alias_limit)
+		.bind(user.require_pinned_certificate)
+		.bind(user.managed),
+		Change::AddPassword { username, password } => sqlx::query(
+			"INSERT INTO mailpasswd.passdb (userid, label, hash, created_at, expires_at)
+			 VALUES ((SELECT id FROM mailpasswd.userdb WHERE username = $1), $2, $3, COALESCE($4, now()), $5)",
+		)
+		.bind(username)
+		.bind(&password.label)
+		.bind(&password.hash)
+		.bind(password.created_at)
+		.bind(password.expires_at),
+		Change::UpdatePassword { username, password } => sqlx::query(
+			"UPDATE mailpasswd.passdb SET hash = $3, expires_at = $4
+			 WHERE userid = (SELECT id FROM mailpasswd.userdb WHERE username = $1) AND label = $2",
+		)
+		.bind(username)
+		.bind(&password.label)
+		.bind(&password.hash)
+		.bind(password.expires_at),
+		Change::AddAlias { alias } => sqlx::query(
+			"INSERT INTO mailpasswd.aliases (alias_name, domain, destination, owner, enabled, created_at, managed)
+			 SELECT $2, $3, id, CASE WHEN $4 THEN id END, $5, COALESCE($6, now()), $7 FROM mailpasswd.userdb WHERE username = $1",
+		)
+		.bind(&alias.destination)
+		.bind(&alias.alias_name)
+		.bind(alias.domain.as_deref())
+		.bind(alias.self_service)
+		.bind(alias.enabled)
+		.bind(alias.created_at)
+		.bind(alias.managed),
+		Change::UpdateAlias { alias } => sqlx::query(
+			"UPDATE mailpasswd.aliases SET owner = CASE WHEN $4 THEN destination END, enabled = $5, managed = $6
+			 WHERE destination = (SELECT id FROM mailpasswd.userdb WHERE username = $1)
+			 AND alias_name = $2 AND domain IS NOT DISTINCT FROM $3",
+		)
+		.bind(&alias.destination)
+		.bind(&alias.alias_name)
+		.bind(alias.domain.as_deref())
+		.bind(alias.self_service)
+		.bind(alias.enabled)
+		.bind(alias.managed),
+		Change::SetAliasInfo { info } => {
+			sqlx::query("DELETE FROM mailpasswd.alias_info WHERE alias_name = $1 AND domain IS NOT DISTINCT FROM $2")
+				.bind(&info.alias_name)
+				.bind(info.domain.as_deref())
+				.execute(&mut *conn)
+				.await?;
+			sqlx::query(
+				"INSERT INTO mailpasswd.alias_info (alias_name, domain, description, self_removable) VALUES ($1, $2, $3, $4)",
+			)
+			.bind(&info.alias_name)
+			.bind(info.domain.as_deref())
+			.bind(&info.description)
+			.bind(info.self_removable)
+		}
+		Change::AddAliasTemplate { template } => {
+			sqlx::query("INSERT INTO mailpasswd.alias_templates (template) VALUES ($1)").bind(template)
+		}
+		Change::RemoveAliasTemplate { template } => {
+			sqlx::query("DELETE FROM mailpasswd.alias_templates WHERE template = $1").bind(template)
+		}
+		Change::RemoveAliasInfo { info } => {
+			sqlx::query("DELETE FROM mailpasswd.alias_info WHERE alias_name = $1 AND domain IS NOT DISTINCT FROM $2")
+				.bind(&info.alias_name)
+				.bind(info.domain.as_deref())
+		}
+		Change::RemoveAlias { alias } => sqlx::query(
+			"DELETE FROM mailpasswd.aliases WHERE destination = (SELECT id FROM mailpasswd.userdb WHERE username = $1)
+			 AND alias_name = $2 AND domain IS NOT DISTINCT FROM $3",
+		)
+		.bind(&alias.destination)
+		.bind(&alias.alias_name)
+		.bind(alias.domain.as_deref()),
+		Change::RemovePassword { username, label } => sqlx::query(
+			"DELETE FROM mailpasswd.passdb WHERE userid = (SELECT id FROM mailpasswd.userdb WHERE username = $1) AND label = $2",
+		)
+		.bind(username)
+		.bind(label),
+		Change::RemoveUser { username } => {
+			sqlx::query("DELETE FROM mailpasswd.userdb WHERE username = $1").bind(username)
+		}
+		Change::RemoveDomain { domain } => sqlx::query("DELETE FROM mailpasswd.domains WHERE name = $1").bind(domain),
+	};
+	query.execute(&mut *conn).await?;
+
+	Ok(())
+}
+
+impl Service<MigrationsDone> {
+	/// Export the whole dataset, as of a single point in time.
+	#[tracing::instrument]
+	pub async fn export(&self) -> sqlx::Result<Dataset> {
+		let mut txn = self.db.begin().await?;
+		sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY")
+			.execute(&mut txn)
+			.await?;
+		let dataset = read(&mut txn).await?;
+		txn.commit().await?;
+
+		Ok(dataset)
+	}
+
+	/// Make the database match `dataset`, and return what was changed.
+	///
+	/// The dataset is checked as a whole first, and everything is applied in one transaction, so
+	/// either all changes are made or none. A dry run applies them too, and then rolls back, so
+	/// that it catches everything a real import would fail on.
+	#[tracing::instrument(skip(dataset))]
+	pub async fn import(&self, dataset: &Dataset, options: ImportOptions) -> Result<Vec<Change>, ImportError> {
+		let mut txn = self.db.begin().await?;
+		// Nothing may change between working out the changes and applying them
+		sqlx::query("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE")
+			.execute(&mut txn)
+			.await?;
+		// Managed users and aliases are carried over with their flag, like everything else, so this
+		// gets past the triggers protecting them the same way reconciling does
+		sqlx::query("SET LOCAL nyanpasswd.reconciling = 'on'")
+			.execute(&mut txn)
+			.await?;
+		let current = read(&mut txn).await?;
+		let problems = validate(&current, dataset, options.prune);
+		if !problems.is_empty() {
+			return Err(ImportError::Invalid(problems));
+		}
+		let changes = diff(&current, dataset, options.prune);
+		for change in &changes {
+			if let Err(source) = apply(&mut txn, change).await {
+				return Err(ImportError::Apply { change: Box::new(change.clone()), source });
+			}
+		}
+		if options.dry_run {
+			txn.rollback().await?;
+		} else {
+			txn.commit().await?;
+			tracing::info!("Imported {} changes", changes.len());
+		}
+
+		Ok(changes)
+	}
+}
+
+/// Quote a CSV field if needed, as described in RFC 4180.
+fn csv_field(field: &str) -> std::borrow::Cow<'_, str> {
+	if field.contains([',', '"', '\n', '\r']) {
+		format!("\"{}\"", field.replace('"', "\"\"")).into()
+	} else {
+		field.into()
+	}
+}
+
+fn write_csv(path: &Path, header: &[&str], rows: impl Iterator<Item = Vec<String>>) -> std::io::Result<()> {
+	let mut csv = header.join(",") + "\r\n";
+	for row in rows {
+		csv += &row.iter().map(|field| csv_field(field)).collect::<Vec<_>>().join(",");
+		csv += "\r\n";
+	}
+	std::fs::write(path, csv)
+}
+
+impl Dataset {
+	/// Write the dataset as CSV files into `dir`, one per kind of entry.
+	///
+	/// Missing values, like an expiry date of something that never expires, are empty fields.
+	pub fn write_csv(&self, dir: &Path) -> std::io::Result<()> {
+		let date = |date: Option<DateTime<FixedOffset>>| date.map(|date| date.to_rfc3339()).unwrap_or_default();
+		std::fs::create_dir_all(dir)?;
+		write_csv(&dir.join("domains.csv"), &["name"], self.domains.iter().map(|domain| vec![domain.clone()]))?;
+		write_csv(
+			&dir.join("users.csv"),
+			&[
+				"id",
+				"username",
+				"login_allowed",
+				"non_human",
+				"created_at",
+				"expires_at",
+				"alias_limit",
+				"require_pinned_certificate",
+				"managed",
+			],
+			self.users.iter().map(|user| {
+				vec![
+					user.id.map(|id| id.to_string()).unwrap_or_default(),
+					user.username.clone(),
+					user.login_allowed.to_string(),
+					user.non_human.to_string(),
+					date(user.created_at),
+					date(user.expires_at),
+					user.alias_limit.to_string(),
+					user.require_pinned_certificate.to_string(),
+					user.managed.to_string(),
+				]
+			}),
+		)?;
+		write_csv(
+			&dir.join("passwords.csv"),
+			&["username", "label", "hash", "created_at", "expires_at"],
+			self.users.iter().flat_map(|user| {
+				user.passwords.iter().map(|password| {
+					vec![
+						user.username.clone(),
+						password.label.clone(),
+						password.hash.clone(),
+						date(password.created_at),
+						date(password.expires_at),
+					]
+				})
+			}),
+		)?;
+		write_csv(
+			&dir.join("aliases.csv"),
+			&["alias_name", "domain", "destination", "self_service", "enabled", "created_at", "managed"],
+			self.aliases.iter().map(|alias| {
+				vec![
+					alias.alias_name.clone(),
+					alias.domain.clone().unwrap_or_default(),
+					alias.destination.clone(),
+					alias.self_service.to_string(),
+					alias.enabled.to_string(),
+					date(alias.created_at),
+					alias.managed.to_string(),
+				]
+			}),
+		)?;
+		write_csv(
+			&dir.join("alias_info.csv"),
+			&["alias_name", "domain", "description", "self_removable"],
+			self.alias_info.iter().map(|info| {
+				vec![
+					info.alias_name.clone(),
+					info.domain.clone().unwrap_or_default(),
+					info.description.clone(),
+					info.self_removable.to_string(),
+				]
+			}),
+		)?;
+		write_csv(
+			&dir.join("alias_templates.csv"),
+			&["template"],
+			self.alias_templates.iter().map(|template| vec![template.clone()]),
+		)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn hash(password: &str) -> String {
+		use argon2::PasswordHasher;
+		let salt = argon2::password_hash::SaltString::generate(&mut rand::thread_rng());
+		argon2::Argon2::default().hash_password(password.as_bytes(), &salt).unwrap().to_string()
+	}
+
+	fn dataset() -> Dataset {
+		serde_json::from_value(serde_json::json!({
+			"version": 1,
+			"domains": ["nyantec.com"],
+			"users": [
+				{"username": "vsh", "alias_limit": 2, "passwords": [{"label": "phone", "hash": hash("longiflorum")}]},
+				{"username": "gitlab", "non_human": true, "expires_at": "2038-01-19T03:14:07Z"}
+			],
+			"aliases": [
+				{"alias_name": "ops", "domain": "nyantec.com", "destination": "vsh"},
+				{"alias_name": "ops", "domain": "nyantec.com", "destination": "gitlab", "enabled": false},
+				{"alias_name": "cat", "destination": "vsh", "self_service": true}
+			],
+			"alias_info": [{"alias_name": "ops", "domain": "nyantec.com", "description": "Operations", "self_removable": true}],
+			"alias_templates": ["<word>.<username>"]
+		}))
+		.unwrap()
+	}
+
+	#[test]
+	fn test_validate() {
+		let mut dataset = dataset();
+		assert!(validate(&Dataset::default(), &dataset, false).is_empty());
+
+		dataset.version = 2;
+		dataset.domains.push("Nyantec.com".to_owned());
+		dataset.users.push(dataset.users[0].clone());
+		dataset.users[1].passwords.push(PasswordEntry {
+			label: "smtp".to_owned(),
+			hash: "$2b$12$notanargon2hash".to_owned(),
+			created_at: None,
+			expires_at: None,
+		});
+		dataset.aliases[0].destination = "nobody".to_owned();
+		dataset.aliases[2].domain = Some("example.com".to_owned());
+		let problems = validate(&Dataset::default(), &dataset, false);
+		assert_eq!(problems.len(), 7, "{:#?}", problems);
+
+		// Whatever is in the database counts, unless it's going to be pruned
+		let mut dataset = super::test::dataset();
+		let mut current = Dataset::default();
+		current.users.push(dataset.users.pop().unwrap());
+		assert!(validate(&current, &dataset, false).is_empty());
+		assert_eq!(validate(&current, &dataset, true).len(), 1);
+	}
+
+	#[test]
+	fn test_diff() {
+		let desired = dataset();
+		let changes = diff(&Dataset::default(), &desired, false);
+		assert_eq!(changes.len(), 9);
+		assert!(matches!(changes[0], Change::AddDomain { .. }));
+		assert!(diff(&desired, &desired, true).is_empty());
+
+		let mut current = desired.clone();
+		current.users[0].alias_limit = 0;
+		current.users[0].passwords.clear();
+		current.aliases[1].enabled = true;
+		current.domains.push("example.com".to_owned());
+		let changes = diff(&current, &desired, false);
+		assert_eq!(changes.len(), 3);
+		assert_eq!(changes[0].to_string(), "~ user vsh: alias_limit 0 -> 2");
+		assert_eq!(changes[1].to_string(), "+ password vsh/phone");
+		assert_eq!(
+			changes[2].to_string(),
+			"~ alias ops@nyantec.com -> gitlab: self_service false, enabled false, managed false"
+		);
+
+		// Removals go last, and before what they depend on
+		let changes = diff(&desired, &Dataset::default(), true);
+		let changes: Vec<_> = changes.iter().map(ToString::to_string).collect();
+		assert_eq!(changes.first().unwrap(), "- alias template <word>.<username>");
+		assert_eq!(changes.last().unwrap(), "- domain nyantec.com");
+		let password = changes.iter().position(|change| change == "- password vsh/phone").unwrap();
+		let user = changes.iter().position(|change| change == "- user vsh").unwrap();
+		let alias = changes.iter().position(|change| change == "- alias cat -> vsh").unwrap();
+		assert!(alias < password && password < user);
+	}
+
+	#[test]
+	fn test_csv() {
+		assert_eq!(csv_field("ops"), "ops");
+		assert_eq!(csv_field("Operations, \"the\" team"), "\"Operations, \"\"the\"\" team\"");
+
+		let dir = std::env::temp_dir().join(format!("nyanpasswd-test-csv-{}", std::process::id()));
+		dataset().write_csv(&dir).unwrap();
+		let aliases = std::fs::read_to_string(dir.join("aliases.csv")).unwrap();
+		std::fs::remove_dir_all(&dir).unwrap();
+		let mut lines = aliases.lines();
+		assert_eq!(lines.next(), Some("alias_name,domain,destination,self_service,enabled,created_at,managed"));
+		assert_eq!(lines.nth(2), Some("cat,,vsh,true,true,,false"));
+	}
+
+	#[sqlx::test]
+	async fn test_import(pool: sqlx::PgPool) -> Result<(), ImportError> {
+		let svc = crate::test::create_service(pool);
+		let dataset = dataset();
+
+		// A dry run checks everything, but changes nothing
+		let options = ImportOptions { prune: false, dry_run: true };
+		assert_eq!(svc.import(&dataset, options).await?.len(), 9);
+		assert!(svc.list_users().await?.is_empty());
+
+		let options = ImportOptions { prune: false, dry_run: false };
+		assert_eq!(svc.import(&dataset, options).await?.len(), 9);
+		assert!(svc.import(&dataset, options).await?.is_empty());
+		assert!(matches!(
+			svc.verify_password("vsh", "longiflorum").await?,
+			crate::AuthenticationResult::Ok
+		));
+		assert_eq!(svc.resolve_recipient("ops@nyantec.com").await?.len(), 1);
+
+		// Exporting gives back what was imported, with the blanks filled in
+		let exported = svc.export().await?;
+		assert_eq!(exported.users.len(), 2);
+		assert!(exported.users.iter().all(|user| user.id.is_some() && user.created_at.is_some()));
+		assert!(diff(&exported, &dataset, true).is_empty());
+		let json = serde_json::to_string(&exported).unwrap();
+		assert_eq!(serde_json::from_str::<Dataset>(&json).unwrap(), exported);
+
+		// Manual additions survive, unless pruning
+		svc.create_user("manual", None, false).await?;
+		assert!(svc.import(&dataset, options).await?.is_empty());
+		let options = ImportOptions { prune: true, dry_run: false };
+		assert_eq!(svc.import(&dataset, options).await?, vec![Change::RemoveUser { username: "manual".to_owned() }]);
+
+		// Nothing is applied if a single change fails
+		let mut broken = dataset.clone();
+		broken.users[1].expires_at = Some("1970-01-01T00:00:00Z".parse().unwrap());
+		broken.alias_templates.clear();
+		assert!(matches!(
+			svc.import(&broken, options).await,
+			Err(ImportError::Apply { change, .. }) if matches!(*change, Change::UpdateUser { .. })
+		));
+		assert_eq!(svc.list_alias_templates().await?.len(), 1);
+
+		// Managed users and aliases keep their flag, and stay protected outside of imports
+		let mut managed = dataset.clone();
+		managed.users[1].managed = true;
+		managed.aliases[1].managed = true;
+		let changes: Vec<_> = svc.import(&managed, options).await?.iter().map(ToString::to_string).collect();
+		assert_eq!(
+			changes,
+			vec![
+				"~ user gitlab: managed false -> true",
+				"~ alias ops@nyantec.com -> gitlab: self_service false, enabled false, managed true"
+			]
+		);
+		assert!(diff(&svc.export().await?, &managed, true).is_empty());
+		let gitlab = svc.find_user_by_name("gitlab").await?.unwrap();
+		let err = svc.set_alias_limit(gitlab.id, 1).await.unwrap_err();
+		assert!(crate::reconcile::is_managed_violation(&err), "{}", err);
+		// Importing can release them again, like reconciling would
+		assert_eq!(svc.import(&dataset, options).await?.len(), 2);
+
+		Ok(())
+	}
+}
@@ -0,0 +1,300 @@
+/*
+  Copyright © 2022 nyantec GmbH <oss@nyantec.com>
+  Written by Vika Shleina <vsh@nyantec.com>
+  
+  Provided that these terms and disclaimer and all copyright notices
+  are retained or reproduced in an accompanying document, permission
+  is granted to deal in this work without restriction, including un‐
+  limited rights to use, publicly perform, distribute, sell, modify,
+  merge, give away, or sublicence.
+  
+  This work is provided "AS IS" and WITHOUT WARRANTY of any kind, to
+  the utmost extent permitted by applicable law, neither express nor
+  implied; without malicious intent or gross negligence. In no event
+  may a licensor, author or contributor be held liable for indirect,
+  direct, other damage, loss, or other issues arising in any way out
+  of dealing in the work, even if advised of the possibility of such
+  damage or existence of a defect, except proven that it results out
+  of said person's immediate fault when using the work as intended.
+ */
+//! Serving the API on a Unix domain socket, authorizing callers by their peer credentials.
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::extract::connect_info::Connected;
+use futures::Future;
+use hyper::server::accept::Accept;
+use crate::ApiPermission;
+use tokio::net::{unix::UCred, UnixListener, UnixStream};
+
+/// Credentials of the process on the other end of a Unix socket, as reported by `SO_PEERCRED`.
+#[derive(Clone, Copy, Debug)]
+pub struct PeerCred(pub UCred);
+
+impl Connected<&UnixStream> for PeerCred {
+	fn connect_info(stream: &UnixStream) -> Self {
+		// `SO_PEERCRED` can only fail on a socket that isn't connected,
+		// which an accepted stream always is.
+		PeerCred(stream.peer_cred().expect("accepted Unix socket has no peer credentials"))
+	}
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Principal {
+	Uid(u32),
+	/// A group, with the UIDs of its supplementary members.
+	Gid(u32, Vec<u32>),
+}
+
+/// Grants permissions to a Unix user or to members of a Unix group.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PeerRule {
+	principal: Principal,
+	permissions: Vec<ApiPermission>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PeerRuleError {
+	#[error("expected `user:<name>=<permissions>` or `group:<name>=<permissions>`, got `{0}`")]
+	Syntax(String),
+	#[error("unknown permission `{0}`")]
+	UnknownPermission(String),
+	#[error("no such user: {0}")]
+	NoSuchUser(String),
+	#[error("no such group: {0}")]
+	NoSuchGroup(String),
+	#[error("error looking up {0}: {1}")]
+	Lookup(String, nix::Error),
+}
+
+impl PeerRule {
+	/// Parse a rule like `user:dovecot2=authenticate,lookup_users` or `group:mail=lookup_aliases`.
+	///
+	/// Users and groups may be given by name or by numeric ID. Names are resolved immediately, so
+	/// renaming a user or changing group membership requires a restart.
+	pub fn parse(rule: &str) -> Result<Self, PeerRuleError> {
+		let syntax_error = || PeerRuleError::Syntax(rule.to_owned());
+		let (principal, permissions) = rule.split_once('=').ok_or_else(syntax_error)?;
+		let (kind, name) = principal.split_once(':').ok_or_else(syntax_error)?;
+		let principal = match kind {
+			"user" => Principal::Uid(match name.parse() {
+				Ok(uid) => uid,
+				Err(_) => match nix::unistd::User::from_name(name) {
+					Ok(Some(user)) => user.uid.as_raw(),
+					Ok(None) => return Err(PeerRuleError::NoSuchUser(name.to_owned())),
+					Err(err) => return Err(PeerRuleError::Lookup(name.to_owned(), err)),
+				},
+			}),
+			"group" => {
+				let group = match name.parse() {
+					Ok(gid) => nix::unistd::Group::from_gid(nix::unistd::Gid::from_raw(gid)),
+					Err(_) => nix::unistd::Group::from_name(name),
+				};
+				match group {
+					Ok(Some(group)) => {
+						let mut members = Vec::with_capacity(group.mem.len());
+						for member in group.mem {
+							match nix::unistd::User::from_name(&member) {
+								Ok(Some(user)) => members.push(user.uid.as_raw()),
+								// Dangling entries in the group database can't match anyone
+								Ok(None) => {}
+								Err(err) => return Err(PeerRuleError::Lookup(member, err)),
+							}
+						}
+						Principal::Gid(group.gid.as_raw(), members)
+					}
+					// A numeric group without a database entry can still match primary groups
+					Ok(None) => match name.parse() {
+						Ok(gid) => Principal::Gid(gid, vec![]),
+						Err(_) => return Err(PeerRuleError::NoSuchGroup(name.to_owned())),
+					},
+					Err(err) => return Err(PeerRuleError::Lookup(name.to_owned(), err)),
+				}
+			}
+			_ => return Err(syntax_error()),
+		};
+		let permissions = permissions
+			.split(',')
+			.filter(|p| !p.is_empty())
+			.map(|p| {
+				ApiPermission::ALL
+					.into_iter()
+					.find(|permission| permission.as_str() == p)
+					.ok_or_else(|| PeerRuleError::UnknownPermission(p.to_owned()))
+			})
+			.collect::<Result<Vec<_>, _>>()?;
+
+		Ok(Self { principal, permissions })
+	}
+
+	fn matches(&self, peer: &UCred) -> bool {
+		match &self.principal {
+			Principal::Uid(uid) => peer.uid() == *uid,
+			Principal::Gid(gid, members) => peer.gid() == *gid || members.contains(&peer.uid()),
+		}
+	}
+}
+
+/// Resolve a group name or numeric ID to a GID.
+pub fn resolve_group(name: &str) -> Result<u32, PeerRuleError> {
+	if let Ok(gid) = name.parse() {
+		return Ok(gid);
+	}
+	match nix::unistd::Group::from_name(name) {
+		Ok(Some(group)) => Ok(group.gid.as_raw()),
+		Ok(None) => Err(PeerRuleError::NoSuchGroup(name.to_owned())),
+		Err(err) => Err(PeerRuleError::Lookup(name.to_owned(), err)),
+	}
+}
+
+/// Check whether any rule grants the peer the permission.
+pub fn peer_has_permission(rules: &[PeerRule], peer: &UCred, permission: ApiPermission) -> bool {
+	rules
+		.iter()
+		.filter(|rule| rule.matches(peer))
+		.any(|rule| rule.permissions.contains(&permission))
+}
+
+/// Accepts connections on a Unix socket for hyper.
+pub struct UnixAccept {
+	listener: UnixListener,
+	/// Set after accepting failed, to wait a bit before trying again.
+	backoff: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl UnixAccept {
+	/// Bind to the socket at `path`, replacing a stale socket left over from a previous run.
+	///
+	/// The socket is given the permission bits in `mode` and, if provided, the group `group`.
+	/// It is created in a private directory next to `path` and only moved into place once
+	/// that's done, so nobody can connect while it still has the permissions of the umask.
+	pub fn bind(path: &Path, mode: u32, group: Option<u32>) -> std::io::Result<Self> {
+		let file_name = path.file_name()
+			.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "socket path has no file name"))?;
+		let mut staging_name = std::ffi::OsString::from(".");
+		staging_name.push(file_name);
+		staging_name.push(".tmp");
+		let staging = path.with_file_name(staging_name);
+		match std::fs::remove_dir_all(&staging) {
+			Ok(()) => {}
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+			Err(err) => return Err(err),
+		}
+		std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+
+		let staged = staging.join(file_name);
+		let result = (|| {
+			let listener = UnixListener::bind(&staged)?;
+			if let Some(gid) = group {
+				nix::unistd::chown(&staged, None, Some(nix::unistd::Gid::from_raw(gid)))?;
+			}
+			std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(mode))?;
+			// Atomically replaces a stale socket, too
+			std::fs::rename(&staged, path)?;
+
+			Ok(Self { listener, backoff: None })
+		})();
+		let _ = std::fs::remove_dir_all(&staging);
+
+		result
+	}
+}
+
+impl Accept for UnixAccept {
+	type Conn = UnixStream;
+	// hyper gives up on the whole server on accept errors, so they're handled here
+	type Error = std::convert::Infallible;
+
+	fn poll_accept(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+		let this = self.get_mut();
+		loop {
+			if let Some(backoff) = this.backoff.as_mut() {
+				futures::ready!(backoff.as_mut().poll(cx));
+				this.backoff = None;
+			}
+			match futures::ready!(this.listener.poll_accept(cx)) {
+				Ok((stream, _addr)) => return Poll::Ready(Some(Ok(stream))),
+				Err(err) => {
+					// Probably out of file descriptors, give other connections time to finish
+					tracing::error!("Accepting a connection on the API socket failed: {}", err);
+					this.backoff = Some(Box::pin(tokio::time::sleep(Duration::from_millis(100))));
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::{peer_has_permission, PeerRule, PeerRuleError, Principal, UnixAccept};
+	use crate::ApiPermission;
+
+	#[tokio::test]
+	async fn test_bind() {
+		use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+		let dir = std::env::temp_dir().join(format!("nyanpasswd-test-socket-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("api.sock");
+		// A stale socket from a previous run is replaced
+		std::fs::write(&path, b"").unwrap();
+
+		let _accept = UnixAccept::bind(&path, 0o660, None).unwrap();
+		let metadata = std::fs::metadata(&path).unwrap();
+		assert!(metadata.file_type().is_socket());
+		assert_eq!(metadata.permissions().mode() & 0o777, 0o660);
+		tokio::net::UnixStream::connect(&path).await.unwrap();
+		// Only the socket is left behind
+		assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn test_parse_peer_rules() {
+		assert_eq!(
+			PeerRule::parse("user:0=authenticate,lookup_users").unwrap(),
+			PeerRule {
+				principal: Principal::Uid(0),
+				permissions: vec![ApiPermission::Authenticate, ApiPermission::LookupUsers]
+			}
+		);
+		assert_eq!(
+			PeerRule::parse("user:root=").unwrap(),
+			PeerRule {
+				principal: Principal::Uid(0),
+				permissions: vec![]
+			}
+		);
+		assert!(matches!(PeerRule::parse("root=authenticate"), Err(PeerRuleError::Syntax(_))));
+		assert!(matches!(PeerRule::parse("user:root"), Err(PeerRuleError::Syntax(_))));
+		assert!(matches!(
+			PeerRule::parse("user:root=everything"),
+			Err(PeerRuleError::UnknownPermission(p)) if p == "everything"
+		));
+		assert!(matches!(
+			PeerRule::parse("user:no-such-user-hopefully=authenticate"),
+			Err(PeerRuleError::NoSuchUser(_))
+		));
+	}
+
+	#[tokio::test]
+	async fn test_peer_has_permission() {
+		// Our own credentials, as seen from the other end of a socket pair
+		let (ours, _theirs) = tokio::net::UnixStream::pair().unwrap();
+		let peer = ours.peer_cred().unwrap();
+
+		let rules = vec![
+			PeerRule::parse(&format!("user:{}=authenticate", peer.uid())).unwrap(),
+			PeerRule::parse(&format!("group:{}=lookup_aliases", peer.gid())).unwrap(),
+			PeerRule::parse(&format!("user:{}=list_users", peer.uid() + 1)).unwrap(),
+		];
+		assert!(peer_has_permission(&rules, &peer, ApiPermission::Authenticate));
+		assert!(peer_has_permission(&rules, &peer, ApiPermission::LookupAliases));
+		assert!(!peer_has_permission(&rules, &peer, ApiPermission::ListUsers));
+		assert!(!peer_has_permission(&rules, &peer, ApiPermission::LookupUsers));
+	}
+}
@@ -0,0 +1,481 @@
+/*
+  Copyright © 2022 nyantec GmbH <oss@nyantec.com>
+  Written by Vika Shleina <vsh@nyantec.com>
+  
+  Provided that these terms and disclaimer and all copyright notices
+  are retained or reproduced in an accompanying document, permission
+  is granted to deal in this work without restriction, including un‐
+  limited rights to use, publicly perform, distribute, sell, modify,
+  merge, give away, or sublicence.
+  
+  This work is provided "AS IS" and WITHOUT WARRANTY of any kind, to
+  the utmost extent permitted by applicable law, neither express nor
+  implied; without malicious intent or gross negligence. In no event
+  may a licensor, author or contributor be held liable for indirect,
+  direct, other damage, loss, or other issues arising in any way out
+  of dealing in the work, even if advised of the possibility of such
+  damage or existence of a defect, except proven that it results out
+  of said person's immediate fault when using the work as intended.
+ */
+//! Verification of WebAuthn ceremonies, for logging into the dashboard with passkeys.
+//!
+//! Only what passkeys need is supported. Attestation statements are not checked, since we ask
+//! authenticators for none and don't care which one a user has, and only ES256, EdDSA and RS256
+//! public keys are accepted, which is what authenticators use in practice.
+use base64::Engine;
+use sha2::Digest;
+
+/// How long browsers should wait for the user to use their authenticator, in milliseconds.
+pub const TIMEOUT: u32 = 120_000;
+/// The COSE algorithms we can verify, in order of preference.
+pub const ALGORITHMS: [i32; 3] = [ES256, EDDSA, RS256];
+
+const ES256: i32 = -7;
+const EDDSA: i32 = -8;
+const RS256: i32 = -257;
+
+const USER_PRESENT: u8 = 0x01;
+const USER_VERIFIED: u8 = 0x04;
+const ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum WebauthnError {
+	#[error("Malformed client data")]
+	ClientData,
+	#[error("Expected a {expected} ceremony, got {actual}")]
+	CeremonyType { expected: &'static str, actual: String },
+	#[error("The challenge does not match")]
+	Challenge,
+	#[error("Unexpected origin {0}")]
+	Origin(String),
+	#[error("Malformed attestation object")]
+	AttestationObject,
+	#[error("Malformed authenticator data")]
+	AuthenticatorData,
+	#[error("The passkey is for a different relying party")]
+	RelyingParty,
+	#[error("The authenticator did not verify the user")]
+	UserNotVerified,
+	#[error("Unsupported or malformed public key")]
+	PublicKey,
+	#[error("Invalid signature")]
+	Signature,
+	#[error("The signature counter went backwards, the passkey may have been cloned")]
+	SignCount,
+}
+
+/// Who passkeys are created for, and where they may be used.
+#[derive(Debug, Clone)]
+pub struct RelyingParty {
+	/// The domain passkeys are bound to: the host name of [`RelyingParty::origin`], or a parent
+	/// domain of it.
+	pub id: String,
+	/// The web origin of the dashboard, like `https://mail.nyantec.com`.
+	pub origin: String,
+	/// A name for the user to recognize us by.
+	pub name: String,
+}
+
+/// A passkey an authenticator created, to be stored for the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewPasskey {
+	pub credential_id: Vec<u8>,
+	/// The COSE-encoded public key.
+	pub public_key: Vec<u8>,
+	pub sign_count: u32,
+}
+
+/// A random challenge for an authenticator to sign.
+pub fn challenge() -> Vec<u8> {
+	use rand::RngCore;
+
+	let mut challenge = vec![0; 32];
+	rand::thread_rng().fill_bytes(&mut challenge);
+	challenge
+}
+
+/// Encode binary data the way WebAuthn JSON does.
+pub fn encode(data: &[u8]) -> String {
+	base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(data)
+}
+
+/// Decode binary data the way WebAuthn JSON encodes it.
+pub fn decode(data: &str) -> Option<Vec<u8>> {
+	base64::engine::general_purpose::URL_SAFE_NO_PAD
+		.decode(data.trim_end_matches('='))
+		.ok()
+}
+
+#[derive(serde::Deserialize)]
+struct ClientData {
+	#[serde(rename = "type")]
+	ceremony: String,
+	challenge: String,
+	origin: String,
+	#[serde(default, rename = "crossOrigin")]
+	cross_origin: bool,
+}
+
+struct AuthenticatorData<'a> {
+	rp_id_hash: &'a [u8],
+	flags: u8,
+	sign_count: u32,
+	/// The credential ID and COSE-encoded public key, when a passkey is created.
+	credential: Option<(&'a [u8], &'a [u8])>,
+}
+
+impl<'a> AuthenticatorData<'a> {
+	fn parse(data: &'a [u8]) -> Result<Self, WebauthnError> {
+		if data.len() < 37 {
+			return Err(WebauthnError::AuthenticatorData);
+		}
+		let flags = data[32];
+		let sign_count = u32::from_be_bytes(data[33..37].try_into().unwrap());
+		let credential = if flags & ATTESTED_CREDENTIAL_DATA != 0 {
+			// The AAGUID, which we don't need, comes first
+			let rest = data.get(37 + 16..).ok_or(WebauthnError::AuthenticatorData)?;
+			let length = u16::from_be_bytes(rest.get(..2).ok_or(WebauthnError::AuthenticatorData)?.try_into().unwrap());
+			let credential_id = rest.get(2..2 + length as usize).ok_or(WebauthnError::AuthenticatorData)?;
+			let key = &rest[2 + length as usize..];
+			// The key is followed by extensions, so it only ends where its encoding does
+			let mut remaining = key;
+			ciborium::de::from_reader::<ciborium::Value, _>(&mut remaining).map_err(|_| WebauthnError::AuthenticatorData)?;
+			Some((credential_id, &key[..key.len() - remaining.len()]))
+		} else {
+			None
+		};
+
+		Ok(AuthenticatorData {
+			rp_id_hash: &data[..32],
+			flags,
+			sign_count,
+			credential,
+		})
+	}
+}
+
+enum PublicKey {
+	/// An uncompressed P-256 point.
+	Es256(Vec<u8>),
+	Ed25519(Vec<u8>),
+	Rs256 { n: Vec<u8>, e: Vec<u8> },
+}
+
+impl PublicKey {
+	fn from_cose(key: &[u8]) -> Result<Self, WebauthnError> {
+		let key = ciborium::de::from_reader::<ciborium::Value, _>(key).map_err(|_| WebauthnError::PublicKey)?;
+		let map = key.as_map().ok_or(WebauthnError::PublicKey)?;
+		let get = |label: i128| {
+			map.iter()
+				.find(|(key, _)| key.as_integer().map(i128::from) == Some(label))
+				.map(|(_, value)| value)
+		};
+		let integer = |label| get(label).and_then(ciborium::Value::as_integer).map(i128::from);
+		let bytes = |label| get(label).and_then(ciborium::Value::as_bytes).cloned();
+
+		// Key type, algorithm and curve are labels 1, 3 and -1; the key itself is -2 and on
+		let key = match (integer(1), integer(3).map(|alg| alg as i32)) {
+			(Some(2), Some(ES256)) if integer(-1) == Some(1) => {
+				let (Some(x), Some(y)) = (bytes(-2), bytes(-3)) else {
+					return Err(WebauthnError::PublicKey);
+				};
+				if x.len() != 32 || y.len() != 32 {
+					return Err(WebauthnError::PublicKey);
+				}
+				PublicKey::Es256([&[0x04], &x[..], &y[..]].concat())
+			}
+			(Some(1), Some(EDDSA)) if integer(-1) == Some(6) => match bytes(-2) {
+				Some(x) if x.len() == 32 => PublicKey::Ed25519(x),
+				_ => return Err(WebauthnError::PublicKey),
+			},
+			(Some(3), Some(RS256)) => match (bytes(-1), bytes(-2)) {
+				(Some(n), Some(e)) => PublicKey::Rs256 { n, e },
+				_ => return Err(WebauthnError::PublicKey),
+			},
+			_ => return Err(WebauthnError::PublicKey),
+		};
+		Ok(key)
+	}
+
+	fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), WebauthnError> {
+		use ring::signature;
+
+		match self {
+			PublicKey::Es256(point) => {
+				signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_ASN1, point).verify(message, signature)
+			}
+			PublicKey::Ed25519(key) => signature::UnparsedPublicKey::new(&signature::ED25519, key).verify(message, signature),
+			PublicKey::Rs256 { n, e } => {
+				signature::RsaPublicKeyComponents { n, e }.verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, signature)
+			}
+		}
+		.map_err(|_| WebauthnError::Signature)
+	}
+}
+
+impl RelyingParty {
+	fn check_client_data(&self, client_data_json: &[u8], ceremony: &'static str, challenge: &[u8]) -> Result<(), WebauthnError> {
+		let client_data: ClientData = serde_json::from_slice(client_data_json).map_err(|_| WebauthnError::ClientData)?;
+		if client_data.ceremony != ceremony {
+			return Err(WebauthnError::CeremonyType {
+				expected: ceremony,
+				actual: client_data.ceremony,
+			});
+		}
+		if decode(&client_data.challenge).as_deref() != Some(challenge) {
+			return Err(WebauthnError::Challenge);
+		}
+		if client_data.origin != self.origin || client_data.cross_origin {
+			return Err(WebauthnError::Origin(client_data.origin));
+		}
+		Ok(())
+	}
+
+	fn check_authenticator_data(&self, data: &AuthenticatorData) -> Result<(), WebauthnError> {
+		if data.rp_id_hash != sha2::Sha256::digest(self.id.as_bytes()).as_slice() {
+			return Err(WebauthnError::RelyingParty);
+		}
+		// A passkey is all it takes to log in, so it has to be protected by a PIN or biometrics
+		if data.flags & (USER_PRESENT | USER_VERIFIED) != USER_PRESENT | USER_VERIFIED {
+			return Err(WebauthnError::UserNotVerified);
+		}
+		Ok(())
+	}
+
+	/// Check the response of `navigator.credentials.create()` to `challenge`, and return the new passkey.
+	pub fn verify_registration(
+		&self,
+		challenge: &[u8],
+		client_data_json: &[u8],
+		attestation_object: &[u8],
+	) -> Result<NewPasskey, WebauthnError> {
+		self.check_client_data(client_data_json, "webauthn.create", challenge)?;
+		let attestation =
+			ciborium::de::from_reader::<ciborium::Value, _>(attestation_object).map_err(|_| WebauthnError::AttestationObject)?;
+		let authenticator_data = attestation
+			.as_map()
+			.and_then(|map| map.iter().find(|(key, _)| key.as_text() == Some("authData")))
+			.and_then(|(_, value)| value.as_bytes())
+			.ok_or(WebauthnError::AttestationObject)?;
+		let data = AuthenticatorData::parse(authenticator_data)?;
+		self.check_authenticator_data(&data)?;
+		let (credential_id, public_key) = data.credential.ok_or(WebauthnError::AuthenticatorData)?;
+		PublicKey::from_cose(public_key)?;
+
+		Ok(NewPasskey {
+			credential_id: credential_id.to_vec(),
+			public_key: public_key.to_vec(),
+			sign_count: data.sign_count,
+		})
+	}
+
+	/// Check the response of `navigator.credentials.get()` to `challenge`, made with a passkey
+	/// with the COSE-encoded `public_key` that last reported `sign_count`.
+	///
+	/// Returns the new signature counter.
+	pub fn verify_assertion(
+		&self,
+		challenge: &[u8],
+		client_data_json: &[u8],
+		authenticator_data: &[u8],
+		signature: &[u8],
+		public_key: &[u8],
+		sign_count: u32,
+	) -> Result<u32, WebauthnError> {
+		self.check_client_data(client_data_json, "webauthn.get", challenge)?;
+		let data = AuthenticatorData::parse(authenticator_data)?;
+		self.check_authenticator_data(&data)?;
+		let message = [authenticator_data, sha2::Sha256::digest(client_data_json).as_slice()].concat();
+		PublicKey::from_cose(public_key)?.verify(&message, signature)?;
+		// Authenticators that don't count signatures always report 0, e.g. synced passkeys
+		if (data.sign_count != 0 || sign_count != 0) && data.sign_count <= sign_count {
+			return Err(WebauthnError::SignCount);
+		}
+
+		Ok(data.sign_count)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::{encode, RelyingParty, WebauthnError};
+	use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+	use sha2::Digest;
+
+	fn relying_party() -> RelyingParty {
+		RelyingParty {
+			id: "nyantec.com".to_owned(),
+			origin: "https://mail.nyantec.com".to_owned(),
+			name: "nyantec GmbH".to_owned(),
+		}
+	}
+
+	fn cbor(value: &ciborium::Value) -> Vec<u8> {
+		let mut encoded = Vec::new();
+		ciborium::ser::into_writer(value, &mut encoded).unwrap();
+		encoded
+	}
+
+	fn client_data(ceremony: &str, challenge: &[u8], origin: &str) -> Vec<u8> {
+		serde_json::json!({ "type": ceremony, "challenge": encode(challenge), "origin": origin })
+			.to_string()
+			.into_bytes()
+	}
+
+	fn authenticator_data(rp_id: &str, flags: u8, sign_count: u32, credential: Option<(&[u8], &[u8])>) -> Vec<u8> {
+		let mut data = sha2::Sha256::digest(rp_id.as_bytes()).to_vec();
+		data.push(flags);
+		data.extend(sign_count.to_be_bytes());
+		if let Some((credential_id, public_key)) = credential {
+			data.extend([0; 16]);
+			data.extend((credential_id.len() as u16).to_be_bytes());
+			data.extend(credential_id);
+			data.extend(public_key);
+		}
+		data
+	}
+
+	/// A software authenticator, holding a single passkey.
+	enum Authenticator {
+		Es256(EcdsaKeyPair),
+		Ed25519(Ed25519KeyPair),
+	}
+
+	impl Authenticator {
+		fn es256() -> Self {
+			let rng = ring::rand::SystemRandom::new();
+			let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+			Authenticator::Es256(EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng).unwrap())
+		}
+
+		fn ed25519() -> Self {
+			let pkcs8 = Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).unwrap();
+			Authenticator::Ed25519(Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap())
+		}
+
+		fn cose_key(&self) -> Vec<u8> {
+			use ciborium::Value;
+
+			let int = |i: i64| Value::Integer(i.into());
+			cbor(&Value::Map(match self {
+				Authenticator::Es256(key) => {
+					let point = key.public_key().as_ref();
+					vec![
+						(int(1), int(2)),
+						(int(3), int(-7)),
+						(int(-1), int(1)),
+						(int(-2), Value::Bytes(point[1..33].to_vec())),
+						(int(-3), Value::Bytes(point[33..].to_vec())),
+					]
+				}
+				Authenticator::Ed25519(key) => vec![
+					(int(1), int(1)),
+					(int(3), int(-8)),
+					(int(-1), int(6)),
+					(int(-2), Value::Bytes(key.public_key().as_ref().to_vec())),
+				],
+			}))
+		}
+
+		fn attestation_object(&self, rp_id: &str, flags: u8) -> Vec<u8> {
+			use ciborium::Value;
+
+			let data = authenticator_data(rp_id, flags | 0x40, 0, Some((b"passkey", &self.cose_key())));
+			cbor(&Value::Map(vec![
+				(Value::Text("fmt".to_owned()), Value::Text("none".to_owned())),
+				(Value::Text("attStmt".to_owned()), Value::Map(vec![])),
+				(Value::Text("authData".to_owned()), Value::Bytes(data)),
+			]))
+		}
+
+		fn sign(&self, authenticator_data: &[u8], client_data_json: &[u8]) -> Vec<u8> {
+			let message = [authenticator_data, sha2::Sha256::digest(client_data_json).as_slice()].concat();
+			match self {
+				Authenticator::Es256(key) => key.sign(&ring::rand::SystemRandom::new(), &message).unwrap().as_ref().to_vec(),
+				Authenticator::Ed25519(key) => key.sign(&message).as_ref().to_vec(),
+			}
+		}
+	}
+
+	#[test]
+	fn test_registration() {
+		let rp = relying_party();
+		let authenticator = Authenticator::es256();
+		let challenge = super::challenge();
+		let create = client_data("webauthn.create", &challenge, "https://mail.nyantec.com");
+
+		let passkey = rp
+			.verify_registration(&challenge, &create, &authenticator.attestation_object("nyantec.com", 0x05))
+			.unwrap();
+		assert_eq!(passkey.credential_id, b"passkey");
+		assert_eq!(passkey.public_key, authenticator.cose_key());
+		assert_eq!(passkey.sign_count, 0);
+
+		assert_eq!(
+			rp.verify_registration(&super::challenge(), &create, &authenticator.attestation_object("nyantec.com", 0x05)),
+			Err(WebauthnError::Challenge)
+		);
+		assert_eq!(
+			rp.verify_registration(
+				&challenge,
+				&client_data("webauthn.create", &challenge, "https://evil.example"),
+				&authenticator.attestation_object("nyantec.com", 0x05)
+			),
+			Err(WebauthnError::Origin("https://evil.example".to_owned()))
+		);
+		assert!(matches!(
+			rp.verify_registration(
+				&challenge,
+				&client_data("webauthn.get", &challenge, "https://mail.nyantec.com"),
+				&authenticator.attestation_object("nyantec.com", 0x05)
+			),
+			Err(WebauthnError::CeremonyType { .. })
+		));
+		assert_eq!(
+			rp.verify_registration(&challenge, &create, &authenticator.attestation_object("evil.example", 0x05)),
+			Err(WebauthnError::RelyingParty)
+		);
+		assert_eq!(
+			rp.verify_registration(&challenge, &create, &authenticator.attestation_object("nyantec.com", 0x01)),
+			Err(WebauthnError::UserNotVerified)
+		);
+		assert_eq!(rp.verify_registration(&challenge, &create, b"garbage"), Err(WebauthnError::AttestationObject));
+	}
+
+	#[test]
+	fn test_assertion() {
+		let rp = relying_party();
+		for authenticator in [Authenticator::es256(), Authenticator::ed25519()] {
+			let public_key = authenticator.cose_key();
+			let challenge = super::challenge();
+			let get = client_data("webauthn.get", &challenge, "https://mail.nyantec.com");
+			let data = authenticator_data("nyantec.com", 0x05, 7, None);
+			let signature = authenticator.sign(&data, &get);
+
+			assert_eq!(rp.verify_assertion(&challenge, &get, &data, &signature, &public_key, 6), Ok(7));
+			assert_eq!(
+				rp.verify_assertion(&challenge, &get, &data, &signature, &public_key, 7),
+				Err(WebauthnError::SignCount)
+			);
+			assert_eq!(
+				rp.verify_assertion(&challenge, &get, &data, &signature, &Authenticator::ed25519().cose_key(), 6),
+				Err(WebauthnError::Signature)
+			);
+			let mut tampered = data.clone();
+			tampered[36] = 8;
+			assert_eq!(
+				rp.verify_assertion(&challenge, &get, &tampered, &signature, &public_key, 6),
+				Err(WebauthnError::Signature)
+			);
+			assert_eq!(
+				rp.verify_assertion(&super::challenge(), &get, &data, &signature, &public_key, 6),
+				Err(WebauthnError::Challenge)
+			);
+
+			// Passkeys that don't count signatures
+			let data = authenticator_data("nyantec.com", 0x05, 0, None);
+			let signature = authenticator.sign(&data, &get);
+			assert_eq!(rp.verify_assertion(&challenge, &get, &data, &signature, &public_key, 0), Ok(0));
+		}
+	}
+}
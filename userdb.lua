@@ -12,19 +12,71 @@ local http_client = dovecot.http.client {
     debug = true;
 }
 
-function auth_password_verify(request, password)
-   local auth_request = http_client:request {
-	  url = "http://127.0.0.1:3000/api/authenticate";
+-- Empty unless nyanpasswd serves the API on a Unix socket, which identifies us by our UID instead of a token
+local api_socket = "@api_socket@"
+
+-- Read on every request, so that a new token is picked up without restarting Dovecot
+local function api_token()
+   local file = assert(io.open("@token_file@", "r"))
+   local token = file:read("*l")
+   file:close()
+   return token
+end
+
+-- A stream socket for LuaSocket's HTTP client that connects to the API socket whatever the URL says
+local function api_socket_connection()
+   local unix = require "socket.unix"
+   local sock = unix.stream()
+   return setmetatable({
+	  connect = function(self, host, port) return sock:connect(api_socket) end
+   }, {
+	  __index = function(self, method)
+		 return function(self, ...) return sock[method](sock, ...) end
+	  end
+   })
+end
+
+-- POSTs a JSON request to the API, returning the status code and the body of the response
+local function api_request(path, req)
+   local payload = json.encode(req)
+   if api_socket ~= "" then
+	  local http = require "socket.http"
+	  local ltn12 = require "ltn12"
+	  local response = {}
+	  local ok, status = http.request {
+		 url = "http://localhost/api/v1/" .. path;
+		 method = "POST";
+		 headers = {
+			["Content-Type"] = "application/json";
+			["Content-Length"] = #payload;
+		 };
+		 source = ltn12.source.string(payload);
+		 sink = ltn12.sink.table(response);
+		 create = api_socket_connection;
+	  }
+	  if not ok then
+		 return 500, "connecting to " .. api_socket .. " failed: " .. status
+	  end
+	  return status, table.concat(response)
+   end
+
+   local request = http_client:request {
+	  url = "http://127.0.0.1:3000/api/v1/" .. path;
 	  method = "POST";
    }
+   request:add_header("Content-Type", "application/json")
+   request:add_header("Authorization", "Bearer " .. api_token())
+   request:set_payload(payload)
+   local response = request:submit()
+   return response:status(), response:payload()
+end
+
+function auth_password_verify(request, password)
    local req = {
 	  user = request.username,
 	  password = password
    }
-   auth_request:add_header("Content-Type", "application/json")
-   auth_request:set_payload(json.encode(req))
-   local auth_response = auth_request:submit()
-   local resp_status = auth_response:status()
+   local resp_status, payload = api_request("authenticate", req)
 
    if resp_status == 200 then
 	  print("Got HTTP 200!")
@@ -36,27 +88,20 @@ function auth_password_verify(request, password)
    elseif resp_status == 401 then
 	  return dovecot.auth.PASSDB_RESULT_PASSWORD_MISMATCH, "no (non-expired) password matches provided password"
    elseif resp_status == 500 then
-	  return dovecot.auth.PASSDB_RESULT_INTERNAL_FAILURE, auth_response:payload()
+	  return dovecot.auth.PASSDB_RESULT_INTERNAL_FAILURE, payload
    else
 	  return dovecot.auth.PASSDB_RESULT_INTERNAL_FAILURE, "service returned " .. resp_status
    end
 end
 
 function auth_userdb_lookup(request)
-   local lookup_request = http_client:request {
-	  url = "http://127.0.0.1:3000/api/user_lookup";
-	  -- Note: it would be more idiomatic to use GET here.  However,
-	  -- it seems that Dovecot lacks facilities for urlencoding things.
-	  -- This is bad, so we use JSON and POST here.
-	  method = "POST";
-   };
+   -- Note: it would be more idiomatic to use GET here.  However,
+   -- it seems that Dovecot lacks facilities for urlencoding things.
+   -- This is bad, so we use JSON and POST here.
    local req = { user = request.username }
-   lookup_request:add_header("Content-Type", "application/json")
-   lookup_request:set_payload(json.encode(req))
-   local lookup_response = lookup_request:submit()
-   local status = lookup_response:status()
+   local status, payload = api_request("user_lookup", req)
    if status == 200 then
-	  local user = json.decode(lookup_response:payload())
+	  local user = json.decode(payload)
 	  local maildir_location = "maildir:~/Maildir"
 	  local mailhome_location = "@mailhome@/" .. user.id
 	  print("Got HTTP 200 with UUID " .. user.id .. ", mailhome: " .. mailhome_location)